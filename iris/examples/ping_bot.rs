@@ -0,0 +1,33 @@
+//! Runs a server with a bot in `#bots` that answers `!ping` with `pong`.
+//!
+//! Start it with `cargo run --example ping_bot`, then connect any IRC client
+//! to 127.0.0.1:6991, join `#bots` and say `!ping`.
+
+use iris_lib::{
+    bot::BotEvent, config::ServerConfig, connect::ConnectionManager, server::start_server,
+};
+
+fn main() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 6991).expect("port 6991 should be free"),
+        ServerConfig::default(),
+    );
+    println!("Listening on {}", server.local_addr());
+
+    let mut bot = server.add_bot("pingbot").expect("the bot should register");
+    bot.join("#bots").expect("the bot should join #bots");
+
+    while let Ok(event) = bot.next_event(None) {
+        match event {
+            BotEvent::Message { target, text, .. }
+                if text == "!ping" && target.starts_with('#') =>
+            {
+                let _ = bot.say(&target, "pong");
+            }
+            BotEvent::Kicked { channel, .. } => {
+                let _ = bot.join(&channel.0);
+            }
+            _ => {}
+        }
+    }
+}
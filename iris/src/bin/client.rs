@@ -0,0 +1,152 @@
+use clap::Parser;
+use iris_lib::{
+    client::{self, ServerLine},
+    connect::{self, ConnectionRead, ConnectionWrite},
+};
+use std::io::{self, BufRead};
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::thread;
+
+/// A bare-bones IRC client for poking at a server by hand.
+///
+/// Type `/join #channel`, `/part #channel`, `/msg target text` or
+/// `/quit [message]`. Other lines starting with `/` are sent to the server
+/// as they are (minus the `/`), and anything else goes to the channel you
+/// joined last.
+#[derive(Parser)]
+struct Arguments {
+    #[clap(default_value = "127.0.0.1")]
+    ip_address: IpAddr,
+
+    #[clap(default_value = "6991")]
+    port: u16,
+
+    /// Nick to register with.
+    #[clap(long)]
+    nick: String,
+
+    /// Real name to register with. Defaults to the nick.
+    #[clap(long)]
+    real_name: Option<String>,
+}
+
+/// Turns a line the user typed into the protocol line to send.
+/// `channel` is the channel plain text goes to, and is updated on `/join`.
+fn to_command(input: &str, channel: &mut Option<String>) -> Result<String, String> {
+    let Some(command) = input.strip_prefix('/') else {
+        return match channel {
+            Some(channel) => Ok(format!("PRIVMSG {channel} :{input}")),
+            None => Err("Join a channel first, or use /msg.".to_string()),
+        };
+    };
+    let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+    let rest = rest.trim();
+    match name.to_lowercase().as_str() {
+        "join" if !rest.is_empty() => {
+            *channel = Some(rest.to_string());
+            Ok(format!("JOIN {rest}"))
+        }
+        "part" => match (rest, channel.as_deref()) {
+            ("", Some(current)) => Ok(format!("PART {current}")),
+            ("", None) => Err("Usage: /part #channel".to_string()),
+            (rest, _) => Ok(format!("PART {rest}")),
+        },
+        "msg" => match rest.split_once(' ') {
+            Some((target, text)) => Ok(format!("PRIVMSG {target} :{text}")),
+            None => Err("Usage: /msg target text".to_string()),
+        },
+        "quit" if rest.is_empty() => Ok("QUIT".to_string()),
+        "quit" => Ok(format!("QUIT :{rest}")),
+        "join" => Err("Usage: /join #channel".to_string()),
+        _ => Ok(command.to_string()),
+    }
+}
+
+/// Formats a line from the server for reading.
+fn pretty(line: &str) -> String {
+    let parsed = ServerLine::from(line);
+    let source = parsed.source_nick().unwrap_or("");
+    let param = |index: usize| parsed.params.get(index).map_or("", String::as_str);
+    match parsed.command.as_str() {
+        "PRIVMSG" if param(0).starts_with(['#', '@', '+']) => {
+            format!("[{}] <{source}> {}", param(0), param(1))
+        }
+        "PRIVMSG" => format!("*{source}* {}", param(1)),
+        "JOIN" => format!("[{}] * {source} joined", param(0)),
+        "PART" => format!("[{}] * {source} left", param(0)),
+        "QUIT" => format!("* {source} quit ({})", param(0)),
+        "KICK" => format!(
+            "[{}] * {} was kicked by {source} ({})",
+            param(0),
+            param(1),
+            param(2)
+        ),
+        "MODE" => format!("* {source} set mode {}", parsed.params.join(" ")),
+        "PONG" => format!("* PONG {}", parsed.params.last().map_or("", String::as_str)),
+        // Numerics and notices: everything after our own nick
+        _ => format!(
+            "-{source}- {}",
+            parsed.params.get(1..).unwrap_or_default().join(" ")
+        ),
+    }
+}
+
+/// Prints everything the server sends until the connection closes.
+fn print_incoming(mut conn_read: ConnectionRead) {
+    loop {
+        match conn_read.read_message() {
+            Ok(line) => println!("{}", pretty(&line)),
+            Err(err) if err.is_fatal() => {
+                println!("* Disconnected: {err}.");
+                process::exit(0);
+            }
+            Err(err) => println!("* Bad line from server: {err}"),
+        }
+    }
+}
+
+/// Sends what the user types until stdin closes or they quit.
+fn send_input(mut conn_write: ConnectionWrite) {
+    let mut channel = None;
+    for input in io::stdin().lock().lines() {
+        let Ok(input) = input else { break };
+        if input.trim().is_empty() {
+            continue;
+        }
+        match to_command(&input, &mut channel) {
+            Ok(command) => {
+                if client::send(&mut conn_write, &command).is_err() {
+                    break;
+                }
+            }
+            Err(usage) => println!("* {usage}"),
+        }
+    }
+    let _ = client::send(&mut conn_write, "QUIT");
+}
+
+fn main() {
+    let arguments = Arguments::parse();
+    let address = SocketAddr::new(arguments.ip_address, arguments.port);
+    let real_name = arguments.real_name.as_deref().unwrap_or(&arguments.nick);
+
+    let (mut conn_read, mut conn_write) = match connect::connect(address) {
+        Ok(connection) => connection,
+        Err(err) => {
+            eprintln!("Could not connect to {address}: {err}");
+            process::exit(1);
+        }
+    };
+    match client::register(&mut conn_read, &mut conn_write, &arguments.nick, real_name) {
+        Ok(()) => println!("* Connected to {address} as {}.", arguments.nick),
+        Err(err) => {
+            eprintln!("Could not register: {err}");
+            process::exit(1);
+        }
+    }
+
+    // Incoming lines are printed as they arrive, while we wait on stdin
+    thread::spawn(move || print_incoming(conn_read));
+    send_input(conn_write);
+}
@@ -0,0 +1,216 @@
+use clap::Parser;
+use iris_lib::{
+    client,
+    connect::{self, ConnectionRead, ConnectionWrite},
+};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Opens many client connections to an IRIS server, spreads them across
+/// channels and sends channel messages at a fixed rate, then reports how
+/// quickly (and whether) each message reached every member.
+#[derive(Parser)]
+struct Arguments {
+    #[clap(default_value = "127.0.0.1")]
+    ip_address: IpAddr,
+
+    #[clap(default_value = "6991")]
+    port: u16,
+
+    /// Number of clients to connect.
+    #[clap(long, default_value = "50")]
+    clients: usize,
+
+    /// Number of channels the clients are spread across.
+    #[clap(long, default_value = "5")]
+    channels: usize,
+
+    /// Channel messages sent per second, across all clients.
+    #[clap(long, default_value = "100")]
+    rate: u32,
+
+    /// Seconds to keep sending for.
+    #[clap(long, default_value = "10")]
+    duration: u64,
+
+    /// Seconds to wait for stragglers after the last message is sent.
+    #[clap(long, default_value = "2")]
+    drain: u64,
+}
+
+/// Counters shared between the driver and every client's reader.
+#[derive(Default)]
+struct Stats {
+    /// Time from sending each message to it arriving, one per delivery.
+    latencies: Mutex<Vec<Duration>>,
+    /// Connections the server dropped, or that never finished joining.
+    disconnects: AtomicUsize,
+}
+
+struct Client {
+    nick: String,
+    channel: String,
+    /// Which of the channels `channel` is, counting from 0.
+    channel_index: usize,
+    conn_write: ConnectionWrite,
+}
+
+/// Connects `nick`, registers them and joins `channel`.
+fn join(
+    address: SocketAddr,
+    nick: &str,
+    channel_index: usize,
+) -> Result<(Client, ConnectionRead), Box<dyn Error>> {
+    let channel = format!("#stress{channel_index}");
+    let (mut conn_read, mut conn_write) = connect::connect(address)?;
+    client::register(&mut conn_read, &mut conn_write, nick, nick)?;
+    client::send(&mut conn_write, &format!("JOIN {channel}"))?;
+    client::wait_until(&mut conn_read, |line| {
+        let line = client::ServerLine::from(line);
+        line.source_nick() == Some(nick)
+            && line.command == "JOIN"
+            && line.params == [channel.as_str()]
+    })?;
+
+    let client = Client {
+        nick: nick.to_string(),
+        channel,
+        channel_index,
+        conn_write,
+    };
+    Ok((client, conn_read))
+}
+
+/// Records the latency of every stress message `conn_read` receives.
+fn read_deliveries(mut conn_read: ConnectionRead, start: Instant, stats: Arc<Stats>) {
+    loop {
+        let message = match conn_read.read_message() {
+            Ok(message) => message,
+            Err(err) if err.is_fatal() => {
+                stats.disconnects.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(_) => continue,
+        };
+        // Stress messages carry the microseconds since `start` they were sent at
+        let sent_at = message
+            .split_once(" PRIVMSG ")
+            .and_then(|(_, rest)| rest.split_once(" :"))
+            .and_then(|(_, text)| text.split_once(' '))
+            .and_then(|(_, sent_at)| sent_at.parse().ok())
+            .map(Duration::from_micros);
+        if let Some(sent_at) = sent_at {
+            let latency = start.elapsed().saturating_sub(sent_at);
+            stats.latencies.lock().unwrap().push(latency);
+        }
+    }
+}
+
+/// The value below which `percent` of the sorted `latencies` fall.
+fn percentile(latencies: &[Duration], percent: usize) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = (latencies.len() * percent / 100).min(latencies.len() - 1);
+    latencies[index]
+}
+
+fn main() {
+    let arguments = Arguments::parse();
+    let address = SocketAddr::new(arguments.ip_address, arguments.port);
+    let channel_count = arguments.channels.max(1);
+    let start = Instant::now();
+    let stats = Arc::new(Stats::default());
+
+    println!(
+        "Connecting {} clients to {} across {} channels...",
+        arguments.clients, address, channel_count
+    );
+    let mut clients = Vec::new();
+    let mut members = vec![0; channel_count];
+    for index in 0..arguments.clients {
+        let nick = format!("stress{index}");
+        match join(address, &nick, index % channel_count) {
+            Ok((client, conn_read)) => {
+                let (start, stats) = (start, stats.clone());
+                thread::spawn(move || read_deliveries(conn_read, start, stats));
+                members[client.channel_index] += 1;
+                clients.push(client);
+            }
+            Err(err) => {
+                eprintln!("{nick} could not join: {err}");
+                stats.disconnects.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    if clients.is_empty() {
+        eprintln!("No clients connected.");
+        return;
+    }
+
+    println!(
+        "Sending {} messages/second for {} seconds...",
+        arguments.rate, arguments.duration
+    );
+    let interval = Duration::from_secs(1) / arguments.rate.max(1);
+    let send_start = Instant::now();
+    let send_until = send_start + Duration::from_secs(arguments.duration);
+    let mut next_send = send_start;
+    let client_count = clients.len();
+    let (mut sent, mut expected) = (0_usize, 0_usize);
+    while next_send < send_until {
+        thread::sleep(next_send.saturating_duration_since(Instant::now()));
+        let client = &mut clients[sent % client_count];
+        let line = format!(
+            "PRIVMSG {} :{} {}",
+            client.channel,
+            sent,
+            start.elapsed().as_micros()
+        );
+        if client::send(&mut client.conn_write, &line).is_ok() {
+            expected += members[client.channel_index];
+        } else {
+            eprintln!("{} failed to send", client.nick);
+        }
+        sent += 1;
+        next_send += interval;
+    }
+    let elapsed = send_start.elapsed();
+    thread::sleep(Duration::from_secs(arguments.drain));
+
+    let mut latencies = stats.latencies.lock().unwrap().clone();
+    latencies.sort();
+    let delivered = latencies.len();
+    println!();
+    println!("Clients connected:   {}", clients.len());
+    println!("Messages sent:       {sent}");
+    println!("Deliveries expected: {expected}");
+    println!("Deliveries received: {delivered}");
+    println!(
+        "Dropped:             {}",
+        expected.saturating_sub(delivered)
+    );
+    println!(
+        "Disconnects:         {}",
+        stats.disconnects.load(Ordering::Relaxed)
+    );
+    println!(
+        "Throughput:          {:.0} deliveries/second",
+        delivered as f64 / elapsed.as_secs_f64()
+    );
+    println!("Latency p50:         {:?}", percentile(&latencies, 50));
+    println!("Latency p90:         {:?}", percentile(&latencies, 90));
+    println!("Latency p99:         {:?}", percentile(&latencies, 99));
+    println!(
+        "Latency max:         {:?}",
+        latencies.last().copied().unwrap_or_default()
+    );
+
+    for client in clients.iter_mut() {
+        let _ = client::send(&mut client.conn_write, "QUIT :Stress test over");
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::types::{ErrorType, Nick};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Registered accounts, each owning the nick it was registered under.
+///
+/// Passwords are never stored: each account keeps an HMAC of its password
+/// keyed by the account name in lowercase, and checks are done in constant
+/// time.
+/// Accounts may also list the fingerprints of client certificates that
+/// identify them.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    accounts: HashMap<Nick, Vec<u8>>,
+    /// Each registered certificate fingerprint, with the account it belongs to.
+    fingerprints: HashMap<String, Nick>,
+    /// Accounts restored from version 1 snapshots, whose HMACs are keyed by
+    /// the nick exactly as it was registered.
+    legacy_digests: HashSet<Nick>,
+}
+
+impl Accounts {
+    /// Registers `nick` as an account protected by `password`.
+    pub fn register(&mut self, nick: Nick, password: &str) -> Result<(), ErrorType> {
+        if self.accounts.contains_key(&nick) {
+            return Err(ErrorType::AccountExists);
+        }
+        let digest = password_mac(&nick.0.to_ascii_lowercase(), password)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        self.accounts.insert(nick, digest);
+        Ok(())
+    }
+
+    /// Checks `password` against the account owning `nick`. Nicks not owned
+    /// by any account never verify.
+    pub fn verify(&self, nick: &Nick, password: &str) -> Result<(), ErrorType> {
+        self.accounts
+            .get_key_value(nick)
+            .filter(|(owner, digest)| {
+                let key = if self.legacy_digests.contains(*owner) {
+                    owner.0.clone()
+                } else {
+                    nick.0.to_ascii_lowercase()
+                };
+                password_mac(&key, password).verify_slice(digest).is_ok()
+            })
+            .map(|_| ())
+            .ok_or(ErrorType::PasswdMismatch)
+    }
+
+    /// Lets a certificate with `fingerprint` identify `nick`'s account. A
+    /// fingerprint can only belong to one account.
+    pub fn add_fingerprint(&mut self, nick: &Nick, fingerprint: String) -> Result<(), CertError> {
+        if !is_fingerprint(&fingerprint) {
+            return Err(CertError::Invalid);
+        }
+        match self.fingerprints.get(&fingerprint) {
+            Some(owner) if owner == nick => Err(CertError::Exists),
+            Some(_) => Err(CertError::InUse),
+            None => {
+                self.fingerprints.insert(fingerprint, nick.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Stops `fingerprint` from identifying `nick`'s account.
+    pub fn remove_fingerprint(&mut self, nick: &Nick, fingerprint: &str) -> Result<(), CertError> {
+        match self.fingerprints.get(fingerprint) {
+            Some(owner) if owner == nick => {
+                self.fingerprints.remove(fingerprint);
+                Ok(())
+            }
+            _ => Err(CertError::NotFound),
+        }
+    }
+
+    /// The fingerprints registered to `nick`'s account, in order.
+    pub fn fingerprints(&self, nick: &Nick) -> Vec<&str> {
+        let mut fingerprints: Vec<&str> = self
+            .fingerprints
+            .iter()
+            .filter(|(_, owner)| *owner == nick)
+            .map(|(fingerprint, _)| fingerprint.as_str())
+            .collect();
+        fingerprints.sort();
+        fingerprints
+    }
+
+    /// The account a certificate with `fingerprint` identifies, if any.
+    pub fn account_for_fingerprint(&self, fingerprint: &str) -> Option<&Nick> {
+        self.fingerprints.get(fingerprint)
+    }
+
+    /// Every account with the HMAC of its password, for saving elsewhere.
+    pub fn digests(&self) -> impl Iterator<Item = (&Nick, &[u8])> {
+        self.accounts
+            .iter()
+            .map(|(nick, digest)| (nick, digest.as_slice()))
+    }
+
+    /// Whether `nick`'s HMAC is keyed by the nick as registered, as version 1
+    /// snapshots saved it, rather than in lowercase.
+    pub fn has_legacy_digest(&self, nick: &Nick) -> bool {
+        self.legacy_digests.contains(nick)
+    }
+
+    /// Restores an account saved from [`Accounts::digests`], replacing any
+    /// account already owning `nick`. See [`Accounts::has_legacy_digest`].
+    pub fn restore(&mut self, nick: Nick, digest: Vec<u8>, legacy_digest: bool) {
+        self.legacy_digests.remove(&nick);
+        if legacy_digest {
+            self.legacy_digests.insert(nick.clone());
+        }
+        self.accounts.insert(nick, digest);
+    }
+}
+
+/// Why a CERT change was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertError {
+    /// Not 64 lowercase hex digits.
+    Invalid,
+    /// The fingerprint is already on this account.
+    Exists,
+    /// The fingerprint belongs to another account.
+    InUse,
+    /// The fingerprint isn't on this account.
+    NotFound,
+}
+
+impl Display for CertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertError::Invalid => write!(f, "is not a SHA-256 fingerprint"),
+            CertError::Exists => write!(f, "is already on your account"),
+            CertError::InUse => write!(f, "belongs to another account"),
+            CertError::NotFound => write!(f, "is not on your account"),
+        }
+    }
+}
+
+/// The SHA-256 fingerprint of a DER-encoded certificate, as lowercase hex.
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Whether `fingerprint` looks like one from [`certificate_fingerprint`].
+fn is_fingerprint(fingerprint: &str) -> bool {
+    fingerprint.len() == 64
+        && fingerprint
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The HMAC of `password` keyed by `key`. That's the account name in
+/// lowercase, so it checks out however the nick is capitalised, except for
+/// legacy digests.
+fn password_mac(key: &str, password: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(password.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_verify() {
+        let mut accounts = Accounts::default();
+        let alice = Nick("alice".to_string());
+
+        assert_eq!(accounts.register(alice.clone(), "hunter2"), Ok(()));
+        assert_eq!(
+            accounts.register(alice.clone(), "other"),
+            Err(ErrorType::AccountExists)
+        );
+        assert_eq!(accounts.verify(&alice, "hunter2"), Ok(()));
+        assert_eq!(
+            accounts.verify(&alice, "hunter3"),
+            Err(ErrorType::PasswdMismatch)
+        );
+        assert_eq!(
+            accounts.verify(&Nick("bob".to_string()), "hunter2"),
+            Err(ErrorType::PasswdMismatch)
+        );
+    }
+
+    #[test]
+    fn test_restore_from_digests() {
+        let mut accounts = Accounts::default();
+        let alice = Nick("alice".to_string());
+        accounts.register(alice.clone(), "hunter2").unwrap();
+
+        let mut restored = Accounts::default();
+        for (nick, digest) in accounts.digests() {
+            let legacy_digest = accounts.has_legacy_digest(nick);
+            restored.restore(nick.clone(), digest.to_vec(), legacy_digest);
+        }
+        assert_eq!(restored.verify(&alice, "hunter2"), Ok(()));
+        assert_eq!(
+            restored.verify(&alice, "hunter3"),
+            Err(ErrorType::PasswdMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_ignores_case() {
+        let mut accounts = Accounts::default();
+        accounts
+            .register(Nick("Alice".to_string()), "hunter2")
+            .unwrap();
+        for nick in ["Alice", "alice", "ALICE"] {
+            assert_eq!(accounts.verify(&Nick(nick.to_string()), "hunter2"), Ok(()));
+            assert_eq!(
+                accounts.verify(&Nick(nick.to_string()), "hunter3"),
+                Err(ErrorType::PasswdMismatch)
+            );
+        }
+    }
+
+    #[test]
+    fn test_legacy_digests_are_keyed_as_registered() {
+        let digest = password_mac("Alice", "hunter2")
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        let mut accounts = Accounts::default();
+        accounts.restore(Nick("Alice".to_string()), digest, true);
+        assert!(accounts.has_legacy_digest(&Nick("alice".to_string())));
+        assert_eq!(
+            accounts.verify(&Nick("alice".to_string()), "hunter2"),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.verify(&Nick("alice".to_string()), "hunter3"),
+            Err(ErrorType::PasswdMismatch)
+        );
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let mut accounts = Accounts::default();
+        let alice = Nick("alice".to_string());
+        let bob = Nick("bob".to_string());
+        let fingerprint = certificate_fingerprint(b"abc");
+        assert_eq!(
+            fingerprint,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        assert_eq!(
+            accounts.add_fingerprint(&alice, "abc".to_string()),
+            Err(CertError::Invalid)
+        );
+        assert_eq!(
+            accounts.add_fingerprint(&alice, fingerprint.clone()),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.add_fingerprint(&alice, fingerprint.clone()),
+            Err(CertError::Exists)
+        );
+        assert_eq!(
+            accounts.add_fingerprint(&bob, fingerprint.clone()),
+            Err(CertError::InUse)
+        );
+        assert_eq!(accounts.account_for_fingerprint(&fingerprint), Some(&alice));
+        assert_eq!(accounts.fingerprints(&alice), [fingerprint.as_str()]);
+
+        assert_eq!(
+            accounts.remove_fingerprint(&bob, &fingerprint),
+            Err(CertError::NotFound)
+        );
+        assert_eq!(accounts.remove_fingerprint(&alice, &fingerprint), Ok(()));
+        assert_eq!(accounts.account_for_fingerprint(&fingerprint), None);
+        assert!(accounts.fingerprints(&alice).is_empty());
+    }
+}
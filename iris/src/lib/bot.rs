@@ -0,0 +1,180 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    client::{register, send, ClientError, ServerLine},
+    connect::{ConnectionError, ConnectionRead, ConnectionWrite},
+    types::{Channel, Nick},
+};
+
+/// Something that happened to a bot which it may want to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotEvent {
+    /// A PRIVMSG sent to the bot, or to a channel it is in.
+    Message {
+        from: Nick,
+        /// The bot's own nick for private messages, otherwise the channel.
+        target: String,
+        text: String,
+    },
+    /// The bot was kicked from `channel`.
+    Kicked {
+        channel: Channel,
+        by: Nick,
+        reason: String,
+    },
+}
+
+impl BotEvent {
+    /// The event `line` describes for `nick`, if it is one bots care about.
+    fn from_line(line: &str, nick: &Nick) -> Option<Self> {
+        let line = ServerLine::from(line);
+        let from = Nick(line.source_nick()?.to_string());
+        let ServerLine {
+            command,
+            mut params,
+            ..
+        } = line;
+        match command.as_str() {
+            // Channel messages are echoed back, but the bot knows what it said
+            "PRIVMSG" if params.len() == 2 && from != *nick => {
+                let text = params.pop()?;
+                let target = params.pop()?;
+                Some(BotEvent::Message { from, target, text })
+            }
+            "KICK" if params.len() == 3 && params[1] == nick.0 => {
+                let reason = params.pop()?;
+                params.pop();
+                let channel = Channel(params.pop()?);
+                Some(BotEvent::Kicked {
+                    channel,
+                    by: from,
+                    reason,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A user that lives inside the server process. To everyone else it looks
+/// like any other client, but it talks to the server over an in-process
+/// connection. It leaves the server when dropped.
+pub struct BotHandle {
+    nick: Nick,
+    conn_read: ConnectionRead,
+    conn_write: ConnectionWrite,
+    /// Events read while waiting for something else.
+    backlog: VecDeque<BotEvent>,
+}
+
+impl BotHandle {
+    /// Registers as `nick` over the client end of an in-process connection.
+    pub(crate) fn register(
+        mut conn_read: ConnectionRead,
+        mut conn_write: ConnectionWrite,
+        nick: &str,
+    ) -> Result<Self, ClientError> {
+        let nick = Nick::parse(nick).map_err(|err| ClientError::Rejected(err.to_string()))?;
+        register(&mut conn_read, &mut conn_write, &nick.0, &nick.0)?;
+        Ok(Self {
+            nick,
+            conn_read,
+            conn_write,
+            backlog: VecDeque::new(),
+        })
+    }
+
+    pub fn nick(&self) -> &Nick {
+        &self.nick
+    }
+
+    /// Joins `channel`, returning once the server has confirmed it.
+    pub fn join(&mut self, channel: &str) -> Result<(), ClientError> {
+        send(&mut self.conn_write, &format!("JOIN {channel}"))?;
+        self.conn_read.set_read_timeout(None);
+        loop {
+            let line = self.conn_read.read_message()?;
+            let parsed = ServerLine::from(line.as_str());
+            if parsed.source_nick() == Some(self.nick.0.as_str())
+                && parsed.command == "JOIN"
+                && parsed.params == [channel]
+            {
+                return Ok(());
+            } else if parsed.is_error() {
+                return Err(ClientError::Rejected(line));
+            } else if let Some(event) = BotEvent::from_line(&line, &self.nick) {
+                self.backlog.push_back(event);
+            }
+        }
+    }
+
+    /// Leaves `channel`.
+    pub fn part(&mut self, channel: &str) -> Result<(), ConnectionError> {
+        send(&mut self.conn_write, &format!("PART {channel}"))
+    }
+
+    /// Sends `text` to `target`, which is a nick or a channel.
+    pub fn say(&mut self, target: &str, text: &str) -> Result<(), ConnectionError> {
+        send(&mut self.conn_write, &format!("PRIVMSG {target} :{text}"))
+    }
+
+    /// Waits for the next event. Gives up with [`ConnectionError::Timeout`]
+    /// after `timeout`, or waits forever when it is `None`.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<BotEvent, ConnectionError> {
+        if let Some(event) = self.backlog.pop_front() {
+            return Ok(event);
+        }
+        self.conn_read.set_read_timeout(timeout);
+        loop {
+            let line = self.conn_read.read_message()?;
+            if let Some(event) = BotEvent::from_line(&line, &self.nick) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+impl Drop for BotHandle {
+    fn drop(&mut self) {
+        // The server cleans up after the bot as it would a dropped client
+        self.conn_write.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_from_lines() {
+        let bot = Nick("pingbot".to_string());
+        assert_eq!(
+            BotEvent::from_line(":tom!tom@127.0.0.1 PRIVMSG #rust :!ping", &bot),
+            Some(BotEvent::Message {
+                from: Nick("tom".to_string()),
+                target: "#rust".to_string(),
+                text: "!ping".to_string(),
+            })
+        );
+        assert_eq!(
+            BotEvent::from_line(":iris-server KICK #rust pingbot :Flooding", &bot),
+            Some(BotEvent::Kicked {
+                channel: Channel("#rust".to_string()),
+                by: Nick("iris-server".to_string()),
+                reason: "Flooding".to_string(),
+            })
+        );
+        assert_eq!(
+            BotEvent::from_line(":iris-server KICK #rust tom :Flooding", &bot),
+            None
+        );
+        assert_eq!(
+            BotEvent::from_line(":pingbot!pingbot@127.0.0.1 PRIVMSG #rust :pong", &bot),
+            None
+        );
+        assert_eq!(
+            BotEvent::from_line(":tom!tom@127.0.0.1 JOIN #rust", &bot),
+            None
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    process,
+    time::{Duration, Instant},
+};
+
+use crate::connect::{connect_timeout, ConnectionError, ConnectionRead, ConnectionWrite};
+
+/// Why a client-side exchange with the server did not go to plan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// No connection could be made to the server.
+    Unreachable(String),
+    /// The connection failed part way through.
+    Connection(ConnectionError),
+    /// The server replied with an error numeric, e.g. because a nick was taken.
+    Rejected(String),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Unreachable(err) => write!(f, "unreachable: {err}"),
+            ClientError::Connection(err) => write!(f, "{err}"),
+            ClientError::Rejected(line) => write!(f, "{line}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ConnectionError> for ClientError {
+    fn from(err: ConnectionError) -> Self {
+        ClientError::Connection(err)
+    }
+}
+
+/// A line received from the server, split into its parts.
+/// For example: `:tom!tom@127.0.0.1 PRIVMSG #rust :hello everyone`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLine {
+    /// Who the line came from, without the leading `:`.
+    pub source: Option<String>,
+    pub command: String,
+    /// The parameters, with the `:` taken off the trailing one.
+    pub params: Vec<String>,
+}
+
+impl From<&str> for ServerLine {
+    fn from(line: &str) -> Self {
+        let (source, rest) = match line.strip_prefix(':') {
+            Some(line) => match line.split_once(' ') {
+                Some((source, rest)) => (Some(source.to_string()), rest),
+                None => (Some(line.to_string()), ""),
+            },
+            None => (None, line),
+        };
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => match rest.strip_prefix(':') {
+                Some(trailing) => ("", Some(trailing)),
+                None => (rest, None),
+            },
+        };
+
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next().unwrap_or_default().to_string();
+        let mut params: Vec<String> = words.map(str::to_string).collect();
+        params.extend(trailing.map(str::to_string));
+        ServerLine {
+            source,
+            command,
+            params,
+        }
+    }
+}
+
+impl ServerLine {
+    /// The nick the line came from: the source up to any `!user@host`.
+    pub fn source_nick(&self) -> Option<&str> {
+        let source = self.source.as_deref()?;
+        Some(source.split_once('!').map_or(source, |(nick, _)| nick))
+    }
+
+    /// Whether this is an error numeric (400 and up) from the server.
+    /// Servers can go by any name, so only the command is checked.
+    pub fn is_error(&self) -> bool {
+        self.source.is_some()
+            && self
+                .command
+                .parse::<u16>()
+                .is_ok_and(|numeric| numeric >= 400)
+    }
+}
+
+/// Sends `line` to the server, adding the trailing CRLF.
+pub fn send(conn_write: &mut ConnectionWrite, line: &str) -> Result<(), ConnectionError> {
+    conn_write.write_message(&format!("{line}\r\n"))
+}
+
+/// Reads lines until one starts with `prefix`, returning it. Other lines are
+/// skipped, unless they are errors.
+pub fn wait_for(conn_read: &mut ConnectionRead, prefix: &str) -> Result<String, ClientError> {
+    wait_until(conn_read, |message| message.starts_with(prefix))
+}
+
+/// Reads lines until one satisfies `done`, returning it. Other lines are
+/// skipped, unless they are errors.
+pub fn wait_until(
+    conn_read: &mut ConnectionRead,
+    done: impl Fn(&str) -> bool,
+) -> Result<String, ClientError> {
+    loop {
+        let message = conn_read.read_message()?;
+        if done(&message) {
+            return Ok(message);
+        } else if ServerLine::from(message.as_str()).is_error() {
+            return Err(ClientError::Rejected(message));
+        }
+    }
+}
+
+/// Registers as `nick`, returning once the server has welcomed us and sent
+/// its message of the day, or said it has none.
+pub fn register(
+    conn_read: &mut ConnectionRead,
+    conn_write: &mut ConnectionWrite,
+    nick: &str,
+    real_name: &str,
+) -> Result<(), ClientError> {
+    send(conn_write, &format!("NICK {nick}"))?;
+    send(conn_write, &format!("USER {nick} 0 * :{real_name}"))?;
+    wait_until(conn_read, |message| {
+        ServerLine::from(message).command == "001"
+    })?;
+    wait_until(conn_read, |message| {
+        matches!(ServerLine::from(message).command.as_str(), "376" | "422")
+    })?;
+    Ok(())
+}
+
+/// Checks the server at `address` is answering: connects, registers under
+/// a throwaway nick, waits for a PONG to a PING, then quits and waits for
+/// the server to hang up. Fails if any step has not finished once `timeout`
+/// has passed since the start.
+pub fn check(address: SocketAddr, timeout: Duration) -> Result<(), ClientError> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || {
+        // A zero timeout would mean waiting forever
+        Some(deadline.saturating_duration_since(Instant::now())).filter(|left| !left.is_zero())
+    };
+
+    let (mut conn_read, mut conn_write) = connect_timeout(address, timeout)
+        .map_err(|err| ClientError::Unreachable(err.to_string()))?;
+    conn_write.set_write_timeout(Some(timeout));
+
+    // Random, so checks run side by side don't collide over the nick
+    let nick = format!(
+        "chk{:08x}",
+        RandomState::new().build_hasher().finish() as u32
+    );
+    conn_read.set_read_timeout(Some(remaining().ok_or(ConnectionError::Timeout)?));
+    register(&mut conn_read, &mut conn_write, &nick, "iris health check")?;
+
+    let token = format!("check-{}", process::id());
+    send(&mut conn_write, &format!("PING {token}"))?;
+    conn_read.set_read_timeout(Some(remaining().ok_or(ConnectionError::Timeout)?));
+    wait_until(&mut conn_read, |message| {
+        let line = ServerLine::from(message);
+        line.command == "PONG" && line.params.last() == Some(&token)
+    })?;
+
+    // Only once the server has hung up is the nick free for the next check
+    send(&mut conn_write, "QUIT :Health check")?;
+    loop {
+        conn_read.set_read_timeout(Some(remaining().ok_or(ConnectionError::Timeout)?));
+        match conn_read.read_message() {
+            Ok(message) if ServerLine::from(message.as_str()).command == "ERROR" => return Ok(()),
+            Err(err) if err.is_fatal() => return Ok(()),
+            Err(ConnectionError::Timeout) => return Err(ConnectionError::Timeout.into()),
+            Ok(_) | Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_line() {
+        assert_eq!(
+            ServerLine::from(":tom PRIVMSG #rust :hello everyone"),
+            ServerLine {
+                source: Some("tom".to_string()),
+                command: "PRIVMSG".to_string(),
+                params: vec!["#rust".to_string(), "hello everyone".to_string()],
+            }
+        );
+        assert_eq!(
+            ServerLine::from("PONG :hello"),
+            ServerLine {
+                source: None,
+                command: "PONG".to_string(),
+                params: vec!["hello".to_string()],
+            }
+        );
+        assert_eq!(
+            ServerLine::from(":tom JOIN #rust"),
+            ServerLine {
+                source: Some("tom".to_string()),
+                command: "JOIN".to_string(),
+                params: vec!["#rust".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_source_nick() {
+        let source_nick = |line| ServerLine::from(line).source_nick().map(str::to_string);
+        assert_eq!(
+            source_nick(":tom!thomas@127.0.0.1 PRIVMSG #rust :hi"),
+            Some("tom".to_string())
+        );
+        assert_eq!(
+            source_nick(":iris-server 001 tom :Welcome!"),
+            Some("iris-server".to_string())
+        );
+        assert_eq!(source_nick("PING :iris-server"), None);
+    }
+
+    #[test]
+    fn test_is_error() {
+        assert!(ServerLine::from(":iris-server 433 :Nickname is already registered").is_error());
+        assert!(!ServerLine::from(":iris-server 001 tom :Welcome!").is_error());
+        assert!(ServerLine::from(":irc.example.net 401 :No such nick/channel").is_error());
+        assert!(!ServerLine::from(":tom PRIVMSG ann :433").is_error());
+    }
+}
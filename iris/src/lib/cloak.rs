@@ -0,0 +1,101 @@
+use std::net::IpAddr;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Suffix appended to every cloaked hostname.
+pub const CLOAK_SUFFIX: &str = "ip";
+
+/// Derives a stable cloaked hostname for `address` using `secret`.
+///
+/// The cloak is made of three labels, hashing progressively shorter
+/// prefixes of the address (the full address, then the /24 and /16 for
+/// IPv4, or the /64 and /48 for IPv6). This keeps enough structure that a
+/// ban on `*!*@*.<label>.<label>.ip` still covers a whole network, without
+/// ever revealing the original octets.
+pub fn cloak_host(secret: &str, address: IpAddr) -> String {
+    let prefixes = match address {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            [
+                format!("{}.{}.{}.{}", o[0], o[1], o[2], o[3]),
+                format!("{}.{}.{}", o[0], o[1], o[2]),
+                format!("{}.{}", o[0], o[1]),
+            ]
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            [
+                format!("{v6}"),
+                format!("{:x}:{:x}:{:x}:{:x}", s[0], s[1], s[2], s[3]),
+                format!("{:x}:{:x}:{:x}", s[0], s[1], s[2]),
+            ]
+        }
+    };
+
+    let labels = prefixes
+        .iter()
+        .map(|prefix| cloak_label(secret, prefix))
+        .collect::<Vec<_>>();
+
+    format!("{}.{CLOAK_SUFFIX}", labels.join("."))
+}
+
+/// Hashes one address prefix into an 8 character hex label.
+fn cloak_label(secret: &str, prefix: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(prefix.as_bytes());
+    mac.finalize().into_bytes()[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloak_is_deterministic() {
+        let address: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_eq!(cloak_host("secret", address), cloak_host("secret", address));
+    }
+
+    #[test]
+    fn test_cloak_differs_across_addresses() {
+        let first = cloak_host("secret", "203.0.113.77".parse().unwrap());
+        let second = cloak_host("secret", "203.0.113.78".parse().unwrap());
+        let other_net = cloak_host("secret", "198.51.100.77".parse().unwrap());
+
+        assert_ne!(first, second);
+        assert_ne!(first, other_net);
+        // Same /24, so everything but the first label is shared.
+        assert_eq!(
+            first.split_once('.').unwrap().1,
+            second.split_once('.').unwrap().1
+        );
+    }
+
+    #[test]
+    fn test_cloak_differs_across_secrets() {
+        let address: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_ne!(cloak_host("secret", address), cloak_host("other", address));
+    }
+
+    #[test]
+    fn test_cloak_hides_octets() {
+        let cloak = cloak_host("secret", "203.0.113.77".parse().unwrap());
+        assert!(cloak.ends_with(".ip"));
+        assert!(!cloak.contains("203.0.113"));
+        for label in cloak.split('.') {
+            assert!(!["203", "0", "113", "77"].contains(&label));
+        }
+
+        let cloak = cloak_host("secret", "2001:db8::1".parse().unwrap());
+        assert!(!cloak.contains("2001"));
+        assert!(!cloak.contains("db8"));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::{Condvar, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use time::{OffsetDateTime, UtcOffset};
+
+/// The machine's offset from UTC. It can only be looked up safely while the
+/// process has a single thread, so it is found once at startup.
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+/// Looks up the machine's offset from UTC for [`local_time`]. Call this
+/// before starting any threads.
+pub fn find_local_offset() {
+    if let Ok(offset) = UtcOffset::current_local_offset() {
+        let _ = LOCAL_OFFSET.set(offset);
+    }
+}
+
+/// The date and time now, in the machine's time zone if
+/// [`find_local_offset`] found it, and in UTC otherwise.
+pub fn local_time() -> OffsetDateTime {
+    let offset = LOCAL_OFFSET.get().copied().unwrap_or(UtcOffset::UTC);
+    OffsetDateTime::now_utc().to_offset(offset)
+}
+
+/// Where the server gets the time from.
+///
+/// Anything time-dependent should ask a `Clock` rather than calling
+/// `Instant::now()`, so tests can move time along instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The date and time now, for times that are shown to users, such as
+    /// when a topic was set.
+    fn system_time(&self) -> SystemTime;
+
+    /// Blocks until `deadline` has passed.
+    fn sleep_until(&self, deadline: Instant);
+
+    /// The instant `duration` from now.
+    fn deadline(&self, duration: Duration) -> Instant {
+        self.now() + duration
+    }
+
+    /// Blocks for `duration`.
+    fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.deadline(duration));
+    }
+}
+
+/// The real clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A clock that only moves when [`ManualClock::advance`] is called.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+    advanced: Condvar,
+    /// When the clock was made, and the date and time it started at.
+    started: (Instant, SystemTime),
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+}
+
+impl ManualClock {
+    /// A clock whose date and time starts at `time`, so the times users
+    /// are shown can be known in advance.
+    pub fn starting_at(time: SystemTime) -> Self {
+        let now = Instant::now();
+        Self {
+            now: Mutex::new(now),
+            advanced: Condvar::new(),
+            started: (now, time),
+        }
+    }
+
+    /// Moves time forward by `duration`, waking anything sleeping past it.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.advanced.notify_all();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        let (started, time) = self.started;
+        time + self.now().duration_since(started)
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = self.now.lock().unwrap();
+        let _now = self
+            .advanced
+            .wait_while(now, |now| *now < deadline)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let deadline = clock.deadline(Duration::from_secs(60));
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep_until(deadline))
+        };
+        clock.advance(Duration::from_secs(30));
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(30));
+        sleeper.join().unwrap();
+        assert_eq!(clock.now(), deadline);
+    }
+
+    #[test]
+    fn test_manual_system_time() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_697_385_600);
+        let clock = ManualClock::starting_at(start);
+        assert_eq!(clock.system_time(), start);
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.system_time(), start + Duration::from_secs(90));
+    }
+}
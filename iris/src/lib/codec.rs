@@ -0,0 +1,219 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::{connect::ConnectionError, types::MAX_LINE_LEN};
+
+/// Frames IRC lines over any byte stream.
+///
+/// Incoming lines end in CRLF, though a bare LF is accepted too. A line
+/// longer than [`MAX_LINE_LEN`] is reported once as
+/// [`ConnectionError::LineTooLong`] and the rest of it is skipped, so the
+/// line after it still comes through intact.
+#[derive(Debug, Default)]
+pub struct IrcCodec {
+    buffer: Vec<u8>,
+    /// Set while skipping the rest of an overlong line.
+    skipping: bool,
+}
+
+impl IrcCodec {
+    /// Adds bytes received from the stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the next whole line out of what has been fed, without its
+    /// terminator. Returns `None` until a whole line has arrived.
+    pub fn decode(&mut self) -> Option<Result<String, ConnectionError>> {
+        loop {
+            let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') else {
+                if self.buffer.len() < MAX_LINE_LEN {
+                    return None;
+                }
+                // No terminator in sight, so this line is too long whatever
+                // comes next
+                self.buffer.clear();
+                if self.skipping {
+                    return None;
+                }
+                self.skipping = true;
+                return Some(Err(ConnectionError::LineTooLong));
+            };
+
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if std::mem::take(&mut self.skipping) {
+                // This was the end of an overlong line that's already been reported
+                continue;
+            }
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.len() + 2 > MAX_LINE_LEN {
+                return Some(Err(ConnectionError::LineTooLong));
+            }
+            return Some(String::from_utf8(line).map_err(|_| ConnectionError::InvalidEncoding));
+        }
+    }
+
+    /// Reads from `reader` until a whole line has arrived, and returns it.
+    pub fn read_line(&mut self, reader: &mut impl Read) -> Result<String, ConnectionError> {
+        let mut bytes = [0; MAX_LINE_LEN];
+        loop {
+            if let Some(line) = self.decode() {
+                return line;
+            }
+            match reader.read(&mut bytes) {
+                Ok(0) => return Err(ConnectionError::Closed),
+                Ok(n_bytes) => self.feed(&bytes[..n_bytes]),
+                // Retry `read` if interrupted...
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(ConnectionError::from_io(&err)),
+            }
+        }
+    }
+
+    /// Frames `message`, which may hold several lines, for sending. Each
+    /// line is given a CRLF, and cut short if it wouldn't fit in
+    /// [`MAX_LINE_LEN`].
+    pub fn encode(message: &str) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(message.len() + 2);
+        Self::encode_into(message, &mut bytes);
+        bytes
+    }
+
+    /// Like [`IrcCodec::encode`], but adds the framed bytes to the end of
+    /// `bytes`, so a buffer can be reused.
+    pub fn encode_into(message: &str, bytes: &mut Vec<u8>) {
+        for line in message.split_terminator("\r\n") {
+            let mut end = line.len().min(MAX_LINE_LEN - 2);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            bytes.extend_from_slice(&line.as_bytes()[..end]);
+            bytes.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Frames and sends `message` to `writer`.
+    pub fn write_message(writer: &mut impl Write, message: &str) -> std::io::Result<()> {
+        writer.write_all(&Self::encode(message))?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut IrcCodec) -> Vec<Result<String, ConnectionError>> {
+        std::iter::from_fn(|| codec.decode()).collect()
+    }
+
+    #[test]
+    fn test_decode_lines() {
+        let mut codec = IrcCodec::default();
+        codec.feed(b"NICK tom\r\nUSER tom 0 * :Tom\r\nPING");
+        assert_eq!(
+            decode_all(&mut codec),
+            vec![
+                Ok("NICK tom".to_string()),
+                Ok("USER tom 0 * :Tom".to_string())
+            ]
+        );
+
+        // The rest of a line can arrive later, even between the CR and LF
+        codec.feed(b" x\r");
+        assert_eq!(codec.decode(), None);
+        codec.feed(b"\n");
+        assert_eq!(codec.decode(), Some(Ok("PING x".to_string())));
+        assert_eq!(codec.decode(), None);
+    }
+
+    #[test]
+    fn test_decode_bare_lf_and_empty_lines() {
+        let mut codec = IrcCodec::default();
+        codec.feed(b"PING a\n\r\n\nPING b\r\n");
+        assert_eq!(
+            decode_all(&mut codec),
+            vec![
+                Ok("PING a".to_string()),
+                Ok(String::new()),
+                Ok(String::new()),
+                Ok("PING b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_max_length() {
+        let longest = "x".repeat(MAX_LINE_LEN - 2);
+        let mut codec = IrcCodec::default();
+        codec.feed(format!("{longest}\r\n{longest}x\r\n").as_bytes());
+        assert_eq!(
+            decode_all(&mut codec),
+            vec![Ok(longest), Err(ConnectionError::LineTooLong)]
+        );
+    }
+
+    #[test]
+    fn test_decode_resyncs_after_overlong_line() {
+        let mut codec = IrcCodec::default();
+        // Fed in pieces, the overlong line is reported as soon as it can't
+        // fit, and only once
+        for _ in 0..3 {
+            codec.feed(&[b'x'; MAX_LINE_LEN]);
+        }
+        assert_eq!(codec.decode(), Some(Err(ConnectionError::LineTooLong)));
+        codec.feed(&[b'x'; 100]);
+        assert_eq!(codec.decode(), None);
+
+        codec.feed(b"xxx\r\nPING x\r\n");
+        assert_eq!(decode_all(&mut codec), vec![Ok("PING x".to_string())]);
+    }
+
+    #[test]
+    fn test_decode_invalid_utf8() {
+        let mut codec = IrcCodec::default();
+        codec.feed(b"PRIVMSG tom :\xff\r\nPING x\r\n");
+        assert_eq!(
+            decode_all(&mut codec),
+            vec![
+                Err(ConnectionError::InvalidEncoding),
+                Ok("PING x".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut codec = IrcCodec::default();
+        let mut reader: &[u8] = b"PING a\r\nPING b\r\nPING";
+        assert_eq!(codec.read_line(&mut reader), Ok("PING a".to_string()));
+        assert_eq!(codec.read_line(&mut reader), Ok("PING b".to_string()));
+        assert_eq!(codec.read_line(&mut reader), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(IrcCodec::encode("PONG :x\r\n"), b"PONG :x\r\n");
+        assert_eq!(
+            IrcCodec::encode(":a 281 b c\r\n:a 282 b :End\r\n"),
+            b":a 281 b c\r\n:a 282 b :End\r\n"
+        );
+        assert_eq!(IrcCodec::encode("PONG :x"), b"PONG :x\r\n");
+        assert_eq!(IrcCodec::encode(""), b"");
+    }
+
+    #[test]
+    fn test_encode_cuts_long_lines() {
+        let line = format!(":tom PRIVMSG ann :{}", "x".repeat(MAX_LINE_LEN));
+        let encoded = IrcCodec::encode(&line);
+        assert_eq!(encoded.len(), MAX_LINE_LEN);
+        assert!(encoded.ends_with(b"x\r\n"));
+
+        // Multi-byte characters aren't split in half
+        let line = format!("{}é", "x".repeat(MAX_LINE_LEN - 3));
+        let encoded = String::from_utf8(IrcCodec::encode(&line)).unwrap();
+        assert_eq!(encoded, format!("{}\r\n", "x".repeat(MAX_LINE_LEN - 3)));
+    }
+}
@@ -0,0 +1,334 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use hmac::digest::{CtOutput, Output};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    transcript::TranscriptConfig,
+    types::{CHANNEL_PREFIX, ISUPPORT_TOKENS, MAX_CHANNEL_LEN, MAX_NICK_LEN, SERVER_NAME},
+    webhook::WebhookConfig,
+};
+
+/// The longest server name allowed, as in RFC 2812.
+pub const MAX_SERVER_NAME_LEN: usize = 63;
+
+/// How long QUIT and KICK reasons can be, in bytes, unless configured otherwise.
+pub const DEFAULT_REASON_LEN: usize = 300;
+
+/// How long channel topics can be, in bytes, unless configured otherwise.
+pub const DEFAULT_TOPIC_LEN: usize = 390;
+
+/// How many recipients a broadcast needs before it is split across
+/// workers, unless configured otherwise.
+pub const DEFAULT_FANOUT_THRESHOLD: usize = 1000;
+
+/// How many workers a large broadcast is split across, unless configured
+/// otherwise.
+pub const DEFAULT_FANOUT_WORKERS: usize = 4;
+
+/// How many messages may wait to be written to a client before they are
+/// disconnected, unless configured otherwise.
+pub const DEFAULT_SEND_QUEUE_LEN: usize = 512;
+
+/// How many channels may exist at once, unless configured otherwise.
+pub const DEFAULT_MAX_CHANNELS: usize = 5000;
+
+/// How long a client can stay quiet before being pinged, unless configured
+/// otherwise.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a pinged client has to answer, unless configured otherwise.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a client has to register after connecting, unless configured
+/// otherwise.
+pub const DEFAULT_REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Server-wide settings, fixed once the server has launched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// What the server calls itself in everything it sends.
+    pub server_name: String,
+    /// Secret used to derive cloaked hostnames. Cloaking is disabled when unset.
+    pub cloak_secret: Option<String>,
+    /// How long a departed user's nick stays reserved for them.
+    pub nick_hold: Duration,
+    /// Limits on repeated channel messages. Disabled when unset.
+    pub repeat_filter: Option<RepeatFilter>,
+    /// Where to record protocol transcripts. Nothing is recorded when unset.
+    pub transcript: Option<TranscriptConfig>,
+    /// How long a write can wait on a client that isn't reading before they
+    /// are treated as gone. Writes wait forever when unset.
+    pub write_timeout: Option<Duration>,
+    /// How many messages may wait to be written to a client. One more
+    /// disconnects them, so a client that stops reading can't pile up
+    /// messages without end.
+    pub send_queue_len: usize,
+    /// How long a registered client can send nothing before the server
+    /// pings them. Nobody is pinged when unset.
+    pub ping_interval: Option<Duration>,
+    /// How long a pinged client has to send something before they are
+    /// disconnected.
+    pub ping_timeout: Duration,
+    /// How long a client has to register after connecting before they are
+    /// disconnected. Clients can take as long as they like when unset.
+    pub registration_timeout: Option<Duration>,
+    /// Longest QUIT, KICK or PART reason relayed, in bytes. Longer ones are
+    /// cut short.
+    pub reason_len: usize,
+    /// Longest channel topic kept, in bytes. Longer ones are cut short.
+    pub topic_len: usize,
+    /// Broadcasts to at least this many recipients are split across workers.
+    pub fanout_threshold: usize,
+    /// How many workers a large broadcast is split across. 1 keeps every
+    /// broadcast on the sender's thread.
+    pub fanout_workers: usize,
+    /// Endpoints told about server events as they happen.
+    pub webhooks: Vec<WebhookConfig>,
+    /// Where operators can save a snapshot of the server with SNAPSHOT.
+    /// Saving is disabled when unset.
+    pub snapshot: Option<PathBuf>,
+    /// Extra names for commands, upper case, mapped to the commands they
+    /// stand for. These are on top of [`crate::types::BUILTIN_ALIASES`].
+    pub aliases: HashMap<String, String>,
+    /// Whether operators may message users in +R without identifying.
+    pub registered_only_exempts_opers: bool,
+    /// Once this many channels exist, JOIN no longer creates new ones.
+    pub max_channels: usize,
+    /// Whether operators may create channels past `max_channels`.
+    pub max_channels_exempts_opers: bool,
+    /// Whether logs and transcripts show what people said. When unset,
+    /// PRIVMSG and NOTICE text is replaced with its length.
+    pub log_message_contents: bool,
+    /// The message of the day, a line at a time, read once at startup.
+    /// Clients are told there is none when unset.
+    pub motd: Option<Vec<String>>,
+    /// Who may become a server operator with OPER. Nobody can when empty.
+    pub operators: Vec<Operator>,
+    /// What clients must send with PASS before they can register. Anyone
+    /// can register when unset.
+    pub password: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            server_name: SERVER_NAME.to_string(),
+            cloak_secret: None,
+            nick_hold: Duration::ZERO,
+            repeat_filter: None,
+            transcript: None,
+            write_timeout: None,
+            send_queue_len: DEFAULT_SEND_QUEUE_LEN,
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            registration_timeout: Some(DEFAULT_REGISTRATION_TIMEOUT),
+            reason_len: DEFAULT_REASON_LEN,
+            topic_len: DEFAULT_TOPIC_LEN,
+            fanout_threshold: DEFAULT_FANOUT_THRESHOLD,
+            fanout_workers: DEFAULT_FANOUT_WORKERS,
+            webhooks: Vec::new(),
+            snapshot: None,
+            aliases: HashMap::new(),
+            registered_only_exempts_opers: true,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            max_channels_exempts_opers: true,
+            log_message_contents: false,
+            motd: None,
+            operators: Vec::new(),
+            password: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Tokens advertised to clients in RPL_ISUPPORT, in alphabetical order.
+    pub fn isupport_tokens(&self) -> Vec<String> {
+        let mut tokens: Vec<String> = ISUPPORT_TOKENS
+            .iter()
+            .map(|token| token.to_string())
+            .collect();
+        tokens.push(format!("CHANNELLEN={MAX_CHANNEL_LEN}"));
+        tokens.push(format!("CHANTYPES={CHANNEL_PREFIX}"));
+        tokens.push(format!("KICKLEN={}", self.reason_len));
+        tokens.push(format!("NICKLEN={MAX_NICK_LEN}"));
+        tokens.push(format!("TOPICLEN={}", self.topic_len));
+        tokens.sort();
+        tokens
+    }
+
+    /// Whether `password` is the one clients must give to register, compared
+    /// in constant time. Any password will do when none is set.
+    pub fn password_matches(&self, password: &str) -> bool {
+        self.password.as_ref().is_none_or(|expected| {
+            CtOutput::<Sha256>::new(Sha256::digest(password))
+                == CtOutput::new(Sha256::digest(expected))
+        })
+    }
+}
+
+/// Checks `name` looks like a hostname: dot-separated labels of letters,
+/// digits and hyphens, none starting or ending with a hyphen.
+pub fn validate_server_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.len() > MAX_SERVER_NAME_LEN {
+        return Err(format!(
+            "server name must be 1 to {MAX_SERVER_NAME_LEN} characters long"
+        ));
+    }
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !name.split('.').all(valid_label) {
+        return Err(format!("{name:?} is not a valid server name"));
+    }
+    Ok(name.to_string())
+}
+
+/// Parses an alias given as `NAME=COMMAND`, such as `J=JOIN`, into upper
+/// case.
+pub fn parse_alias(alias: &str) -> Result<(String, String), String> {
+    let valid = |word: &str| !word.is_empty() && word.chars().all(|c| c.is_ascii_alphabetic());
+    match alias.split_once('=') {
+        Some((name, command)) if valid(name) && valid(command) => {
+            Ok((name.to_ascii_uppercase(), command.to_ascii_uppercase()))
+        }
+        _ => Err(format!("{alias:?} is not of the form NAME=COMMAND")),
+    }
+}
+
+/// A name and password that OPER accepts. Only a SHA-256 of the password
+/// is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operator {
+    pub name: String,
+    digest: Output<Sha256>,
+}
+
+impl Operator {
+    pub fn new(name: &str, password: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            digest: Sha256::digest(password),
+        }
+    }
+
+    /// Whether `name` and `password` are this operator's. The password is
+    /// compared in constant time.
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        let digest = CtOutput::<Sha256>::new(Sha256::digest(password));
+        name == self.name && digest == CtOutput::new(self.digest)
+    }
+}
+
+/// Parses operators, one `name password` per line. A password written as
+/// `sha256:` and 64 hex digits is the SHA-256 of the real one. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn parse_operators(text: &str) -> Result<Vec<Operator>, String> {
+    let mut operators = Vec::new();
+    for (number, line) in text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, password) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, password] => (name, password),
+            _ => return Err(format!("line {number} is not of the form NAME PASSWORD")),
+        };
+        let operator = match password.strip_prefix("sha256:") {
+            Some(hex) => Operator {
+                name: name.to_string(),
+                digest: parse_digest(hex)
+                    .ok_or_else(|| format!("line {number} has a malformed SHA-256"))?,
+            },
+            None => Operator::new(name, password),
+        };
+        operators.push(operator);
+    }
+    Ok(operators)
+}
+
+/// 64 hex digits as a SHA-256 digest.
+fn parse_digest(hex: &str) -> Option<Output<Sha256>> {
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut digest = Output::<Sha256>::default();
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Limits on a member sending the same message to a channel over and over.
+///
+/// Messages are compared after trimming and lowercasing. CTCP ACTIONs are
+/// compared like any other text, so repeated `/me` lines are caught too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatFilter {
+    /// Copies beyond this many within `window` are not relayed.
+    pub suppress_after: u32,
+    /// Copies beyond this many within `window` get the sender kicked.
+    pub kick_after: u32,
+    /// How long a run of copies is counted for before starting over.
+    pub window: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_server_name() {
+        assert_eq!(
+            validate_server_name("iris-server"),
+            Ok("iris-server".to_string())
+        );
+        assert!(validate_server_name("irc2.example.net").is_ok());
+        assert!(validate_server_name("").is_err());
+        assert!(validate_server_name("iris server").is_err());
+        assert!(validate_server_name("iris:server").is_err());
+        assert!(validate_server_name("-iris").is_err());
+        assert!(validate_server_name("iris..net").is_err());
+        assert!(validate_server_name(&"x".repeat(MAX_SERVER_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_password_matches() {
+        let config = ServerConfig::default();
+        assert!(config.password_matches("anything"));
+        let config = ServerConfig {
+            password: Some("hunter2".to_string()),
+            ..ServerConfig::default()
+        };
+        assert!(config.password_matches("hunter2"));
+        assert!(!config.password_matches("hunter3"));
+        assert!(!config.password_matches(""));
+    }
+
+    #[test]
+    fn test_parse_operators() {
+        // The SHA-256 of "hunter2"
+        let hashed = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+        let operators = parse_operators(&format!(
+            "# name password\nadmin secret\n\n  root sha256:{hashed}\n"
+        ))
+        .unwrap();
+        assert_eq!(operators.len(), 2);
+        assert!(operators[0].verify("admin", "secret"));
+        assert!(!operators[0].verify("admin", "Secret"));
+        assert!(!operators[0].verify("root", "secret"));
+        assert!(operators[1].verify("root", "hunter2"));
+        assert!(!operators[1].verify("root", hashed));
+
+        assert!(parse_operators("admin").is_err());
+        assert!(parse_operators("admin two words").is_err());
+        assert!(parse_operators("admin sha256:abc").is_err());
+        assert!(parse_operators(&format!("admin sha256:{}", "g".repeat(64))).is_err());
+    }
+}
@@ -1,133 +1,499 @@
 use std::{
+    cell::Cell,
     error::Error,
     fmt::{Debug, Display},
-    io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    codec::IrcCodec,
+    transcript::{Direction, Transcript},
 };
 
 pub struct ConnectionManager {
     listener: TcpListener,
 }
 
+/// The first pause after `accept` fails. It doubles with each failure in a
+/// row, up to [`MAX_ACCEPT_BACKOFF`].
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
 impl ConnectionManager {
-    pub fn launch(address: impl Into<IpAddr>, port: u16) -> Self {
-        let address = address.into();
-        let listener = TcpListener::bind((address, port))
-            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+    /// Starts listening on `address` and `port`, failing with
+    /// [`ConnectionError::Bind`] if the port is taken or off limits.
+    pub fn launch(address: impl Into<IpAddr>, port: u16) -> Result<Self, ConnectionError> {
+        let address = SocketAddr::new(address.into(), port);
+        let listener = TcpListener::bind(address).map_err(|err| ConnectionError::Bind {
+            address,
+            kind: err.kind(),
+        })?;
 
-        Self { listener }
+        Ok(Self { listener })
+    }
+
+    /// The address the server is listening on. Useful after launching on
+    /// port 0 to find out which port was picked.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener
+            .local_addr()
+            .expect("listener should have a local address")
     }
 
     pub fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
-        loop {
-            match self.listener.accept() {
-                Ok((socket, addr)) => {
-                    let (socket_read, socket_write) = (
-                        match socket.try_clone() {
-                            Ok(socket) => socket,
-                            Err(err) => {
-                                eprintln!("[WARN] Failed to clone socket: {err}");
-                                continue;
-                            }
-                        },
-                        socket,
-                    );
-
-                    return (
-                        ConnectionRead::from_socket(socket_read, addr),
-                        ConnectionWrite::from_socket(socket_write, addr),
-                    );
-                }
-                Err(err) => {
-                    eprintln!("[WARN] failed to connect to client: {err}");
-                }
+        let listener = &self.listener;
+        accept_with(|| listener.accept())
+    }
+}
+
+/// Where the server gets its clients from: [`ConnectionManager`] accepts
+/// them over TCP, and [`InProcessListener`] from inside the process.
+pub trait Transport: Send + 'static {
+    /// Waits for the next client, returning the server's end of their
+    /// connection, or `None` once no more clients can arrive.
+    fn accept(&mut self) -> Option<(ConnectionRead, ConnectionWrite)>;
+}
+
+impl Transport for ConnectionManager {
+    /// Never gives up, as anyone may connect to the listener at any time.
+    fn accept(&mut self) -> Option<(ConnectionRead, ConnectionWrite)> {
+        Some(self.accept_new_connection())
+    }
+}
+
+/// Accepts the connections its [`InProcessConnector`]s open, without going
+/// near the network.
+pub struct InProcessListener {
+    incoming: Receiver<(ConnectionRead, ConnectionWrite)>,
+}
+
+/// Opens connections to an [`InProcessListener`].
+#[derive(Clone)]
+pub struct InProcessConnector {
+    outgoing: Sender<(ConnectionRead, ConnectionWrite)>,
+}
+
+/// A listener for in-process connections, and a connector for opening them.
+/// The listener stops accepting once every connector is gone.
+pub fn in_process_listener() -> (InProcessListener, InProcessConnector) {
+    let (outgoing, incoming) = mpsc::channel();
+    (
+        InProcessListener { incoming },
+        InProcessConnector { outgoing },
+    )
+}
+
+impl Transport for InProcessListener {
+    fn accept(&mut self) -> Option<(ConnectionRead, ConnectionWrite)> {
+        self.incoming.recv().ok()
+    }
+}
+
+impl InProcessConnector {
+    /// Opens a connection, returning the client's end. Fails if the
+    /// listener has gone.
+    pub fn connect(&self) -> io::Result<(ConnectionRead, ConnectionWrite)> {
+        let (server_end, client_end) = in_process();
+        self.outgoing
+            .send(server_end)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client_end)
+    }
+}
+
+/// Calls `accept` until it produces a connection. Failures, such as running
+/// out of file descriptors (EMFILE) or a client giving up part way through
+/// connecting (ECONNABORTED), are logged and retried after a pause that
+/// grows while they keep happening. Interrupted calls are retried at once.
+fn accept_with(
+    mut accept: impl FnMut() -> io::Result<(TcpStream, SocketAddr)>,
+) -> (ConnectionRead, ConnectionWrite) {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        match accept() {
+            Ok((socket, addr)) => {
+                let socket_read = match socket.try_clone() {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        eprintln!("[WARN] Failed to clone socket: {err}");
+                        continue;
+                    }
+                };
+
+                return (
+                    ConnectionRead::from_socket(socket_read, addr),
+                    ConnectionWrite::from_socket(socket, addr),
+                );
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                eprintln!("[WARN] failed to connect to client: {err}, retrying in {backoff:?}");
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
             }
         }
     }
 }
 
-pub struct ConnectionRead {
+/// Connects to a server at `address`, as a client would.
+pub fn connect(address: SocketAddr) -> std::io::Result<(ConnectionRead, ConnectionWrite)> {
+    from_client_socket(TcpStream::connect(address)?, address)
+}
+
+/// Like [`connect`], but gives up if the server hasn't answered within
+/// `timeout`.
+pub fn connect_timeout(
+    address: SocketAddr,
+    timeout: Duration,
+) -> std::io::Result<(ConnectionRead, ConnectionWrite)> {
+    from_client_socket(TcpStream::connect_timeout(&address, timeout)?, address)
+}
+
+fn from_client_socket(
     socket: TcpStream,
+    address: SocketAddr,
+) -> std::io::Result<(ConnectionRead, ConnectionWrite)> {
+    let socket_read = socket.try_clone()?;
+
+    Ok((
+        ConnectionRead::from_socket(socket_read, address),
+        ConnectionWrite::from_socket(socket, address),
+    ))
+}
+
+/// Opens a connection that never leaves the process, returning the server's
+/// end and then the client's. Both ends claim to be on the loopback address.
+pub fn in_process() -> (
+    (ConnectionRead, ConnectionWrite),
+    (ConnectionRead, ConnectionWrite),
+) {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let (to_server, server_incoming) = mpsc::channel();
+    let (to_client, client_incoming) = mpsc::channel();
+    let server_end = (
+        ConnectionRead::from_stream(Inbound::Pipe(PipeReader::new(server_incoming)), address),
+        ConnectionWrite::from_stream(
+            Outbound::Pipe(PipeWriter {
+                outgoing: to_client.clone(),
+                own_incoming: to_server.clone(),
+            }),
+            address,
+        ),
+    );
+    let client_end = (
+        ConnectionRead::from_stream(Inbound::Pipe(PipeReader::new(client_incoming)), address),
+        ConnectionWrite::from_stream(
+            Outbound::Pipe(PipeWriter {
+                outgoing: to_server,
+                own_incoming: to_client,
+            }),
+            address,
+        ),
+    );
+    (server_end, client_end)
+}
+
+/// Where a [`ConnectionRead`] gets its bytes from.
+enum Inbound {
+    Tcp(TcpStream),
+    Pipe(PipeReader),
+}
+
+/// Where a [`ConnectionWrite`] sends its bytes. The socket is shared with
+/// any [`ConnectionCloser`] for it.
+enum Outbound {
+    Tcp(Arc<TcpStream>),
+    Pipe(PipeWriter),
+}
+
+/// The reading half of an in-process connection. Bytes arrive in chunks, and
+/// an empty chunk means the connection was closed.
+struct PipeReader {
+    incoming: Receiver<Vec<u8>>,
+    /// What is left of a chunk too big for the last read.
+    pending: Vec<u8>,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl PipeReader {
+    fn new(incoming: Receiver<Vec<u8>>) -> Self {
+        Self {
+            incoming,
+            pending: Vec::new(),
+            timeout: Cell::new(None),
+        }
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.timeout.get() {
+                Some(timeout) => match self.incoming.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                    Err(RecvTimeoutError::Disconnected) => Vec::new(),
+                },
+                None => self.incoming.recv().unwrap_or_default(),
+            };
+        }
+        let n_bytes = buf.len().min(self.pending.len());
+        buf[..n_bytes].copy_from_slice(&self.pending[..n_bytes]);
+        self.pending.drain(..n_bytes);
+        Ok(n_bytes)
+    }
+}
+
+/// The writing half of an in-process connection.
+struct PipeWriter {
+    outgoing: Sender<Vec<u8>>,
+    /// Lets a shutdown wake this end's own reader, as it would for a socket.
+    own_incoming: Sender<Vec<u8>>,
+}
+
+impl PipeWriter {
+    fn shutdown(&self) {
+        shutdown_pipe(&self.outgoing, &self.own_incoming);
+    }
+}
+
+/// Tells both ends of an in-process connection that it has closed.
+fn shutdown_pipe(outgoing: &Sender<Vec<u8>>, own_incoming: &Sender<Vec<u8>>) {
+    let _ = outgoing.send(Vec::new());
+    let _ = own_incoming.send(Vec::new());
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    /// The other end is told the connection closed, as the last handle on
+    /// a socket closing would.
+    fn drop(&mut self) {
+        let _ = self.outgoing.send(Vec::new());
+    }
+}
+
+pub struct ConnectionRead {
+    stream: Inbound,
     socket_addr: SocketAddr,
-    buffer: Box<[u8; 512]>,
-    buflen: usize,
+    codec: IrcCodec,
+    transcript: Option<Transcript>,
 }
 
 pub struct ConnectionWrite {
-    socket: TcpStream,
+    stream: Outbound,
     socket_addr: SocketAddr,
+    transcript: Option<Transcript>,
+    /// Set once a write fails, after which nothing more is sent.
+    broken: bool,
+    /// Reused for framing each write, so sending doesn't allocate.
+    buffer: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ConnectionError {
-    ConnectionLost,
-    ConnectionClosed,
-    MessageTooLong,
-    MessageInvalidUtf8,
+    /// The other end closed the connection cleanly.
+    Closed,
+    /// The connection was reset or aborted, e.g. because the other end
+    /// crashed.
+    Reset,
+    /// A read or write did not finish before its timeout.
+    Timeout,
+    /// A line was longer than IRC allows. The rest of it is skipped.
+    LineTooLong,
+    /// A line was not valid UTF-8.
+    InvalidEncoding,
+    /// Any other I/O failure.
+    Io(io::ErrorKind),
+    /// The server couldn't start listening on `address`.
+    Bind {
+        address: SocketAddr,
+        kind: io::ErrorKind,
+    },
+}
+
+impl ConnectionError {
+    /// The error an I/O failure on a connection amounts to.
+    pub fn from_io(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => ConnectionError::Closed,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => ConnectionError::Reset,
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ConnectionError::Timeout,
+            kind => ConnectionError::Io(kind),
+        }
+    }
+
+    /// Whether the connection is unusable after this error. Otherwise only
+    /// the line being read was lost.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::Closed
+                | ConnectionError::Reset
+                | ConnectionError::Io(_)
+                | ConnectionError::Bind { .. }
+        )
+    }
 }
 
 impl Display for ConnectionError {
+    /// Short enough for an ERROR line or a quit reason.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        <Self as Debug>::fmt(self, f)
+        match self {
+            ConnectionError::Closed => write!(f, "Connection closed"),
+            ConnectionError::Reset => write!(f, "Connection reset by peer"),
+            ConnectionError::Timeout => write!(f, "Connection timed out"),
+            ConnectionError::LineTooLong => write!(f, "Line too long"),
+            ConnectionError::InvalidEncoding => write!(f, "Line is not valid UTF-8"),
+            ConnectionError::Io(kind) => write!(f, "I/O error: {kind}"),
+            ConnectionError::Bind { address, kind } => {
+                write!(f, "Unable to listen on {address}: {kind}")
+            }
+        }
     }
 }
 
 impl Error for ConnectionError {}
 
-impl ConnectionRead {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
-        Self {
-            socket,
-            socket_addr,
-            buffer: Box::from([0; 512]),
-            buflen: 0,
+/// Closes a connection from another thread, even while a write to it is
+/// stuck. See [`ConnectionWrite::closer`].
+#[derive(Clone)]
+pub struct ConnectionCloser(Closing);
+
+#[derive(Clone)]
+enum Closing {
+    Tcp(Arc<TcpStream>),
+    Pipe {
+        outgoing: Sender<Vec<u8>>,
+        own_incoming: Sender<Vec<u8>>,
+    },
+}
+
+impl ConnectionCloser {
+    /// Closes the connection, as [`ConnectionWrite::shutdown`] does.
+    pub fn shutdown(&self) {
+        match &self.0 {
+            Closing::Tcp(socket) => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            Closing::Pipe {
+                outgoing,
+                own_incoming,
+            } => shutdown_pipe(outgoing, own_incoming),
         }
     }
+}
+
+/// A batch of messages that failed part way through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialWrite {
+    /// How many messages, from the start of the batch, were sent in full.
+    /// The next one may have been cut off mid-line.
+    pub delivered: usize,
+    pub error: ConnectionError,
+}
 
-    fn buffer_crlf(&self) -> Option<usize> {
-        self.buffer[..self.buflen]
-            .windows(2)
-            .enumerate()
-            .find(|(_, bytes)| bytes[0] == b'\r' && bytes[1] == b'\n')
-            .map(|(index, _)| index)
+impl Display for PartialWrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} after {} messages", self.error, self.delivered)
     }
+}
 
-    pub fn read_message(&mut self) -> Result<String, ConnectionError> {
-        use std::io::ErrorKind;
+impl Error for PartialWrite {}
 
-        if self.buffer_crlf().is_none() {
-            let n_bytes = loop {
-                break match self.socket.read(&mut self.buffer[self.buflen..]) {
-                    Ok(0) => return Err(ConnectionError::ConnectionClosed),
-                    Ok(n_bytes) => n_bytes,
-                    Err(err) => {
-                        match err.kind() {
-                            // Retry `read` if interrupted...
-                            ErrorKind::Interrupted => continue,
-                            _ => return Err(ConnectionError::ConnectionLost),
-                        }
-                    }
-                };
-            };
+/// Frames `messages` into `buffer` and sends them to `writer` in as few
+/// writes as it takes. On failure, gives how many messages were sent in
+/// full.
+fn write_batch(
+    writer: &mut impl Write,
+    buffer: &mut Vec<u8>,
+    messages: &[impl AsRef<str>],
+) -> Result<(), (usize, io::Error)> {
+    buffer.clear();
+    for message in messages {
+        IrcCodec::encode_into(message.as_ref(), buffer);
+    }
+    // Only worked out on failure, so the usual case needs no bookkeeping
+    let delivered = |sent: usize| {
+        let mut end = 0;
+        messages
+            .iter()
+            .take_while(|message| {
+                end += IrcCodec::encode(message.as_ref()).len();
+                end <= sent
+            })
+            .count()
+    };
 
-            self.buflen += n_bytes;
+    let mut sent = 0;
+    while sent < buffer.len() {
+        match writer.write(&buffer[sent..]) {
+            Ok(0) => return Err((delivered(sent), io::ErrorKind::WriteZero.into())),
+            Ok(n_bytes) => sent += n_bytes,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err((delivered(sent), err)),
         }
+    }
+    writer.flush().map_err(|err| (delivered(sent), err))
+}
 
-        let end = self.buffer_crlf().ok_or_else(|| {
-            // Clear out their data...
-            self.buflen = 0;
-            ConnectionError::MessageTooLong
-        })?;
+impl ConnectionRead {
+    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+        Self::from_stream(Inbound::Tcp(socket), socket_addr)
+    }
 
-        let bytes = Vec::from(&self.buffer[0..end]);
+    fn from_stream(stream: Inbound, socket_addr: SocketAddr) -> Self {
+        Self {
+            stream,
+            socket_addr,
+            codec: IrcCodec::default(),
+            transcript: None,
+        }
+    }
 
-        // end + '\r' + '\n'
-        let after_crlf = end + 2;
+    /// Records every message read from now on to `transcript`.
+    pub fn set_transcript(&mut self, transcript: Transcript) {
+        self.transcript = Some(transcript);
+    }
 
-        self.buffer.copy_within(after_crlf..self.buflen, 0);
-        self.buflen -= after_crlf;
+    /// Makes reads give up with [`ConnectionError::Timeout`] after `timeout`.
+    /// `None` waits forever, which is the default.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        match &self.stream {
+            Inbound::Tcp(socket) => {
+                let _ = socket.set_read_timeout(timeout);
+            }
+            Inbound::Pipe(pipe) => pipe.timeout.set(timeout),
+        }
+    }
 
-        let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
+    pub fn read_message(&mut self) -> Result<String, ConnectionError> {
+        let message = match &mut self.stream {
+            Inbound::Tcp(socket) => self.codec.read_line(socket)?,
+            Inbound::Pipe(pipe) => self.codec.read_line(pipe)?,
+        };
+        if let Some(transcript) = &self.transcript {
+            transcript.record(Direction::Inbound, &message);
+        }
 
         Ok(message)
     }
@@ -135,26 +501,376 @@ impl ConnectionRead {
     pub fn id(&self) -> String {
         self.socket_addr.to_string()
     }
+
+    pub fn ip(&self) -> IpAddr {
+        self.socket_addr.ip()
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.socket_addr
+    }
 }
 
 impl ConnectionWrite {
     fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+        Self::from_stream(Outbound::Tcp(Arc::new(socket)), socket_addr)
+    }
+
+    fn from_stream(stream: Outbound, socket_addr: SocketAddr) -> Self {
         Self {
-            socket,
+            stream,
             socket_addr,
+            transcript: None,
+            broken: false,
+            buffer: Vec::new(),
         }
     }
 
+    /// Records every message written from now on to `transcript`.
+    pub fn set_transcript(&mut self, transcript: Transcript) {
+        self.transcript = Some(transcript);
+    }
+
+    /// Makes writes give up with [`ConnectionError::Timeout`] if the client
+    /// stops reading for `timeout`. `None` waits forever, which is the default.
+    /// In-process connections never wait, so this does nothing for them.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        if let Outbound::Tcp(socket) = &self.stream {
+            let _ = socket.set_write_timeout(timeout);
+        }
+    }
+
+    /// Sends `message`. Once a write has failed the connection is closed and
+    /// every later write fails too, as the client may have been left with
+    /// half a line that anything sent after it would run into.
     pub fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
-        self.socket
-            .write_all(message.as_bytes())
-            .map_err(|_| ConnectionError::ConnectionClosed)?;
-        let _ = self.socket.flush();
+        self.write_all_lines(&[message])
+            .map_err(|partial| partial.error)
+    }
+
+    /// Sends `messages` in order, joined into as few writes as possible.
+    /// Each line is capped at the IRC length limit, as with
+    /// [`ConnectionWrite::write_message`]. If a write fails, the error says
+    /// how many messages got through, and the connection is closed.
+    pub fn write_all_lines(&mut self, messages: &[impl AsRef<str>]) -> Result<(), PartialWrite> {
+        if self.broken {
+            return Err(PartialWrite {
+                delivered: 0,
+                error: ConnectionError::Closed,
+            });
+        }
+        if let Some(transcript) = &self.transcript {
+            for message in messages {
+                transcript.record(Direction::Outbound, message.as_ref());
+            }
+        }
+        let written = match &mut self.stream {
+            Outbound::Tcp(socket) => write_batch(&mut &**socket, &mut self.buffer, messages),
+            Outbound::Pipe(pipe) => write_batch(pipe, &mut self.buffer, messages),
+        };
+        if let Err((delivered, err)) = written {
+            self.broken = true;
+            // Closing both halves wakes the reader, which cleans the client up
+            self.shutdown();
+            return Err(PartialWrite {
+                delivered,
+                error: ConnectionError::from_io(&err),
+            });
+        }
 
         Ok(())
     }
 
+    /// Whether the connection is safe from eavesdropping. The server has no
+    /// TLS listener, so only in-process connections, which never leave the
+    /// process, count.
+    pub fn is_secure(&self) -> bool {
+        matches!(self.stream, Outbound::Pipe(_))
+    }
+
+    /// Whether the other end is inside this process, as bots are.
+    pub fn is_in_process(&self) -> bool {
+        matches!(self.stream, Outbound::Pipe(_))
+    }
+
+    /// Closes the connection, which also ends any read waiting on it.
+    pub fn shutdown(&self) {
+        match &self.stream {
+            Outbound::Tcp(socket) => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            Outbound::Pipe(pipe) => pipe.shutdown(),
+        }
+    }
+
+    /// A handle that can close this connection from elsewhere, for once the
+    /// connection itself has been handed to a writer thread.
+    pub fn closer(&self) -> ConnectionCloser {
+        ConnectionCloser(match &self.stream {
+            Outbound::Tcp(socket) => Closing::Tcp(socket.clone()),
+            Outbound::Pipe(pipe) => Closing::Pipe {
+                outgoing: pipe.outgoing.clone(),
+                own_incoming: pipe.own_incoming.clone(),
+            },
+        })
+    }
+
     pub fn id(&self) -> String {
         self.socket_addr.to_string()
     }
+
+    pub fn ip(&self) -> IpAddr {
+        self.socket_addr.ip()
+    }
+
+    /// Sends `bytes` exactly as given, for lines [`ConnectionWrite`] would
+    /// frame differently.
+    #[cfg(feature = "testing")]
+    pub(crate) fn write_raw(&mut self, bytes: &[u8]) -> Result<(), ConnectionError> {
+        let written = match &mut self.stream {
+            Outbound::Tcp(socket) => (&**socket).write_all(bytes),
+            Outbound::Pipe(pipe) => pipe.write_all(bytes),
+        };
+        written.map_err(|err| ConnectionError::from_io(&err))
+    }
+}
+
+#[cfg(test)]
+impl ConnectionWrite {
+    /// A connection over a local socket, returned with the client's end so
+    /// tests can read what the server sent.
+    pub(crate) fn loopback() -> (Self, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, addr) = listener.accept().unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+
+        (Self::from_socket(socket, addr), client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_write_timeout_on_stalled_client() {
+        let (mut conn_write, mut client) = ConnectionWrite::loopback();
+        conn_write.set_write_timeout(Some(Duration::from_millis(100)));
+
+        // The client never reads, so the socket buffers eventually fill up
+        let line = format!(":iris-server NOTICE tom :{}\r\n", "x".repeat(400));
+        let err = (0..1_000_000)
+            .find_map(|_| conn_write.write_message(&line).err())
+            .expect("writes to a stalled client should time out");
+        assert_eq!(err, ConnectionError::Timeout);
+        assert_eq!(
+            conn_write.write_message(":iris-server NOTICE tom :after\r\n"),
+            Err(ConnectionError::Closed)
+        );
+
+        // Nothing after the timed out line reached the client
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(!received.contains("after"));
+        assert!(received
+            .split_terminator("\r\n")
+            .all(|sent| line.starts_with(sent)));
+    }
+
+    #[test]
+    fn test_in_process_connection() {
+        let ((mut server_read, server_write), (mut client_read, mut client_write)) = in_process();
+        client_write
+            .write_message("NICK tom\r\nUSER tom 0 * :Tom\r\n")
+            .unwrap();
+        assert_eq!(server_read.read_message(), Ok("NICK tom".to_string()));
+        assert_eq!(
+            server_read.read_message(),
+            Ok("USER tom 0 * :Tom".to_string())
+        );
+
+        server_read.set_read_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(server_read.read_message(), Err(ConnectionError::Timeout));
+
+        // Shutting down closes the connection for both ends
+        server_write.shutdown();
+        assert_eq!(server_read.read_message(), Err(ConnectionError::Closed));
+        assert_eq!(client_read.read_message(), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn test_write_all_lines_is_one_write() {
+        let ((_, mut server_write), (client_read, _)) = in_process();
+        server_write
+            .write_all_lines(&[":iris-server 001 tom :Welcome", ":iris-server 005 tom :hi"])
+            .unwrap();
+
+        let Inbound::Pipe(pipe) = &client_read.stream else {
+            unreachable!("in-process connections use pipes");
+        };
+        assert_eq!(
+            pipe.incoming.try_recv().as_deref(),
+            Ok(&b":iris-server 001 tom :Welcome\r\n:iris-server 005 tom :hi\r\n"[..])
+        );
+        assert!(pipe.incoming.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_partial_batch_reports_delivered() {
+        /// Takes a few bytes at a time, then fails.
+        struct Trickle {
+            room: usize,
+        }
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.room == 0 {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                let n_bytes = buf.len().min(self.room).min(4);
+                self.room -= n_bytes;
+                Ok(n_bytes)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Each message is 11 bytes with its CRLF
+        let messages = ["PING :one", "PING :two", "PING :ten"];
+        let mut trickle = Trickle { room: 25 };
+        let (delivered, err) = write_batch(&mut trickle, &mut Vec::new(), &messages).unwrap_err();
+        assert_eq!(delivered, 2);
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(write_batch(&mut Trickle { room: 33 }, &mut Vec::new(), &messages).is_ok());
+    }
+
+    #[test]
+    fn test_in_process_listener() {
+        let (mut listener, connector) = in_process_listener();
+        let (mut client_read, mut client_write) = connector.connect().unwrap();
+        let (mut server_read, mut server_write) = listener.accept().unwrap();
+        client_write.write_message("PING :1").unwrap();
+        assert_eq!(server_read.read_message(), Ok("PING :1".to_string()));
+        server_write.write_message("PONG :1").unwrap();
+        assert_eq!(client_read.read_message(), Ok("PONG :1".to_string()));
+
+        // Nobody is left to connect, so nothing more will be accepted
+        drop(connector);
+        assert!(listener.accept().is_none());
+
+        let (listener, connector) = in_process_listener();
+        drop(listener);
+        assert!(connector.connect().is_err());
+    }
+
+    #[test]
+    fn test_in_process_connection_closes_on_drop() {
+        let ((mut server_read, _server_write), (_client_read, client_write)) = in_process();
+        drop(client_write);
+        assert_eq!(server_read.read_message(), Err(ConnectionError::Closed));
+    }
+
+    /// A client connection to a fresh listener, with the server's socket.
+    fn tcp_pair() -> ((ConnectionRead, ConnectionWrite), TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_read_errors() {
+        use std::io::Write;
+
+        let ((mut conn_read, _conn_write), mut server) = tcp_pair();
+        conn_read.set_read_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(conn_read.read_message(), Err(ConnectionError::Timeout));
+
+        server
+            .write_all(format!("{}\r\nok\r\n", "x".repeat(600)).as_bytes())
+            .unwrap();
+        assert_eq!(conn_read.read_message(), Err(ConnectionError::LineTooLong));
+        assert_eq!(conn_read.read_message(), Ok("ok".to_string()));
+
+        server.write_all(b"\xff\xfe\r\nok\r\n").unwrap();
+        assert_eq!(
+            conn_read.read_message(),
+            Err(ConnectionError::InvalidEncoding)
+        );
+        assert_eq!(conn_read.read_message(), Ok("ok".to_string()));
+
+        drop(server);
+        assert_eq!(conn_read.read_message(), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn test_reset() {
+        let ((mut conn_read, mut conn_write), server) = tcp_pair();
+        // Closing a socket with unread data makes the kernel reset the
+        // connection rather than close it cleanly
+        conn_write.write_message("NICK tom\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        drop(server);
+        conn_read.set_read_timeout(Some(Duration::from_secs(1)));
+        let err = conn_read.read_message().unwrap_err();
+        assert_eq!(err, ConnectionError::Reset);
+        assert!(err.is_fatal());
+    }
+
+    #[test]
+    fn test_from_io() {
+        let from_kind = |kind| ConnectionError::from_io(&io::Error::from(kind));
+        assert_eq!(from_kind(io::ErrorKind::BrokenPipe), ConnectionError::Reset);
+        assert_eq!(from_kind(io::ErrorKind::TimedOut), ConnectionError::Timeout);
+        assert_eq!(
+            from_kind(io::ErrorKind::UnexpectedEof),
+            ConnectionError::Closed
+        );
+        let other = from_kind(io::ErrorKind::PermissionDenied);
+        assert_eq!(other, ConnectionError::Io(io::ErrorKind::PermissionDenied));
+        assert!(other.is_fatal());
+        assert!(!ConnectionError::LineTooLong.is_fatal());
+        assert_eq!(other.to_string(), "I/O error: permission denied");
+    }
+
+    #[test]
+    fn test_launch_on_taken_port() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = taken.local_addr().unwrap();
+        let err = ConnectionManager::launch(address.ip(), address.port())
+            .err()
+            .expect("the port is taken");
+        assert_eq!(
+            err.to_string(),
+            format!("Unable to listen on {address}: address in use")
+        );
+    }
+
+    #[test]
+    fn test_accept_retries_failures() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut failures = vec![
+            io::Error::from(io::ErrorKind::Interrupted),
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            // EMFILE, "Too many open files"
+            io::Error::from_raw_os_error(24),
+        ];
+        let mut calls = 0;
+        let (conn_read, _) = accept_with(|| {
+            calls += 1;
+            match failures.pop() {
+                Some(err) => Err(err),
+                None => listener.accept(),
+            }
+        });
+        assert_eq!(calls, 4);
+        assert_eq!(conn_read.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+    }
 }
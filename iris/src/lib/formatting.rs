@@ -0,0 +1,187 @@
+/// Bold, italics, underline, strikethrough, monospace, reverse and reset.
+const TOGGLE_CODES: &[char] = &['\x02', '\x1D', '\x1F', '\x1E', '\x11', '\x16', '\x0F'];
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+/// Marks where text was cut short.
+const ELLIPSIS: char = '…';
+
+/// Whether `text` contains any mIRC color or formatting codes.
+pub fn has_formatting(text: &str) -> bool {
+    text.chars()
+        .any(|c| c == COLOR || c == HEX_COLOR || TOGGLE_CODES.contains(&c))
+}
+
+/// Removes mIRC color and formatting codes from `text`.
+///
+/// Colors are `\x03` followed by an optional foreground of up to two digits
+/// and, only if a foreground was given, an optional `,` and background of up
+/// to two digits. Hex colors (`\x04`) work the same way with six hex digits.
+pub fn strip_formatting(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let (max_len, is_digit): (usize, fn(&char) -> bool) = match c {
+            COLOR => (2, char::is_ascii_digit),
+            HEX_COLOR => (6, char::is_ascii_hexdigit),
+            _ if TOGGLE_CODES.contains(&c) => continue,
+            _ => {
+                stripped.push(c);
+                continue;
+            }
+        };
+
+        let take_color = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut taken = 0;
+            while taken < max_len && chars.peek().is_some_and(is_digit) {
+                chars.next();
+                taken += 1;
+            }
+            taken > 0
+        };
+
+        if take_color(&mut chars) && chars.peek() == Some(&',') {
+            // Only treat the comma as part of the code if a background follows.
+            let mut lookahead = chars.clone();
+            lookahead.next();
+            if lookahead.peek().is_some_and(is_digit) {
+                chars.next();
+                take_color(&mut chars);
+            }
+        }
+    }
+
+    stripped
+}
+
+/// Cuts `text` down to at most `max_len` bytes, ending it with an ellipsis
+/// if anything was taken off. Multi-byte characters are never split.
+pub fn truncate(text: &str, max_len: usize) -> String {
+    if text.len() <= max_len {
+        return text.to_string();
+    }
+    let Some(mut end) = max_len.checked_sub(ELLIPSIS.len_utf8()) else {
+        return String::new();
+    };
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{ELLIPSIS}", &text[..end])
+}
+
+/// Splits `text` into pieces of at most `max_len` bytes, which join back up
+/// into `text`. Multi-byte characters are never split, so `max_len` must be
+/// at least 4.
+pub fn split_len(text: &str, max_len: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(max_len);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, remainder) = rest.split_at(end);
+        pieces.push(piece.to_string());
+        rest = remainder;
+    }
+    pieces
+}
+
+/// Splits `text` into lines of at most `max_len` bytes, breaking between
+/// words where it can. Only words longer than a whole line are split
+/// themselves. An empty `text` gives one empty line.
+pub fn wrap_words(text: &str, max_len: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let fits = line.is_empty() || line.len() + 1 + word.len() <= max_len;
+        if !fits || word.len() > max_len {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+        } else if !line.is_empty() {
+            line.push(' ');
+        }
+        if word.len() > max_len {
+            let mut pieces = split_len(word, max_len);
+            line = pieces.pop().unwrap_or_default();
+            lines.extend(pieces);
+        } else {
+            line.push_str(word);
+        }
+    }
+    lines.push(line);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_toggles() {
+        assert_eq!(
+            strip_formatting("\x02bold\x02 \x1Ditalic\x1D \x1Funder\x0F"),
+            "bold italic under"
+        );
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        assert_eq!(strip_formatting("\x034red"), "red");
+        assert_eq!(strip_formatting("\x0304,12red on blue\x03"), "red on blue");
+        // A bare color code just resets colors.
+        assert_eq!(strip_formatting("a\x03b"), "ab");
+        // Only two digits belong to the code.
+        assert_eq!(strip_formatting("\x03123"), "3");
+        // A comma without a background is kept as text.
+        assert_eq!(strip_formatting("\x034,hi"), ",hi");
+        assert_eq!(strip_formatting("\x04ff0000,00ff00hex"), "hex");
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly10!", 10), "exactly10!");
+        // The ellipsis takes three bytes of the limit
+        assert_eq!(truncate("eleven long", 10), "eleven …");
+        assert_eq!(truncate("eleven long", 10).len(), 10);
+        assert_eq!(truncate("eleven long", 2), "");
+    }
+
+    #[test]
+    fn test_truncate_multi_byte() {
+        // "é" is two bytes, and the cut would land in the middle of the third
+        assert_eq!(truncate("ééééé", 8), "éé…");
+        assert_eq!(truncate("ééééé", 9), "ééé…");
+        assert_eq!(truncate("ééééé", 10), "ééééé");
+    }
+
+    #[test]
+    fn test_split_len() {
+        assert_eq!(split_len("abcdefg", 3), vec!["abc", "def", "g"]);
+        assert_eq!(split_len("ééé", 5), vec!["éé", "é"]);
+        assert!(split_len("", 3).is_empty());
+    }
+
+    #[test]
+    fn test_wrap_words() {
+        assert_eq!(
+            wrap_words("the quick brown fox", 10),
+            vec!["the quick", "brown fox"]
+        );
+        assert_eq!(
+            wrap_words("a abcdefghijkl b", 5),
+            vec!["a", "abcde", "fghij", "kl b"]
+        );
+        assert_eq!(wrap_words("short", 10), vec!["short"]);
+        assert_eq!(wrap_words("", 10), vec![""]);
+    }
+
+    #[test]
+    fn test_has_formatting() {
+        assert!(has_formatting("\x0304red"));
+        assert!(has_formatting("\x02bold"));
+        assert!(!has_formatting("plain, 04 text"));
+    }
+}
@@ -1,211 +1,2675 @@
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write as _},
     sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    connect::ConnectionWrite,
+    accounts::Accounts,
+    clock::local_time,
+    config::ServerConfig,
+    formatting::truncate,
+    outbox::Outbox,
+    redact::loggable,
+    state::{ChannelState, Channels, RepeatVerdict, UserState},
     types::{
-        Channel, ErrorType, JoinMsg, JoinReply, Nick, PartMsg, PartReply, PrivMsg, PrivReply,
-        QuitMsg, QuitReply, Reply, Target,
+        AcceptEntry, AcceptListReply, AcceptMsg, AwayMsg, AwayReply, AwayStatusReply, BanListReply,
+        CallerIdNotifyReply, CertMsg, Channel, ChannelMode, ChannelModeIsReply, ClosingLinkReply,
+        EndOfNamesReply, ErrorType, ForceChannelMsg, GhostMsg, IdentifyMsg, InfoReply, InviteMsg,
+        InviteReply, InvitingReply, IsonMsg, IsonReply, JoinChannelsMsg, JoinMsg, JoinReply,
+        KickMsg, KickReply, KillMsg, ListEntry, ListMsg, ListReply, LoggedInReply, LusersReply,
+        MemberStatus, MessageKind, ModeMsg, ModeReply, MotdReply, NamesReply, Nick, OperMsg,
+        PartChannelsMsg, PartMsg, PartReply, Prefix, PrivMsg, PrivReply, QuitMsg, QuitReply,
+        RegisterMsg, Reply, ServerMessage, ServerNoticeReply, TargNotifyReply, Target, TimeReply,
+        TopicIsReply, TopicMsg, TopicReply, UserModeIsReply, UserModeMsg, UserModeReply,
+        UserhostEntry, UserhostMsg, UserhostReply, VersionReply, WallopsMsg, WallopsReply,
+        WhoEntry, WhoMsg, WhoReply, WhoisMsg, WhoisReply, WhoisUser, YoureOperReply,
     },
+    webhook::{Event, Webhooks},
 };
+use time::format_description::well_known::Rfc2822;
 
-pub fn write_to_conn(target_nick: &Nick, target_conn: &mut ConnectionWrite, conn_message: String) {
-    match target_conn.write_message(&conn_message) {
-        Ok(_) => {
-            log::info!("Sent to {}: {}", target_nick, conn_message);
+thread_local! {
+    static LINE_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Composes outbound lines in a buffer kept by each thread, so relaying a
+/// message doesn't allocate a fresh `String` every time.
+pub struct LineBuffer;
+
+impl LineBuffer {
+    /// Writes `line` into the buffer and calls `use_line` with the text.
+    /// If the buffer is already in use further up the stack, a new one is
+    /// allocated instead.
+    pub fn format<T>(line: impl Display, use_line: impl FnOnce(&str) -> T) -> T {
+        LINE_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                let _ = write!(buffer, "{line}");
+                use_line(&buffer)
+            }
+            Err(_) => use_line(&line.to_string()),
+        })
+    }
+}
+
+/// Queues `conn_message` for `target_nick`. Never waits on the client.
+pub fn write_to_conn(target_nick: &Nick, target_conn: &Outbox, conn_message: impl Into<Arc<str>>) {
+    let conn_message = conn_message.into();
+    match target_conn.send(conn_message.clone()) {
+        Ok(()) => {
+            log::info!("Sent to {}: {}", target_nick, loggable(&conn_message));
+        }
+        Err(err) => {
+            log::error!("Unable to send message to {target_nick}: {err}");
+        }
+    };
+}
+
+/// Queues `conn_message` for `nickname`, returning whether they were still
+/// there to get it. Someone answered for their own message may already be
+/// gone, e.g. if they were killed or renamed while it was handled.
+pub fn write_to_user(
+    user_map: &HashMap<Nick, UserState>,
+    nickname: &Nick,
+    conn_message: impl Into<Arc<str>>,
+) -> bool {
+    match user_map.get(nickname) {
+        Some(user) => {
+            write_to_conn(nickname, &user.conn_write, conn_message);
+            true
+        }
+        None => {
+            log::warn!("Not sending to {nickname}, who is no longer connected");
+            false
+        }
+    }
+}
+
+/// Sends a burst of messages to one client in as few writes as possible.
+pub fn write_lines_to_conn(target_nick: &Nick, target_conn: &Outbox, conn_messages: &[String]) {
+    match target_conn.send_all(conn_messages.to_vec()) {
+        Ok(()) => {
+            for conn_message in conn_messages {
+                log::info!("Sent to {}: {}", target_nick, loggable(conn_message));
+            }
+        }
+        Err(err) => {
+            log::error!("Unable to send messages to {target_nick}: {err}");
+        }
+    };
+}
+
+/// Sends each of `lines` to `nickname` as a server notice, all at once.
+fn send_notices(
+    user_state: &UserState,
+    config: &ServerConfig,
+    nickname: &Nick,
+    lines: impl IntoIterator<Item = String>,
+) {
+    let messages: Vec<String> = lines
+        .into_iter()
+        .map(|line| {
+            let reply = Reply::ServerNotice(ServerNoticeReply {
+                target_nick: nickname.clone(),
+                message: line,
+            });
+            format!("{}", reply.sent_by(&config.server_name))
+        })
+        .collect();
+    write_lines_to_conn(nickname, &user_state.conn_write, &messages);
+}
+
+/// Sends `message` to every nick in `recipients`.
+///
+/// The user map is only locked while the recipients' outboxes are looked
+/// up. The message is only copied once, however many recipients there are.
+/// Once there are at least `config.fanout_threshold` recipients they are
+/// split between `config.fanout_workers` threads, which are all done before
+/// this returns.
+pub fn broadcast(
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    recipients: &[Nick],
+    message: &str,
+) {
+    let message: Arc<str> = message.into();
+    let mut targets: Vec<(&Nick, Outbox)> = Vec::with_capacity(recipients.len());
+    {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        targets.extend(recipients.iter().filter_map(|nick| {
+            let Some(user_state) = user_map_mutex.get(nick) else {
+                log::warn!("Not sending to {nick}, who has no connection");
+                return None;
+            };
+            Some((nick, user_state.conn_write.clone()))
+        }));
+    }
+    if targets.len() < config.fanout_threshold || config.fanout_workers < 2 {
+        for (nick, c_write) in &targets {
+            write_to_conn(nick, c_write, message.clone());
         }
-        Err(_) => {
-            log::error!("Unable to send message to client.");
+        return;
+    }
+
+    let chunk_size = targets.len().div_ceil(config.fanout_workers).max(1);
+    thread::scope(|scope| {
+        for chunk in targets.chunks(chunk_size) {
+            let message = &message;
+            scope.spawn(move || {
+                for (nick, c_write) in chunk {
+                    write_to_conn(nick, c_write, message.clone());
+                }
+            });
         }
+    });
+}
+
+/// Sends `message` to every user `wanted` picks out, as [`broadcast`] does.
+pub fn broadcast_to_users(
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    wanted: impl Fn(&UserState) -> bool,
+    message: &str,
+) {
+    let recipients: Vec<Nick> = user_map_clone
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, user_state)| wanted(user_state))
+        .map(|(nick, _)| nick.clone())
+        .collect();
+    broadcast(user_map_clone, config, &recipients, message);
+}
+
+/// Takes anyone left in `channel` without a connection out of it. Leaving
+/// cleans up after users, so this only finds someone if that went wrong.
+fn remove_departed(
+    channels: &mut Channels,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    channel: &Channel,
+) {
+    let departed = {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        channels.remove_departed(channel, |nick| user_map_mutex.contains_key(nick))
     };
+    for nick in departed {
+        log::warn!("Removed {nick} from {channel}, as they have no connection");
+    }
+}
+
+/// How `nickname` is shown as the source of what they send: by their full
+/// `nick!user@host`, or by nick alone if they're gone.
+fn prefix_of(user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>, nickname: &Nick) -> Prefix {
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    user_map_mutex
+        .get(nickname)
+        .map_or_else(|| Prefix::nick(nickname), |user| user.prefix(nickname))
+}
+
+/// Whether `nickname` is a server operator.
+fn is_oper(user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>, nickname: &Nick) -> bool {
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    user_map_mutex.get(nickname).is_some_and(|user| user.oper)
+}
+
+/// Sends a PRIVMSG or NOTICE on to wherever it's addressed.
+pub fn send_message(
+    channels: &Mutex<Channels>,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    now: Instant,
+    nickname: &Nick,
+    kind: MessageKind,
+    priv_msg: PrivMsg,
+) {
+    match priv_msg.target {
+        Target::Channel(channel) => private_msg_channel(
+            channels.lock().unwrap(),
+            user_map_clone.clone(),
+            config,
+            now,
+            kind,
+            channel,
+            MemberStatus::Regular,
+            priv_msg.message,
+            nickname.clone(),
+        ),
+        Target::ChannelStatus(min_status, channel) => private_msg_channel(
+            channels.lock().unwrap(),
+            user_map_clone.clone(),
+            config,
+            now,
+            kind,
+            channel,
+            min_status,
+            priv_msg.message,
+            nickname.clone(),
+        ),
+        Target::User(user) => private_msg_user(
+            user_map_clone.lock().unwrap(),
+            config,
+            kind,
+            nickname,
+            user,
+            priv_msg.message,
+            now,
+        ),
+    }
 }
 
+/// Sends a message to a channel's members, or to those of them with at
+/// least `min_status`. Anything stopping it is reported back to the sender,
+/// unless it's a NOTICE.
+///
+/// The channels lock is let go before the message is handed out, as
+/// nothing more about the channel is needed. Membership changes are handed
+/// out under the lock, which keeps anyone joining from hearing a message
+/// before their own JOIN.
+#[allow(clippy::too_many_arguments)]
 pub fn private_msg_channel(
-    channel_mutex: MutexGuard<HashMap<Channel, Vec<Nick>>>,
-    user_map_clone: Arc<Mutex<HashMap<Nick, ConnectionWrite>>>,
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    now: Instant,
+    kind: MessageKind,
     channel: Channel,
+    min_status: MemberStatus,
     priv_msg: String,
     nickname: Nick,
 ) {
-    match channel_mutex.get(&channel) {
-        Some(list) => {
-            list.iter().for_each(|nick| {
-                let mut user_map_mutex = user_map_clone.lock().unwrap();
-                let c_write = user_map_mutex.get_mut(nick).unwrap();
-                write_to_conn(
-                    nick,
-                    c_write,
-                    format!(
+    let tell_sender = |line: String| {
+        if kind == MessageKind::Notice {
+            return;
+        }
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(&user_map_mutex, &nickname, line);
+    };
+    let channel = channel_mutex.canonical(&channel);
+    remove_departed(&mut channel_mutex, &user_map_clone, &channel);
+    match channel_mutex.get_mut(&channel) {
+        Some(channel_state) => {
+            let (hostmasks, is_identified) = user_map_clone
+                .lock()
+                .unwrap()
+                .get(&nickname)
+                .map_or((Vec::new(), false), |user| {
+                    (user.hostmasks(&nickname), user.account.is_some())
+                });
+            if let Err(err) = channel_state.can_speak(&nickname, &hostmasks, is_identified) {
+                tell_sender(format!("{}\r\n", err.sent_by(&config.server_name)));
+                return;
+            }
+            let verdict = match &config.repeat_filter {
+                Some(filter) if channel_state.members.contains(&nickname) => {
+                    channel_state.check_repeat(&nickname, &priv_msg, filter, now)
+                }
+                _ => RepeatVerdict::Deliver,
+            };
+            match verdict {
+                RepeatVerdict::Deliver => {}
+                RepeatVerdict::Suppress => {
+                    tell_sender(format!(
                         "{}",
-                        Reply::PrivMsg(PrivReply {
-                            message: PrivMsg {
-                                target: Target::Channel(Channel((channel).to_string())),
-                                message: priv_msg.clone(),
-                            },
-                            sender_nick: nickname.clone()
+                        Reply::ServerNotice(ServerNoticeReply {
+                            target_nick: nickname.clone(),
+                            message: format!("Repeated message to {channel} was not delivered"),
                         })
-                    ),
-                );
+                        .sent_by(&config.server_name)
+                    ));
+                    return;
+                }
+                RepeatVerdict::Kick => {
+                    kick_member(
+                        &mut channel_mutex,
+                        &user_map_clone,
+                        config,
+                        &channel,
+                        Prefix::server(&config.server_name),
+                        &nickname,
+                        "Repeated messages".to_string(),
+                    );
+                    return;
+                }
+            }
+
+            let priv_msg = match channel_state.filter_formatting(priv_msg) {
+                Ok(priv_msg) => priv_msg,
+                Err(err) => {
+                    tell_sender(format!("{}\r\n", err.sent_by(&config.server_name)));
+                    return;
+                }
+            };
+            let (recipients, target) = if min_status == MemberStatus::Regular {
+                (channel_state.members.to_vec(), Target::Channel(channel))
+            } else {
+                match channel_state.status_recipients(&nickname, min_status) {
+                    Ok(recipients) => (recipients, Target::ChannelStatus(min_status, channel)),
+                    Err(err) => {
+                        tell_sender(format!("{}\r\n", err.sent_by(&config.server_name)));
+                        return;
+                    }
+                }
+            };
+            let reply = kind.reply(PrivReply {
+                message: PrivMsg {
+                    target,
+                    message: priv_msg,
+                },
+                sender: prefix_of(&user_map_clone, &nickname),
+            });
+            drop(channel_mutex);
+            LineBuffer::format(reply, |line| {
+                broadcast(&user_map_clone, config, &recipients, line)
             });
         }
-        None => {
-            let mut user_map_mutex = user_map_clone.lock().unwrap();
-            let c_write = user_map_mutex.get_mut(&nickname).unwrap();
-            write_to_conn(
-                &nickname,
-                c_write,
-                format!("{}\r\n", ErrorType::NoSuchChannel),
-            );
-        }
+        None => tell_sender(format!(
+            "{}\r\n",
+            ErrorType::NoSuchChannel.sent_by(&config.server_name)
+        )),
     }
 }
 
+/// Removes `kicked` from the channel, telling every member (including the
+/// one being kicked) who removed them and why.
+fn kick_member(
+    channels: &mut Channels,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    channel: &Channel,
+    kicker: Prefix,
+    kicked: &Nick,
+    reason: String,
+) {
+    let reply = Reply::Kick(KickReply {
+        sender: kicker,
+        channel: channel.clone(),
+        kicked_nick: kicked.clone(),
+        reason: truncate(&reason, config.reason_len),
+    });
+    if let Some(channel_state) = channels.get(channel) {
+        LineBuffer::format(reply, |line| {
+            broadcast(user_map_clone, config, &channel_state.members, line)
+        });
+    }
+    channels.part(channel, kicked);
+}
+
+/// Sends a message to `user`. Anything stopping it is reported back to the
+/// sender, unless it's a NOTICE.
 pub fn private_msg_user(
-    mut user_map_mutex: MutexGuard<HashMap<Nick, ConnectionWrite>>,
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    kind: MessageKind,
     nickname: &Nick,
     user: Nick,
     priv_msg: String,
+    now: Instant,
 ) {
+    let answers = kind == MessageKind::PrivMsg;
     if user_map_mutex.contains_key(&user) {
-        let c_write = user_map_mutex.get_mut(&user).unwrap();
-        write_to_conn(
-            &user,
-            c_write,
+        let Some(sender) = user_map_mutex.get(nickname) else {
+            return;
+        };
+        let sender_exempt =
+            sender.account.is_some() || (sender.oper && config.registered_only_exempts_opers);
+        let sender = sender.prefix(nickname);
+        if user != *nickname && user_map_mutex[&user].registered_only && !sender_exempt {
+            if answers {
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    format!("{}\r\n", ErrorType::NoNonReg.sent_by(&config.server_name)),
+                );
+            }
+            return;
+        }
+        let recipient = user_map_mutex.get_mut(&user).unwrap();
+        if user != *nickname && !recipient.caller_id.allows(nickname) {
+            if answers {
+                let notify = recipient.caller_id.should_notify(now);
+                caller_id_blocked(&mut user_map_mutex, config, nickname, &user, notify);
+            }
+            return;
+        }
+        let reply = kind.reply(PrivReply {
+            message: PrivMsg {
+                target: Target::User(user.clone()),
+                message: priv_msg,
+            },
+            sender,
+        });
+        LineBuffer::format(reply, |line| {
+            write_to_conn(&user, &recipient.conn_write, line)
+        });
+        if let Some(message) = recipient.away.clone().filter(|_| answers) {
+            let reply = Reply::Away(AwayReply {
+                target_nick: nickname.clone(),
+                nick: user,
+                message,
+            });
+            write_to_user(
+                &user_map_mutex,
+                nickname,
+                format!("{}", reply.sent_by(&config.server_name)),
+            );
+        }
+    } else if answers {
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", ErrorType::NoSuchNick.sent_by(&config.server_name)),
+        );
+    }
+}
+
+/// Tells `nickname` that `user` is in caller-ID mode and, if `notify` is
+/// set, lets `user` know who tried to reach them.
+fn caller_id_blocked(
+    user_map_mutex: &mut MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    user: &Nick,
+    notify: bool,
+) {
+    if notify {
+        let sender = user_map_mutex[nickname].prefix(nickname);
+        write_to_user(
+            user_map_mutex,
+            user,
             format!(
                 "{}",
-                Reply::PrivMsg(PrivReply {
-                    message: PrivMsg {
-                        target: Target::User(user.clone()),
-                        message: priv_msg,
-                    },
-                    sender_nick: nickname.clone()
+                Reply::CallerIdNotify(CallerIdNotifyReply {
+                    target_nick: user.clone(),
+                    sender,
                 })
+                .sent_by(&config.server_name)
+            ),
+        );
+    }
+
+    write_to_user(
+        user_map_mutex,
+        nickname,
+        format!("{}\r\n", ErrorType::TargUModeG.sent_by(&config.server_name)),
+    );
+    if notify {
+        write_to_user(
+            user_map_mutex,
+            nickname,
+            format!(
+                "{}",
+                Reply::TargNotify(TargNotifyReply {
+                    target_nick: nickname.clone(),
+                    notified_nick: user.clone(),
+                })
+                .sent_by(&config.server_name)
             ),
         );
-    } else {
-        let c_write = user_map_mutex.get_mut(nickname).unwrap();
-        write_to_conn(&user, c_write, format!("{}\r\n", ErrorType::NoSuchNick));
     }
 }
 
 pub fn join_channel(
-    mut channel_mutex: MutexGuard<HashMap<Channel, Vec<Nick>>>,
-    user_map_clone: Arc<Mutex<HashMap<Nick, ConnectionWrite>>>,
+    channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
     nickname: &Nick,
     join_msg: JoinMsg,
 ) {
-    match channel_mutex.get_mut(&join_msg.channel) {
-        Some(list) => {
-            if !list.contains(nickname) {
-                list.push(nickname.clone());
-                list.iter().for_each(|nick| {
-                    let mut user_map_mutex = user_map_clone.lock().unwrap();
-                    let c_write = user_map_mutex.get_mut(nick).unwrap();
-                    write_to_conn(
-                        nick,
-                        c_write,
-                        format!(
-                            "{}",
-                            Reply::Join(JoinReply {
-                                message: JoinMsg {
-                                    channel: Channel(join_msg.channel.to_string())
-                                },
-                                sender_nick: nickname.clone()
-                            })
-                        ),
-                    );
-                });
+    let (hostmasks, is_oper, is_secure, is_identified) = user_map_clone
+        .lock()
+        .unwrap()
+        .get(nickname)
+        .map_or((Vec::new(), false, false, false), |user| {
+            (
+                user.hostmasks(nickname),
+                user.oper,
+                user.secure,
+                user.account.is_some(),
+            )
+        });
+    let allowed = match channel_mutex.get(&join_msg.channel) {
+        Some(channel_state) if channel_state.members.contains(nickname) => return,
+        Some(channel_state) => channel_state.can_join(
+            nickname,
+            &hostmasks,
+            join_msg.key.as_deref(),
+            is_oper,
+            is_secure,
+            is_identified,
+        ),
+        None if channel_mutex.len() >= config.max_channels
+            && !(is_oper && config.max_channels_exempts_opers) =>
+        {
+            Err(ErrorType::TooManyChannels)
+        }
+        None => Ok(()),
+    };
+    if let Err(err) = allowed {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+    add_member(
+        channel_mutex,
+        &user_map_clone,
+        config,
+        webhooks,
+        nickname,
+        join_msg.channel,
+    );
+}
+
+/// Puts `nickname` in `channel`, creating it +n if need be, and tells every
+/// member about the join. The new member is then told the topic, if there
+/// is one, and who else is there. Nothing is checked.
+fn add_member(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    channel: Channel,
+) {
+    let channel = channel_mutex.canonical(&channel);
+    remove_departed(&mut channel_mutex, user_map_clone, &channel);
+    let created = channel_mutex.get(&channel).is_none();
+    if created {
+        // New channels start out +n, as they do on other servers
+        channel_mutex.get_or_create(&channel).no_external = true;
+    }
+    channel_mutex.join(&channel, nickname);
+    let channel_state = channel_mutex.get(&channel).unwrap();
+    let reply = Reply::Join(JoinReply {
+        message: JoinMsg {
+            channel: channel.clone(),
+            key: None,
+        },
+        sender: prefix_of(user_map_clone, nickname),
+    });
+    // The new member gets the join, topic and names in one write, so the
+    // later lines don't wait on the first being acknowledged
+    let mut lines = Vec::new();
+    let others: Vec<Nick> = channel_state
+        .members
+        .iter()
+        .filter(|nick| *nick != nickname)
+        .cloned()
+        .collect();
+    LineBuffer::format(reply, |line| {
+        lines.push(line.to_string());
+        broadcast(user_map_clone, config, &others, line);
+    });
+    if let Some(topic) = &channel_state.topic {
+        let reply = Reply::TopicIs(TopicIsReply {
+            target_nick: nickname.clone(),
+            channel: channel.clone(),
+            topic: Some(topic.clone()),
+        });
+        lines.push(format!("{}", reply.sent_by(&config.server_name)));
+    }
+    {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        lines.extend(names_reply(
+            &channel_mutex,
+            &user_map_mutex,
+            config,
+            nickname,
+            Some(&channel),
+        ));
+        if let Some(user) = user_map_mutex.get(nickname) {
+            write_lines_to_conn(nickname, &user.conn_write, &lines);
+        }
+    }
+    if created {
+        webhooks.notify(Event::ChannelCreated {
+            channel,
+            nick: nickname.clone(),
+        });
+    }
+}
+
+/// Joins `nickname` to each channel in `join_msg` in turn, as if each had
+/// its own JOIN, or parts them from every channel for `JOIN 0`.
+pub fn join_channels(
+    channels: &Mutex<Channels>,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    join_msg: JoinChannelsMsg,
+) {
+    let names = match join_msg {
+        JoinChannelsMsg::Channels(names) => names,
+        JoinChannelsMsg::PartAll => {
+            let mut their_channels = channels.lock().unwrap().channels_of(nickname);
+            their_channels.sort_by(|a, b| a.0.cmp(&b.0));
+            for channel in their_channels {
+                part_channel(
+                    channels.lock().unwrap(),
+                    user_map_clone.clone(),
+                    config,
+                    PartMsg {
+                        channel,
+                        reason: None,
+                    },
+                    nickname,
+                );
             }
+            return;
         }
-        None => {
-            let mut user_map_mutex = user_map_clone.lock().unwrap();
-            let c_write = user_map_mutex.get_mut(nickname).unwrap();
-            write_to_conn(
+    };
+    for (name, key) in names {
+        match Channel::parse(&name) {
+            Ok(channel) => join_channel(
+                channels.lock().unwrap(),
+                user_map_clone.clone(),
+                config,
+                webhooks,
                 nickname,
-                c_write,
-                format!(
-                    "{}",
-                    Reply::Join(JoinReply {
-                        message: JoinMsg {
-                            channel: Channel(join_msg.channel.to_string())
-                        },
-                        sender_nick: nickname.clone()
-                    })
-                ),
-            );
-            channel_mutex.insert(join_msg.channel, vec![nickname.clone()]);
+                JoinMsg { channel, key },
+            ),
+            Err(err) => {
+                let user_map_mutex = user_map_clone.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    format!("{}\r\n", err.sent_by(&config.server_name)),
+                );
+            }
+        }
+    }
+}
+
+/// Parts `nickname` from each channel in `part_msg` in turn, as if each had
+/// its own PART.
+pub fn part_channels(
+    channels: &Mutex<Channels>,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    part_msg: PartChannelsMsg,
+) {
+    for name in part_msg.channels {
+        match Channel::try_from(name) {
+            Ok(channel) => part_channel(
+                channels.lock().unwrap(),
+                user_map_clone.clone(),
+                config,
+                PartMsg {
+                    channel,
+                    reason: part_msg.reason.clone(),
+                },
+                nickname,
+            ),
+            Err(err) => {
+                let user_map_mutex = user_map_clone.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    format!("{}\r\n", err.sent_by(&config.server_name)),
+                );
+            }
         }
     }
 }
 
 pub fn part_channel(
-    mut channel_mutex: MutexGuard<HashMap<Channel, Vec<Nick>>>,
-    user_map_clone: Arc<Mutex<HashMap<Nick, ConnectionWrite>>>,
+    channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
     part_msg: PartMsg,
     nickname: &Nick,
 ) {
-    match channel_mutex.get_mut(&part_msg.channel) {
-        Some(list) => {
-            if list.contains(nickname) {
-                list.iter().for_each(|nick| {
-                    let mut user_map_mutex = user_map_clone.lock().unwrap();
-                    let c_write = user_map_mutex.get_mut(nick).unwrap();
-                    write_to_conn(
-                        nick,
-                        c_write,
-                        format!(
-                            "{}",
-                            Reply::Part(PartReply {
-                                message: PartMsg {
-                                    channel: Channel(part_msg.channel.to_string())
-                                },
-                                sender_nick: nickname.clone()
-                            })
+    let allowed = match channel_mutex.get(&part_msg.channel) {
+        Some(channel_state) if channel_state.members.contains(nickname) => Ok(()),
+        Some(_) => Err(ErrorType::NotOnChannel),
+        None => Err(ErrorType::NoSuchChannel),
+    };
+    if let Err(err) = allowed {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+    remove_member(
+        channel_mutex,
+        &user_map_clone,
+        config,
+        nickname,
+        part_msg.channel,
+        part_msg.reason,
+    );
+}
+
+/// Removes `kick_msg.nick` from the channel on `nickname`'s behalf. The
+/// reason defaults to the kicker's nick.
+pub fn kick_channel(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    kick_msg: KickMsg,
+) {
+    let allowed = match channel_mutex.get(&kick_msg.channel) {
+        Some(channel_state) => channel_state.can_kick(nickname, &kick_msg.nick),
+        None => Err(ErrorType::NoSuchChannel),
+    };
+    if let Err(err) = allowed {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+    kick_member(
+        &mut channel_mutex,
+        &user_map_clone,
+        config,
+        &kick_msg.channel,
+        prefix_of(&user_map_clone, nickname),
+        &kick_msg.nick,
+        kick_msg.reason.unwrap_or_else(|| nickname.to_string()),
+    );
+}
+
+/// Invites `invite_msg.nick` to the channel on `nickname`'s behalf, letting
+/// them in once even if it is +i.
+pub fn invite_channel(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    invite_msg: InviteMsg,
+) {
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    let allowed = match channel_mutex.get(&invite_msg.channel) {
+        None => Err(ErrorType::NoSuchChannel),
+        Some(_) if !user_map_mutex.contains_key(&invite_msg.nick) => Err(ErrorType::NoSuchNick),
+        Some(channel_state) => channel_state.can_invite(nickname, &invite_msg.nick),
+    };
+    if allowed.is_ok() {
+        channel_mutex.invite(&invite_msg.channel, &invite_msg.nick);
+    }
+    let Some(sender) = user_map_mutex.get(nickname) else {
+        return;
+    };
+    let c_write = &sender.conn_write;
+    if let Err(err) = allowed {
+        write_to_conn(
+            nickname,
+            c_write,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+
+    let reply = Reply::Inviting(InvitingReply {
+        target_nick: nickname.clone(),
+        message: invite_msg.clone(),
+    });
+    write_to_conn(
+        nickname,
+        c_write,
+        format!("{}", reply.sent_by(&config.server_name)),
+    );
+    let invited = invite_msg.nick.clone();
+    let reply = Reply::Invite(InviteReply {
+        sender: sender.prefix(nickname),
+        message: invite_msg,
+    });
+    write_to_user(
+        &user_map_mutex,
+        &invited,
+        format!("{}", reply.sent_by(&config.server_name)),
+    );
+}
+
+/// Tells every member of `channel` that `nickname` is leaving, and why if
+/// `reason` says, then takes them out of it.
+fn remove_member(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    channel: Channel,
+    reason: Option<String>,
+) {
+    let channel = channel_mutex.canonical(&channel);
+    if let Some(channel_state) = channel_mutex.get(&channel) {
+        let reply = Reply::Part(PartReply {
+            message: PartMsg {
+                channel: channel.clone(),
+                reason: reason.map(|reason| truncate(&reason, config.reason_len)),
+            },
+            sender: prefix_of(user_map_clone, nickname),
+        });
+        LineBuffer::format(reply, |line| {
+            broadcast(user_map_clone, config, &channel_state.members, line)
+        });
+    }
+    channel_mutex.part(&channel, nickname);
+}
+
+/// Makes `force_msg.nick` join or part `force_msg.channel` on an operator's
+/// say-so. A forced join ignores +O and +z. The target is told who moved them.
+pub fn force_channel(
+    channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    joining: bool,
+    force_msg: ForceChannelMsg,
+) {
+    let ForceChannelMsg { nick, channel } = force_msg;
+    let is_member = channel_mutex
+        .get(&channel)
+        .map(|channel_state| channel_state.members.contains(&nick));
+    let result = if !is_oper(&user_map_clone, nickname) {
+        Err(ErrorType::NoPrivileges)
+    } else if !user_map_clone.lock().unwrap().contains_key(&nick) {
+        Err(ErrorType::NoSuchNick)
+    } else {
+        match (joining, is_member) {
+            (true, Some(true)) => Err(ErrorType::UserOnChannel),
+            (true, _) => Ok(()),
+            (false, None) => Err(ErrorType::NoSuchChannel),
+            (false, Some(false)) => Err(ErrorType::UserNotInChannel),
+            (false, Some(true)) => Ok(()),
+        }
+    };
+    if let Err(err) = result {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+
+    let (command, verb) = if joining {
+        ("SAJOIN", "join")
+    } else {
+        ("SAPART", "leave")
+    };
+    webhooks.notify(Event::OperAction {
+        nick: nickname.clone(),
+        action: format!("{command} {nick} {channel}"),
+    });
+    let notice = Reply::ServerNotice(ServerNoticeReply {
+        target_nick: nick.clone(),
+        message: format!("Operator {nickname} made you {verb} {channel}"),
+    });
+    let notice = format!("{}", notice.sent_by(&config.server_name));
+    if joining {
+        add_member(
+            channel_mutex,
+            &user_map_clone,
+            config,
+            webhooks,
+            &nick,
+            channel,
+        );
+    } else {
+        remove_member(channel_mutex, &user_map_clone, config, &nick, channel, None);
+    }
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    if let Some(user_state) = user_map_mutex.get(&nick) {
+        write_to_conn(&nick, &user_state.conn_write, notice);
+    }
+}
+
+pub fn quit_server(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    message: String,
+) {
+    // The nick is free again before anyone hears they've gone
+    let sender = match user_map_clone.lock().unwrap().remove(nickname) {
+        Some(user_state) => user_state.prefix(nickname),
+        None => Prefix::nick(nickname),
+    };
+    let reply = Reply::Quit(QuitReply {
+        message: QuitMsg {
+            message: Some(message),
+        },
+        sender,
+    });
+    // Everyone left in their channels, once each
+    let neighbours: HashSet<Nick> = channel_mutex
+        .quit(nickname)
+        .iter()
+        .filter_map(|channel| channel_mutex.get(channel))
+        .flat_map(|channel_state| channel_state.members.iter().cloned())
+        .collect();
+    let neighbours: Vec<Nick> = neighbours.into_iter().collect();
+    LineBuffer::format(reply, |line| {
+        broadcast(&user_map_clone, config, &neighbours, line)
+    });
+}
+
+/// The LUSERS reply, ready to send to `nickname`. Every registered user is
+/// counted, bots included, with invisible users counted apart.
+pub fn lusers_reply(
+    channels: &Channels,
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    max_users: usize,
+) -> String {
+    let invisible = user_map.values().filter(|user| user.invisible).count();
+    let reply = Reply::Lusers(LusersReply {
+        target_nick: nickname.clone(),
+        users: user_map.len(),
+        invisible,
+        max_users,
+        channels: channels.len(),
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The message of the day, ready to send to `nickname`, or ERR_NOMOTD if
+/// there isn't one.
+pub fn motd_reply(config: &ServerConfig, nickname: &Nick) -> String {
+    match &config.motd {
+        Some(lines) => {
+            let reply = Reply::Motd(MotdReply {
+                target_nick: nickname.clone(),
+                lines: lines.clone(),
+            });
+            format!("{}", reply.sent_by(&config.server_name))
+        }
+        None => format!("{}\r\n", ErrorType::NoMotd.sent_by(&config.server_name)),
+    }
+}
+
+/// The server's version, ready to send to `nickname`.
+pub fn version_reply(config: &ServerConfig, nickname: &Nick) -> String {
+    let reply = Reply::Version(VersionReply {
+        target_nick: nickname.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The server's local time, ready to send to `nickname`.
+pub fn time_reply(config: &ServerConfig, nickname: &Nick) -> String {
+    let reply = Reply::Time(TimeReply {
+        target_nick: nickname.clone(),
+        time: local_time().format(&Rfc2822).unwrap_or_default(),
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// A few lines about the server, ready to send to `nickname`.
+pub fn info_reply(config: &ServerConfig, nickname: &Nick) -> String {
+    let reply = Reply::Info(InfoReply {
+        target_nick: nickname.clone(),
+        lines: vec![
+            format!("iris {}", env!("CARGO_PKG_VERSION")),
+            "A small IRC server, written in Rust.".to_string(),
+            format!("Running as {}.", config.server_name),
+        ],
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The NAMES reply for `channel`, or for every channel when it is `None`,
+/// ready to send to `nickname`. Each member is listed with their status
+/// prefix, such as `@` for channel operators. Secret channels `nickname`
+/// isn't in are left out, as are invisible members of channels they aren't
+/// in.
+pub fn names_reply(
+    channels: &Channels,
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    channel: Option<&Channel>,
+) -> Vec<String> {
+    let names = |channel: &Channel, channel_state: &ChannelState| {
+        let is_member = channel_state.members.contains(nickname);
+        let names = channel_state
+            .members
+            .iter()
+            .filter(|member| is_member || user_map.get(*member).is_none_or(|user| !user.invisible))
+            .map(|member| match channel_state.status(member).prefix() {
+                Some(prefix) => format!("{prefix}{member}"),
+                None => member.to_string(),
+            })
+            .collect();
+        let reply = Reply::Names(NamesReply {
+            target_nick: nickname.clone(),
+            channel: channel.clone(),
+            names,
+        });
+        format!("{}", reply.sent_by(&config.server_name))
+    };
+
+    let channel = channel.map(|channel| channels.canonical(channel));
+    let mut lines: Vec<String> = match &channel {
+        Some(channel) => channels
+            .get(channel)
+            .filter(|channel_state| channel_state.is_visible_to(nickname))
+            .map(|channel_state| names(channel, channel_state))
+            .into_iter()
+            .collect(),
+        None => {
+            let mut listed: Vec<_> = channels
+                .iter()
+                .filter(|(_, channel_state)| channel_state.is_visible_to(nickname))
+                .collect();
+            listed.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+            listed
+                .into_iter()
+                .map(|(channel, channel_state)| names(channel, channel_state))
+                .collect()
+        }
+    };
+    // Channels kept open with nobody in them have no names to list
+    lines.retain(|line| !line.is_empty());
+    let end = Reply::EndOfNames(EndOfNamesReply {
+        target_nick: nickname.clone(),
+        mask: channel.map_or_else(|| "*".to_string(), |channel| channel.to_string()),
+    });
+    lines.push(format!("{}", end.sent_by(&config.server_name)));
+    lines
+}
+
+/// The LIST reply to `list_msg`, ready to send to `nickname`. Channels are
+/// listed by name, leaving out secret channels `nickname` isn't in.
+pub fn list_reply(
+    channels: &Channels,
+    config: &ServerConfig,
+    nickname: &Nick,
+    list_msg: ListMsg,
+) -> String {
+    let mut entries: Vec<ListEntry> = channels
+        .iter()
+        .filter(|(channel, _)| list_msg.channels.is_empty() || list_msg.channels.contains(channel))
+        .filter(|(_, channel_state)| channel_state.is_visible_to(nickname))
+        .filter(|(_, channel_state)| {
+            list_msg
+                .min_members
+                .is_none_or(|min_members| channel_state.members.len() > min_members)
+        })
+        .map(|(channel, channel_state)| ListEntry {
+            channel: channel.clone(),
+            members: channel_state.members.len(),
+            topic: channel_state
+                .topic
+                .as_ref()
+                .map_or_else(String::new, |topic| topic.text.clone()),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.channel.0.cmp(&b.channel.0));
+    let reply = Reply::List(ListReply {
+        target_nick: nickname.clone(),
+        entries,
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The WHO reply to `who_msg`, ready to send to `nickname`. A mask that
+/// matches no channel or user gets just the end of the list. Invisible
+/// users are left out unless they share a channel with `nickname`.
+pub fn who_reply(
+    channels: &Channels,
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    who_msg: WhoMsg,
+) -> String {
+    let entry = |channel: &str, nick: &Nick, status: MemberStatus| {
+        let user_state = user_map.get(nick)?;
+        if user_state.invisible && nick != nickname && !channels.share_channel(nick, nickname) {
+            return None;
+        }
+        Some(WhoEntry {
+            channel: channel.to_string(),
+            nick: nick.clone(),
+            user: user_state.username.clone(),
+            host: user_state.visible_host(),
+            oper: user_state.oper,
+            away: user_state.away.is_some(),
+            status,
+            real_name: user_state.real_name.clone(),
+        })
+    };
+    let entries = match Channel::try_from(who_msg.mask.clone()) {
+        Ok(channel) => channels
+            .get(&channel)
+            .map_or_else(Vec::new, |channel_state| {
+                channel_state
+                    .members
+                    .iter()
+                    .filter_map(|member| entry(&who_msg.mask, member, channel_state.status(member)))
+                    .collect()
+            }),
+        Err(_) => Nick::parse(&who_msg.mask)
+            .ok()
+            .and_then(|nick| entry("*", &nick, MemberStatus::Regular))
+            .into_iter()
+            .collect(),
+    };
+    let reply = Reply::Who(WhoReply {
+        target_nick: nickname.clone(),
+        mask: who_msg.mask,
+        entries,
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The ISON reply to `ison_msg`, ready to send to `nickname`. Nicks are
+/// listed as the server knows them.
+pub fn ison_reply(
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    ison_msg: IsonMsg,
+) -> String {
+    let reply = Reply::Ison(IsonReply {
+        target_nick: nickname.clone(),
+        nicks: ison_msg
+            .nicks
+            .iter()
+            .filter_map(|nick| user_map.get_key_value(nick))
+            .map(|(nick, _)| nick.clone())
+            .collect(),
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The USERHOST reply to `userhost_msg`, ready to send to `nickname`.
+pub fn userhost_reply(
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    userhost_msg: UserhostMsg,
+) -> String {
+    let reply = Reply::Userhost(UserhostReply {
+        target_nick: nickname.clone(),
+        entries: userhost_msg
+            .nicks
+            .iter()
+            .filter_map(|nick| user_map.get_key_value(nick))
+            .map(|(nick, user_state)| UserhostEntry {
+                nick: nick.clone(),
+                user: user_state.username.clone(),
+                host: user_state.visible_host(),
+                oper: user_state.oper,
+                away: user_state.away.is_some(),
+            })
+            .collect(),
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// The WHOIS reply to `whois_msg`, ready to send to `nickname`. Secret
+/// channels are only listed if `nickname` is in them too.
+pub fn whois_reply(
+    channels: &Channels,
+    user_map: &HashMap<Nick, UserState>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    whois_msg: WhoisMsg,
+) -> String {
+    let user = user_map.get(&whois_msg.nick).map(|user_state| {
+        let mut their_channels = channels.channels_of(&whois_msg.nick);
+        their_channels.retain(|channel| {
+            channels
+                .get(channel)
+                .is_some_and(|channel_state| channel_state.is_visible_to(nickname))
+        });
+        their_channels.sort_by(|a, b| a.0.cmp(&b.0));
+        WhoisUser {
+            user: user_state.username.clone(),
+            host: user_state.visible_host(),
+            real_name: user_state.real_name.clone(),
+            channels: their_channels
+                .into_iter()
+                .map(|channel| {
+                    let status = channels
+                        .get(&channel)
+                        .map_or(MemberStatus::Regular, |channel_state| {
+                            channel_state.status(&whois_msg.nick)
+                        });
+                    match status.prefix() {
+                        Some(prefix) => format!("{prefix}{channel}"),
+                        None => channel.to_string(),
+                    }
+                })
+                .collect(),
+            away: user_state.away.clone(),
+        }
+    });
+    let reply = Reply::Whois(WhoisReply {
+        target_nick: nickname.clone(),
+        nick: whois_msg.nick,
+        user,
+    });
+    format!("{}", reply.sent_by(&config.server_name))
+}
+
+/// Tells `nickname` the topic of `topic_msg.channel`, or sets it at `now`
+/// and tells every member. Only members may set the topic, and only
+/// operators under +t.
+pub fn topic_channel(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    topic_msg: TopicMsg,
+    now: SystemTime,
+) {
+    let reply = |reply: Reply| {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}", reply.sent_by(&config.server_name)),
+        );
+    };
+
+    let Some(channel_state) = channel_mutex.get_mut(&topic_msg.channel) else {
+        reply(Reply::Error(ErrorType::NoSuchChannel));
+        return;
+    };
+
+    let Some(text) = topic_msg.topic else {
+        reply(Reply::TopicIs(TopicIsReply {
+            target_nick: nickname.clone(),
+            channel: topic_msg.channel,
+            topic: channel_state.topic.clone(),
+        }));
+        return;
+    };
+
+    if let Err(err) = channel_state.can_set_topic(nickname) {
+        reply(Reply::Error(err));
+        return;
+    }
+
+    let set_at = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let text = truncate(&text, config.topic_len);
+    channel_state.set_topic(nickname, text.clone(), set_at);
+    let reply = Reply::Topic(TopicReply {
+        sender: prefix_of(&user_map_clone, nickname),
+        channel: topic_msg.channel,
+        topic: text,
+    });
+    LineBuffer::format(reply, |line| {
+        broadcast(&user_map_clone, config, &channel_state.members, line)
+    });
+}
+
+pub fn mode_channel(
+    mut channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    mode_msg: ModeMsg,
+) {
+    let error = |error: ErrorType| {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", error.sent_by(&config.server_name)),
+        );
+    };
+
+    let Some(channel_state) = channel_mutex.get_mut(&mode_msg.channel) else {
+        error(ErrorType::NoSuchChannel);
+        return;
+    };
+
+    if mode_msg.changes.is_empty() {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!(
+                "{}",
+                Reply::ChannelModeIs(ChannelModeIsReply {
+                    target_nick: nickname.clone(),
+                    channel: mode_msg.channel.clone(),
+                    modes: channel_state.mode_string_for(nickname),
+                })
+                .sent_by(&config.server_name)
+            ),
+        );
+        return;
+    }
+
+    let is_oper = is_oper(&user_map_clone, nickname);
+
+    // Changes are applied in order; any that are not permitted are skipped.
+    let mut applied = Vec::new();
+    for change in mode_msg.changes {
+        if change.mode == ChannelMode::Ban(None) {
+            let reply = Reply::BanList(BanListReply {
+                target_nick: nickname.clone(),
+                channel: mode_msg.channel.clone(),
+                masks: channel_state.bans.clone(),
+            });
+            let user_map_mutex = user_map_clone.lock().unwrap();
+            write_to_user(
+                &user_map_mutex,
+                nickname,
+                format!("{}", reply.sent_by(&config.server_name)),
+            );
+            continue;
+        }
+        match channel_state.can_change_mode(nickname, is_oper, &change.mode) {
+            Ok(()) => {
+                channel_state.apply_mode(change.adding, &change.mode);
+                if matches!(change.mode, ChannelMode::OperOnly | ChannelMode::Persistent) {
+                    let sign = if change.adding { '+' } else { '-' };
+                    webhooks.notify(Event::OperAction {
+                        nick: nickname.clone(),
+                        action: format!(
+                            "MODE {} {}{}",
+                            mode_msg.channel,
+                            sign,
+                            change.mode.letter()
                         ),
-                    );
+                    });
+                }
+                applied.push(change);
+            }
+            Err(err) => error(err),
+        }
+    }
+
+    if applied.is_empty() {
+        return;
+    }
+
+    let reply = Reply::Mode(ModeReply {
+        message: ModeMsg {
+            channel: mode_msg.channel.clone(),
+            changes: applied,
+        },
+        sender: prefix_of(&user_map_clone, nickname),
+    });
+    // Opers may change modes on channels they are not in, so make sure
+    // the sender always sees the result.
+    let mut recipients = channel_state.members.to_vec();
+    if !channel_state.members.contains(nickname) {
+        recipients.push(nickname.clone());
+    }
+    LineBuffer::format(reply, |line| {
+        recipients.iter().for_each(|nick| {
+            let user_map_mutex = user_map_clone.lock().unwrap();
+            write_to_user(&user_map_mutex, nick, line);
+        })
+    });
+
+    channel_mutex.remove_if_disposable(&mode_msg.channel);
+}
+
+pub fn mode_user(
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    mode_msg: UserModeMsg,
+) {
+    let Some(user_state) = user_map_mutex.get_mut(nickname) else {
+        return;
+    };
+    if mode_msg.nick != *nickname {
+        write_to_conn(
+            nickname,
+            &user_state.conn_write,
+            format!(
+                "{}\r\n",
+                ErrorType::UsersDontMatch.sent_by(&config.server_name)
+            ),
+        );
+        return;
+    }
+
+    let reply = if mode_msg.changes.is_empty() {
+        Reply::UserModeIs(UserModeIsReply {
+            target_nick: nickname.clone(),
+            modes: user_state.mode_string(),
+        })
+    } else {
+        mode_msg
+            .changes
+            .iter()
+            .for_each(|change| user_state.apply_mode(change.adding, change.mode));
+        Reply::UserMode(UserModeReply {
+            message: mode_msg,
+            sender: user_state.prefix(nickname),
+        })
+    };
+    write_to_conn(
+        nickname,
+        &user_state.conn_write,
+        format!("{}", reply.sent_by(&config.server_name)),
+    );
+}
+
+/// Marks `nickname` as away with `away_msg`'s message, or as back if there
+/// isn't one.
+pub fn set_away(
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    away_msg: AwayMsg,
+) {
+    let Some(user_state) = user_map_mutex.get_mut(nickname) else {
+        return;
+    };
+    let reply = Reply::AwayStatus(AwayStatusReply {
+        target_nick: nickname.clone(),
+        away: away_msg.message.is_some(),
+    });
+    user_state.away = away_msg.message;
+    write_to_conn(
+        nickname,
+        &user_state.conn_write,
+        format!("{}", reply.sent_by(&config.server_name)),
+    );
+}
+
+/// Makes `nickname` a server operator if `oper_msg` holds the name and
+/// password of one of the configured operators.
+pub fn oper_up(
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    oper_msg: OperMsg,
+) {
+    let verified = config
+        .operators
+        .iter()
+        .any(|operator| operator.verify(&oper_msg.name, &oper_msg.password));
+    let Some(user_state) = user_map_mutex.get_mut(nickname) else {
+        return;
+    };
+    let reply = if verified {
+        user_state.oper = true;
+        webhooks.notify(Event::OperAction {
+            nick: nickname.clone(),
+            action: format!("OPER {}", oper_msg.name),
+        });
+        Reply::YoureOper(YoureOperReply {
+            target_nick: nickname.clone(),
+        })
+    } else {
+        Reply::Error(ErrorType::PasswdMismatch)
+    };
+    write_to_conn(
+        nickname,
+        &user_state.conn_write,
+        format!("{}", reply.sent_by(&config.server_name)),
+    );
+}
+
+pub fn accept_users(
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    accept_msg: AcceptMsg,
+) {
+    for entry in accept_msg.entries {
+        let result = match &entry {
+            AcceptEntry::Add(nick) if !user_map_mutex.contains_key(nick) => {
+                Err(ErrorType::NoSuchNick)
+            }
+            AcceptEntry::Add(nick) => user_map_mutex
+                .get_mut(nickname)
+                .unwrap()
+                .caller_id
+                .accept(nick.clone()),
+            AcceptEntry::Remove(nick) => user_map_mutex
+                .get_mut(nickname)
+                .unwrap()
+                .caller_id
+                .unaccept(nick),
+            AcceptEntry::List => Ok(()),
+        };
+
+        let Some(user_state) = user_map_mutex.get_mut(nickname) else {
+            return;
+        };
+        match (result, entry) {
+            (Err(err), _) => {
+                let message = format!("{}\r\n", err.sent_by(&config.server_name));
+                write_to_conn(nickname, &user_state.conn_write, message);
+            }
+            (Ok(()), AcceptEntry::List) => {
+                let reply = Reply::AcceptList(AcceptListReply {
+                    target_nick: nickname.clone(),
+                    accepted: user_state.caller_id.accepted.clone(),
                 });
-                list.retain(|x| x != nickname);
+                let message = format!("{}", reply.sent_by(&config.server_name));
+                write_to_conn(nickname, &user_state.conn_write, message);
             }
+            (Ok(()), _) => {}
         }
-        None => {
-            //return no such channel error
-            let mut user_map_mutex = user_map_clone.lock().unwrap();
-            let c_write = user_map_mutex.get_mut(nickname).unwrap();
-            let _ = c_write.write_message(format!("{}\r\n", ErrorType::NoSuchChannel).as_str());
+    }
+}
+
+pub fn register_account(
+    mut accounts_mutex: MutexGuard<Accounts>,
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    register_msg: RegisterMsg,
+) {
+    let result = accounts_mutex.register(nickname.clone(), &register_msg.password);
+    logged_in(&mut user_map_mutex, config, nickname, result);
+}
+
+pub fn identify_account(
+    accounts_mutex: MutexGuard<Accounts>,
+    mut user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    identify_msg: IdentifyMsg,
+) {
+    let result = accounts_mutex.verify(nickname, &identify_msg.password);
+    logged_in(&mut user_map_mutex, config, nickname, result);
+}
+
+/// Marks `nickname` as identified to their account, or reports why not.
+fn logged_in(
+    user_map_mutex: &mut MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    result: Result<(), ErrorType>,
+) {
+    let Some(user_state) = user_map_mutex.get_mut(nickname) else {
+        return;
+    };
+    let message = match result {
+        Ok(()) => {
+            user_state.account = Some(nickname.clone());
+            format!(
+                "{}",
+                Reply::LoggedIn(LoggedInReply {
+                    target_nick: nickname.clone(),
+                    account: nickname.clone(),
+                })
+                .sent_by(&config.server_name)
+            )
+        }
+        Err(err) => format!("{}\r\n", err.sent_by(&config.server_name)),
+    };
+    write_to_conn(nickname, &user_state.conn_write, message);
+}
+
+/// Adds, removes or lists the certificate fingerprints on the account
+/// `nickname` is identified to, answering with server notices.
+pub fn manage_certs(
+    mut accounts_mutex: MutexGuard<Accounts>,
+    user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    cert_msg: CertMsg,
+) {
+    let Some(user_state) = user_map_mutex.get(nickname) else {
+        return;
+    };
+    let Some(account) = user_state.account.clone() else {
+        let error = ErrorType::NeedReggedNick.sent_by(&config.server_name);
+        write_to_conn(nickname, &user_state.conn_write, format!("{}\r\n", error));
+        return;
+    };
+
+    let lines = match cert_msg {
+        CertMsg::Add(fingerprint) => {
+            match accounts_mutex.add_fingerprint(&account, fingerprint.clone()) {
+                Ok(()) => vec![format!("Added fingerprint {fingerprint}")],
+                Err(err) => vec![format!("Fingerprint {fingerprint} {err}")],
+            }
+        }
+        CertMsg::Del(fingerprint) => {
+            match accounts_mutex.remove_fingerprint(&account, &fingerprint) {
+                Ok(()) => vec![format!("Removed fingerprint {fingerprint}")],
+                Err(err) => vec![format!("Fingerprint {fingerprint} {err}")],
+            }
+        }
+        CertMsg::List => accounts_mutex
+            .fingerprints(&account)
+            .into_iter()
+            .map(|fingerprint| format!("Fingerprint {fingerprint}"))
+            .chain(["End of CERT LIST".to_string()])
+            .collect(),
+    };
+    send_notices(user_state, config, nickname, lines);
+}
+
+/// Sends `report`, the result of `command`, to `nickname` as server notices
+/// if they are a server operator, or refuses them.
+pub fn send_oper_report(
+    user_map_mutex: MutexGuard<HashMap<Nick, UserState>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    command: &str,
+    report: Vec<String>,
+) {
+    let Some(user_state) = user_map_mutex.get(nickname) else {
+        return;
+    };
+    if !user_state.oper {
+        let error = ErrorType::NoPrivileges.sent_by(&config.server_name);
+        write_to_conn(nickname, &user_state.conn_write, format!("{}\r\n", error));
+        return;
+    }
+    webhooks.notify(Event::OperAction {
+        nick: nickname.clone(),
+        action: command.to_string(),
+    });
+    send_notices(user_state, config, nickname, report);
+}
+
+/// Disconnects the session using a registered nick, given the password of
+/// the account that owns it.
+pub fn ghost_user(
+    accounts_mutex: MutexGuard<Accounts>,
+    channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    nickname: &Nick,
+    ghost_msg: GhostMsg,
+) {
+    let result = accounts_mutex.verify(&ghost_msg.nick, &ghost_msg.password);
+    drop(accounts_mutex);
+
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    let result = result.and_then(|()| match user_map_mutex.get(&ghost_msg.nick) {
+        Some(ghost) if ghost_msg.nick != *nickname => {
+            ghost.conn_write.shutdown();
+            Ok(())
         }
+        _ => Err(ErrorType::NoSuchNick),
+    });
+    if let Err(err) = result {
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
     }
+    drop(user_map_mutex);
+
+    quit_server(
+        channel_mutex,
+        user_map_clone,
+        config,
+        &ghost_msg.nick,
+        "Ghosted".to_string(),
+    );
 }
 
-pub fn quit_server(
-    mut channel_mutex: MutexGuard<HashMap<Channel, Vec<Nick>>>,
-    user_map_clone: Arc<Mutex<HashMap<Nick, ConnectionWrite>>>,
+/// Sends an operator's WALLOPS to every user with +w.
+pub fn send_wallops(
+    user_map_clone: &Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
     nickname: &Nick,
-    message: String,
+    wallops_msg: WallopsMsg,
 ) {
-    for (_channel, channel_users) in channel_mutex.iter_mut() {
-        if channel_users.contains(nickname) {
-            channel_users.retain(|user| user != nickname);
-            channel_users.iter().for_each(|nick| {
-                let mut user_map_mutex = user_map_clone.lock().unwrap();
-                let c_write = user_map_mutex.get_mut(nick).unwrap();
+    if !is_oper(user_map_clone, nickname) {
+        let user_map_mutex = user_map_clone.lock().unwrap();
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!(
+                "{}\r\n",
+                ErrorType::NoPrivileges.sent_by(&config.server_name)
+            ),
+        );
+        return;
+    }
+    let reply = Reply::Wallops(WallopsReply {
+        message: wallops_msg,
+        sender: prefix_of(user_map_clone, nickname),
+    });
+    LineBuffer::format(reply, |line| {
+        broadcast_to_users(user_map_clone, config, |user| user.wallops, line)
+    });
+}
+
+/// Disconnects `kill_msg.nick` for the operator `nickname`: they are sent
+/// an ERROR with the reason, their connection is closed, and everyone who
+/// shares a channel with them sees them quit.
+pub fn kill_user(
+    channel_mutex: MutexGuard<Channels>,
+    user_map_clone: Arc<Mutex<HashMap<Nick, UserState>>>,
+    config: &ServerConfig,
+    webhooks: &Webhooks,
+    nickname: &Nick,
+    kill_msg: KillMsg,
+) {
+    let reason = truncate(
+        &kill_msg.reason.unwrap_or_else(|| nickname.to_string()),
+        config.reason_len,
+    );
+    let reason = format!("Killed ({nickname} ({reason}))");
+
+    let user_map_mutex = user_map_clone.lock().unwrap();
+    let result = if !user_map_mutex.get(nickname).is_some_and(|user| user.oper) {
+        Err(ErrorType::NoPrivileges)
+    } else {
+        match user_map_mutex.get(&kill_msg.nick) {
+            Some(victim) => {
+                let reply = Reply::ClosingLink(ClosingLinkReply {
+                    target_nick: Some(kill_msg.nick.clone()),
+                    reason: reason.clone(),
+                });
                 write_to_conn(
-                    nick,
-                    c_write,
-                    format!(
-                        "{}",
-                        Reply::Quit(QuitReply {
-                            message: QuitMsg {
-                                message: Some(message.clone())
-                            },
-                            sender_nick: nickname.clone()
-                        })
-                    ),
+                    &kill_msg.nick,
+                    &victim.conn_write,
+                    format!("{}", reply.sent_by(&config.server_name)),
                 );
-            });
+                // Wakes their thread from its read, to find them gone
+                victim.conn_write.close();
+                Ok(())
+            }
+            None => Err(ErrorType::NoSuchNick),
+        }
+    };
+    if let Err(err) = result {
+        write_to_user(
+            &user_map_mutex,
+            nickname,
+            format!("{}\r\n", err.sent_by(&config.server_name)),
+        );
+        return;
+    }
+    drop(user_map_mutex);
+
+    webhooks.notify(Event::OperAction {
+        nick: nickname.clone(),
+        action: format!("KILL {}", kill_msg.nick),
+    });
+    quit_server(
+        channel_mutex,
+        user_map_clone,
+        config,
+        &kill_msg.nick,
+        reason,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpStream, time::Duration};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::{RepeatFilter, DEFAULT_SEND_QUEUE_LEN},
+        connect::{in_process, ConnectionRead, ConnectionWrite},
+    };
+
+    fn nick(name: &str) -> Nick {
+        Nick(name.to_string())
+    }
+
+    /// Registers `name` in `user_map`, returning the client end of its connection.
+    fn connect(user_map: &Arc<Mutex<HashMap<Nick, UserState>>>, name: &str) -> TcpStream {
+        let (conn_write, client) = ConnectionWrite::loopback();
+        let user_state = UserState::new(
+            nick(name),
+            conn_write,
+            name.to_string(),
+            name.to_string(),
+            "127.0.0.1".parse().unwrap(),
+            &ServerConfig::default(),
+            Instant::now(),
+        );
+        user_map.lock().unwrap().insert(nick(name), user_state);
+        client
+    }
+
+    /// Like [`connect`], but nothing reaches the client until `resume` is
+    /// called, as if they had stopped reading until then.
+    fn connect_stalled(
+        user_map: &Arc<Mutex<HashMap<Nick, UserState>>>,
+        name: &str,
+    ) -> (TcpStream, impl FnOnce()) {
+        let client = connect(user_map, name);
+        let (conn_write, stalled) = ConnectionWrite::loopback();
+        let (outbox, resume) = Outbox::paused(conn_write, DEFAULT_SEND_QUEUE_LEN);
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick(name))
+            .unwrap()
+            .conn_write = outbox;
+        drop(client);
+        (stalled, resume)
+    }
+
+    /// Like [`connect`], but over an in-process connection, which counts as
+    /// secure. Returns the client's reading end.
+    fn connect_secure(
+        user_map: &Arc<Mutex<HashMap<Nick, UserState>>>,
+        name: &str,
+    ) -> ConnectionRead {
+        let ((_, conn_write), (client_read, _)) = in_process();
+        let user_state = UserState::new(
+            nick(name),
+            conn_write,
+            name.to_string(),
+            name.to_string(),
+            "127.0.0.1".parse().unwrap(),
+            &ServerConfig::default(),
+            Instant::now(),
+        );
+        user_map.lock().unwrap().insert(nick(name), user_state);
+        client_read.set_read_timeout(Some(Duration::from_secs(1)));
+        client_read
+    }
+
+    /// Reads one line a byte at a time, so nothing after it is consumed.
+    fn read_line(mut client: &TcpStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while line.last() != Some(&b'\n') {
+            client.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn test_parallel_broadcast_keeps_order() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let config = ServerConfig {
+            fanout_threshold: 3,
+            fanout_workers: 2,
+            ..ServerConfig::default()
+        };
+        let names = ["alice", "bob", "carol", "dave", "erin"];
+        let clients: Vec<TcpStream> = names.iter().map(|name| connect(&user_map, name)).collect();
+        let recipients: Vec<Nick> = names.iter().map(|name| nick(name)).collect();
+
+        for index in 0..10 {
+            broadcast(
+                &user_map,
+                &config,
+                &recipients,
+                &format!("PING :{index}\r\n"),
+            );
+        }
+        // Too few recipients to split, but still delivered
+        broadcast(&user_map, &config, &recipients[..1], "PING :alone\r\n");
+
+        for client in &clients {
+            for index in 0..10 {
+                assert_eq!(read_line(client), format!("PING :{index}\r\n"));
+            }
+        }
+        assert_eq!(read_line(&clients[0]), "PING :alone\r\n");
+    }
+
+    #[test]
+    fn test_stalled_recipient_does_not_hold_up_broadcasts() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let (alice, resume_alice) = connect_stalled(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let message = ":carol!carol@127.0.0.1 PRIVMSG #rust :hi\r\n";
+        broadcast(
+            &user_map,
+            &ServerConfig::default(),
+            &[nick("alice"), nick("bob")],
+            message,
+        );
+        assert_eq!(read_line(&bob), message);
+
+        // Alice gets it once they start reading again
+        resume_alice();
+        assert_eq!(read_line(&alice), message);
+    }
+
+    #[test]
+    fn test_stalled_member_does_not_block_joins() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let (alice, resume_alice) = connect_stalled(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let carol = connect(&user_map, "carol");
+        let slow = Channel("#slow".to_string());
+        channels.lock().unwrap().join(&slow, &nick("alice"));
+        channels.lock().unwrap().join(&slow, &nick("bob"));
+
+        private_msg_channel(
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &ServerConfig::default(),
+            Instant::now(),
+            MessageKind::PrivMsg,
+            slow,
+            MemberStatus::Regular,
+            "hi".to_string(),
+            nick("bob"),
+        );
+        assert_eq!(read_line(&bob), ":bob!bob@127.0.0.1 PRIVMSG #slow :hi\r\n");
+        join_channel(
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &ServerConfig::default(),
+            &Webhooks::default(),
+            &nick("carol"),
+            JoinMsg {
+                channel: Channel("#other".to_string()),
+                key: None,
+            },
+        );
+        assert_eq!(read_line(&carol), ":carol!carol@127.0.0.1 JOIN #other\r\n");
+
+        resume_alice();
+        assert_eq!(
+            read_line(&alice),
+            ":bob!bob@127.0.0.1 PRIVMSG #slow :hi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_oper_report_is_for_opers() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let config = ServerConfig::default();
+        let alice = connect(&user_map, "alice");
+        let report = vec!["Users: 1 registered".to_string()];
+
+        send_oper_report(
+            user_map.lock().unwrap(),
+            &config,
+            &Webhooks::default(),
+            &nick("alice"),
+            "DEBUG",
+            report.clone(),
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server 481 :Permission Denied- You're not an IRC operator\r\n"
+        );
+
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick("alice"))
+            .unwrap()
+            .oper = true;
+        send_oper_report(
+            user_map.lock().unwrap(),
+            &config,
+            &Webhooks::default(),
+            &nick("alice"),
+            "DEBUG",
+            report,
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server NOTICE alice :Users: 1 registered\r\n"
+        );
+    }
+
+    #[test]
+    fn test_ghost_takeover() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let accounts = Mutex::new(Accounts::default());
+        accounts
+            .lock()
+            .unwrap()
+            .register(nick("alice"), "hunter2")
+            .unwrap();
+
+        let mut stale = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let carol = connect(&user_map, "carol");
+        let channel = Channel("#chan".to_string());
+        channels.lock().unwrap().join(&channel, &nick("alice"));
+        channels.lock().unwrap().join(&channel, &nick("carol"));
+
+        ghost_user(
+            accounts.lock().unwrap(),
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &ServerConfig::default(),
+            &nick("bob"),
+            GhostMsg {
+                nick: nick("alice"),
+                password: "hunter3".to_string(),
+            },
+        );
+        assert_eq!(read_line(&bob), ":iris-server 464 :Password incorrect\r\n");
+        assert!(user_map.lock().unwrap().contains_key(&nick("alice")));
+
+        ghost_user(
+            accounts.lock().unwrap(),
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &ServerConfig::default(),
+            &nick("bob"),
+            GhostMsg {
+                nick: nick("alice"),
+                password: "hunter2".to_string(),
+            },
+        );
+        assert_eq!(
+            read_line(&carol),
+            ":alice!alice@127.0.0.1 QUIT :Ghosted\r\n"
+        );
+        assert!(!user_map.lock().unwrap().contains_key(&nick("alice")));
+        assert_eq!(
+            channels.lock().unwrap().get(&channel).unwrap().members,
+            vec![nick("carol")]
+        );
+        // The stale session's connection has been closed.
+        assert_eq!(stale.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_ghost_unowned_nick() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let accounts = Mutex::new(Accounts::default());
+        let _alice = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+
+        ghost_user(
+            accounts.lock().unwrap(),
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &ServerConfig::default(),
+            &nick("bob"),
+            GhostMsg {
+                nick: nick("alice"),
+                password: "hunter2".to_string(),
+            },
+        );
+        assert_eq!(read_line(&bob), ":iris-server 464 :Password incorrect\r\n");
+        assert!(user_map.lock().unwrap().contains_key(&nick("alice")));
+    }
+
+    #[test]
+    fn test_repeated_messages_escalate() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let config = ServerConfig {
+            repeat_filter: Some(RepeatFilter {
+                suppress_after: 2,
+                kick_after: 3,
+                window: Duration::from_secs(60),
+            }),
+            ..ServerConfig::default()
+        };
+        let alice = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let channel = Channel("#chan".to_string());
+        channels.lock().unwrap().join(&channel, &nick("alice"));
+        channels.lock().unwrap().join(&channel, &nick("bob"));
+
+        let clock = ManualClock::default();
+        let send = || {
+            private_msg_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &config,
+                clock.now(),
+                MessageKind::PrivMsg,
+                channel.clone(),
+                MemberStatus::Regular,
+                "Buy now!".to_string(),
+                nick("alice"),
+            )
+        };
+
+        send();
+        send();
+        assert_eq!(
+            read_line(&bob),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Buy now!\r\n"
+        );
+        assert_eq!(
+            read_line(&bob),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Buy now!\r\n"
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Buy now!\r\n"
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Buy now!\r\n"
+        );
+
+        send();
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server NOTICE alice :Repeated message to #chan was not delivered\r\n"
+        );
+
+        send();
+        let kick = ":iris-server KICK #chan alice :Repeated messages\r\n";
+        assert_eq!(read_line(&alice), kick);
+        // Bob never saw the suppressed copy, only the kick.
+        assert_eq!(read_line(&bob), kick);
+        assert_eq!(
+            channels.lock().unwrap().get(&channel).unwrap().members,
+            vec![nick("bob")]
+        );
+    }
+
+    #[test]
+    fn test_members_without_connections_are_skipped() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let config = ServerConfig::default();
+        let alice = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let carol = connect(&user_map, "carol");
+        let channel = Channel("#chan".to_string());
+        for name in ["alice", "bob", "carol"] {
+            channels.lock().unwrap().join(&channel, &nick(name));
+        }
+        // carol's connection is gone, but she was never taken out of #chan
+        user_map.lock().unwrap().remove(&nick("carol"));
+        drop(carol);
+
+        // Kicking bob still reaches alice, with carol skipped
+        kick_channel(
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &config,
+            &nick("alice"),
+            KickMsg {
+                channel: channel.clone(),
+                nick: nick("bob"),
+                reason: None,
+            },
+        );
+        let kick = ":alice!alice@127.0.0.1 KICK #chan bob :alice\r\n";
+        assert_eq!(read_line(&alice), kick);
+        assert_eq!(read_line(&bob), kick);
+
+        private_msg_channel(
+            channels.lock().unwrap(),
+            user_map.clone(),
+            &config,
+            Instant::now(),
+            MessageKind::PrivMsg,
+            channel.clone(),
+            MemberStatus::Regular,
+            "Anyone here?".to_string(),
+            nick("alice"),
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Anyone here?\r\n"
+        );
+        assert_eq!(
+            channels.lock().unwrap().get(&channel).unwrap().members,
+            [nick("alice")]
+        );
+    }
+
+    #[test]
+    fn test_repeat_window_expires() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let config = ServerConfig {
+            repeat_filter: Some(RepeatFilter {
+                suppress_after: 1,
+                kick_after: 10,
+                window: Duration::from_secs(30),
+            }),
+            ..ServerConfig::default()
+        };
+        let alice = connect(&user_map, "alice");
+        let channel = Channel("#chan".to_string());
+        channels.lock().unwrap().join(&channel, &nick("alice"));
+
+        let clock = ManualClock::default();
+        let send = || {
+            private_msg_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &config,
+                clock.now(),
+                MessageKind::PrivMsg,
+                channel.clone(),
+                MemberStatus::Regular,
+                "Hello?".to_string(),
+                nick("alice"),
+            )
+        };
+
+        send();
+        assert_eq!(
+            read_line(&alice),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Hello?\r\n"
+        );
+        clock.advance(Duration::from_secs(29));
+        send();
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server NOTICE alice :Repeated message to #chan was not delivered\r\n"
+        );
+
+        // Once the window has passed, the run starts over.
+        clock.advance(Duration::from_secs(31));
+        send();
+        assert_eq!(
+            read_line(&alice),
+            ":alice!alice@127.0.0.1 PRIVMSG #chan :Hello?\r\n"
+        );
+    }
+
+    #[test]
+    fn test_force_join_and_part() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let config = ServerConfig::default();
+        let oper = connect(&user_map, "oper");
+        let tom = connect(&user_map, "tom");
+        let ann = connect(&user_map, "ann");
+        let channel = Channel("#ops".to_string());
+        channels.lock().unwrap().join(&channel, &nick("ann"));
+        channels
+            .lock()
+            .unwrap()
+            .get_mut(&channel)
+            .unwrap()
+            .apply_mode(true, &ChannelMode::OperOnly);
+        let force = |joining, target: &str| {
+            force_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &config,
+                &Webhooks::default(),
+                &nick("oper"),
+                joining,
+                ForceChannelMsg {
+                    nick: nick(target),
+                    channel: channel.clone(),
+                },
+            )
+        };
+
+        force(true, "tom");
+        assert_eq!(
+            read_line(&oper),
+            ":iris-server 481 :Permission Denied- You're not an IRC operator\r\n"
+        );
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick("oper"))
+            .unwrap()
+            .oper = true;
+
+        // +O doesn't keep the target out
+        force(true, "tom");
+        assert_eq!(read_line(&ann), ":tom!tom@127.0.0.1 JOIN #ops\r\n");
+        assert_eq!(read_line(&tom), ":tom!tom@127.0.0.1 JOIN #ops\r\n");
+        assert_eq!(read_line(&tom), ":iris-server 353 tom = #ops :@ann tom\r\n");
+        assert_eq!(
+            read_line(&tom),
+            ":iris-server 366 tom #ops :End of /NAMES list\r\n"
+        );
+        assert_eq!(
+            read_line(&tom),
+            ":iris-server NOTICE tom :Operator oper made you join #ops\r\n"
+        );
+        assert!(channels
+            .lock()
+            .unwrap()
+            .get(&channel)
+            .unwrap()
+            .members
+            .contains(&nick("tom")));
+
+        force(true, "tom");
+        assert_eq!(
+            read_line(&oper),
+            ":iris-server 443 :is already on channel\r\n"
+        );
+        force(true, "nobody");
+        assert_eq!(
+            read_line(&oper),
+            ":iris-server 401 :No such nick/channel\r\n"
+        );
+
+        force(false, "tom");
+        assert_eq!(read_line(&ann), ":tom!tom@127.0.0.1 PART #ops\r\n");
+        assert_eq!(read_line(&tom), ":tom!tom@127.0.0.1 PART #ops\r\n");
+        assert_eq!(
+            read_line(&tom),
+            ":iris-server NOTICE tom :Operator oper made you leave #ops\r\n"
+        );
+        assert_eq!(
+            channels.lock().unwrap().get(&channel).unwrap().members,
+            vec![nick("ann")]
+        );
+        force(false, "tom");
+        assert_eq!(
+            read_line(&oper),
+            ":iris-server 441 :They aren't on that channel\r\n"
+        );
+    }
+
+    #[test]
+    fn test_secure_only_join() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let mut alice = connect_secure(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let mut carol = connect_secure(&user_map, "carol");
+        let channel = Channel("#safe".to_string());
+        channels.lock().unwrap().join(&channel, &nick("alice"));
+        channels
+            .lock()
+            .unwrap()
+            .get_mut(&channel)
+            .unwrap()
+            .apply_mode(true, &ChannelMode::SecureOnly);
+        let join = |name: &str| {
+            join_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &ServerConfig::default(),
+                &Webhooks::default(),
+                &nick(name),
+                JoinMsg {
+                    channel: channel.clone(),
+                    key: None,
+                },
+            )
+        };
+
+        join("bob");
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 489 :Cannot join channel (you must be connected via TLS)\r\n"
+        );
+        join("carol");
+        assert_eq!(
+            alice.read_message().unwrap(),
+            ":carol!carol@127.0.0.1 JOIN #safe"
+        );
+        assert_eq!(
+            carol.read_message().unwrap(),
+            ":carol!carol@127.0.0.1 JOIN #safe"
+        );
+        assert_eq!(
+            channels.lock().unwrap().get(&channel).unwrap().members,
+            [nick("alice"), nick("carol")]
+        );
+    }
+
+    #[test]
+    fn test_registered_only_channel() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let accounts = Mutex::new(Accounts::default());
+        accounts
+            .lock()
+            .unwrap()
+            .register(nick("bob"), "hunter2")
+            .unwrap();
+        let alice = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        let channel = Channel("#club".to_string());
+        channels.lock().unwrap().join(&channel, &nick("alice"));
+        {
+            let mut channels = channels.lock().unwrap();
+            let channel_state = channels.get_mut(&channel).unwrap();
+            channel_state.apply_mode(true, &ChannelMode::RegisteredOnly);
+            channel_state.apply_mode(true, &ChannelMode::RegisteredSpeak);
         }
+        let join = || {
+            join_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &ServerConfig::default(),
+                &Webhooks::default(),
+                &nick("bob"),
+                JoinMsg {
+                    channel: channel.clone(),
+                    key: None,
+                },
+            )
+        };
+        let say = |name: &str| {
+            private_msg_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                &ServerConfig::default(),
+                Instant::now(),
+                MessageKind::PrivMsg,
+                channel.clone(),
+                MemberStatus::Regular,
+                "hi".to_string(),
+                nick(name),
+            )
+        };
+
+        join();
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 477 :You need to identify to an account first\r\n"
+        );
+        // The founder joined before +M, but still can't speak unidentified.
+        say("alice");
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server 477 :You need to identify to an account first\r\n"
+        );
+
+        identify_account(
+            accounts.lock().unwrap(),
+            user_map.lock().unwrap(),
+            &ServerConfig::default(),
+            &nick("bob"),
+            IdentifyMsg {
+                password: "hunter2".to_string(),
+            },
+        );
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 900 bob bob :You are now logged in as bob\r\n"
+        );
+        join();
+        assert_eq!(read_line(&alice), ":bob!bob@127.0.0.1 JOIN #club\r\n");
+        assert_eq!(read_line(&bob), ":bob!bob@127.0.0.1 JOIN #club\r\n");
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 353 bob = #club :@alice bob\r\n"
+        );
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 366 bob #club :End of /NAMES list\r\n"
+        );
+        say("bob");
+        assert_eq!(
+            read_line(&alice),
+            ":bob!bob@127.0.0.1 PRIVMSG #club :hi\r\n"
+        );
+        assert_eq!(read_line(&bob), ":bob!bob@127.0.0.1 PRIVMSG #club :hi\r\n");
+    }
+
+    #[test]
+    fn test_registered_only_exempts_opers() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let alice = connect(&user_map, "alice");
+        let bob = connect(&user_map, "bob");
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick("alice"))
+            .unwrap()
+            .registered_only = true;
+        user_map.lock().unwrap().get_mut(&nick("bob")).unwrap().oper = true;
+        let send = |config: &ServerConfig| {
+            private_msg_user(
+                user_map.lock().unwrap(),
+                config,
+                MessageKind::PrivMsg,
+                &nick("bob"),
+                nick("alice"),
+                "hi".to_string(),
+                Instant::now(),
+            )
+        };
+
+        send(&ServerConfig::default());
+        assert_eq!(
+            read_line(&alice),
+            ":bob!bob@127.0.0.1 PRIVMSG alice :hi\r\n"
+        );
+        send(&ServerConfig {
+            registered_only_exempts_opers: false,
+            ..ServerConfig::default()
+        });
+        assert_eq!(
+            read_line(&bob),
+            ":iris-server 486 :You must identify to an account to message that user\r\n"
+        );
+    }
+
+    #[test]
+    fn test_manage_certs() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let accounts = Mutex::new(Accounts::default());
+        let alice = connect(&user_map, "alice");
+        let fingerprint = "ab".repeat(32);
+        let cert = |cert_msg| {
+            manage_certs(
+                accounts.lock().unwrap(),
+                user_map.lock().unwrap(),
+                &ServerConfig::default(),
+                &nick("alice"),
+                cert_msg,
+            )
+        };
+
+        cert(CertMsg::List);
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server 477 :You need to identify to an account first\r\n"
+        );
+
+        accounts
+            .lock()
+            .unwrap()
+            .register(nick("alice"), "hunter2")
+            .unwrap();
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick("alice"))
+            .unwrap()
+            .account = Some(nick("alice"));
+        cert(CertMsg::Add(fingerprint.clone()));
+        assert_eq!(
+            read_line(&alice),
+            format!(":iris-server NOTICE alice :Added fingerprint {fingerprint}\r\n")
+        );
+        cert(CertMsg::Add("nope".to_string()));
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server NOTICE alice :Fingerprint nope is not a SHA-256 fingerprint\r\n"
+        );
+        cert(CertMsg::List);
+        assert_eq!(
+            read_line(&alice),
+            format!(":iris-server NOTICE alice :Fingerprint {fingerprint}\r\n")
+        );
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server NOTICE alice :End of CERT LIST\r\n"
+        );
+        cert(CertMsg::Del(fingerprint.clone()));
+        assert_eq!(
+            read_line(&alice),
+            format!(":iris-server NOTICE alice :Removed fingerprint {fingerprint}\r\n")
+        );
+        assert_eq!(
+            accounts
+                .lock()
+                .unwrap()
+                .account_for_fingerprint(&fingerprint),
+            None
+        );
+    }
+
+    #[test]
+    fn test_channel_cap_exempts_opers() {
+        let user_map = Arc::new(Mutex::new(HashMap::new()));
+        let channels = Mutex::new(Channels::default());
+        let alice = connect(&user_map, "alice");
+        user_map
+            .lock()
+            .unwrap()
+            .get_mut(&nick("alice"))
+            .unwrap()
+            .oper = true;
+        channels
+            .lock()
+            .unwrap()
+            .join(&Channel("#full".to_string()), &nick("bob"));
+        let join = |config: &ServerConfig, channel: &str| {
+            join_channel(
+                channels.lock().unwrap(),
+                user_map.clone(),
+                config,
+                &Webhooks::default(),
+                &nick("alice"),
+                JoinMsg {
+                    channel: Channel(channel.to_string()),
+                    key: None,
+                },
+            )
+        };
+
+        let strict = ServerConfig {
+            max_channels: 1,
+            max_channels_exempts_opers: false,
+            ..ServerConfig::default()
+        };
+        join(&strict, "#new");
+        assert_eq!(
+            read_line(&alice),
+            ":iris-server 405 :Cannot create channel (channel creation limit reached)\r\n"
+        );
+        let exempt = ServerConfig {
+            max_channels: 1,
+            ..ServerConfig::default()
+        };
+        join(&exempt, "#new");
+        assert_eq!(read_line(&alice), ":alice!alice@127.0.0.1 JOIN #new\r\n");
+        assert_eq!(channels.lock().unwrap().len(), 2);
     }
-    let mut user_map_mutex = user_map_clone.lock().unwrap();
-    user_map_mutex.remove(nickname);
 }
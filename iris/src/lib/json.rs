@@ -0,0 +1,366 @@
+use std::{
+    fmt::{self, Display, Write as _},
+    str::FromStr,
+};
+
+/// A JSON value, with just enough support to write and read back the
+/// documents the server produces.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys are kept in the order they were added or read.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// An object from `fields`, in order.
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// The value of `key`, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as a whole number, if it is one that fits in a `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(value) if value.fract() == 0.0 && *value >= 0.0 => Some(*value as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl Display for Json {
+    /// Writes the value compactly, on one line.
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(fmt, "null"),
+            Json::Bool(value) => write!(fmt, "{value}"),
+            Json::Number(value) => write!(fmt, "{value}"),
+            Json::String(value) => write!(fmt, "\"{}\"", escape(value)),
+            Json::Array(values) => {
+                write!(fmt, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(fmt, ",")?;
+                    }
+                    write!(fmt, "{value}")?;
+                }
+                write!(fmt, "]")
+            }
+            Json::Object(fields) => {
+                write!(fmt, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(fmt, ",")?;
+                    }
+                    write!(fmt, "\"{}\":{value}", escape(key))?;
+                }
+                write!(fmt, "}}")
+            }
+        }
+    }
+}
+
+/// Escapes `value` for use inside a JSON string.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl FromStr for Json {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { text, position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    /// Byte offset of the next character to read.
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{message} at byte {}", self.position)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .peek()
+            .is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+        {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected {expected:?}"))),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if self.text[self.position..].starts_with(word) {
+            self.position += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unknown literal"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.literal("null", Json::Null),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            self.position += 1;
+        }
+        self.text[start..self.position]
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("bad number"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('/') => value.push('/'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => {
+                        let digits = self
+                            .text
+                            .get(self.position..self.position + 4)
+                            .ok_or_else(|| self.error("short unicode escape"))?;
+                        let code = u32::from_str_radix(digits, 16)
+                            .map_err(|_| self.error("bad unicode escape"))?;
+                        self.position += 4;
+                        // Surrogate pairs are never written by the server
+                        value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return Err(self.error("bad escape")),
+                },
+                Some(c) => value.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(values)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let value = Json::object([
+            ("nick", Json::from("tom")),
+            ("oper", Json::from(false)),
+            ("account", Json::from(None::<String>)),
+            ("since", Json::from(1697385600u64)),
+            ("channels", Json::Array(vec![Json::from("#rust")])),
+            ("quote", Json::from("said \"hi\"\n\u{1}")),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r##"{"nick":"tom","oper":false,"account":null,"since":1697385600,"channels":["#rust"],"quote":"said \"hi\"\n\u0001"}"##
+        );
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":{"c":"d\"eé"},"f":[],"g":{}}"#;
+        let value: Json = text.parse().unwrap();
+        assert_eq!(
+            value.get("b").and_then(|b| b.get("c")),
+            Some(&Json::from("d\"eé"))
+        );
+        assert_eq!(
+            value.get("a").and_then(Json::as_array).map(<[Json]>::len),
+            Some(4)
+        );
+        assert_eq!(value.to_string().parse::<Json>(), Ok(value));
+        assert_eq!(
+            " { \"x\" : 1 } ".parse::<Json>(),
+            Ok(Json::object([("x", Json::from(1u64))]))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("".parse::<Json>().is_err());
+        assert!("{\"a\":1".parse::<Json>().is_err());
+        assert!("[1,]".parse::<Json>().is_err());
+        assert!("\"open".parse::<Json>().is_err());
+        assert!("{} {}".parse::<Json>().is_err());
+        assert!("nul".parse::<Json>().is_err());
+    }
+}
@@ -1,3 +1,21 @@
+pub mod accounts;
+pub mod bot;
+pub mod client;
+pub mod cloak;
+pub mod clock;
+pub mod codec;
+pub mod config;
 pub mod connect;
+pub mod formatting;
 pub mod helpers;
+pub mod json;
+pub mod outbox;
+pub mod redact;
+pub mod server;
+pub mod snapshot;
+pub mod state;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod transcript;
 pub mod types;
+pub mod webhook;
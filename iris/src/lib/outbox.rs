@@ -0,0 +1,268 @@
+use std::{
+    fmt::Display,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, OnceLock,
+    },
+    thread,
+};
+
+use crate::connect::{ConnectionCloser, ConnectionError, ConnectionWrite};
+
+/// Something waiting to be done by a connection's writer.
+enum Outgoing {
+    Line(Arc<str>),
+    /// Written together, in as few writes as possible.
+    Lines(Vec<String>),
+    /// Closes the connection once everything before it is written.
+    Close,
+}
+
+/// Why a message couldn't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxError {
+    /// The connection has closed, so nothing more will be written.
+    Closed,
+    /// Too many messages were already waiting, so the connection has been
+    /// closed.
+    Overflowed,
+    /// A write failed, so the connection has been closed.
+    Failed(ConnectionError),
+}
+
+impl OutboxError {
+    /// Whether this end cut the client off, for not keeping up, rather than
+    /// the connection failing under it. Only then does the failure say more
+    /// than whatever the reading end saw.
+    pub fn is_cut_off(&self) -> bool {
+        matches!(
+            self,
+            OutboxError::Overflowed | OutboxError::Failed(ConnectionError::Timeout)
+        )
+    }
+}
+
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::Closed => write!(f, "Connection closed"),
+            OutboxError::Overflowed => write!(f, "SendQ exceeded"),
+            OutboxError::Failed(err) => write!(f, "Write error: {err}"),
+        }
+    }
+}
+
+/// Queues messages for a connection, which a thread of its own writes in
+/// the order they were queued. Queueing never waits, so writing to one user
+/// never holds up anyone else.
+#[derive(Clone)]
+pub struct Outbox {
+    queue: SyncSender<Outgoing>,
+    closer: ConnectionCloser,
+    in_process: bool,
+    /// Set when this end closed the connection because something went
+    /// wrong, to say what.
+    failure: Arc<OnceLock<OutboxError>>,
+}
+
+impl Outbox {
+    /// Hands `conn_write` to a writer thread of its own. At most `capacity`
+    /// messages can wait for it, and one more closes the connection. The
+    /// thread finishes once the connection is closed or a write fails, or
+    /// when every clone of the outbox is gone and the queue is empty.
+    pub fn launch(conn_write: ConnectionWrite, capacity: usize) -> Self {
+        let (outbox, outgoing) = Self::new(&conn_write, capacity);
+        let failure = outbox.failure.clone();
+        thread::spawn(move || write_queued(conn_write, outgoing, &failure));
+        outbox
+    }
+
+    fn new(conn_write: &ConnectionWrite, capacity: usize) -> (Self, Receiver<Outgoing>) {
+        // A queue with no room would turn away every message
+        let (queue, outgoing) = mpsc::sync_channel(capacity.max(1));
+        let outbox = Self {
+            queue,
+            closer: conn_write.closer(),
+            in_process: conn_write.is_in_process(),
+            failure: Arc::new(OnceLock::new()),
+        };
+        (outbox, outgoing)
+    }
+
+    /// Queues `message` to be written.
+    pub fn send(&self, message: impl Into<Arc<str>>) -> Result<(), OutboxError> {
+        self.push(Outgoing::Line(message.into()))
+    }
+
+    /// Queues `messages` to be written together, taking up one place in
+    /// the queue.
+    pub fn send_all(&self, messages: Vec<String>) -> Result<(), OutboxError> {
+        self.push(Outgoing::Lines(messages))
+    }
+
+    /// Closes the connection once everything already queued is written,
+    /// which also ends any read waiting on it.
+    pub fn close(&self) {
+        let _ = self.push(Outgoing::Close);
+    }
+
+    /// Closes the connection straight away, dropping anything still
+    /// waiting to be written.
+    pub fn shutdown(&self) {
+        self.closer.shutdown();
+    }
+
+    /// Why this end closed the connection, if it was because its queue
+    /// filled up or a write failed. A connection that's still open, or that
+    /// was closed on purpose, has no failure.
+    pub fn failure(&self) -> Option<OutboxError> {
+        self.failure.get().copied()
+    }
+
+    /// Whether the other end is inside this process, as bots are.
+    pub fn is_in_process(&self) -> bool {
+        self.in_process
+    }
+
+    fn push(&self, outgoing: Outgoing) -> Result<(), OutboxError> {
+        match self.queue.try_send(outgoing) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                if self.failure.set(OutboxError::Overflowed).is_ok() {
+                    self.closer.shutdown();
+                }
+                Err(OutboxError::Overflowed)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err(self.failure().unwrap_or(OutboxError::Closed))
+            }
+        }
+    }
+}
+
+/// Writes everything that comes through `outgoing` to `conn_write`, until
+/// told to close or a write fails. A failed write is kept in `failure`.
+fn write_queued(
+    mut conn_write: ConnectionWrite,
+    outgoing: Receiver<Outgoing>,
+    failure: &OnceLock<OutboxError>,
+) {
+    for next in outgoing {
+        let written = match next {
+            Outgoing::Line(line) => conn_write.write_message(&line),
+            Outgoing::Lines(lines) => conn_write.write_all_lines(&lines).map_err(|partial| {
+                log::error!(
+                    "Only {} of {} messages reached {}.",
+                    partial.delivered,
+                    lines.len(),
+                    conn_write.id()
+                );
+                partial.error
+            }),
+            Outgoing::Close => {
+                conn_write.shutdown();
+                return;
+            }
+        };
+        // A failed write has already closed the connection
+        if let Err(err) = written {
+            log::error!("Unable to send to {}: {err}", conn_write.id());
+            let _ = failure.set(OutboxError::Failed(err));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Outbox {
+    /// An outbox whose writer doesn't start until `resume` is called, as if
+    /// the client had stopped reading until then.
+    pub(crate) fn paused(conn_write: ConnectionWrite, capacity: usize) -> (Self, impl FnOnce()) {
+        let (outbox, outgoing) = Self::new(&conn_write, capacity);
+        let failure = outbox.failure.clone();
+        let resume = move || {
+            thread::spawn(move || write_queued(conn_write, outgoing, &failure));
+        };
+        (outbox, resume)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+
+    use super::*;
+    use crate::connect::in_process;
+
+    #[test]
+    fn test_messages_arrive_in_order() {
+        let (conn_write, client) = ConnectionWrite::loopback();
+        let outbox = Outbox::launch(conn_write, 1000);
+        let mut expected = Vec::new();
+        for batch in 0..100 {
+            let line = format!("PING :{batch}");
+            outbox.send(line.as_str()).unwrap();
+            expected.push(line);
+            let lines: Vec<String> = (0..3).map(|n| format!("PING :{batch}.{n}")).collect();
+            outbox.send_all(lines.clone()).unwrap();
+            expected.extend(lines);
+        }
+        outbox.close();
+
+        let received: Vec<String> = BufReader::new(client)
+            .lines()
+            .map(|line| line.unwrap().trim_end().to_string())
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_overflow_closes_connection() {
+        let (conn_write, mut client) = ConnectionWrite::loopback();
+        let (outbox, resume) = Outbox::paused(conn_write, 2);
+        outbox.send("PING :1").unwrap();
+        outbox.send("PING :2").unwrap();
+        assert_eq!(outbox.failure(), None);
+
+        assert_eq!(outbox.send("PING :3"), Err(OutboxError::Overflowed));
+        assert_eq!(outbox.failure(), Some(OutboxError::Overflowed));
+        assert!(OutboxError::Overflowed.is_cut_off());
+        // Closed without anything being written
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "");
+
+        resume();
+        drop(outbox);
+    }
+
+    #[test]
+    fn test_failed_write_is_kept() {
+        let ((_, conn_write), (client_read, _client_write)) = in_process();
+        drop(client_read);
+        let outbox = Outbox::launch(conn_write, 10);
+        outbox.send("PING :1").unwrap();
+
+        let failed = OutboxError::Failed(ConnectionError::Reset);
+        while outbox.failure().is_none() {
+            thread::yield_now();
+        }
+        assert_eq!(outbox.failure(), Some(failed));
+        assert_eq!(outbox.send("PING :2"), Err(failed));
+        assert_eq!(failed.to_string(), "Write error: Connection reset by peer");
+        assert!(!failed.is_cut_off());
+        assert!(OutboxError::Failed(ConnectionError::Timeout).is_cut_off());
+    }
+
+    #[test]
+    fn test_close_after_queued() {
+        let (conn_write, mut client) = ConnectionWrite::loopback();
+        let outbox = Outbox::launch(conn_write, 10);
+        outbox.send("ERROR :Bye").unwrap();
+        outbox.close();
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "ERROR :Bye\r\n");
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::client;
+
+/// Stands in for a password taken out of a line.
+pub const REDACTED: &str = "<redacted>";
+
+/// Whether logs show what people said. Logging is process-wide, so this is
+/// too.
+static LOG_MESSAGE_CONTENTS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether [`loggable`] leaves message text in.
+pub fn set_log_message_contents(log_message_contents: bool) {
+    LOG_MESSAGE_CONTENTS.store(log_message_contents, Ordering::Relaxed);
+}
+
+/// `line` as it should appear in the log: passwords are always taken out,
+/// and message text is too unless [`set_log_message_contents`] said
+/// otherwise.
+pub fn loggable(line: &str) -> Cow<'_, str> {
+    if LOG_MESSAGE_CONTENTS.load(Ordering::Relaxed) {
+        Cow::Borrowed(line)
+    } else {
+        Cow::Owned(redact(line))
+    }
+}
+
+/// Takes message text and passwords out of `line`. Message text becomes its
+/// length in bytes, so it's still clear something was said; passwords
+/// become [`REDACTED`].
+pub fn redact(line: &str) -> String {
+    let parsed = client::ServerLine::from(line.trim_end_matches(['\r', '\n']));
+    let (secret, replacement) = match parsed.command.to_uppercase().as_str() {
+        "PRIVMSG" | "NOTICE" => match parsed.params.get(1) {
+            Some(text) => (text, format!("<{} bytes>", text.len())),
+            None => return line.to_string(),
+        },
+        "PASS" | "REGISTER" | "IDENTIFY" | "GHOST" | "OPER" => match parsed.params.last() {
+            Some(password) => (password, REDACTED.to_string()),
+            None => return line.to_string(),
+        },
+        _ => return line.to_string(),
+    };
+    if secret.is_empty() {
+        return line.to_string();
+    }
+    match line.rsplit_once(secret.as_str()) {
+        Some((before, after)) => format!("{before}{replacement}{after}"),
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("PRIVMSG #rust :my secret plans"),
+            "PRIVMSG #rust :<15 bytes>"
+        );
+        assert_eq!(
+            redact(":tom PRIVMSG ann :my secret plans"),
+            ":tom PRIVMSG ann :<15 bytes>"
+        );
+        assert_eq!(redact("NOTICE ann :psst\r\n"), "NOTICE ann :<4 bytes>\r\n");
+        assert_eq!(redact("PRIVMSG #rust"), "PRIVMSG #rust");
+        assert_eq!(redact("IDENTIFY hunter2"), "IDENTIFY <redacted>");
+        assert_eq!(redact("GHOST tom hunter2"), "GHOST tom <redacted>");
+        assert_eq!(redact("OPER admin hunter2"), "OPER admin <redacted>");
+        assert_eq!(redact("PASS :hunter2"), "PASS :<redacted>");
+        assert_eq!(redact("JOIN #rust"), "JOIN #rust");
+    }
+}
@@ -0,0 +1,1544 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::{
+    accounts::Accounts,
+    bot::BotHandle,
+    client::ClientError,
+    clock::{local_time, Clock, SystemClock},
+    config::ServerConfig,
+    connect::{
+        in_process, ConnectionError, ConnectionManager, ConnectionRead, ConnectionWrite, Transport,
+    },
+    formatting::{split_len, truncate},
+    helpers::{
+        accept_users, broadcast, force_channel, ghost_user, identify_account, info_reply,
+        invite_channel, ison_reply, join_channels, kick_channel, kill_user, list_reply,
+        lusers_reply, manage_certs, mode_channel, mode_user, motd_reply, names_reply, oper_up,
+        part_channels, quit_server, register_account, send_message, send_oper_report, send_wallops,
+        set_away, time_reply, topic_channel, userhost_reply, version_reply, who_reply, whois_reply,
+        write_lines_to_conn, write_to_conn, write_to_user,
+    },
+    json::Json,
+    outbox::OutboxError,
+    redact::{self, loggable},
+    snapshot::Snapshot,
+    state::{Channels, NickHolds, PendingNicks, PingDue, UserState},
+    transcript::TranscriptRecorder,
+    types::{
+        is_notice, Channel, ClosingLinkReply, CreatedReply, ErrorType, ISupportReply, Message,
+        MessageKind, MyInfoReply, Nick, NickReply, ParsedMessage, Reply, SaNickMsg, ServerMessage,
+        ServerNoticeReply, UnknownCommandReply, UnparsedMessage, UserMsg, WelcomeReply,
+        YourHostReply,
+    },
+    webhook::{Event, Webhooks},
+};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+/// Everything the server keeps track of, shared between connections.
+///
+/// When more than one of its locks is needed at once, they are taken in the
+/// order `accounts`, `channels`, `user_map`, `pending_nicks`, `nick_holds`,
+/// skipping any that aren't needed.
+#[derive(Clone)]
+pub struct ServerState {
+    pub config: Arc<ServerConfig>,
+    /// Hashmap for storing the state of registered users
+    pub user_map: Arc<Mutex<HashMap<Nick, UserState>>>,
+    /// Channels, their users, and which channels each user is in
+    pub channels: Arc<Mutex<Channels>>,
+    /// Registered accounts
+    pub accounts: Arc<Mutex<Accounts>>,
+    /// Nicks reserved for users who recently left
+    pub nick_holds: Arc<Mutex<NickHolds>>,
+    /// Nicks picked by connections still registering
+    pub pending_nicks: Arc<Mutex<PendingNicks>>,
+    /// Where every time-dependent feature gets the time from
+    pub clock: Arc<dyn Clock>,
+    /// How many connection handlers are running
+    pub handlers: Arc<AtomicUsize>,
+    /// The most users registered at once since the server started. Only
+    /// raised while `user_map` is locked, so it is never behind its size.
+    pub max_users: Arc<AtomicUsize>,
+    /// Where server events are sent
+    pub webhooks: Arc<Webhooks>,
+    /// When the server started, as told to users when they register
+    pub started: OffsetDateTime,
+}
+
+/// How much of an export is sent in each notice, in bytes.
+const EXPORT_CHUNK_LEN: usize = 400;
+
+/// How many wrong or missing passwords a client may give before they are
+/// disconnected.
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+
+/// How often the server looks for users to ping or give up on.
+const PING_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often a client who is yet to register, and sends nothing, is
+/// checked against their deadline. The deadline is on the server's clock,
+/// which needn't keep pace with the time reads wait for.
+const REGISTRATION_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Counts a connection handler as live for as long as it is held.
+struct LiveHandler(Arc<AtomicUsize>);
+
+impl LiveHandler {
+    fn new(handlers: &Arc<AtomicUsize>) -> Self {
+        handlers.fetch_add(1, Ordering::Relaxed);
+        Self(handlers.clone())
+    }
+}
+
+impl Drop for LiveHandler {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerState {
+    pub fn new(config: ServerConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// A server whose idea of the time comes from `clock`.
+    pub fn with_clock(config: ServerConfig, clock: Arc<dyn Clock>) -> Self {
+        let webhooks = Webhooks::launch(&config.webhooks, &config.server_name);
+        Self {
+            config: Arc::new(config),
+            user_map: Arc::new(Mutex::new(HashMap::new())),
+            channels: Arc::new(Mutex::new(Channels::default())),
+            accounts: Arc::new(Mutex::new(Accounts::default())),
+            nick_holds: Arc::new(Mutex::new(NickHolds::default())),
+            pending_nicks: Arc::new(Mutex::new(PendingNicks::default())),
+            clock,
+            handlers: Arc::new(AtomicUsize::new(0)),
+            max_users: Arc::new(AtomicUsize::new(0)),
+            webhooks: Arc::new(webhooks),
+            started: local_time(),
+        }
+    }
+
+    /// Opens a connection to the server that never leaves the process, and
+    /// returns the client's end. The server handles it on a thread of its
+    /// own, as it would a TCP client, except that no password is asked for.
+    pub fn connect_in_process(&self) -> (ConnectionRead, ConnectionWrite) {
+        let ((server_read, server_write), client) = in_process();
+        let state = self.clone();
+        thread::spawn(move || handle_connection(server_read, server_write, state));
+        client
+    }
+
+    /// A summary of the server's internals for operators, one line per
+    /// section. Each lock is taken on its own, so this never waits on two.
+    pub fn debug_report(&self) -> Vec<String> {
+        let handlers = self.handlers.load(Ordering::Relaxed);
+        let users = self.user_map.lock().unwrap().len();
+        let channels = self.channels.lock().unwrap().len();
+        let nick_holds = self.nick_holds.lock().unwrap().pending(self.clock.now());
+        let memory = match resident_memory_kb() {
+            Some(kb) => format!("{kb} kB resident"),
+            None => "unknown".to_string(),
+        };
+        vec![
+            format!("Handler threads: {handlers} live"),
+            format!("Users: {users} registered"),
+            format!(
+                "Channels: {channels} of {} allowed",
+                self.config.max_channels
+            ),
+            format!("Nick holds: {nick_holds} pending"),
+            format!("Memory: {memory}"),
+        ]
+    }
+
+    /// The server's channels and users, for dashboards. Secrets such as
+    /// passwords and real addresses behind cloaks are left out. Both locks
+    /// are held while it is built, so it is a single moment's view.
+    pub fn export(&self) -> Json {
+        let channels_mutex = self.channels.lock().unwrap();
+        let user_map_mutex = self.user_map.lock().unwrap();
+
+        let mut channels: Vec<_> = channels_mutex.iter().collect();
+        channels.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        let channels = channels
+            .into_iter()
+            .map(|(channel, channel_state)| {
+                let members = channel_state
+                    .members
+                    .iter()
+                    .map(|nick| {
+                        let prefix = channel_state.status(nick).prefix();
+                        Json::object([
+                            ("nick", Json::from(nick.to_string())),
+                            ("status", Json::from(prefix.map(String::from))),
+                        ])
+                    })
+                    .collect();
+                let topic = channel_state.topic.as_ref().map(|topic| {
+                    Json::object([
+                        ("text", Json::from(topic.text.as_str())),
+                        ("set_by", Json::from(topic.set_by.to_string())),
+                        ("set_at", Json::from(topic.set_at)),
+                    ])
+                });
+                Json::object([
+                    ("name", Json::from(channel.to_string())),
+                    ("modes", Json::from(channel_state.mode_string())),
+                    ("topic", Json::from(topic)),
+                    ("members", Json::Array(members)),
+                ])
+            })
+            .collect();
+
+        let mut users: Vec<_> = user_map_mutex.iter().collect();
+        users.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        let users = users
+            .into_iter()
+            .map(|(nick, user)| {
+                let connected_since = user
+                    .connected_since
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Json::object([
+                    ("nick", Json::from(nick.to_string())),
+                    ("username", Json::from(user.username.as_str())),
+                    ("real_name", Json::from(user.real_name.as_str())),
+                    ("host", Json::from(user.visible_host())),
+                    ("modes", Json::from(user.mode_string())),
+                    ("oper", Json::from(user.oper)),
+                    ("away", Json::from(user.away.as_deref())),
+                    (
+                        "account",
+                        Json::from(user.account.as_ref().map(Nick::to_string)),
+                    ),
+                    ("connected_since", Json::from(connected_since)),
+                ])
+            })
+            .collect();
+
+        Json::object([
+            ("server", Json::from(self.config.server_name.as_str())),
+            ("channels", Json::Array(channels)),
+            ("users", Json::Array(users)),
+        ])
+    }
+
+    /// Saves what should survive a restart to `path`. See [`Snapshot`].
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        Snapshot::capture(self).save(path)
+    }
+
+    /// The channels `nickname` is in.
+    pub fn channels_of(&self, nickname: &Nick) -> Vec<Channel> {
+        self.channels.lock().unwrap().channels_of(nickname)
+    }
+
+    /// Renames `old` to `new` wherever they are known, and tells them and
+    /// everyone sharing a channel with them. Both NICK and SANICK come
+    /// through here. The old nick is held for the user, as if they had left.
+    pub fn rename_user(&self, old: &Nick, new: Nick) -> Result<(), ErrorType> {
+        let mut channels_mutex = self.channels.lock().unwrap();
+        let mut user_map_mutex = self.user_map.lock().unwrap();
+        let address = user_map_mutex
+            .get(old)
+            .ok_or(ErrorType::NoSuchNick)?
+            .address;
+        if new.0 == old.0 {
+            return Ok(());
+        }
+        // Changing only the case keeps the same nick, so there's nothing
+        // to collide with and nothing to hold
+        if new != *old {
+            if user_map_mutex.contains_key(&new)
+                || self.pending_nicks.lock().unwrap().is_claimed(&new)
+            {
+                return Err(ErrorType::NickCollision);
+            }
+            let mut nick_holds_mutex = self.nick_holds.lock().unwrap();
+            nick_holds_mutex.check(&new, address, self.clock.now())?;
+            nick_holds_mutex.hold(
+                old.clone(),
+                address,
+                self.clock.deadline(self.config.nick_hold),
+            );
+        }
+
+        let user_state = user_map_mutex.remove(old).unwrap();
+        let sender = user_state.prefix(old);
+        *user_state.nick.lock().unwrap() = new.clone();
+        user_map_mutex.insert(new.clone(), user_state);
+        drop(user_map_mutex);
+
+        let mut recipients = channels_mutex.rename(old, &new);
+        if !recipients.contains(&new) {
+            recipients.push(new.clone());
+        }
+        let reply = Reply::Nick(NickReply {
+            sender,
+            new_nick: new,
+        });
+        broadcast(
+            &self.user_map,
+            &self.config,
+            &recipients,
+            &format!("{reply}"),
+        );
+        Ok(())
+    }
+
+    /// Handles SANICK from `nickname`, who must be a server operator.
+    fn force_rename(&self, nickname: &Nick, sanick_msg: SaNickMsg) {
+        let is_oper = self
+            .user_map
+            .lock()
+            .unwrap()
+            .get(nickname)
+            .is_some_and(|user| user.oper);
+        let SaNickMsg { nick, new_nick } = sanick_msg;
+        let result = if is_oper {
+            self.rename_user(&nick, new_nick.clone())
+        } else {
+            Err(ErrorType::NoPrivileges)
+        };
+
+        let server_name = &self.config.server_name;
+        let user_map_mutex = self.user_map.lock().unwrap();
+        if let Err(err) = result {
+            if let Some(user_state) = user_map_mutex.get(nickname) {
+                let error = format!("{}\r\n", err.sent_by(server_name));
+                write_to_conn(nickname, &user_state.conn_write, error);
+            }
+            return;
+        }
+        self.webhooks.notify(Event::OperAction {
+            nick: nickname.clone(),
+            action: format!("SANICK {nick} {new_nick}"),
+        });
+        if let Some(user_state) = user_map_mutex.get(&new_nick) {
+            let notice = Reply::ServerNotice(ServerNoticeReply {
+                target_nick: new_nick.clone(),
+                message: format!("Operator {nickname} changed your nick to {new_nick}"),
+            });
+            let notice = format!("{}", notice.sent_by(server_name));
+            write_to_conn(&new_nick, &user_state.conn_write, notice);
+        }
+    }
+
+    /// Pings each user who has been quiet for `interval` as of `now`, and
+    /// disconnects those who were pinged and never answered. Bots are left
+    /// alone, as they can't vanish without the server noticing.
+    fn check_pings(&self, now: Instant, interval: Duration) {
+        let server_name = &self.config.server_name;
+        let mut timed_out = Vec::new();
+        let mut user_map_mutex = self.user_map.lock().unwrap();
+        for (nickname, user) in user_map_mutex.iter_mut() {
+            if user.conn_write.is_in_process() {
+                continue;
+            }
+            match user.ping_due(now, interval, self.config.ping_timeout) {
+                PingDue::No => {}
+                PingDue::Ping => {
+                    let ping = format!("{}", Reply::Ping.sent_by(server_name));
+                    write_to_conn(nickname, &user.conn_write, ping);
+                    user.pinged_at = Some(now);
+                }
+                PingDue::TimedOut => {
+                    timed_out.push((nickname.clone(), user.address, user.conn_write.clone()))
+                }
+            }
+        }
+        drop(user_map_mutex);
+
+        let reason = format!(
+            "Ping timeout: {} seconds",
+            self.config.ping_timeout.as_secs()
+        );
+        for (nickname, address, conn_write) in timed_out {
+            let reply = Reply::ClosingLink(ClosingLinkReply {
+                target_nick: Some(nickname.clone()),
+                reason: reason.clone(),
+            });
+            write_to_conn(
+                &nickname,
+                &conn_write,
+                format!("{}", reply.sent_by(server_name)),
+            );
+            self.leave(&nickname, address, reason.clone());
+            // Wakes their thread from its read, to find them gone
+            conn_write.close();
+        }
+    }
+
+    /// Carries out `message` from the registered user `nickname`, queueing
+    /// whatever replies it calls for. Breaks once the user has left, after
+    /// which nothing more should be read from them.
+    pub fn handle_message(&self, nickname: &Nick, message: Message) -> ControlFlow<()> {
+        let server_name = self.config.server_name.as_str();
+        match message {
+            Message::PrivMsg(priv_msg) => send_message(
+                &self.channels,
+                &self.user_map,
+                &self.config,
+                self.clock.now(),
+                nickname,
+                MessageKind::PrivMsg,
+                priv_msg,
+            ),
+            Message::Notice(notice) => send_message(
+                &self.channels,
+                &self.user_map,
+                &self.config,
+                self.clock.now(),
+                nickname,
+                MessageKind::Notice,
+                notice,
+            ),
+            Message::Ping(ping_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    format!("{}", Reply::Pong(ping_msg.clone()).sent_by(server_name)),
+                );
+                log::info!("Sent to {}: PONG {}", nickname, ping_msg);
+            }
+            // Any message counts as a sign of life, which the handler notes
+            Message::Pong(_) => {}
+            Message::Join(join_msg) => {
+                join_channels(
+                    &self.channels,
+                    &self.user_map,
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    join_msg,
+                );
+            }
+            Message::Kick(kick_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                kick_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    nickname,
+                    kick_msg,
+                );
+            }
+            Message::Invite(invite_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                invite_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    nickname,
+                    invite_msg,
+                );
+            }
+            Message::Part(part_msg) => {
+                part_channels(
+                    &self.channels,
+                    &self.user_map,
+                    &self.config,
+                    nickname,
+                    part_msg,
+                );
+            }
+            Message::Topic(topic_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                topic_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    nickname,
+                    topic_msg,
+                    self.clock.system_time(),
+                );
+            }
+            Message::Names(names_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let lines = names_reply(
+                    &channels_mutex,
+                    &user_map_mutex,
+                    &self.config,
+                    nickname,
+                    names_msg.channel.as_ref(),
+                );
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_lines_to_conn(nickname, &user.conn_write, &lines);
+                }
+            }
+            Message::List(list_msg) => {
+                let reply = list_reply(
+                    &self.channels.lock().unwrap(),
+                    &self.config,
+                    nickname,
+                    list_msg,
+                );
+                let user_map_mutex = self.user_map.lock().unwrap();
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_to_conn(nickname, &user.conn_write, reply);
+                }
+            }
+            Message::Who(who_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let reply = who_reply(
+                    &channels_mutex,
+                    &user_map_mutex,
+                    &self.config,
+                    nickname,
+                    who_msg,
+                );
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_to_conn(nickname, &user.conn_write, reply);
+                }
+            }
+            Message::Ison(ison_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let reply = ison_reply(&user_map_mutex, &self.config, nickname, ison_msg);
+                write_to_user(&user_map_mutex, nickname, reply);
+            }
+            Message::Userhost(userhost_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let reply = userhost_reply(&user_map_mutex, &self.config, nickname, userhost_msg);
+                write_to_user(&user_map_mutex, nickname, reply);
+            }
+            Message::Lusers => {
+                let channels_mutex = self.channels.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let reply = lusers_reply(
+                    &channels_mutex,
+                    &user_map_mutex,
+                    &self.config,
+                    nickname,
+                    self.max_users.load(Ordering::Relaxed),
+                );
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_to_conn(nickname, &user.conn_write, reply);
+                }
+            }
+            Message::Whois(whois_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                let reply = whois_reply(
+                    &channels_mutex,
+                    &user_map_mutex,
+                    &self.config,
+                    nickname,
+                    whois_msg,
+                );
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_to_conn(nickname, &user.conn_write, reply);
+                }
+            }
+            Message::Mode(mode_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                mode_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    mode_msg,
+                );
+            }
+            Message::UserMode(mode_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                mode_user(user_map_mutex, &self.config, nickname, mode_msg);
+            }
+            Message::Motd => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    motd_reply(&self.config, nickname),
+                );
+            }
+            Message::Version => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    version_reply(&self.config, nickname),
+                );
+            }
+            Message::Time => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    time_reply(&self.config, nickname),
+                );
+            }
+            Message::Info => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                write_to_user(
+                    &user_map_mutex,
+                    nickname,
+                    info_reply(&self.config, nickname),
+                );
+            }
+            Message::Away(away_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                set_away(user_map_mutex, &self.config, nickname, away_msg);
+            }
+            Message::Oper(oper_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                oper_up(
+                    user_map_mutex,
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    oper_msg,
+                );
+            }
+            Message::Accept(accept_msg) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                accept_users(user_map_mutex, &self.config, nickname, accept_msg);
+            }
+            Message::Register(register_msg) => {
+                let accounts_mutex = self.accounts.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                register_account(
+                    accounts_mutex,
+                    user_map_mutex,
+                    &self.config,
+                    nickname,
+                    register_msg,
+                );
+            }
+            Message::Identify(identify_msg) => {
+                let accounts_mutex = self.accounts.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                identify_account(
+                    accounts_mutex,
+                    user_map_mutex,
+                    &self.config,
+                    nickname,
+                    identify_msg,
+                );
+            }
+            Message::Cert(cert_msg) => {
+                let accounts_mutex = self.accounts.lock().unwrap();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                manage_certs(
+                    accounts_mutex,
+                    user_map_mutex,
+                    &self.config,
+                    nickname,
+                    cert_msg,
+                );
+            }
+            Message::Ghost(ghost_msg) => {
+                let accounts_mutex = self.accounts.lock().unwrap();
+                let channels_mutex = self.channels.lock().unwrap();
+                ghost_user(
+                    accounts_mutex,
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    nickname,
+                    ghost_msg,
+                );
+            }
+            Message::Nick(nick_msg) => {
+                // The handler picks up the new nick with the next message
+                if let Err(err) = self.rename_user(nickname, nick_msg.nick) {
+                    let user_map_mutex = self.user_map.lock().unwrap();
+                    if let Some(user) = user_map_mutex.get(nickname) {
+                        let error = err.sent_by(server_name);
+                        write_to_conn(nickname, &user.conn_write, format!("{}", error));
+                    }
+                }
+            }
+            Message::SaNick(sanick_msg) => self.force_rename(nickname, sanick_msg),
+            Message::Wallops(wallops_msg) => {
+                send_wallops(&self.user_map, &self.config, nickname, wallops_msg);
+            }
+            Message::Kill(kill_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                kill_user(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    kill_msg,
+                );
+            }
+            Message::SaJoin(force_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                force_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    true,
+                    force_msg,
+                );
+            }
+            Message::SaPart(force_msg) => {
+                let channels_mutex = self.channels.lock().unwrap();
+                force_channel(
+                    channels_mutex,
+                    self.user_map.clone(),
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    false,
+                    force_msg,
+                );
+            }
+            Message::Debug => {
+                let report = self.debug_report();
+                let user_map_mutex = self.user_map.lock().unwrap();
+                send_oper_report(
+                    user_map_mutex,
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    "DEBUG",
+                    report,
+                );
+            }
+            Message::Export => {
+                // Sent in pieces between markers, as it won't fit on a line
+                let document = self.export().to_string();
+                let mut report = vec!["EXPORT BEGIN".to_string()];
+                report.extend(split_len(&document, EXPORT_CHUNK_LEN));
+                report.push("EXPORT END".to_string());
+                let user_map_mutex = self.user_map.lock().unwrap();
+                send_oper_report(
+                    user_map_mutex,
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    "EXPORT",
+                    report,
+                );
+            }
+            Message::Snapshot => {
+                // Nothing is written to disk unless the sender may do so
+                let is_oper = self
+                    .user_map
+                    .lock()
+                    .unwrap()
+                    .get(nickname)
+                    .is_some_and(|user| user.oper);
+                let report = match &self.config.snapshot {
+                    _ if !is_oper => Vec::new(),
+                    None => vec!["Snapshots are not enabled".to_string()],
+                    Some(path) => vec![match self.save_snapshot(path) {
+                        Ok(()) => format!("Snapshot saved to {}", path.display()),
+                        Err(err) => format!("Unable to save snapshot: {err}"),
+                    }],
+                };
+                let user_map_mutex = self.user_map.lock().unwrap();
+                send_oper_report(
+                    user_map_mutex,
+                    &self.config,
+                    &self.webhooks,
+                    nickname,
+                    "SNAPSHOT",
+                    report,
+                );
+            }
+            Message::Quit(quit_msg) => {
+                //save quit msg
+                let message = match quit_msg.message {
+                    Some(msg) => truncate(&msg, self.config.reason_len),
+                    None => nickname.to_string(),
+                };
+                let address = self
+                    .user_map
+                    .lock()
+                    .unwrap()
+                    .get(nickname)
+                    .map(|user| user.address);
+                if let Some(address) = address {
+                    self.leave(nickname, address, message);
+                }
+                return ControlFlow::Break(());
+            }
+            Message::User(_) | Message::Pass(_) => {
+                let user_map_mutex = self.user_map.lock().unwrap();
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    let error = ErrorType::AlreadyRegistered.sent_by(server_name);
+                    write_to_conn(nickname, &user.conn_write, format!("{}", error));
+                }
+            }
+            Message::Unknown(command) => {
+                let reply = Reply::UnknownCommand(UnknownCommandReply {
+                    target_nick: Some(nickname.clone()),
+                    command,
+                });
+                let user_map_mutex = self.user_map.lock().unwrap();
+                if let Some(user) = user_map_mutex.get(nickname) {
+                    write_to_conn(
+                        nickname,
+                        &user.conn_write,
+                        format!("{}", reply.sent_by(server_name)),
+                    );
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Removes `nickname` from the server, telling their channels why, and
+    /// reserves the nick so nobody can pose as them straight away. Does
+    /// nothing if they are already gone, e.g. after being ghosted.
+    fn leave(&self, nickname: &Nick, address: IpAddr, message: String) {
+        // Checked under the channel lock, which GHOST holds throughout
+        let channels_mutex = self.channels.lock().unwrap();
+        if !self.user_map.lock().unwrap().contains_key(nickname) {
+            return;
+        }
+        quit_server(
+            channels_mutex,
+            self.user_map.clone(),
+            &self.config,
+            nickname,
+            message.clone(),
+        );
+        self.webhooks.notify(Event::Quit {
+            nick: nickname.clone(),
+            reason: message,
+        });
+        self.nick_holds.lock().unwrap().hold(
+            nickname.clone(),
+            address,
+            self.clock.deadline(self.config.nick_hold),
+        );
+    }
+}
+
+/// The process's resident set size, where the platform reports it.
+fn resident_memory_kb() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// A server running in the background, for programs that embed one.
+pub struct ServerHandle {
+    state: ServerState,
+    address: SocketAddr,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The server's channels and users. See [`ServerState::export`].
+    pub fn export(&self) -> Json {
+        self.state.export()
+    }
+
+    /// Saves what should survive a restart to `path`. See [`Snapshot`].
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        self.state.save_snapshot(path)
+    }
+
+    /// Adds a user called `nick` that is driven from this process rather
+    /// than over the network, returning once it has registered.
+    pub fn add_bot(&self, nick: &str) -> Result<BotHandle, ClientError> {
+        let (bot_read, bot_write) = self.state.connect_in_process();
+        BotHandle::register(bot_read, bot_write, nick)
+    }
+}
+
+/// Runs the server on `connection_manager` on a background thread, for the
+/// rest of the process.
+pub fn start_server(connection_manager: ConnectionManager, config: ServerConfig) -> ServerHandle {
+    let state = ServerState::new(config);
+    let address = connection_manager.local_addr();
+    let serve_state = state.clone();
+    thread::spawn(move || serve(connection_manager, serve_state));
+    ServerHandle { state, address }
+}
+
+/// Runs the server on `connection_manager` until the process exits.
+pub fn run_server(connection_manager: ConnectionManager, config: ServerConfig) {
+    serve(connection_manager, ServerState::new(config));
+}
+
+/// Runs the server on `transport` starting from `state`, until no more
+/// clients can arrive. A TCP listener accepts clients until the process
+/// exits.
+pub fn serve(mut transport: impl Transport, state: ServerState) {
+    redact::set_log_message_contents(state.config.log_message_contents);
+    let recorder = state.config.transcript.clone().and_then(|mut transcript| {
+        transcript.redact |= !state.config.log_message_contents;
+        TranscriptRecorder::launch(transcript)
+            .map_err(|err| log::error!("Unable to record transcripts: {}", err))
+            .ok()
+    });
+    state.webhooks.notify(Event::ServerStart);
+    if let Some(interval) = state.config.ping_interval {
+        let state = state.clone();
+        thread::spawn(move || ping_users(state, interval));
+    }
+    loop {
+        // This function call will block until a new client connects!
+        let Some((mut conn_read, mut conn_write)) = transport.accept() else {
+            return;
+        };
+        conn_write.set_write_timeout(state.config.write_timeout);
+        if let Some(transcript) = recorder
+            .as_ref()
+            .and_then(|recorder| recorder.open(conn_read.address()))
+        {
+            conn_read.set_transcript(transcript.clone());
+            conn_write.set_transcript(transcript);
+        }
+        let state = state.clone();
+        // Spawn a thread for each client that connects
+        thread::spawn(move || handle_connection(conn_read, conn_write, state));
+    }
+}
+
+/// Pings users who have sent nothing for `interval`, and disconnects those
+/// who still haven't once the ping times out. Runs for the rest of the
+/// process.
+fn ping_users(state: ServerState, interval: Duration) {
+    loop {
+        // Read once, so a clock moved during the check doesn't skip a beat
+        let now = state.clock.now();
+        state.check_pings(now, interval);
+        state.clock.sleep_until(now + PING_CHECK_PERIOD);
+    }
+}
+
+/// Tells an unregistered client their password was wrong or missing,
+/// returning whether that was their last attempt. If it was, they are told
+/// the connection is closing.
+fn reject_password(
+    conn_write: &mut ConnectionWrite,
+    server_name: &str,
+    failures: &mut u32,
+) -> bool {
+    let error = ErrorType::PasswdMismatch.sent_by(server_name);
+    let _ = conn_write.write_message(&format!("{}\r\n", error));
+    *failures += 1;
+    if *failures < MAX_PASSWORD_ATTEMPTS {
+        return false;
+    }
+    let reply = Reply::ClosingLink(ClosingLinkReply {
+        target_nick: None,
+        reason: "Bad password".to_string(),
+    });
+    let _ = conn_write.write_message(&format!("{}", reply.sent_by(server_name)));
+    true
+}
+
+/// Reads from `conn_read` until the client has registered, and returns
+/// their nick, which is kept up to date if it changes. Returns `None` if
+/// they leave or are turned away first, once any nick they claimed has
+/// been released, so they never reach the command loop half-registered.
+fn register_client(
+    conn_read: &mut ConnectionRead,
+    mut conn_write: ConnectionWrite,
+    state: &ServerState,
+) -> Option<Arc<Mutex<Nick>>> {
+    let ServerState {
+        config,
+        user_map,
+        nick_holds,
+        pending_nicks,
+        clock,
+        max_users,
+        webhooks,
+        started,
+        ..
+    } = state;
+    let server_name = config.server_name.as_str();
+
+    // The nick they've claimed, until they register with it
+    let mut nickname: Option<Nick> = None;
+    // Bots run inside the server, so needn't know its password
+    let mut password_ok = config.password.is_none() || conn_write.is_in_process();
+    let mut password_failures = 0;
+    // Their USER, until they have a nick to register with
+    let mut user: Option<UserMsg> = None;
+    let deadline = config
+        .registration_timeout
+        .map(|timeout| clock.deadline(timeout));
+
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(clock.now());
+            if left.is_zero() {
+                let reply = Reply::ClosingLink(ClosingLinkReply {
+                    target_nick: nickname.clone(),
+                    reason: "Registration timeout".to_string(),
+                });
+                let _ = conn_write.write_message(&format!("{}", reply.sent_by(server_name)));
+                log::warn!("{} didn't register in time", conn_read.id());
+                if let Some(nick) = &nickname {
+                    pending_nicks.lock().unwrap().release(nick);
+                }
+                return None;
+            }
+            // Wakes up in time to check the deadline again
+            conn_read.set_read_timeout(Some(left.min(REGISTRATION_CHECK_PERIOD)));
+        }
+        println!("Waiting for message...");
+        let message = match conn_read.read_message() {
+            Ok(message) => message,
+            Err(err) if err.is_fatal() => {
+                println!("Lost connection: {err}");
+                if let Some(nick) = &nickname {
+                    pending_nicks.lock().unwrap().release(nick);
+                }
+                return None;
+            }
+            Err(ConnectionError::LineTooLong) => {
+                let error = ErrorType::InputTooLong.sent_by(server_name);
+                let _ = conn_write.write_message(&format!("{}\r\n", error));
+                continue;
+            }
+            Err(ConnectionError::Timeout) => continue,
+            Err(err) => {
+                println!("Invalid message received ({err})... ignoring message.");
+                continue;
+            }
+        };
+
+        let who = nickname
+            .as_ref()
+            .map_or_else(|| conn_read.id(), Nick::to_string);
+        log::info!("Received from {}: {}", who, loggable(&message));
+
+        match ParsedMessage::parse(
+            UnparsedMessage {
+                message: &message,
+                sender_nick: nickname.clone(),
+            },
+            &config.aliases,
+        ) {
+            Ok(parsed) => match parsed.message {
+                Message::Nick(nick_msg) => {
+                    // Each NICK replaces the last, which is kept until the
+                    // new one is known to be free
+                    let nick = nick_msg.nick;
+                    let user_map_mutex = user_map.lock().unwrap();
+                    let mut pending_nicks_mutex = pending_nicks.lock().unwrap();
+
+                    let result = if user_map_mutex.contains_key(&nick) {
+                        Err(ErrorType::NickCollision)
+                    } else {
+                        nick_holds
+                            .lock()
+                            .unwrap()
+                            .check(&nick, conn_read.ip(), clock.now())
+                            .and_then(|()| pending_nicks_mutex.claim(&nick, nickname.as_ref()))
+                    };
+                    match result {
+                        Ok(()) => nickname = Some(nick),
+                        Err(err) => {
+                            let _ = conn_write
+                                .write_message(&format!("{}\r\n", err.sent_by(server_name)));
+                            log::warn!("Sent to {}: {}", conn_read.id(), err);
+                        }
+                    }
+                }
+
+                Message::Pass(password) if !password_ok => {
+                    password_ok = config.password_matches(&password);
+                    if !password_ok
+                        && reject_password(&mut conn_write, server_name, &mut password_failures)
+                    {
+                        if let Some(nick) = &nickname {
+                            pending_nicks.lock().unwrap().release(nick);
+                        }
+                        return None;
+                    }
+                }
+
+                Message::User(_) if !password_ok => {
+                    let last_attempt =
+                        reject_password(&mut conn_write, server_name, &mut password_failures);
+                    if last_attempt {
+                        if let Some(nick) = &nickname {
+                            pending_nicks.lock().unwrap().release(nick);
+                        }
+                        return None;
+                    }
+                }
+
+                // Clients probing for what's supported wait for an answer
+                Message::Unknown(command) => {
+                    let reply = Reply::UnknownCommand(UnknownCommandReply {
+                        target_nick: nickname.clone(),
+                        command,
+                    });
+                    let _ = conn_write.write_message(&format!("{}", reply.sent_by(server_name)));
+                }
+
+                // Kept until NICK arrives too, if it hasn't yet
+                Message::User(user_msg) => user = Some(user_msg),
+
+                Message::Quit(_) => {
+                    if let Some(nick) = &nickname {
+                        pending_nicks.lock().unwrap().release(nick);
+                    }
+                    return None;
+                }
+
+                // Never answered, or harmless before registering
+                Message::Notice(_) | Message::Ping(_) | Message::Pong(_) | Message::Pass(_) => {}
+
+                // Everything else needs them registered, so say why it was ignored
+                _ => {
+                    let error = ErrorType::NotRegistered.sent_by(server_name);
+                    let _ = conn_write.write_message(&format!("{}\r\n", error));
+                }
+            },
+            Err(err) => {
+                let _ = conn_write.write_message(&format!("{}\r\n", err.sent_by(server_name)));
+                log::error!("Sent to {}: {}", who, err);
+            }
+        };
+
+        // Registration is done once both NICK and USER have arrived, in
+        // whichever order they were sent
+        let Some(nickname) = nickname.clone() else {
+            continue;
+        };
+        let Some(user_msg) = user.take() else {
+            continue;
+        };
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        let burst = [
+            Reply::Welcome(WelcomeReply {
+                target_nick: nickname.clone(),
+                message: format!("Welcome to this server, {}!", user_msg.real_name),
+            }),
+            Reply::YourHost(YourHostReply {
+                target_nick: nickname.clone(),
+                version: version.clone(),
+            }),
+            Reply::Created(CreatedReply {
+                target_nick: nickname.clone(),
+                created: started.format(&Rfc2822).unwrap_or_default(),
+            }),
+            Reply::MyInfo(MyInfoReply {
+                target_nick: nickname.clone(),
+                version,
+            }),
+            Reply::ISupport(ISupportReply {
+                target_nick: nickname.clone(),
+                tokens: config.isupport_tokens(),
+            }),
+        ];
+
+        // Add the user before welcoming them, so anything sent once they
+        // see the welcome can already reach them.
+        let address = conn_read.ip();
+        let mut user_map_mutex = user_map.lock().unwrap();
+        let user_state = UserState::new(
+            nickname.clone(),
+            conn_write,
+            user_msg.username,
+            user_msg.real_name,
+            address,
+            config,
+            clock.now(),
+        );
+        let current_nick = user_state.nick.clone();
+        user_map_mutex.insert(nickname.clone(), user_state);
+        max_users.fetch_max(user_map_mutex.len(), Ordering::Relaxed);
+        pending_nicks.lock().unwrap().release(&nickname);
+        let c_write = &user_map_mutex[&nickname].conn_write;
+        let mut lines: Vec<String> = burst
+            .iter()
+            .map(|reply| format!("{}", reply.sent_by(server_name)))
+            .collect();
+        lines.push(motd_reply(config, &nickname));
+        write_lines_to_conn(&nickname, c_write, &lines);
+        webhooks.notify(Event::Registered {
+            nick: nickname.clone(),
+        });
+        return Some(current_nick);
+    }
+}
+
+/// Registers a client, then handles their commands until they leave.
+fn handle_connection(
+    mut conn_read: ConnectionRead,
+    conn_write: ConnectionWrite,
+    state: ServerState,
+) {
+    let ServerState {
+        config: config_clone,
+        user_map: user_map_clone,
+        clock,
+        handlers,
+        ..
+    } = state.clone();
+    let _live = LiveHandler::new(&handlers);
+
+    let server_name = config_clone.server_name.as_str();
+
+    println!("New connection from {}", conn_read.id());
+    let Some(current_nick) = register_client(&mut conn_read, conn_write, &state) else {
+        return;
+    };
+
+    // Registered users can stay as long as they answer pings
+    conn_read.set_read_timeout(None);
+
+    // This loop handles all the commands once user has nicked/usered
+    loop {
+        println!("Waiting for message...");
+        let result = conn_read.read_message();
+        // An operator may have renamed the user while we waited
+        let nickname = current_nick.lock().unwrap().clone();
+        let message = match result {
+            Ok(message) => message,
+            Err(err) if err.is_fatal() => {
+                println!("Lost connection: {err}");
+                // The server may have cut them off for falling too far
+                // behind. Any other failed write is just this loss seen from
+                // the other end, and which end noticed first is down to luck
+                let failure = user_map_clone
+                    .lock()
+                    .unwrap()
+                    .get(&nickname)
+                    .and_then(|user| user.conn_write.failure())
+                    .filter(OutboxError::is_cut_off);
+                let reason = failure.map_or_else(|| err.to_string(), |failure| failure.to_string());
+                // Clean up after clients that vanish without a QUIT
+                state.leave(&nickname, conn_read.ip(), reason);
+                break;
+            }
+            Err(ConnectionError::LineTooLong) => {
+                let user_map_mutex = user_map_clone.lock().unwrap();
+                if let Some(user) = user_map_mutex.get(&nickname) {
+                    let error = ErrorType::InputTooLong.sent_by(server_name);
+                    write_to_conn(&nickname, &user.conn_write, format!("{}\r\n", error));
+                }
+                continue;
+            }
+            Err(err) => {
+                println!("Invalid message received ({err})... ignoring message.");
+                continue;
+            }
+        };
+
+        log::info!("Received from {}: {}", nickname, loggable(&message));
+        // Whatever the client sends shows they are still there
+        if let Some(user) = user_map_clone.lock().unwrap().get_mut(&nickname) {
+            user.seen(clock.now());
+        }
+
+        match ParsedMessage::parse(
+            UnparsedMessage {
+                message: &message,
+                sender_nick: Some(nickname.clone()),
+            },
+            &config_clone.aliases,
+        ) {
+            Ok(parsed) => {
+                if state.handle_message(&nickname, parsed.message).is_break() {
+                    break;
+                }
+            }
+            Err(_) if is_notice(&message, &config_clone.aliases) => {}
+            Err(err) => {
+                // If they were killed meanwhile, the next read finds the
+                // connection closed and ends the loop
+                let user_map_mutex = user_map_clone.lock().unwrap();
+                let error = format!("{}\r\n", err.sent_by(server_name));
+                write_to_user(&user_map_mutex, &nickname, error);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::client::{register, send};
+
+    /// Connects `nick` to `state` in-process and registers them.
+    fn connect(state: &ServerState, nick: &str) -> (ConnectionRead, ConnectionWrite) {
+        let (mut conn_read, mut conn_write) = state.connect_in_process();
+        register(&mut conn_read, &mut conn_write, nick, nick).unwrap();
+        conn_read.set_read_timeout(Some(Duration::from_secs(2)));
+        (conn_read, conn_write)
+    }
+
+    /// Adds `nick` to `state` as a registered user, with nothing reading
+    /// their messages, and returns the client's reading end.
+    fn add_user(state: &ServerState, nick: &str) -> ConnectionRead {
+        let ((_, server_write), (client_read, _)) = in_process();
+        let user_state = UserState::new(
+            Nick(nick.to_string()),
+            server_write,
+            nick.to_string(),
+            nick.to_string(),
+            "127.0.0.1".parse().unwrap(),
+            &state.config,
+            state.clock.now(),
+        );
+        state
+            .user_map
+            .lock()
+            .unwrap()
+            .insert(Nick(nick.to_string()), user_state);
+        client_read.set_read_timeout(Some(Duration::from_millis(200)));
+        client_read
+    }
+
+    /// `line` as if `nick` had sent it.
+    fn parse(nick: &str, line: &str) -> Message {
+        let unparsed = UnparsedMessage {
+            message: line,
+            sender_nick: Some(Nick(nick.to_string())),
+        };
+        ParsedMessage::parse(unparsed, &HashMap::new())
+            .unwrap()
+            .message
+    }
+
+    #[test]
+    fn test_registration_abandoned_after_nick() {
+        let state = ServerState::new(ServerConfig::default());
+        let ((mut server_read, server_write), (_client_read, mut client_write)) = in_process();
+        send(&mut client_write, "NICK tom").unwrap();
+        drop(client_write);
+
+        assert!(register_client(&mut server_read, server_write, &state).is_none());
+        let tom = Nick("tom".to_string());
+        assert!(!state.pending_nicks.lock().unwrap().is_claimed(&tom));
+        assert!(state.user_map.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_handle_message() {
+        let state = ServerState::new(ServerConfig::default());
+        let mut tom = add_user(&state, "tom");
+        let mut ann = add_user(&state, "ann");
+        let mut bob = add_user(&state, "bob");
+        let channel = Channel("#chan".to_string());
+        for nick in ["tom", "ann"] {
+            state
+                .channels
+                .lock()
+                .unwrap()
+                .join(&channel, &Nick(nick.to_string()));
+        }
+
+        let tom_nick = Nick("tom".to_string());
+        let message = parse("tom", "PRIVMSG #chan :hello");
+        assert!(state.handle_message(&tom_nick, message).is_continue());
+        let relayed = ":tom!tom@127.0.0.1 PRIVMSG #chan :hello";
+        assert_eq!(tom.read_message().unwrap(), relayed);
+        assert_eq!(ann.read_message().unwrap(), relayed);
+        assert_eq!(bob.read_message(), Err(ConnectionError::Timeout));
+
+        let message = parse("tom", "QUIT :bye");
+        assert!(state.handle_message(&tom_nick, message).is_break());
+        assert_eq!(ann.read_message().unwrap(), ":tom!tom@127.0.0.1 QUIT :bye");
+        assert!(!state.user_map.lock().unwrap().contains_key(&tom_nick));
+    }
+
+    #[test]
+    fn test_failed_write_quits_user() {
+        let state = ServerState::new(ServerConfig::default());
+        let (mut tom_read, mut tom_write) = connect(&state, "tom");
+        let (mut ann_read, mut ann_write) = connect(&state, "ann");
+        send(&mut tom_write, "JOIN #rust").unwrap();
+        for _ in 0..3 {
+            tom_read.read_message().unwrap();
+        }
+        send(&mut ann_write, "JOIN #rust").unwrap();
+        for _ in 0..3 {
+            ann_read.read_message().unwrap();
+        }
+
+        // Tom stops reading altogether, so the next write to him fails
+        drop(tom_read);
+        send(&mut ann_write, "PRIVMSG #rust :hi").unwrap();
+        // Tom's quit can overtake the echo of what Ann said
+        let mut received = [
+            ann_read.read_message().unwrap(),
+            ann_read.read_message().unwrap(),
+        ];
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                ":ann!ann@127.0.0.1 PRIVMSG #rust :hi",
+                // Losing the connection isn't the server cutting tom off
+                ":tom!tom@127.0.0.1 QUIT :Connection closed",
+            ]
+        );
+        let tom = Nick("tom".to_string());
+        assert!(!state.user_map.lock().unwrap().contains_key(&tom));
+        let channels = state.channels.lock().unwrap();
+        let rust = channels.get(&Channel("#rust".to_string())).unwrap();
+        assert_eq!(rust.members, [Nick("ann".to_string())]);
+    }
+
+    #[test]
+    fn test_replies_to_removed_user() {
+        let state = ServerState::new(ServerConfig::default());
+        let (mut tom_read, mut tom_write) = connect(&state, "tom");
+        let tom = Nick("tom".to_string());
+        let removed = state.user_map.lock().unwrap().remove(&tom).unwrap();
+
+        // Each of these answers tom, who is no longer there to answer
+        for line in [
+            "PRIVMSG",
+            "PING :1",
+            "PRIVMSG nobody :hi",
+            "JOIN #rust",
+            "AWAY :lunch",
+        ] {
+            send(&mut tom_write, line).unwrap();
+        }
+
+        // Their thread carries on once they're back
+        thread::sleep(Duration::from_millis(100));
+        state.user_map.lock().unwrap().insert(tom, removed);
+        send(&mut tom_write, "PING :3").unwrap();
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":iris-server PONG iris-server :3"
+        );
+    }
+
+    #[test]
+    fn test_debug_report_sections() {
+        let state = ServerState::new(ServerConfig::default());
+        let _live = LiveHandler::new(&state.handlers);
+        state
+            .channels
+            .lock()
+            .unwrap()
+            .join(&Channel("#rust".to_string()), &Nick("tom".to_string()));
+
+        let report = state.debug_report();
+        assert_eq!(report[0], "Handler threads: 1 live");
+        assert_eq!(report[1], "Users: 0 registered");
+        assert_eq!(report[2], "Channels: 1 of 5000 allowed");
+        assert_eq!(report[3], "Nick holds: 0 pending");
+        assert!(report[4].starts_with("Memory: "));
+    }
+
+    #[test]
+    fn test_sanick() {
+        let state = ServerState::new(ServerConfig::default());
+        let (mut tom_read, mut tom_write) = connect(&state, "tom");
+        let (mut ann_read, mut ann_write) = connect(&state, "ann");
+        let (mut oper_read, mut oper_write) = connect(&state, "oper");
+        send(&mut tom_write, "JOIN #rust").unwrap();
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":tom!tom@127.0.0.1 JOIN #rust"
+        );
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":iris-server 353 tom = #rust :@tom"
+        );
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":iris-server 366 tom #rust :End of /NAMES list"
+        );
+        send(&mut ann_write, "JOIN #rust").unwrap();
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":ann!ann@127.0.0.1 JOIN #rust"
+        );
+        assert_eq!(
+            ann_read.read_message().unwrap(),
+            ":ann!ann@127.0.0.1 JOIN #rust"
+        );
+        assert_eq!(
+            ann_read.read_message().unwrap(),
+            ":iris-server 353 ann = #rust :@tom ann"
+        );
+        assert_eq!(
+            ann_read.read_message().unwrap(),
+            ":iris-server 366 ann #rust :End of /NAMES list"
+        );
+
+        send(&mut oper_write, "SANICK tom thomas").unwrap();
+        assert_eq!(
+            oper_read.read_message().unwrap(),
+            ":iris-server 481 :Permission Denied- You're not an IRC operator"
+        );
+        state
+            .user_map
+            .lock()
+            .unwrap()
+            .get_mut(&Nick("oper".to_string()))
+            .unwrap()
+            .oper = true;
+        send(&mut oper_write, "SANICK tom ann").unwrap();
+        assert_eq!(
+            oper_read.read_message().unwrap(),
+            ":iris-server 436 :Nickname collision"
+        );
+        send(&mut oper_write, "SANICK nobody thomas").unwrap();
+        assert_eq!(
+            oper_read.read_message().unwrap(),
+            ":iris-server 401 :No such nick/channel"
+        );
+
+        send(&mut oper_write, "SANICK tom thomas").unwrap();
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":tom!tom@127.0.0.1 NICK thomas"
+        );
+        assert_eq!(
+            tom_read.read_message().unwrap(),
+            ":iris-server NOTICE thomas :Operator oper changed your nick to thomas"
+        );
+        assert_eq!(
+            ann_read.read_message().unwrap(),
+            ":tom!tom@127.0.0.1 NICK thomas"
+        );
+
+        // The renamed user's own handler carries on under the new nick
+        send(&mut tom_write, "PRIVMSG #rust :hello").unwrap();
+        assert_eq!(
+            ann_read.read_message().unwrap(),
+            ":thomas!tom@127.0.0.1 PRIVMSG #rust :hello"
+        );
+        assert_eq!(
+            state.channels_of(&Nick("thomas".to_string())),
+            [Channel("#rust".to_string())]
+        );
+        state.channels.lock().unwrap().check_invariants();
+    }
+}
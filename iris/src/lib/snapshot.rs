@@ -0,0 +1,532 @@
+use std::{fmt::Write as _, fs, io, path::Path};
+
+use crate::{
+    json::Json,
+    server::ServerState,
+    types::{Channel, Hostmask, Nick, Topic},
+};
+
+/// The snapshot format written by this version of the server. Snapshots
+/// from older versions still load; newer ones are refused.
+///
+/// Version 2 keys account digests by the nick in lowercase. Accounts from
+/// version 1 snapshots keep their digests, marked as legacy ones.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+/// The state worth keeping across a restart: persistent channels with their
+/// modes and topics, and accounts. Live connections, and the channels that only exist
+/// because someone is in them, are left behind.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Snapshot {
+    pub channels: Vec<ChannelSnapshot>,
+    pub accounts: Vec<AccountSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelSnapshot {
+    pub name: Channel,
+    pub oper_only: bool,
+    pub strip_formatting: bool,
+    pub block_formatting: bool,
+    pub secure_only: bool,
+    pub registered_only: bool,
+    pub registered_speak: bool,
+    pub no_external: bool,
+    pub topic_ops: bool,
+    pub moderated: bool,
+    pub invite_only: bool,
+    pub secret: bool,
+    pub key: Option<String>,
+    pub limit: Option<usize>,
+    pub bans: Vec<Hostmask>,
+    pub topic: Option<Topic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountSnapshot {
+    pub nick: Nick,
+    /// The HMAC of the account's password. The password itself is never kept.
+    pub digest: Vec<u8>,
+    /// Whether `digest` is keyed by the nick as registered rather than in
+    /// lowercase. See [`Accounts::has_legacy_digest`].
+    ///
+    /// [`Accounts::has_legacy_digest`]: crate::accounts::Accounts::has_legacy_digest
+    pub legacy_digest: bool,
+    /// Fingerprints of the client certificates that identify the account.
+    pub fingerprints: Vec<String>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of `state`.
+    pub fn capture(state: &ServerState) -> Self {
+        // Accounts before channels, the same order GHOST takes them in
+        let accounts_mutex = state.accounts.lock().unwrap();
+        let channels_mutex = state.channels.lock().unwrap();
+
+        let mut channels: Vec<_> = channels_mutex
+            .iter()
+            .filter(|(_, channel_state)| channel_state.persistent)
+            .map(|(channel, channel_state)| ChannelSnapshot {
+                name: channel.clone(),
+                oper_only: channel_state.oper_only,
+                strip_formatting: channel_state.strip_formatting,
+                block_formatting: channel_state.block_formatting,
+                secure_only: channel_state.secure_only,
+                registered_only: channel_state.registered_only,
+                registered_speak: channel_state.registered_speak,
+                no_external: channel_state.no_external,
+                topic_ops: channel_state.topic_ops,
+                moderated: channel_state.moderated,
+                invite_only: channel_state.invite_only,
+                secret: channel_state.secret,
+                key: channel_state.key.clone(),
+                limit: channel_state.limit,
+                bans: channel_state.bans.clone(),
+                topic: channel_state.topic.clone(),
+            })
+            .collect();
+        channels.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+
+        let mut accounts: Vec<_> = accounts_mutex
+            .digests()
+            .map(|(nick, digest)| AccountSnapshot {
+                nick: nick.clone(),
+                digest: digest.to_vec(),
+                legacy_digest: accounts_mutex.has_legacy_digest(nick),
+                fingerprints: accounts_mutex
+                    .fingerprints(nick)
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.nick.0.cmp(&b.nick.0));
+
+        Self { channels, accounts }
+    }
+
+    /// Loads the snapshot into `state`, which should be freshly started.
+    pub fn restore(&self, state: &ServerState) {
+        let mut accounts_mutex = state.accounts.lock().unwrap();
+        let mut channels_mutex = state.channels.lock().unwrap();
+        for account in &self.accounts {
+            accounts_mutex.restore(
+                account.nick.clone(),
+                account.digest.clone(),
+                account.legacy_digest,
+            );
+            for fingerprint in &account.fingerprints {
+                let _ = accounts_mutex.add_fingerprint(&account.nick, fingerprint.clone());
+            }
+        }
+        for channel in &self.channels {
+            let channel_state = channels_mutex.get_or_create(&channel.name);
+            channel_state.persistent = true;
+            channel_state.oper_only = channel.oper_only;
+            channel_state.strip_formatting = channel.strip_formatting;
+            channel_state.block_formatting = channel.block_formatting;
+            channel_state.secure_only = channel.secure_only;
+            channel_state.registered_only = channel.registered_only;
+            channel_state.registered_speak = channel.registered_speak;
+            channel_state.no_external = channel.no_external;
+            channel_state.topic_ops = channel.topic_ops;
+            channel_state.moderated = channel.moderated;
+            channel_state.invite_only = channel.invite_only;
+            channel_state.secret = channel.secret;
+            channel_state.key = channel.key.clone();
+            channel_state.limit = channel.limit;
+            channel_state.bans = channel.bans.clone();
+            channel_state.topic = channel.topic.clone();
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let channels = self
+            .channels
+            .iter()
+            .map(|channel| {
+                Json::object([
+                    ("name", Json::from(channel.name.to_string())),
+                    ("oper_only", Json::from(channel.oper_only)),
+                    ("strip_formatting", Json::from(channel.strip_formatting)),
+                    ("block_formatting", Json::from(channel.block_formatting)),
+                    ("secure_only", Json::from(channel.secure_only)),
+                    ("registered_only", Json::from(channel.registered_only)),
+                    ("registered_speak", Json::from(channel.registered_speak)),
+                    ("no_external", Json::from(channel.no_external)),
+                    ("topic_ops", Json::from(channel.topic_ops)),
+                    ("moderated", Json::from(channel.moderated)),
+                    ("invite_only", Json::from(channel.invite_only)),
+                    ("secret", Json::from(channel.secret)),
+                    ("key", Json::from(channel.key.clone())),
+                    ("limit", Json::from(channel.limit.map(|limit| limit as u64))),
+                    (
+                        "bans",
+                        Json::Array(
+                            channel
+                                .bans
+                                .iter()
+                                .map(|ban| Json::from(ban.to_string()))
+                                .collect(),
+                        ),
+                    ),
+                    (
+                        "topic",
+                        Json::from(channel.topic.as_ref().map(|topic| {
+                            Json::object([
+                                ("text", Json::from(topic.text.clone())),
+                                ("set_by", Json::from(topic.set_by.to_string())),
+                                ("set_at", Json::from(topic.set_at)),
+                            ])
+                        })),
+                    ),
+                ])
+            })
+            .collect();
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|account| {
+                Json::object([
+                    ("nick", Json::from(account.nick.to_string())),
+                    ("digest", Json::from(to_hex(&account.digest))),
+                    ("legacy_digest", Json::from(account.legacy_digest)),
+                    (
+                        "fingerprints",
+                        Json::Array(
+                            account
+                                .fingerprints
+                                .iter()
+                                .map(|fingerprint| Json::from(fingerprint.clone()))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+        Json::object([
+            ("version", Json::from(SNAPSHOT_VERSION)),
+            ("channels", Json::Array(channels)),
+            ("accounts", Json::Array(accounts)),
+        ])
+    }
+
+    /// Reads a snapshot back from [`Snapshot::to_json`]. Fields this version
+    /// doesn't know about are ignored, and ones it expects but which older
+    /// versions didn't write take their defaults.
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let version = json
+            .get("version")
+            .and_then(Json::as_u64)
+            .ok_or("snapshot has no version")?;
+        if version > SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot is version {version}, but this server only reads up to {SNAPSHOT_VERSION}"
+            ));
+        }
+
+        let channels = list(json, "channels")?
+            .iter()
+            .map(|channel| {
+                let flag = |name| channel.get(name).and_then(Json::as_bool).unwrap_or(false);
+                Ok(ChannelSnapshot {
+                    name: Channel::try_from(string(channel, "name")?)
+                        .map_err(|_| "snapshot has an invalid channel name")?,
+                    oper_only: flag("oper_only"),
+                    strip_formatting: flag("strip_formatting"),
+                    block_formatting: flag("block_formatting"),
+                    secure_only: flag("secure_only"),
+                    registered_only: flag("registered_only"),
+                    registered_speak: flag("registered_speak"),
+                    no_external: flag("no_external"),
+                    topic_ops: flag("topic_ops"),
+                    moderated: flag("moderated"),
+                    invite_only: flag("invite_only"),
+                    secret: flag("secret"),
+                    key: channel
+                        .get("key")
+                        .and_then(Json::as_str)
+                        .map(str::to_string),
+                    limit: channel
+                        .get("limit")
+                        .and_then(Json::as_u64)
+                        .map(|limit| limit as usize),
+                    bans: list(channel, "bans")?
+                        .iter()
+                        .map(|ban| {
+                            ban.as_str()
+                                .map(|ban| Hostmask(ban.to_string()))
+                                .ok_or("snapshot has an invalid ban")
+                        })
+                        .collect::<Result<_, _>>()?,
+                    topic: match channel.get("topic") {
+                        None | Some(Json::Null) => None,
+                        Some(topic) => Some(Topic {
+                            text: string(topic, "text")?,
+                            set_by: Nick::parse(&string(topic, "set_by")?)
+                                .map_err(|_| "snapshot has an invalid topic setter")?,
+                            set_at: topic
+                                .get("set_at")
+                                .and_then(Json::as_u64)
+                                .ok_or("snapshot entry has no set_at")?,
+                        }),
+                    },
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let accounts = list(json, "accounts")?
+            .iter()
+            .map(|account| {
+                Ok(AccountSnapshot {
+                    nick: Nick::parse(&string(account, "nick")?)
+                        .map_err(|_| "snapshot has an invalid account nick")?,
+                    digest: from_hex(&string(account, "digest")?)
+                        .ok_or("snapshot has an invalid account digest")?,
+                    // Version 1 keyed every digest by the nick as registered
+                    legacy_digest: version < 2
+                        || account
+                            .get("legacy_digest")
+                            .and_then(Json::as_bool)
+                            .unwrap_or(false),
+                    fingerprints: list(account, "fingerprints")?
+                        .iter()
+                        .map(|fingerprint| {
+                            fingerprint
+                                .as_str()
+                                .map(str::to_string)
+                                .ok_or("snapshot has an invalid fingerprint")
+                        })
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { channels, accounts })
+    }
+
+    /// Writes the snapshot to `path`. The file is replaced in one step, so
+    /// a crash part way through leaves any earlier snapshot intact.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, self.to_json().to_string())?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Reads a snapshot from `path`, failing if it is missing or corrupt.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::from_json(&text.parse()?)
+    }
+}
+
+/// The array `json` holds under `key`. A missing array is empty.
+fn list<'a>(json: &'a Json, key: &str) -> Result<&'a [Json], String> {
+    match json.get(key) {
+        None => Ok(&[]),
+        Some(value) => value
+            .as_array()
+            .ok_or_else(|| format!("snapshot's {key} is not a list")),
+    }
+}
+
+/// The string `json` holds under `key`.
+fn string(json: &Json, key: &str) -> Result<String, String> {
+    json.get(key)
+        .and_then(Json::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| format!("snapshot entry has no {key}"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        types::{ChannelMode, ErrorType},
+    };
+
+    fn channel(name: &str) -> Channel {
+        Channel(name.to_string())
+    }
+
+    /// A server with an account and a persistent channel, plus a channel
+    /// that only exists while someone is in it.
+    fn populated_state() -> ServerState {
+        let state = ServerState::new(ServerConfig::default());
+        state
+            .accounts
+            .lock()
+            .unwrap()
+            .register(Nick("Alice".to_string()), "hunter2")
+            .unwrap();
+        state
+            .accounts
+            .lock()
+            .unwrap()
+            .add_fingerprint(&Nick("alice".to_string()), "ab".repeat(32))
+            .unwrap();
+        let mut channels = state.channels.lock().unwrap();
+        channels.join(&channel("#rust"), &Nick("alice".to_string()));
+        let rust = channels.get_mut(&channel("#rust")).unwrap();
+        rust.apply_mode(true, &ChannelMode::Persistent);
+        rust.apply_mode(true, &ChannelMode::OperOnly);
+        rust.apply_mode(true, &ChannelMode::TopicOps);
+        rust.apply_mode(true, &ChannelMode::Key("secret".to_string()));
+        rust.apply_mode(true, &ChannelMode::Limit(25));
+        rust.apply_mode(
+            true,
+            &ChannelMode::Ban(Some(Hostmask("spammer!*@*".to_string()))),
+        );
+        rust.apply_mode(true, &ChannelMode::Secret);
+        rust.set_topic(
+            &Nick("alice".to_string()),
+            "All things \"Rust\"".to_string(),
+            1_700_000_000,
+        );
+        channels.join(&channel("#go"), &Nick("alice".to_string()));
+        drop(channels);
+        state
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = Snapshot::capture(&populated_state());
+        assert_eq!(
+            snapshot.channels,
+            vec![ChannelSnapshot {
+                name: channel("#rust"),
+                oper_only: true,
+                strip_formatting: false,
+                block_formatting: false,
+                secure_only: false,
+                registered_only: false,
+                registered_speak: false,
+                no_external: false,
+                topic_ops: true,
+                moderated: false,
+                invite_only: false,
+                secret: true,
+                key: Some("secret".to_string()),
+                limit: Some(25),
+                bans: vec![Hostmask("spammer!*@*".to_string())],
+                topic: Some(Topic {
+                    text: "All things \"Rust\"".to_string(),
+                    set_by: Nick("alice".to_string()),
+                    set_at: 1_700_000_000,
+                }),
+            }]
+        );
+
+        let path = std::env::temp_dir().join(format!("iris-snapshot-{}.json", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded, snapshot);
+
+        let restored = ServerState::new(ServerConfig::default());
+        loaded.restore(&restored);
+        assert_eq!(
+            restored
+                .accounts
+                .lock()
+                .unwrap()
+                .verify(&Nick("alice".to_string()), "hunter2"),
+            Ok(())
+        );
+        assert_eq!(
+            restored
+                .accounts
+                .lock()
+                .unwrap()
+                .account_for_fingerprint(&"ab".repeat(32)),
+            Some(&Nick("alice".to_string()))
+        );
+        let channels = restored.channels.lock().unwrap();
+        let rust = channels.get(&channel("#rust")).unwrap();
+        assert!(rust.persistent && rust.oper_only && rust.members.is_empty());
+        assert_eq!(rust.key.as_deref(), Some("secret"));
+        assert_eq!(rust.bans, [Hostmask("spammer!*@*".to_string())]);
+        assert_eq!(rust.topic, snapshot.channels[0].topic);
+        assert!(channels.get(&channel("#go")).is_none());
+        channels.check_invariants();
+    }
+
+    #[test]
+    fn test_version_1_digests_still_verify() {
+        use hmac::{Hmac, Mac};
+
+        // Version 1 keyed the digest by the nick as registered
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"Alice").unwrap();
+        mac.update(b"hunter2");
+        let digest = to_hex(&mac.finalize().into_bytes());
+        let json =
+            format!(r#"{{"version":1,"accounts":[{{"nick":"Alice","digest":"{digest}"}}]}}"#)
+                .parse()
+                .unwrap();
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert!(snapshot.accounts[0].legacy_digest);
+
+        let state = ServerState::new(ServerConfig::default());
+        snapshot.restore(&state);
+        let accounts = state.accounts.lock().unwrap();
+        assert_eq!(
+            accounts.verify(&Nick("alice".to_string()), "hunter2"),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.verify(&Nick("alice".to_string()), "hunter3"),
+            Err(ErrorType::PasswdMismatch)
+        );
+        drop(accounts);
+
+        // Saving again keeps it a legacy digest
+        assert_eq!(Snapshot::capture(&state), snapshot);
+    }
+
+    #[test]
+    fn test_unknown_fields_are_ignored() {
+        let json = r##"{"version":1,"bans":[],"channels":[{"name":"#rust","colour":"blue","oper_only":true}]}"##
+            .parse()
+            .unwrap();
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert_eq!(snapshot.channels[0].name, channel("#rust"));
+        assert!(snapshot.channels[0].oper_only);
+        assert_eq!(snapshot.channels[0].topic, None);
+        assert!(snapshot.accounts.is_empty());
+    }
+
+    #[test]
+    fn test_bad_snapshots_are_refused() {
+        let from_text = |text: &str| Snapshot::from_json(&text.parse().unwrap());
+        assert!(from_text(r#"{"channels":[]}"#).is_err());
+        assert!(from_text(r#"{"version":3}"#).is_err());
+        assert!(from_text(r#"{"version":1,"channels":{}}"#).is_err());
+        assert!(from_text(r#"{"version":1,"channels":[{"name":"rust"}]}"#).is_err());
+        assert!(
+            from_text(r##"{"version":2,"channels":[{"name":"#rust","topic":"hi"}]}"##).is_err()
+        );
+        assert!(from_text(r#"{"version":1,"accounts":[{"nick":"a","digest":"xyz"}]}"#).is_err());
+
+        let path = std::env::temp_dir().join(format!("iris-corrupt-{}.json", std::process::id()));
+        fs::write(&path, "{\"version\":1,").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
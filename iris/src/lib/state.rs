@@ -0,0 +1,1947 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    cloak::cloak_host,
+    config::{RepeatFilter, ServerConfig},
+    connect::ConnectionWrite,
+    formatting::{has_formatting, strip_formatting},
+    outbox::Outbox,
+    types::{
+        Channel, ChannelMode, ErrorType, Hostmask, MemberStatus, Nick, Prefix, Topic, UserMode,
+    },
+};
+
+/// The most nicks a user may keep on their accept list.
+pub const ACCEPT_LIST_MAX: usize = 20;
+
+/// How often a +g user is told about blocked senders.
+pub const CALLER_ID_NOTIFY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything the server knows about a registered user.
+pub struct UserState {
+    /// The user's nick, shared with their connection's handler so it
+    /// notices when someone else renames them.
+    pub nick: Arc<Mutex<Nick>>,
+    /// Messages queued here are written by the connection's own thread, so
+    /// writing to one user doesn't hold up everyone else.
+    pub conn_write: Outbox,
+    /// What the user gave as their username with USER.
+    pub username: String,
+    pub real_name: String,
+    /// Whether the user's connection is safe from eavesdropping.
+    pub secure: bool,
+    /// The user's real address. Only shown to operators.
+    pub address: IpAddr,
+    /// The cloaked hostname, if cloaking is enabled.
+    pub cloaked_host: Option<String>,
+    /// Whether the user is a server operator.
+    pub oper: bool,
+    /// Hidden from WHO and NAMES for anyone not sharing a channel (+i).
+    pub invisible: bool,
+    pub caller_id: CallerId,
+    /// Only identified users may send the user private messages (+R).
+    pub registered_only: bool,
+    /// The user receives WALLOPS (+w).
+    pub wallops: bool,
+    /// The account the user has identified to, if any.
+    pub account: Option<Nick>,
+    /// When the user finished registering.
+    pub connected_since: SystemTime,
+    /// Why the user is away, if they are.
+    pub away: Option<String>,
+    /// When the user last sent anything, by the server's clock.
+    pub last_active: Instant,
+    /// When the server pinged the user for going quiet, if they haven't
+    /// sent anything since.
+    pub pinged_at: Option<Instant>,
+}
+
+/// Whether a user has gone quiet for long enough that the server should
+/// act. See [`UserState::ping_due`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingDue {
+    /// The user has been heard from recently, or was pinged and still has
+    /// time to answer.
+    No,
+    /// The user has been quiet for the whole ping interval.
+    Ping,
+    /// The user was pinged and never answered.
+    TimedOut,
+}
+
+impl UserState {
+    pub fn new(
+        nick: Nick,
+        conn_write: ConnectionWrite,
+        username: String,
+        real_name: String,
+        address: IpAddr,
+        config: &ServerConfig,
+        now: Instant,
+    ) -> Self {
+        Self {
+            nick: Arc::new(Mutex::new(nick)),
+            secure: conn_write.is_secure(),
+            conn_write: Outbox::launch(conn_write, config.send_queue_len),
+            username,
+            real_name,
+            address,
+            cloaked_host: config
+                .cloak_secret
+                .as_ref()
+                .map(|secret| cloak_host(secret, address)),
+            oper: false,
+            invisible: false,
+            caller_id: CallerId::default(),
+            registered_only: false,
+            wallops: false,
+            account: None,
+            connected_since: SystemTime::now(),
+            away: None,
+            last_active: now,
+            pinged_at: None,
+        }
+    }
+
+    /// Notes that the user sent something at `now`, which answers any ping.
+    pub fn seen(&mut self, now: Instant) {
+        self.last_active = now;
+        self.pinged_at = None;
+    }
+
+    /// Whether the user should be pinged or given up on at `now`.
+    pub fn ping_due(&self, now: Instant, interval: Duration, timeout: Duration) -> PingDue {
+        match self.pinged_at {
+            Some(pinged_at) if now.saturating_duration_since(pinged_at) >= timeout => {
+                PingDue::TimedOut
+            }
+            None if now.saturating_duration_since(self.last_active) >= interval => PingDue::Ping,
+            _ => PingDue::No,
+        }
+    }
+
+    /// The hostname shown to other users: the cloak when enabled,
+    /// otherwise the real address.
+    pub fn visible_host(&self) -> String {
+        match &self.cloaked_host {
+            Some(cloak) => cloak.clone(),
+            None => self.address.to_string(),
+        }
+    }
+
+    /// How the user is shown as the source of what they send, going by
+    /// `nick`.
+    pub fn prefix(&self, nick: &Nick) -> Prefix {
+        Prefix {
+            name: nick.to_string(),
+            user_host: Some((self.username.clone(), self.visible_host())),
+        }
+    }
+
+    /// What bans are matched against: `nick!user@host` with the host the
+    /// user is shown with, and with their real address too if it is cloaked.
+    pub fn hostmasks(&self, nick: &Nick) -> Vec<String> {
+        let mut hostmasks = vec![format!("{nick}!{}@{}", self.username, self.visible_host())];
+        if self.cloaked_host.is_some() {
+            hostmasks.push(format!("{nick}!{}@{}", self.username, self.address));
+        }
+        hostmasks
+    }
+
+    /// The user's current modes, e.g. `+g`.
+    pub fn mode_string(&self) -> String {
+        let mut modes = "+".to_string();
+        if self.invisible {
+            modes.push(UserMode::Invisible.letter());
+        }
+        if self.caller_id.enabled {
+            modes.push(UserMode::CallerId.letter());
+        }
+        if self.registered_only {
+            modes.push(UserMode::RegisteredOnly.letter());
+        }
+        if self.wallops {
+            modes.push(UserMode::Wallops.letter());
+        }
+        modes
+    }
+
+    /// Applies a single user mode.
+    pub fn apply_mode(&mut self, adding: bool, mode: UserMode) {
+        match mode {
+            UserMode::Invisible => self.invisible = adding,
+            UserMode::CallerId => self.caller_id.enabled = adding,
+            UserMode::RegisteredOnly => self.registered_only = adding,
+            UserMode::Wallops => self.wallops = adding,
+        }
+    }
+}
+
+/// Caller-ID (+g) state: who may send private messages to the user.
+#[derive(Debug, Default)]
+pub struct CallerId {
+    pub enabled: bool,
+    pub accepted: Vec<Nick>,
+    last_notified: Option<Instant>,
+}
+
+impl CallerId {
+    /// Whether a private message from `sender` should be delivered.
+    pub fn allows(&self, sender: &Nick) -> bool {
+        !self.enabled || self.accepted.contains(sender)
+    }
+
+    pub fn accept(&mut self, nick: Nick) -> Result<(), ErrorType> {
+        if self.accepted.contains(&nick) {
+            Err(ErrorType::AcceptExist)
+        } else if self.accepted.len() >= ACCEPT_LIST_MAX {
+            Err(ErrorType::AcceptFull)
+        } else {
+            self.accepted.push(nick);
+            Ok(())
+        }
+    }
+
+    pub fn unaccept(&mut self, nick: &Nick) -> Result<(), ErrorType> {
+        if self.accepted.contains(nick) {
+            self.accepted.retain(|accepted| accepted != nick);
+            Ok(())
+        } else {
+            Err(ErrorType::AcceptNot)
+        }
+    }
+
+    /// Whether the user should be told about a blocked message at `now`.
+    /// At most one notification is sent per `CALLER_ID_NOTIFY_INTERVAL`.
+    pub fn should_notify(&mut self, now: Instant) -> bool {
+        match self.last_notified {
+            Some(last) if now.duration_since(last) < CALLER_ID_NOTIFY_INTERVAL => false,
+            _ => {
+                self.last_notified = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Nicks recently released by departing users, reserved so nobody else
+/// can take them straight away. Expired holds are dropped lazily.
+#[derive(Debug, Default)]
+pub struct NickHolds {
+    holds: HashMap<Nick, (IpAddr, Instant)>,
+}
+
+impl NickHolds {
+    /// Reserves `nick` for connections from `address` until `expires`.
+    pub fn hold(&mut self, nick: Nick, address: IpAddr, expires: Instant) {
+        self.holds.insert(nick, (address, expires));
+    }
+
+    /// Checks whether a connection from `address` may use `nick` at `now`.
+    pub fn check(&mut self, nick: &Nick, address: IpAddr, now: Instant) -> Result<(), ErrorType> {
+        self.holds
+            .retain(|_nick, (_address, expires)| *expires > now);
+        match self.holds.get(nick) {
+            Some((held_for, _expires)) if *held_for != address => Err(ErrorType::UnavailResource),
+            _ => Ok(()),
+        }
+    }
+
+    /// How many nicks are still reserved at `now`.
+    pub fn pending(&self, now: Instant) -> usize {
+        self.holds
+            .values()
+            .filter(|(_address, expires)| *expires > now)
+            .count()
+    }
+}
+
+/// Nicks picked by connections that haven't finished registering, so two
+/// of them can't both register the same one.
+#[derive(Debug, Default)]
+pub struct PendingNicks {
+    claimed: HashSet<Nick>,
+}
+
+impl PendingNicks {
+    /// Claims `nick` for a connection currently holding `previous`, which is
+    /// given up in the same step. Fails, keeping `previous`, if another
+    /// connection has already claimed `nick`.
+    pub fn claim(&mut self, nick: &Nick, previous: Option<&Nick>) -> Result<(), ErrorType> {
+        if previous != Some(nick) && self.claimed.contains(nick) {
+            return Err(ErrorType::NickCollision);
+        }
+        if let Some(previous) = previous {
+            self.claimed.remove(previous);
+        }
+        self.claimed.insert(nick.clone());
+        Ok(())
+    }
+
+    pub fn is_claimed(&self, nick: &Nick) -> bool {
+        self.claimed.contains(nick)
+    }
+
+    /// Gives up the claim on `nick`, once it is registered or abandoned.
+    pub fn release(&mut self, nick: &Nick) {
+        self.claimed.remove(nick);
+    }
+}
+
+/// A channel's members, in the order they joined, which is the order NAMES
+/// lists them in. Looking someone up doesn't go through the whole list.
+#[derive(Debug, Default, Clone)]
+pub struct Members {
+    in_order: Vec<Nick>,
+    set: HashSet<Nick>,
+}
+
+impl Members {
+    pub fn contains(&self, nick: &Nick) -> bool {
+        self.set.contains(nick)
+    }
+
+    /// Adds `nick` after everyone else, unless they are already a member.
+    /// Returns whether they were added.
+    fn insert(&mut self, nick: &Nick) -> bool {
+        if !self.set.insert(nick.clone()) {
+            return false;
+        }
+        self.in_order.push(nick.clone());
+        true
+    }
+
+    /// Takes `nick` out. Returns whether they were a member.
+    fn remove(&mut self, nick: &Nick) -> bool {
+        if !self.set.remove(nick) {
+            return false;
+        }
+        self.in_order.retain(|member| member != nick);
+        true
+    }
+
+    /// Puts `new` in `old`'s place, if `old` is a member.
+    fn rename(&mut self, old: &Nick, new: &Nick) {
+        if !self.set.remove(old) {
+            return;
+        }
+        self.set.insert(new.clone());
+        for member in self.in_order.iter_mut().filter(|member| *member == old) {
+            *member = new.clone();
+        }
+    }
+}
+
+impl Deref for Members {
+    type Target = [Nick];
+
+    fn deref(&self) -> &[Nick] {
+        &self.in_order
+    }
+}
+
+impl<const N: usize> PartialEq<[Nick; N]> for Members {
+    fn eq(&self, other: &[Nick; N]) -> bool {
+        self.in_order == other
+    }
+}
+
+impl PartialEq<Vec<Nick>> for Members {
+    fn eq(&self, other: &Vec<Nick>) -> bool {
+        self.in_order == *other
+    }
+}
+
+impl<'a> IntoIterator for &'a Members {
+    type Item = &'a Nick;
+    type IntoIter = std::slice::Iter<'a, Nick>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.in_order.iter()
+    }
+}
+
+/// Everything the server knows about a channel.
+#[derive(Debug, Default)]
+pub struct ChannelState {
+    pub members: Members,
+    /// Members holding channel operator status (+o).
+    pub ops: HashSet<Nick>,
+    /// Members holding voice (+v).
+    pub voiced: HashSet<Nick>,
+    /// Only server operators may join (+O).
+    pub oper_only: bool,
+    /// Kept even when the last member leaves (+P).
+    pub persistent: bool,
+    /// Colors and formatting are removed from messages (+c).
+    pub strip_formatting: bool,
+    /// Messages with colors or formatting are refused (+C).
+    pub block_formatting: bool,
+    /// Only users with secure connections may join (+z).
+    pub secure_only: bool,
+    /// Only users identified to an account may join (+R).
+    pub registered_only: bool,
+    /// Only users identified to an account may speak (+M).
+    pub registered_speak: bool,
+    /// Only members may send messages to the channel (+n).
+    pub no_external: bool,
+    /// Only channel operators may set the topic (+t).
+    pub topic_ops: bool,
+    /// Only voiced members and operators may speak (+m).
+    pub moderated: bool,
+    /// Only invited users may join (+i).
+    pub invite_only: bool,
+    /// Only members can see the channel in LIST, NAMES and WHOIS (+s).
+    pub secret: bool,
+    /// Users must give this key to join (+k).
+    pub key: Option<String>,
+    /// No more than this many members may join (+l).
+    pub limit: Option<usize>,
+    /// Users matching any of these may neither join nor speak (+b).
+    pub bans: Vec<Hostmask>,
+    /// Users invited in, each of whom may join once. Only [`Channels`] may
+    /// change this, so its index stays up to date.
+    invites: HashSet<Nick>,
+    /// Set by members with TOPIC, and shown to everyone who joins.
+    pub topic: Option<Topic>,
+    /// The message each member last sent, for the repetition filter.
+    repeats: HashMap<Nick, RepeatCount>,
+}
+
+/// A run of identical messages from one member.
+#[derive(Debug)]
+struct RepeatCount {
+    text: String,
+    count: u32,
+    since: Instant,
+}
+
+/// What the repetition filter decided to do with a channel message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatVerdict {
+    Deliver,
+    Suppress,
+    Kick,
+}
+
+impl ChannelState {
+    /// The highest status `nick` holds in this channel.
+    pub fn status(&self, nick: &Nick) -> MemberStatus {
+        if self.ops.contains(nick) {
+            MemberStatus::Op
+        } else if self.voiced.contains(nick) {
+            MemberStatus::Voice
+        } else {
+            MemberStatus::Regular
+        }
+    }
+
+    /// The channel's current modes, e.g. `+O`.
+    pub fn mode_string(&self) -> String {
+        let flags = [
+            (self.invite_only, ChannelMode::InviteOnly),
+            (self.secret, ChannelMode::Secret),
+            (self.moderated, ChannelMode::Moderated),
+            (self.no_external, ChannelMode::NoExternal),
+            (self.topic_ops, ChannelMode::TopicOps),
+            (self.strip_formatting, ChannelMode::StripFormatting),
+            (self.block_formatting, ChannelMode::BlockFormatting),
+            (self.registered_speak, ChannelMode::RegisteredSpeak),
+            (self.registered_only, ChannelMode::RegisteredOnly),
+            (self.secure_only, ChannelMode::SecureOnly),
+            (self.oper_only, ChannelMode::OperOnly),
+            (self.persistent, ChannelMode::Persistent),
+            (self.limit.is_some(), ChannelMode::Limit(0)),
+            (self.key.is_some(), ChannelMode::Key(String::new())),
+        ];
+        let mut modes = "+".to_string();
+        modes.extend(
+            flags
+                .iter()
+                .filter(|(set, _mode)| *set)
+                .map(|(_set, mode)| mode.letter()),
+        );
+        modes
+    }
+
+    /// The channel's modes as `nick` may see them, with their arguments.
+    /// Members are shown the key, if there is one, and everyone else only
+    /// that there is a key. Only members see that the channel is secret.
+    pub fn mode_string_for(&self, nick: &Nick) -> String {
+        let mut modes = self.mode_string();
+        if !self.members.contains(nick) {
+            modes.retain(|letter| letter != ChannelMode::Secret.letter());
+        }
+        if let Some(limit) = self.limit {
+            modes.push_str(&format!(" {limit}"));
+        }
+        if let Some(key) = self.key.as_ref().filter(|_| self.members.contains(nick)) {
+            modes.push(' ');
+            modes.push_str(key);
+        }
+        modes
+    }
+
+    /// Passes `old`'s membership, status and repeat count on to `new`.
+    fn rename_member(&mut self, old: &Nick, new: &Nick) {
+        self.rename_invite(old, new);
+        self.members.rename(old, new);
+        if self.ops.remove(old) {
+            self.ops.insert(new.clone());
+        }
+        if self.voiced.remove(old) {
+            self.voiced.insert(new.clone());
+        }
+        if let Some(count) = self.repeats.remove(old) {
+            self.repeats.insert(new.clone(), count);
+        }
+    }
+
+    /// Moves any invitation `old` had over to `new`.
+    fn rename_invite(&mut self, old: &Nick, new: &Nick) {
+        if self.invites.remove(old) {
+            self.invites.insert(new.clone());
+        }
+    }
+
+    /// Whether `nick` has been invited in and has yet to join.
+    pub fn is_invited(&self, nick: &Nick) -> bool {
+        self.invites.contains(nick)
+    }
+
+    /// Whether `nick` may see the channel in LIST, NAMES and WHOIS. Secret
+    /// channels are hidden from everyone but their members.
+    pub fn is_visible_to(&self, nick: &Nick) -> bool {
+        !self.secret || self.members.contains(nick)
+    }
+
+    /// Whether any of a user's `hostmasks` is banned.
+    pub fn is_banned(&self, hostmasks: &[String]) -> bool {
+        self.bans
+            .iter()
+            .any(|ban| hostmasks.iter().any(|hostmask| ban.matches(hostmask)))
+    }
+
+    /// Checks whether `nick`, known by `hostmasks`, may join, giving `key`.
+    /// Only the user's bans, invitation, key, privileges, connection and
+    /// account are considered, so existing members are unaffected by later
+    /// changes.
+    pub fn can_join(
+        &self,
+        nick: &Nick,
+        hostmasks: &[String],
+        key: Option<&str>,
+        is_oper: bool,
+        is_secure: bool,
+        is_identified: bool,
+    ) -> Result<(), ErrorType> {
+        if self.is_banned(hostmasks) {
+            return Err(ErrorType::BannedFromChan);
+        }
+        if self.invite_only && !self.invites.contains(nick) {
+            return Err(ErrorType::InviteOnlyChan);
+        }
+        if self
+            .key
+            .as_deref()
+            .is_some_and(|expected| key != Some(expected))
+        {
+            return Err(ErrorType::BadChannelKey);
+        }
+        if self.limit.is_some_and(|limit| self.members.len() >= limit) {
+            return Err(ErrorType::ChannelIsFull);
+        }
+        if self.oper_only && !is_oper {
+            return Err(ErrorType::OperOnlyChannel);
+        }
+        if self.secure_only && !is_secure {
+            return Err(ErrorType::SecureOnlyChannel);
+        }
+        if self.registered_only && !is_identified {
+            return Err(ErrorType::NeedReggedNick);
+        }
+        Ok(())
+    }
+
+    /// Sets the topic to `text` on `nick`'s behalf at `set_at`, in seconds
+    /// since the Unix epoch. An empty `text` clears it.
+    pub fn set_topic(&mut self, nick: &Nick, text: String, set_at: u64) {
+        self.topic = (!text.is_empty()).then(|| Topic {
+            text,
+            set_by: nick.clone(),
+            set_at,
+        });
+    }
+
+    /// Checks whether `nick`, known by `hostmasks`, may send messages to
+    /// the channel under +b, +n, +m and +M.
+    pub fn can_speak(
+        &self,
+        nick: &Nick,
+        hostmasks: &[String],
+        is_identified: bool,
+    ) -> Result<(), ErrorType> {
+        if self.is_banned(hostmasks) {
+            return Err(ErrorType::CannotSendToChan);
+        }
+        if self.no_external && !self.members.contains(nick) {
+            return Err(ErrorType::CannotSendToChan);
+        }
+        if self.moderated && self.status(nick) < MemberStatus::Voice {
+            return Err(ErrorType::CannotSendToChan);
+        }
+        if self.registered_speak && !is_identified {
+            return Err(ErrorType::NeedReggedNick);
+        }
+        Ok(())
+    }
+
+    /// Checks whether `inviter` may invite `invited`: members may, unless
+    /// +i leaves it to operators, and only people who aren't already in.
+    pub fn can_invite(&self, inviter: &Nick, invited: &Nick) -> Result<(), ErrorType> {
+        if !self.members.contains(inviter) {
+            Err(ErrorType::NotOnChannel)
+        } else if self.invite_only && self.status(inviter) < MemberStatus::Op {
+            Err(ErrorType::ChanOPrivsNeeded)
+        } else if self.members.contains(invited) {
+            Err(ErrorType::UserOnChannel)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks whether `kicker` may remove `kicked` from the channel: only
+    /// operators may, and only people who are in it.
+    pub fn can_kick(&self, kicker: &Nick, kicked: &Nick) -> Result<(), ErrorType> {
+        if !self.members.contains(kicker) {
+            Err(ErrorType::NotOnChannel)
+        } else if self.status(kicker) < MemberStatus::Op {
+            Err(ErrorType::ChanOPrivsNeeded)
+        } else if !self.members.contains(kicked) {
+            Err(ErrorType::UserNotInChannel)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks whether `nick` may set the topic: members may, unless +t
+    /// leaves it to operators.
+    pub fn can_set_topic(&self, nick: &Nick) -> Result<(), ErrorType> {
+        if !self.members.contains(nick) {
+            Err(ErrorType::NotOnChannel)
+        } else if self.topic_ops && self.status(nick) < MemberStatus::Op {
+            Err(ErrorType::ChanOPrivsNeeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks whether `nick` may apply `mode` to this channel.
+    pub fn can_change_mode(
+        &self,
+        nick: &Nick,
+        is_oper: bool,
+        mode: &ChannelMode,
+    ) -> Result<(), ErrorType> {
+        match mode {
+            // Anyone may look at the ban list
+            ChannelMode::Ban(None) => Ok(()),
+            ChannelMode::Op(target) | ChannelMode::Voice(target) => {
+                if self.status(nick) < MemberStatus::Op {
+                    Err(ErrorType::ChanOPrivsNeeded)
+                } else if !self.members.contains(target) {
+                    Err(ErrorType::UserNotInChannel)
+                } else {
+                    Ok(())
+                }
+            }
+            ChannelMode::OperOnly | ChannelMode::Persistent => {
+                if is_oper {
+                    Ok(())
+                } else {
+                    Err(ErrorType::NoPrivileges)
+                }
+            }
+            ChannelMode::StripFormatting
+            | ChannelMode::BlockFormatting
+            | ChannelMode::SecureOnly
+            | ChannelMode::RegisteredOnly
+            | ChannelMode::RegisteredSpeak
+            | ChannelMode::NoExternal
+            | ChannelMode::TopicOps
+            | ChannelMode::Moderated
+            | ChannelMode::InviteOnly
+            | ChannelMode::Secret
+            | ChannelMode::Key(_)
+            | ChannelMode::Limit(_)
+            | ChannelMode::Ban(Some(_)) => {
+                if self.status(nick) < MemberStatus::Op {
+                    Err(ErrorType::ChanOPrivsNeeded)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Applies the channel's +c and +C modes to a message, giving the text
+    /// to relay or an error if it may not be sent.
+    pub fn filter_formatting(&self, text: String) -> Result<String, ErrorType> {
+        if self.block_formatting && has_formatting(&text) {
+            Err(ErrorType::CannotSendToChan)
+        } else if self.strip_formatting {
+            Ok(strip_formatting(&text))
+        } else {
+            Ok(text)
+        }
+    }
+
+    /// The members a message addressed to those holding at least
+    /// `min_status` should reach. Only members may message a subset of
+    /// the channel.
+    pub fn status_recipients(
+        &self,
+        sender: &Nick,
+        min_status: MemberStatus,
+    ) -> Result<Vec<Nick>, ErrorType> {
+        if !self.members.contains(sender) {
+            return Err(ErrorType::CannotSendToChan);
+        }
+        Ok(self
+            .members
+            .iter()
+            .filter(|member| self.status(member) >= min_status)
+            .cloned()
+            .collect())
+    }
+
+    /// Whether the channel should be deleted: it is empty and not +P.
+    pub fn is_disposable(&self) -> bool {
+        self.members.is_empty() && !self.persistent
+    }
+
+    /// Adds `nick` to the channel, using up any invitation they had. The
+    /// first member to join an empty channel becomes its operator. Returns
+    /// false, changing nothing, if they are already a member. Only
+    /// [`Channels`] may do this, so its index stays up to date.
+    fn add_member(&mut self, nick: &Nick) -> bool {
+        if self.members.contains(nick) {
+            return false;
+        }
+        self.invites.remove(nick);
+        if self.members.is_empty() {
+            self.ops.insert(nick.clone());
+        }
+        self.members.insert(nick)
+    }
+
+    /// Removes `nick` from the channel, along with any status it held.
+    /// Only [`Channels`] may do this, so its index stays up to date.
+    fn remove_member(&mut self, nick: &Nick) {
+        self.members.remove(nick);
+        self.ops.remove(nick);
+        self.voiced.remove(nick);
+        self.repeats.remove(nick);
+    }
+
+    /// Records `text` being sent by `nick` at `now` and decides, based on how
+    /// many times in a row it has been sent, whether it should be relayed.
+    pub fn check_repeat(
+        &mut self,
+        nick: &Nick,
+        text: &str,
+        filter: &RepeatFilter,
+        now: Instant,
+    ) -> RepeatVerdict {
+        let text = text.trim().to_lowercase();
+        let repeat = self.repeats.entry(nick.clone()).or_insert(RepeatCount {
+            text: String::new(),
+            count: 0,
+            since: now,
+        });
+        if repeat.text == text && now.duration_since(repeat.since) < filter.window {
+            repeat.count += 1;
+        } else {
+            *repeat = RepeatCount {
+                text,
+                count: 1,
+                since: now,
+            };
+        }
+
+        if repeat.count > filter.kick_after {
+            RepeatVerdict::Kick
+        } else if repeat.count > filter.suppress_after {
+            RepeatVerdict::Suppress
+        } else {
+            RepeatVerdict::Deliver
+        }
+    }
+
+    /// Applies a single mode to the channel.
+    pub fn apply_mode(&mut self, adding: bool, mode: &ChannelMode) {
+        let (set, nick) = match mode {
+            ChannelMode::Op(nick) => (&mut self.ops, nick),
+            ChannelMode::Voice(nick) => (&mut self.voiced, nick),
+            ChannelMode::OperOnly => {
+                self.oper_only = adding;
+                return;
+            }
+            ChannelMode::Persistent => {
+                self.persistent = adding;
+                return;
+            }
+            ChannelMode::StripFormatting => {
+                self.strip_formatting = adding;
+                return;
+            }
+            ChannelMode::BlockFormatting => {
+                self.block_formatting = adding;
+                return;
+            }
+            ChannelMode::SecureOnly => {
+                self.secure_only = adding;
+                return;
+            }
+            ChannelMode::RegisteredOnly => {
+                self.registered_only = adding;
+                return;
+            }
+            ChannelMode::RegisteredSpeak => {
+                self.registered_speak = adding;
+                return;
+            }
+            ChannelMode::NoExternal => {
+                self.no_external = adding;
+                return;
+            }
+            ChannelMode::TopicOps => {
+                self.topic_ops = adding;
+                return;
+            }
+            ChannelMode::Moderated => {
+                self.moderated = adding;
+                return;
+            }
+            ChannelMode::InviteOnly => {
+                self.invite_only = adding;
+                return;
+            }
+            ChannelMode::Secret => {
+                self.secret = adding;
+                return;
+            }
+            // A new key replaces the old one
+            ChannelMode::Key(key) => {
+                self.key = adding.then(|| key.clone());
+                return;
+            }
+            ChannelMode::Limit(limit) => {
+                self.limit = adding.then_some(*limit);
+                return;
+            }
+            ChannelMode::Ban(Some(mask)) => {
+                let known = |ban: &Hostmask| ban.0.eq_ignore_ascii_case(&mask.0);
+                if !adding {
+                    self.bans.retain(|ban| !known(ban));
+                } else if !self.bans.iter().any(known) {
+                    self.bans.push(mask.clone());
+                }
+                return;
+            }
+            ChannelMode::Ban(None) => return,
+        };
+        if adding {
+            set.insert(nick.clone());
+        } else {
+            set.remove(nick);
+        }
+    }
+}
+
+/// Every channel, along with the channels each nick is in or invited to.
+///
+/// Membership and invitations only change through these methods, which
+/// keep both sides in step, so finding a user's channels never means
+/// scanning them all.
+#[derive(Debug, Default)]
+pub struct Channels {
+    channels: HashMap<Channel, ChannelState>,
+    memberships: HashMap<Nick, HashSet<Channel>>,
+    invitations: HashMap<Nick, HashSet<Channel>>,
+}
+
+impl Channels {
+    pub fn get(&self, channel: &Channel) -> Option<&ChannelState> {
+        self.channels.get(channel)
+    }
+
+    pub fn get_mut(&mut self, channel: &Channel) -> Option<&mut ChannelState> {
+        self.channels.get_mut(channel)
+    }
+
+    /// `channel` as it was named when created, which may differ in case
+    /// from how it was asked for. Channels that don't exist keep the name
+    /// given.
+    pub fn canonical(&self, channel: &Channel) -> Channel {
+        self.channels
+            .get_key_value(channel)
+            .map_or_else(|| channel.clone(), |(name, _)| name.clone())
+    }
+
+    /// Every channel, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Channel, &ChannelState)> {
+        self.channels.iter()
+    }
+
+    /// The channels `nick` is in.
+    pub fn channels_of(&self, nick: &Nick) -> Vec<Channel> {
+        self.memberships
+            .get(nick)
+            .map(|channels| channels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// How many channels there are.
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Whether `a` and `b` are in any channel together.
+    pub fn share_channel(&self, a: &Nick, b: &Nick) -> bool {
+        match (self.memberships.get(a), self.memberships.get(b)) {
+            (Some(a), Some(b)) => !a.is_disjoint(b),
+            _ => false,
+        }
+    }
+
+    /// How many channels `nick` is in.
+    pub fn count_of(&self, nick: &Nick) -> usize {
+        self.memberships.get(nick).map_or(0, HashSet::len)
+    }
+
+    /// Adds `nick` to `channel`, creating it if need be. Does nothing if
+    /// they are already in it.
+    pub fn join(&mut self, channel: &Channel, nick: &Nick) {
+        let channel_state = self.channels.entry(channel.clone()).or_default();
+        if channel_state.add_member(nick) {
+            self.memberships
+                .entry(nick.clone())
+                .or_default()
+                .insert(channel.clone());
+            // Joining used up any invitation
+            Self::unindex(&mut self.invitations, nick, channel);
+        }
+    }
+
+    /// Invites `nick` into `channel`, letting them join once. Does nothing
+    /// if the channel doesn't exist.
+    pub fn invite(&mut self, channel: &Channel, nick: &Nick) {
+        if let Some(channel_state) = self.channels.get_mut(channel) {
+            channel_state.invites.insert(nick.clone());
+            self.invitations
+                .entry(nick.clone())
+                .or_default()
+                .insert(channel.clone());
+        }
+    }
+
+    /// Takes `channel` out of `nick`'s entry in `index`, dropping the entry
+    /// once it is empty.
+    fn unindex(index: &mut HashMap<Nick, HashSet<Channel>>, nick: &Nick, channel: &Channel) {
+        if let Some(channels) = index.get_mut(nick) {
+            channels.remove(channel);
+            if channels.is_empty() {
+                index.remove(nick);
+            }
+        }
+    }
+
+    /// The state of `channel`, creating it empty if it doesn't exist.
+    /// Members can only be added through [`Channels::join`].
+    pub fn get_or_create(&mut self, channel: &Channel) -> &mut ChannelState {
+        self.channels.entry(channel.clone()).or_default()
+    }
+
+    /// Removes `nick` from `channel`, deleting the channel if that leaves
+    /// it disposable.
+    pub fn part(&mut self, channel: &Channel, nick: &Nick) {
+        if let Some(channel_state) = self.channels.get_mut(channel) {
+            channel_state.remove_member(nick);
+        }
+        Self::unindex(&mut self.memberships, nick, channel);
+        self.remove_if_disposable(channel);
+    }
+
+    /// Renames `old` to `new` in every channel they are in or invited to.
+    /// Returns everyone who shares a channel with them, `new` included.
+    pub fn rename(&mut self, old: &Nick, new: &Nick) -> Vec<Nick> {
+        if let Some(invited) = self.invitations.remove(old) {
+            for channel in &invited {
+                if let Some(channel_state) = self.channels.get_mut(channel) {
+                    channel_state.rename_invite(old, new);
+                }
+            }
+            self.invitations.insert(new.clone(), invited);
+        }
+        let Some(channels) = self.memberships.remove(old) else {
+            return Vec::new();
+        };
+        let mut neighbours = HashSet::new();
+        for channel in &channels {
+            if let Some(channel_state) = self.channels.get_mut(channel) {
+                channel_state.rename_member(old, new);
+                neighbours.extend(channel_state.members.iter().cloned());
+            }
+        }
+        self.memberships.insert(new.clone(), channels);
+        neighbours.into_iter().collect()
+    }
+
+    /// Removes `nick` from every channel they are in, returning those
+    /// channels. Their invitations go too.
+    pub fn quit(&mut self, nick: &Nick) -> Vec<Channel> {
+        for channel in self.invitations.remove(nick).unwrap_or_default() {
+            if let Some(channel_state) = self.channels.get_mut(&channel) {
+                channel_state.invites.remove(nick);
+            }
+        }
+        let channels = self.channels_of(nick);
+        for channel in &channels {
+            self.part(channel, nick);
+        }
+        channels
+    }
+
+    /// Removes the members of `channel` who aren't `connected`, deleting
+    /// the channel if that leaves it disposable. Returns who was removed.
+    pub fn remove_departed(
+        &mut self,
+        channel: &Channel,
+        connected: impl Fn(&Nick) -> bool,
+    ) -> Vec<Nick> {
+        let departed: Vec<Nick> =
+            self.channels
+                .get(channel)
+                .map_or_else(Vec::new, |channel_state| {
+                    channel_state
+                        .members
+                        .iter()
+                        .filter(|member| !connected(member))
+                        .cloned()
+                        .collect()
+                });
+        for nick in &departed {
+            self.part(channel, nick);
+        }
+        departed
+    }
+
+    /// Deletes `channel`, and any invitations to it, if it is empty and
+    /// not +P.
+    pub fn remove_if_disposable(&mut self, channel: &Channel) {
+        if self
+            .channels
+            .get(channel)
+            .is_some_and(ChannelState::is_disposable)
+        {
+            let channel_state = self.channels.remove(channel).unwrap();
+            for nick in &channel_state.invites {
+                Self::unindex(&mut self.invitations, nick, channel);
+            }
+        }
+    }
+
+    /// Panics unless every member and invited user is indexed under their
+    /// channel and the indexes hold nothing else.
+    #[cfg(test)]
+    pub(crate) fn check_invariants(&self) {
+        let mut expected: HashMap<Nick, HashSet<Channel>> = HashMap::new();
+        let mut invited: HashMap<Nick, HashSet<Channel>> = HashMap::new();
+        for (channel, channel_state) in &self.channels {
+            for nick in &channel_state.invites {
+                invited
+                    .entry(nick.clone())
+                    .or_default()
+                    .insert(channel.clone());
+            }
+            assert!(!channel_state.is_disposable(), "{channel} was kept");
+            for member in &channel_state.members {
+                assert!(
+                    expected
+                        .entry(member.clone())
+                        .or_default()
+                        .insert(channel.clone()),
+                    "{member} is in {channel} twice"
+                );
+            }
+        }
+        assert_eq!(self.memberships, expected);
+        assert_eq!(self.invitations, invited);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nick(name: &str) -> Nick {
+        Nick(name.to_string())
+    }
+
+    fn channel(name: &str) -> Channel {
+        Channel(name.to_string())
+    }
+
+    #[test]
+    fn test_first_member_is_op() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+
+        assert_eq!(channel.status(&nick("alice")), MemberStatus::Op);
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Regular);
+    }
+
+    #[test]
+    fn test_channel_outlives_its_last_op() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+        channel.remove_member(&nick("alice"));
+
+        // Nobody is promoted in alice's place, and bob can't promote themselves
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Regular);
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ChannelMode::Op(nick("bob"))),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(channel.can_speak(&nick("bob"), &[], false), Ok(()));
+    }
+
+    #[test]
+    fn test_can_kick() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+
+        assert_eq!(channel.can_kick(&nick("alice"), &nick("bob")), Ok(()));
+        assert_eq!(
+            channel.can_kick(&nick("bob"), &nick("alice")),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(
+            channel.can_kick(&nick("carol"), &nick("bob")),
+            Err(ErrorType::NotOnChannel)
+        );
+        assert_eq!(
+            channel.can_kick(&nick("alice"), &nick("carol")),
+            Err(ErrorType::UserNotInChannel)
+        );
+    }
+
+    #[test]
+    fn test_invite_only() {
+        let mut channels = Channels::default();
+        let rust = channel("#rust");
+        channels.join(&rust, &nick("alice"));
+        channels.join(&rust, &nick("bob"));
+        let channel_state = channels.get_mut(&rust).unwrap();
+        channel_state.apply_mode(true, &ChannelMode::InviteOnly);
+        assert_eq!(
+            channel_state.can_invite(&nick("bob"), &nick("carol")),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(
+            channel_state.can_invite(&nick("alice"), &nick("bob")),
+            Err(ErrorType::UserOnChannel)
+        );
+        assert_eq!(
+            channel_state.can_join(&nick("carol"), &[], None, false, false, false),
+            Err(ErrorType::InviteOnlyChan)
+        );
+
+        // An invitation lets carol in once
+        channels.invite(&rust, &nick("carol"));
+        let channel_state = channels.get(&rust).unwrap();
+        assert_eq!(
+            channel_state.can_join(&nick("carol"), &[], None, false, false, false),
+            Ok(())
+        );
+        channels.join(&rust, &nick("carol"));
+        channels.part(&rust, &nick("carol"));
+        assert_eq!(
+            channels
+                .get(&rust)
+                .unwrap()
+                .can_join(&nick("carol"), &[], None, false, false, false),
+            Err(ErrorType::InviteOnlyChan)
+        );
+
+        channels.check_invariants();
+    }
+
+    #[test]
+    fn test_invites_go_on_quit() {
+        let mut channels = Channels::default();
+        let (rust, go) = (channel("#rust"), channel("#go"));
+        channels.join(&rust, &nick("alice"));
+        channels.join(&go, &nick("alice"));
+        channels.invite(&rust, &nick("dave"));
+        channels.invite(&go, &nick("dave"));
+        channels.invite(&go, &nick("erin"));
+        channels.check_invariants();
+
+        channels.quit(&nick("dave"));
+        assert!(!channels.get(&rust).unwrap().is_invited(&nick("dave")));
+        assert!(!channels.get(&go).unwrap().is_invited(&nick("dave")));
+        assert!(channels.get(&go).unwrap().is_invited(&nick("erin")));
+        channels.check_invariants();
+
+        // So do invitations to a channel that is deleted
+        channels.part(&go, &nick("alice"));
+        assert!(channels.get(&go).is_none());
+        channels.check_invariants();
+    }
+
+    #[test]
+    fn test_invites_follow_rename() {
+        let mut channels = Channels::default();
+        let rust = channel("#rust");
+        channels.join(&rust, &nick("alice"));
+        channels
+            .get_mut(&rust)
+            .unwrap()
+            .apply_mode(true, &ChannelMode::InviteOnly);
+        channels.invite(&rust, &nick("dave"));
+
+        channels.rename(&nick("dave"), &nick("david"));
+        let channel_state = channels.get(&rust).unwrap();
+        assert!(!channel_state.is_invited(&nick("dave")));
+        assert!(channel_state.is_invited(&nick("david")));
+        channels.check_invariants();
+
+        // The old nick can't be used to get in, and a new user taking it has
+        // no invitation either
+        assert_eq!(
+            channel_state.can_join(&nick("dave"), &[], None, false, false, false),
+            Err(ErrorType::InviteOnlyChan)
+        );
+        channels.quit(&nick("dave"));
+        assert!(channels.get(&rust).unwrap().is_invited(&nick("david")));
+
+        channels.join(&rust, &nick("david"));
+        assert!(!channels.get(&rust).unwrap().is_invited(&nick("david")));
+        channels.check_invariants();
+    }
+
+    #[test]
+    fn test_apply_status_modes() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+
+        channel.apply_mode(true, &ChannelMode::Voice(nick("bob")));
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Voice);
+        channel.apply_mode(true, &ChannelMode::Op(nick("bob")));
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Op);
+        channel.apply_mode(false, &ChannelMode::Op(nick("bob")));
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Voice);
+
+        channel.remove_member(&nick("bob"));
+        assert_eq!(channel.status(&nick("bob")), MemberStatus::Regular);
+    }
+
+    #[test]
+    fn test_status_recipients() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+        channel.add_member(&nick("carol"));
+        channel.apply_mode(true, &ChannelMode::Voice(nick("carol")));
+
+        assert_eq!(
+            channel.status_recipients(&nick("bob"), MemberStatus::Op),
+            Ok(vec![nick("alice")])
+        );
+        assert_eq!(
+            channel.status_recipients(&nick("bob"), MemberStatus::Voice),
+            Ok(vec![nick("alice"), nick("carol")])
+        );
+        assert_eq!(
+            channel.status_recipients(&nick("dave"), MemberStatus::Op),
+            Err(ErrorType::CannotSendToChan)
+        );
+    }
+
+    #[test]
+    fn test_caller_id_blocks_unaccepted() {
+        let mut caller_id = CallerId::default();
+        assert!(caller_id.allows(&nick("bob")));
+
+        caller_id.enabled = true;
+        assert!(!caller_id.allows(&nick("bob")));
+        caller_id.accept(nick("bob")).unwrap();
+        assert!(caller_id.allows(&nick("bob")));
+        assert!(!caller_id.allows(&nick("carol")));
+    }
+
+    #[test]
+    fn test_caller_id_notifies_once() {
+        let mut caller_id = CallerId::default();
+        let now = Instant::now();
+
+        assert!(caller_id.should_notify(now));
+        assert!(!caller_id.should_notify(now + Duration::from_secs(1)));
+        assert!(caller_id.should_notify(now + CALLER_ID_NOTIFY_INTERVAL));
+    }
+
+    #[test]
+    fn test_accept_list_management() {
+        let mut caller_id = CallerId::default();
+        assert_eq!(caller_id.accept(nick("bob")), Ok(()));
+        assert_eq!(caller_id.accept(nick("bob")), Err(ErrorType::AcceptExist));
+        assert_eq!(caller_id.unaccept(&nick("bob")), Ok(()));
+        assert_eq!(caller_id.unaccept(&nick("bob")), Err(ErrorType::AcceptNot));
+
+        for i in 0..ACCEPT_LIST_MAX {
+            caller_id.accept(nick(&format!("user{i}"))).unwrap();
+        }
+        assert_eq!(caller_id.accept(nick("bob")), Err(ErrorType::AcceptFull));
+    }
+
+    #[test]
+    fn test_persistent_channel_survives_emptying() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.apply_mode(true, &ChannelMode::OperOnly);
+        channel.apply_mode(true, &ChannelMode::Persistent);
+        channel.remove_member(&nick("alice"));
+
+        assert!(!channel.is_disposable());
+        assert_eq!(channel.mode_string(), "+OP");
+
+        channel.apply_mode(false, &ChannelMode::Persistent);
+        assert!(channel.is_disposable());
+    }
+
+    #[test]
+    fn test_nick_hold() {
+        let mut holds = NickHolds::default();
+        let owner: IpAddr = "203.0.113.1".parse().unwrap();
+        let other: IpAddr = "203.0.113.2".parse().unwrap();
+        let now = Instant::now();
+        holds.hold(nick("alice"), owner, now + Duration::from_secs(60));
+
+        assert_eq!(
+            holds.check(&nick("alice"), other, now),
+            Err(ErrorType::UnavailResource)
+        );
+        assert_eq!(holds.check(&nick("alice"), owner, now), Ok(()));
+        assert_eq!(holds.check(&nick("bob"), other, now), Ok(()));
+        assert_eq!(
+            holds.check(&nick("alice"), other, now + Duration::from_secs(60)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#rust"), &nick("alice"));
+        channels.join(&channel("#rust"), &nick("bob"));
+        channels.join(&channel("#go"), &nick("alice"));
+        channels.join(&channel("#go"), &nick("carol"));
+        channels.join(&channel("#c"), &nick("dave"));
+
+        let mut neighbours = channels.rename(&nick("alice"), &nick("ann"));
+        neighbours.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(neighbours, [nick("ann"), nick("bob"), nick("carol")]);
+
+        let rust = channels.get(&channel("#rust")).unwrap();
+        assert_eq!(rust.members, [nick("ann"), nick("bob")]);
+        assert_eq!(rust.status(&nick("ann")), MemberStatus::Op);
+        assert_eq!(channels.count_of(&nick("ann")), 2);
+        assert_eq!(channels.count_of(&nick("alice")), 0);
+        channels.check_invariants();
+
+        assert!(channels.rename(&nick("nobody"), &nick("eve")).is_empty());
+    }
+
+    #[test]
+    fn test_pending_nicks() {
+        let mut pending = PendingNicks::default();
+        assert_eq!(pending.claim(&nick("alice"), None), Ok(()));
+        assert_eq!(
+            pending.claim(&nick("alice"), None),
+            Err(ErrorType::NickCollision)
+        );
+        // Claiming your own nick again is fine
+        assert_eq!(pending.claim(&nick("alice"), Some(&nick("alice"))), Ok(()));
+
+        assert_eq!(pending.claim(&nick("bob"), None), Ok(()));
+        assert_eq!(
+            pending.claim(&nick("bob"), Some(&nick("alice"))),
+            Err(ErrorType::NickCollision)
+        );
+        assert!(pending.claimed.contains(&nick("alice")));
+
+        assert_eq!(pending.claim(&nick("carol"), Some(&nick("alice"))), Ok(()));
+        assert_eq!(pending.claim(&nick("alice"), None), Ok(()));
+        pending.release(&nick("bob"));
+        assert!(!pending.is_claimed(&nick("bob")));
+        assert_eq!(pending.claim(&nick("bob"), None), Ok(()));
+    }
+
+    #[test]
+    fn test_repeats_reset_after_window() {
+        let filter = RepeatFilter {
+            suppress_after: 1,
+            kick_after: 5,
+            window: Duration::from_secs(10),
+        };
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        let now = Instant::now();
+
+        let mut check = |text, now| channel.check_repeat(&nick("alice"), text, &filter, now);
+        assert_eq!(check("spam", now), RepeatVerdict::Deliver);
+        assert_eq!(check(" SPAM ", now), RepeatVerdict::Suppress);
+        assert_eq!(check("ham", now), RepeatVerdict::Deliver);
+        assert_eq!(
+            check("ham", now + Duration::from_secs(10)),
+            RepeatVerdict::Deliver
+        );
+    }
+
+    #[test]
+    fn test_formatting_modes() {
+        let mut channel = ChannelState::default();
+        let colored = "\x0304red\x03 text".to_string();
+        assert_eq!(
+            channel.filter_formatting(colored.clone()),
+            Ok(colored.clone())
+        );
+
+        channel.apply_mode(true, &ChannelMode::StripFormatting);
+        assert_eq!(
+            channel.filter_formatting(colored.clone()),
+            Ok("red text".to_string())
+        );
+
+        channel.apply_mode(true, &ChannelMode::BlockFormatting);
+        assert_eq!(
+            channel.filter_formatting(colored),
+            Err(ErrorType::CannotSendToChan)
+        );
+        assert_eq!(
+            channel.filter_formatting("plain".to_string()),
+            Ok("plain".to_string())
+        );
+        assert_eq!(channel.mode_string(), "+cC");
+    }
+
+    #[test]
+    fn test_oper_only_join() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.apply_mode(true, &ChannelMode::OperOnly);
+
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, true, false, false),
+            Ok(())
+        );
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, false, false),
+            Err(ErrorType::OperOnlyChannel)
+        );
+        assert_eq!(channel.mode_string(), "+O");
+    }
+
+    #[test]
+    fn test_secret_visibility() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.apply_mode(true, &ChannelMode::TopicOps);
+        assert!(channel.is_visible_to(&nick("bob")));
+
+        channel.apply_mode(true, &ChannelMode::Secret);
+        assert!(channel.is_visible_to(&nick("alice")));
+        assert!(!channel.is_visible_to(&nick("bob")));
+        assert_eq!(channel.mode_string(), "+st");
+        assert_eq!(channel.mode_string_for(&nick("alice")), "+st");
+        assert_eq!(channel.mode_string_for(&nick("bob")), "+t");
+
+        channel.apply_mode(false, &ChannelMode::Secret);
+        assert!(channel.is_visible_to(&nick("bob")));
+    }
+
+    #[test]
+    fn test_key_join() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ChannelMode::Key("a".to_string())),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        channel.apply_mode(true, &ChannelMode::Key("secret".to_string()));
+
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], Some("secret"), false, false, false),
+            Ok(())
+        );
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], Some("wrong"), false, false, false),
+            Err(ErrorType::BadChannelKey)
+        );
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, false, false),
+            Err(ErrorType::BadChannelKey)
+        );
+        assert_eq!(channel.mode_string(), "+k");
+        assert_eq!(channel.mode_string_for(&nick("alice")), "+k secret");
+        assert_eq!(channel.mode_string_for(&nick("bob")), "+k");
+
+        // Setting a key again replaces it
+        channel.apply_mode(true, &ChannelMode::Key("other".to_string()));
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], Some("secret"), false, false, false),
+            Err(ErrorType::BadChannelKey)
+        );
+        channel.apply_mode(false, &ChannelMode::Key("*".to_string()));
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, false, false),
+            Ok(())
+        );
+        assert_eq!(channel.mode_string_for(&nick("alice")), "+");
+    }
+
+    #[test]
+    fn test_limit_join() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.apply_mode(true, &ChannelMode::Limit(2));
+
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, false, false),
+            Ok(())
+        );
+        channel.add_member(&nick("bob"));
+        assert_eq!(
+            channel.can_join(&nick("carol"), &[], None, true, false, false),
+            Err(ErrorType::ChannelIsFull)
+        );
+        assert_eq!(channel.mode_string(), "+l");
+        assert_eq!(channel.mode_string_for(&nick("carol")), "+l 2");
+
+        channel.apply_mode(true, &ChannelMode::Key("secret".to_string()));
+        assert_eq!(channel.mode_string_for(&nick("alice")), "+lk 2 secret");
+
+        channel.apply_mode(false, &ChannelMode::Limit(0));
+        assert_eq!(
+            channel.can_join(&nick("carol"), &[], Some("secret"), false, false, false),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+        let ban = |mask: &str| ChannelMode::Ban(Some(Hostmask(mask.to_string())));
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ban("*!*@*")),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ChannelMode::Ban(None)),
+            Ok(())
+        );
+
+        channel.apply_mode(true, &ban("bob!*@*"));
+        channel.apply_mode(true, &ban("BOB!*@*"));
+        channel.apply_mode(true, &ban("*!*@10.0.0.*"));
+        assert_eq!(channel.bans.len(), 2);
+
+        // Bans are matched against each of the user's hostmasks
+        let bob = ["bob!robert@cloaked.ip".to_string()];
+        let carol = [
+            "carol!carol@cloaked.ip".to_string(),
+            "carol!carol@10.0.0.7".to_string(),
+        ];
+        assert_eq!(
+            channel.can_speak(&nick("bob"), &bob, false),
+            Err(ErrorType::CannotSendToChan)
+        );
+        assert_eq!(
+            channel.can_join(&nick("carol"), &carol, None, false, false, false),
+            Err(ErrorType::BannedFromChan)
+        );
+        assert_eq!(
+            channel.can_join(
+                &nick("dave"),
+                &["dave!dave@10.1.0.7".to_string()],
+                None,
+                false,
+                false,
+                false
+            ),
+            Ok(())
+        );
+
+        channel.apply_mode(false, &ban("Bob!*@*"));
+        assert_eq!(channel.can_speak(&nick("bob"), &bob, false), Ok(()));
+    }
+
+    #[test]
+    fn test_secure_only_join() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        assert_eq!(
+            channel.can_change_mode(&nick("alice"), false, &ChannelMode::SecureOnly),
+            Ok(())
+        );
+        channel.apply_mode(true, &ChannelMode::SecureOnly);
+
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, true, false),
+            Ok(())
+        );
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, true, false, false),
+            Err(ErrorType::SecureOnlyChannel)
+        );
+        assert_eq!(channel.mode_string(), "+z");
+    }
+
+    #[test]
+    fn test_registered_only_modes() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ChannelMode::RegisteredOnly),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(channel.can_speak(&nick("bob"), &[], false), Ok(()));
+        channel.apply_mode(true, &ChannelMode::RegisteredOnly);
+        channel.apply_mode(true, &ChannelMode::RegisteredSpeak);
+
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, false, false, true),
+            Ok(())
+        );
+        assert_eq!(
+            channel.can_join(&nick("bob"), &[], None, true, true, false),
+            Err(ErrorType::NeedReggedNick)
+        );
+        assert_eq!(channel.can_speak(&nick("bob"), &[], true), Ok(()));
+        assert_eq!(
+            channel.can_speak(&nick("bob"), &[], false),
+            Err(ErrorType::NeedReggedNick)
+        );
+        assert_eq!(channel.mode_string(), "+MR");
+    }
+
+    #[test]
+    fn test_set_topic() {
+        let mut channel = ChannelState::default();
+        channel.set_topic(&nick("alice"), "All things Rust".to_string(), 1697385600);
+        assert_eq!(
+            channel.topic,
+            Some(Topic {
+                text: "All things Rust".to_string(),
+                set_by: nick("alice"),
+                set_at: 1697385600,
+            })
+        );
+        channel.set_topic(&nick("bob"), String::new(), 1697385601);
+        assert_eq!(channel.topic, None);
+    }
+
+    #[test]
+    fn test_speaking_and_topic_modes() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+        channel.add_member(&nick("bob"));
+        channel.add_member(&nick("carol"));
+        channel.apply_mode(true, &ChannelMode::Voice(nick("carol")));
+        assert_eq!(channel.can_speak(&nick("dave"), &[], false), Ok(()));
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), false, &ChannelMode::Moderated),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        channel.apply_mode(true, &ChannelMode::NoExternal);
+        assert_eq!(
+            channel.can_speak(&nick("dave"), &[], false),
+            Err(ErrorType::CannotSendToChan)
+        );
+        assert_eq!(channel.can_speak(&nick("bob"), &[], false), Ok(()));
+
+        channel.apply_mode(true, &ChannelMode::Moderated);
+        assert_eq!(
+            channel.can_speak(&nick("bob"), &[], false),
+            Err(ErrorType::CannotSendToChan)
+        );
+        assert_eq!(channel.can_speak(&nick("carol"), &[], false), Ok(()));
+        assert_eq!(channel.can_speak(&nick("alice"), &[], false), Ok(()));
+
+        assert_eq!(channel.can_set_topic(&nick("bob")), Ok(()));
+        assert_eq!(
+            channel.can_set_topic(&nick("dave")),
+            Err(ErrorType::NotOnChannel)
+        );
+        channel.apply_mode(true, &ChannelMode::TopicOps);
+        assert_eq!(
+            channel.can_set_topic(&nick("bob")),
+            Err(ErrorType::ChanOPrivsNeeded)
+        );
+        assert_eq!(channel.can_set_topic(&nick("alice")), Ok(()));
+        assert_eq!(channel.mode_string(), "+mnt");
+    }
+
+    #[test]
+    fn test_oper_only_mode_needs_oper() {
+        let mut channel = ChannelState::default();
+        channel.add_member(&nick("alice"));
+
+        // Being a channel operator is not enough.
+        assert_eq!(
+            channel.can_change_mode(&nick("alice"), false, &ChannelMode::OperOnly),
+            Err(ErrorType::NoPrivileges)
+        );
+        assert_eq!(
+            channel.can_change_mode(&nick("bob"), true, &ChannelMode::OperOnly),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_channels_index_follows_membership() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#a"), &nick("alice"));
+        channels.join(&channel("#b"), &nick("alice"));
+        channels.join(&channel("#b"), &nick("bob"));
+        channels.join(&channel("#b"), &nick("bob"));
+        channels.check_invariants();
+        assert_eq!(channels.count_of(&nick("alice")), 2);
+        assert_eq!(channels.channels_of(&nick("bob")), vec![channel("#b")]);
+
+        channels.part(&channel("#a"), &nick("alice"));
+        channels.check_invariants();
+        assert!(channels.get(&channel("#a")).is_none());
+
+        let mut left = channels.quit(&nick("alice"));
+        left.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(left, vec![channel("#b")]);
+        channels.check_invariants();
+        assert_eq!(channels.count_of(&nick("alice")), 0);
+        assert_eq!(
+            channels.get(&channel("#b")).unwrap().members,
+            vec![nick("bob")]
+        );
+    }
+
+    #[test]
+    fn test_share_channel() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#rust"), &nick("alice"));
+        channels.join(&channel("#rust"), &nick("bob"));
+        channels.join(&channel("#go"), &nick("carol"));
+        assert!(channels.share_channel(&nick("alice"), &nick("bob")));
+        assert!(!channels.share_channel(&nick("alice"), &nick("carol")));
+        assert!(!channels.share_channel(&nick("alice"), &nick("dave")));
+
+        channels.join(&channel("#go"), &nick("alice"));
+        assert!(channels.share_channel(&nick("carol"), &nick("alice")));
+    }
+
+    #[test]
+    fn test_mixed_case_lookups() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#Rust"), &nick("Alice"));
+        channels.join(&channel("#RUST"), &nick("bob"));
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels.canonical(&channel("#rust")).0, "#Rust");
+        assert_eq!(channels.canonical(&channel("#go")).0, "#go");
+        let rust = channels.get(&channel("#rust")).unwrap();
+        assert_eq!(rust.status(&nick("ALICE")), MemberStatus::Op);
+        assert_eq!(rust.members[0].0, "Alice");
+        assert!(channels.share_channel(&nick("alice"), &nick("BOB")));
+
+        // Changing only the case of a nick shows the new case
+        channels.rename(&nick("Alice"), &nick("alice"));
+        let rust = channels.get(&channel("#rust")).unwrap();
+        assert_eq!(rust.members[0].0, "alice");
+        assert_eq!(rust.status(&nick("alice")), MemberStatus::Op);
+
+        channels.part(&channel("#rUsT"), &nick("Bob"));
+        channels.quit(&nick("ALICE"));
+        assert!(channels.is_empty());
+    }
+
+    #[test]
+    fn test_members() {
+        let mut members = Members::default();
+        assert!(members.insert(&nick("ann")));
+        assert!(members.insert(&nick("bob")));
+        // Joining again, in any case, changes nothing
+        assert!(!members.insert(&nick("ANN")));
+        assert_eq!(members, [nick("ann"), nick("bob")]);
+        assert!(members.contains(&nick("Bob")));
+
+        members.rename(&nick("ann"), &nick("anna"));
+        assert_eq!(members, [nick("anna"), nick("bob")]);
+        assert!(!members.contains(&nick("ann")));
+        assert!(members.remove(&nick("anna")));
+        assert!(!members.remove(&nick("anna")));
+        assert_eq!(members, [nick("bob")]);
+        assert!(members.remove(&nick("bob")));
+        assert!(members.is_empty());
+    }
+
+    #[test]
+    fn test_last_member_leaving_deletes_channel() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#rust"), &nick("ann"));
+        channels.join(&channel("#rust"), &nick("ann"));
+        assert_eq!(
+            channels.get(&channel("#rust")).unwrap().members,
+            [nick("ann")]
+        );
+        channels.part(&channel("#rust"), &nick("ann"));
+        assert!(channels.get(&channel("#rust")).is_none());
+        channels.check_invariants();
+    }
+
+    #[test]
+    fn test_remove_departed() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#rust"), &nick("alice"));
+        channels.join(&channel("#rust"), &nick("bob"));
+        channels.join(&channel("#go"), &nick("bob"));
+        assert_eq!(
+            channels.remove_departed(&channel("#rust"), |member| *member == nick("alice")),
+            [nick("bob")]
+        );
+        assert_eq!(
+            channels.get(&channel("#rust")).unwrap().members,
+            [nick("alice")]
+        );
+        assert_eq!(channels.channels_of(&nick("bob")), [channel("#go")]);
+
+        // Nobody left means no channel
+        assert_eq!(
+            channels.remove_departed(&channel("#go"), |_| false),
+            [nick("bob")]
+        );
+        assert!(channels.get(&channel("#go")).is_none());
+        assert!(channels
+            .remove_departed(&channel("#c"), |_| false)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_channels_index_keeps_persistent_channels() {
+        let mut channels = Channels::default();
+        channels.join(&channel("#a"), &nick("alice"));
+        channels
+            .get_mut(&channel("#a"))
+            .unwrap()
+            .apply_mode(true, &ChannelMode::Persistent);
+        channels.quit(&nick("alice"));
+        channels.check_invariants();
+        assert!(channels.get(&channel("#a")).is_some());
+
+        channels
+            .get_mut(&channel("#a"))
+            .unwrap()
+            .apply_mode(false, &ChannelMode::Persistent);
+        channels.remove_if_disposable(&channel("#a"));
+        channels.check_invariants();
+        assert!(channels.get(&channel("#a")).is_none());
+    }
+
+    /// Random joins, parts and quits, from a fixed seed so failures repeat.
+    #[test]
+    fn test_channels_index_random_sequences() {
+        let nicks = ["alice", "bob", "carol", "dave"].map(nick);
+        let names = ["#a", "#b", "#c"].map(channel);
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = |bound: usize| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+
+        for _ in 0..200 {
+            let mut channels = Channels::default();
+            for _ in 0..50 {
+                let nick = &nicks[next(nicks.len())];
+                let channel = &names[next(names.len())];
+                match next(10) {
+                    0..=4 => channels.join(channel, nick),
+                    5..=7 => channels.part(channel, nick),
+                    8 => {
+                        channels.quit(nick);
+                    }
+                    _ => {
+                        if let Some(channel_state) = channels.get_mut(channel) {
+                            let persistent = !channel_state.persistent;
+                            channel_state.apply_mode(persistent, &ChannelMode::Persistent);
+                        }
+                        channels.remove_if_disposable(channel);
+                    }
+                }
+                channels.check_invariants();
+            }
+        }
+    }
+
+    #[test]
+    fn test_ping_due() {
+        let ((_, conn_write), _client) = crate::connect::in_process();
+        let start = Instant::now();
+        let mut user = UserState::new(
+            nick("tom"),
+            conn_write,
+            "tom".to_string(),
+            "Tom".to_string(),
+            "127.0.0.1".parse().unwrap(),
+            &ServerConfig::default(),
+            start,
+        );
+        let minute = Duration::from_secs(60);
+        let due = |user: &UserState, secs| {
+            user.ping_due(start + Duration::from_secs(secs), minute, minute)
+        };
+
+        assert_eq!(due(&user, 59), PingDue::No);
+        assert_eq!(due(&user, 60), PingDue::Ping);
+
+        // Anything sent puts off the ping
+        user.seen(start + Duration::from_secs(30));
+        assert_eq!(due(&user, 60), PingDue::No);
+        assert_eq!(due(&user, 90), PingDue::Ping);
+
+        user.pinged_at = Some(start + Duration::from_secs(90));
+        assert_eq!(due(&user, 149), PingDue::No);
+        assert_eq!(due(&user, 150), PingDue::TimedOut);
+
+        user.seen(start + Duration::from_secs(100));
+        assert_eq!(due(&user, 150), PingDue::No);
+    }
+}
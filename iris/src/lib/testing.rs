@@ -0,0 +1,203 @@
+//! Helpers for tests that talk to a real server, whether over TCP or over
+//! in-process connections that never touch the network. Every line a
+//! client receives can be checked, in order.
+
+use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+
+use crate::{
+    clock::Clock,
+    config::ServerConfig,
+    connect::{
+        connect, in_process_listener, ConnectionError, ConnectionRead, ConnectionWrite,
+        InProcessConnector,
+    },
+    server::{serve, ServerState},
+    types::SERVER_NAME,
+};
+
+/// How long to wait for a line before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before deciding that nothing else is coming.
+const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// A server that only in-process clients can reach. It stops accepting
+/// clients once dropped.
+pub struct TestServer {
+    state: ServerState,
+    connector: InProcessConnector,
+}
+
+impl TestServer {
+    pub fn new(config: ServerConfig) -> Self {
+        Self::from_state(ServerState::new(config))
+    }
+
+    /// Like [`TestServer::new`], but the server gets the time from `clock`.
+    pub fn with_clock(config: ServerConfig, clock: Arc<dyn Clock>) -> Self {
+        Self::from_state(ServerState::with_clock(config, clock))
+    }
+
+    /// Serves `state`, such as one restored from a snapshot.
+    pub fn from_state(state: ServerState) -> Self {
+        let (listener, connector) = in_process_listener();
+        let serve_state = state.clone();
+        thread::spawn(move || serve(listener, serve_state));
+        Self { state, connector }
+    }
+
+    /// The server's channels and users, for checking what commands did.
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    /// Connects a client. `name` is only used to label failures.
+    pub fn connect(&self, name: &str) -> TestClient {
+        let connection = self
+            .connector
+            .connect()
+            .expect("server should accept connections");
+        TestClient::new(name, &self.state.config.server_name, connection)
+    }
+
+    /// Connects and registers as `nick`, consuming the welcome burst.
+    pub fn register(&self, nick: &str) -> TestClient {
+        self.connect(nick).register_as(nick)
+    }
+}
+
+/// A bare-bones IRC client that fails the test whenever the server says
+/// something it didn't expect.
+pub struct TestClient {
+    name: String,
+    server_name: String,
+    conn_read: ConnectionRead,
+    conn_write: ConnectionWrite,
+}
+
+impl TestClient {
+    fn new(name: &str, server_name: &str, connection: (ConnectionRead, ConnectionWrite)) -> Self {
+        let (conn_read, conn_write) = connection;
+        Self {
+            name: name.to_string(),
+            server_name: server_name.to_string(),
+            conn_read,
+            conn_write,
+        }
+    }
+
+    /// Connects to a server with the default name over TCP. `name` is only
+    /// used to label failures.
+    pub fn connect(address: SocketAddr, name: &str) -> Self {
+        let connection = connect(address).expect("server should accept connections");
+        Self::new(name, SERVER_NAME, connection)
+    }
+
+    /// Connects over TCP and registers as `nick`, consuming the welcome
+    /// burst.
+    pub fn register(address: SocketAddr, nick: &str) -> Self {
+        Self::connect(address, nick).register_as(nick)
+    }
+
+    fn register_as(mut self, nick: &str) -> Self {
+        let server = self.server_name.clone();
+        self.send(&format!("NICK {nick}"));
+        self.send(&format!("USER {nick} 0 * :{nick}"));
+        self.expect(&format!(
+            ":{server} 001 {nick} :Welcome to this server, {nick}!"
+        ));
+        self.expect_prefix(&format!(":{server} 002 {nick} "));
+        self.expect_prefix(&format!(":{server} 003 {nick} "));
+        self.expect_prefix(&format!(":{server} 004 {nick} "));
+        self.expect_prefix(&format!(":{server} 005 {nick} "));
+        self.expect(&format!(":{server} 422 :MOTD File is missing"));
+        self
+    }
+
+    /// Sends `line`, adding the trailing CRLF.
+    pub fn send(&mut self, line: &str) {
+        self.conn_write
+            .write_message(line)
+            .unwrap_or_else(|err| panic!("{} failed to send {line:?}: {err}", self.name));
+    }
+
+    /// Sends `bytes` exactly as given, for lines the codec wouldn't frame.
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.conn_write
+            .write_raw(bytes)
+            .unwrap_or_else(|err| panic!("{} failed to send raw bytes: {err}", self.name));
+    }
+
+    /// Reads the next line without its CRLF, or `None` if the server closed
+    /// the connection. Fails the test if nothing arrives in time.
+    fn read_line(&mut self) -> Option<String> {
+        self.conn_read.set_read_timeout(Some(TIMEOUT));
+        match self.conn_read.read_message() {
+            Ok(line) => Some(line),
+            Err(ConnectionError::Timeout) => panic!("{} timed out waiting for a line", self.name),
+            Err(err) if err.is_fatal() => None,
+            Err(err) => panic!("{} received a bad line: {err}", self.name),
+        }
+    }
+
+    /// Asserts that the next line is exactly `expected`.
+    pub fn expect(&mut self, expected: &str) {
+        match self.read_line() {
+            Some(line) => assert_eq!(line, expected, "unexpected line for {}", self.name),
+            None => panic!("{} was disconnected waiting for {expected:?}", self.name),
+        }
+    }
+
+    /// Asserts that the next lines are exactly `expected`, in order, and
+    /// that nothing follows them.
+    pub fn expect_only(&mut self, expected: &[&str]) {
+        for line in expected {
+            self.expect(line);
+        }
+        self.expect_silence();
+    }
+
+    /// Expects the names list sent on joining `channel`, such as
+    /// `"@tom ann"`.
+    pub fn expect_names(&mut self, channel: &str, names: &str) {
+        let (server, nick) = (&self.server_name, &self.name);
+        let names_line = format!(":{server} 353 {nick} = {channel} :{names}");
+        let end_line = format!(":{server} 366 {nick} {channel} :End of /NAMES list");
+        self.expect(&names_line);
+        self.expect(&end_line);
+    }
+
+    /// Asserts that the next line starts with `prefix`, returning it.
+    pub fn expect_prefix(&mut self, prefix: &str) -> String {
+        match self.read_line() {
+            Some(line) if line.starts_with(prefix) => line,
+            Some(line) => panic!("{} expected {prefix:?}..., got {line:?}", self.name),
+            None => panic!("{} was disconnected waiting for {prefix:?}", self.name),
+        }
+    }
+
+    /// Asserts that nothing arrives for a short while.
+    pub fn expect_silence(&mut self) {
+        self.conn_read.set_read_timeout(Some(QUIET_PERIOD));
+        match self.conn_read.read_message() {
+            Err(ConnectionError::Timeout) => {}
+            Ok(line) => panic!("{} expected silence, got {line:?}", self.name),
+            Err(err) => panic!("{} expected silence, got {err}", self.name),
+        }
+    }
+
+    /// Asserts that the server closes the connection.
+    pub fn expect_closed(&mut self) {
+        if let Some(line) = self.read_line() {
+            panic!(
+                "{} expected the connection to close, got {line:?}",
+                self.name
+            );
+        }
+    }
+
+    /// Drops the connection without saying goodbye.
+    pub fn disconnect(self) {
+        self.conn_write.shutdown();
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    client,
+    connect::{self, ConnectionRead, ConnectionWrite},
+    redact::redact,
+};
+
+/// Where to record transcripts, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptConfig {
+    /// Every connection gets its own file in here.
+    pub dir: PathBuf,
+    /// Whether to leave message text and passwords out of transcripts. They
+    /// are left out regardless unless
+    /// [`crate::config::ServerConfig::log_message_contents`] is set.
+    pub redact: bool,
+}
+
+/// Which way a line in a transcript went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client.
+    Inbound,
+    /// Sent by the server.
+    Outbound,
+}
+
+impl Direction {
+    fn tag(self) -> &'static str {
+        match self {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        }
+    }
+}
+
+/// One line of a transcript.
+/// For example: `1697385600123 in PRIVMSG #rust :hello`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u128,
+    pub direction: Direction,
+    pub line: String,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            fmt,
+            "{} {} {}",
+            self.timestamp,
+            self.direction.tag(),
+            self.line
+        )
+    }
+}
+
+impl TryFrom<&str> for Entry {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parts = value.splitn(3, ' ');
+        let timestamp = parts
+            .next()
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or_else(|| format!("bad timestamp in {value:?}"))?;
+        let direction = match parts.next() {
+            Some("in") => Direction::Inbound,
+            Some("out") => Direction::Outbound,
+            _ => return Err(format!("bad direction in {value:?}")),
+        };
+        Ok(Entry {
+            timestamp,
+            direction,
+            line: parts.next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+/// Reads a transcript file back into its entries.
+pub fn read_transcript(path: &Path) -> io::Result<Vec<Entry>> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            Entry::try_from(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
+enum Event {
+    Open(u64, File),
+    Line(u64, Entry),
+    Close(u64),
+}
+
+/// Hands connections their transcripts, which a background thread writes
+/// out so that recording never holds up the connection itself.
+pub struct TranscriptRecorder {
+    config: TranscriptConfig,
+    events: Sender<Event>,
+    next_id: AtomicU64,
+}
+
+impl TranscriptRecorder {
+    /// Creates the transcript directory and starts the writer thread.
+    pub fn launch(config: TranscriptConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let (events, receiver) = mpsc::channel();
+        let redact_lines = config.redact;
+        thread::spawn(move || {
+            let mut files = std::collections::HashMap::new();
+            for event in receiver {
+                match event {
+                    Event::Open(id, file) => {
+                        files.insert(id, BufWriter::new(file));
+                    }
+                    Event::Line(id, mut entry) => {
+                        if redact_lines {
+                            entry.line = redact(&entry.line);
+                        }
+                        if let Some(file) = files.get_mut(&id) {
+                            let _ = writeln!(file, "{entry}").and_then(|()| file.flush());
+                        }
+                    }
+                    Event::Close(id) => {
+                        files.remove(&id);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            config,
+            events,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts a transcript for the connection from `address`. Recording is
+    /// skipped, with a warning, if its file can't be created.
+    pub fn open(&self, address: SocketAddr) -> Option<Transcript> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}-{}-{}.txt", now_millis(), address.ip(), address.port());
+        let path = self.config.dir.join(name);
+        match File::create(&path) {
+            Ok(file) => {
+                let _ = self.events.send(Event::Open(id, file));
+                Some(Transcript {
+                    inner: Arc::new(TranscriptHandle {
+                        id,
+                        events: self.events.clone(),
+                    }),
+                })
+            }
+            Err(err) => {
+                log::warn!("Unable to create transcript {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+struct TranscriptHandle {
+    id: u64,
+    events: Sender<Event>,
+}
+
+impl Drop for TranscriptHandle {
+    fn drop(&mut self) {
+        let _ = self.events.send(Event::Close(self.id));
+    }
+}
+
+/// One connection's transcript, shared by its read and write halves. The
+/// file is closed once both halves are gone.
+#[derive(Clone)]
+pub struct Transcript {
+    inner: Arc<TranscriptHandle>,
+}
+
+impl Transcript {
+    /// Records `message`, which may hold several CRLF-terminated lines.
+    pub fn record(&self, direction: Direction, message: &str) {
+        let timestamp = now_millis();
+        for line in message.split("\r\n").filter(|line| !line.is_empty()) {
+            let entry = Entry {
+                timestamp,
+                direction,
+                line: line.to_string(),
+            };
+            let _ = self.inner.events.send(Event::Line(self.inner.id, entry));
+        }
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// A line the server sent differently when a transcript was replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Which of the replayed transcripts it happened in.
+    pub transcript: usize,
+    /// What the transcript recorded, if anything.
+    pub expected: Option<String>,
+    /// What the server sent this time, if anything.
+    pub actual: Option<String>,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let describe = |line: &Option<String>| match line {
+            Some(line) => format!("{line:?}"),
+            None => "nothing".to_string(),
+        };
+        write!(
+            fmt,
+            "expected {}, got {}",
+            describe(&self.expected),
+            describe(&self.actual)
+        )
+    }
+}
+
+/// How long to wait for each line the server is expected to send.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to let the server catch up before switching which connection
+/// is sending, so it handles lines from different clients in order.
+const REPLAY_SETTLE: Duration = Duration::from_millis(20);
+
+/// How long to wait for unexpected lines once a replay is done.
+const REPLAY_QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// The part of `line` a replay compares. RPL_CREATED says when the server
+/// started, which is different every run, so only its start is kept.
+fn comparable(line: &str) -> &str {
+    if client::ServerLine::from(line).command != "003" {
+        return line;
+    }
+    line.split_once(" :").map_or(line, |(start, _)| start)
+}
+
+/// Replays transcripts against the server at `address`, which should be
+/// freshly started so its state matches the recording.
+///
+/// Each transcript gets its own connection, and their entries are played
+/// back together in timestamp order: inbound lines are sent, and outbound
+/// lines are read and compared with what the server actually sends, apart
+/// from when it started.
+pub fn replay(address: SocketAddr, transcripts: &[Vec<Entry>]) -> io::Result<Vec<Divergence>> {
+    let mut events: Vec<(usize, &Entry)> = transcripts
+        .iter()
+        .enumerate()
+        .flat_map(|(index, entries)| entries.iter().map(move |entry| (index, entry)))
+        .collect();
+    // Lines recorded in the same millisecond send before they are read
+    events.sort_by_key(|(_, entry)| (entry.timestamp, entry.direction == Direction::Outbound));
+
+    let mut connections: Vec<Option<(ConnectionRead, ConnectionWrite)>> =
+        transcripts.iter().map(|_| None).collect();
+    let mut divergences = Vec::new();
+    let mut last_sender = None;
+    for (index, entry) in events {
+        let (conn_read, conn_write) = match &mut connections[index] {
+            Some(connection) => connection,
+            connection => {
+                let (conn_read, conn_write) = connect::connect(address)?;
+                conn_read.set_read_timeout(Some(REPLAY_TIMEOUT));
+                connection.insert((conn_read, conn_write))
+            }
+        };
+        match entry.direction {
+            Direction::Inbound => {
+                if last_sender.is_some_and(|last_sender| last_sender != index) {
+                    thread::sleep(REPLAY_SETTLE);
+                }
+                last_sender = Some(index);
+                // The server may have hung up on us, which the outbound
+                // lines will show
+                let _ = client::send(conn_write, &entry.line);
+            }
+            Direction::Outbound => {
+                let actual = conn_read.read_message().ok();
+                if actual.as_deref().map(comparable) != Some(comparable(&entry.line)) {
+                    divergences.push(Divergence {
+                        transcript: index,
+                        expected: Some(entry.line.clone()),
+                        actual,
+                    });
+                }
+            }
+        }
+    }
+
+    // Anything still arriving wasn't in the transcript
+    for (index, connection) in connections.iter_mut().enumerate() {
+        let Some((conn_read, _)) = connection else {
+            continue;
+        };
+        conn_read.set_read_timeout(Some(REPLAY_QUIET_PERIOD));
+        while let Ok(actual) = conn_read.read_message() {
+            divergences.push(Divergence {
+                transcript: index,
+                expected: None,
+                actual: Some(actual),
+            });
+        }
+    }
+
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparable() {
+        assert_eq!(
+            comparable(
+                ":iris-server 003 tom :This server was created Thu, 15 Oct 2026 12:00:00 +0000"
+            ),
+            ":iris-server 003 tom"
+        );
+        assert_eq!(
+            comparable(":iris-server 001 tom :Welcome to this server, tom!"),
+            ":iris-server 001 tom :Welcome to this server, tom!"
+        );
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = Entry {
+            timestamp: 1697385600123,
+            direction: Direction::Inbound,
+            line: "PRIVMSG #rust :hello there".to_string(),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "1697385600123 in PRIVMSG #rust :hello there"
+        );
+        assert_eq!(Entry::try_from(entry.to_string().as_str()), Ok(entry));
+        assert!(Entry::try_from("1697385600123 sideways PING x").is_err());
+    }
+}
@@ -1,61 +1,259 @@
+use crate::formatting::{truncate, wrap_words};
+
+/// The longest line allowed on the wire, including its CRLF. Longer lines
+/// are turned away when read, and replies are split or cut short to fit.
+pub const MAX_LINE_LEN: usize = 512;
+
 /// All relevant IRC errors are listed here.
 /// See the assignment documentation for more information.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum ErrorType {
     NoNickNameGiven = 431,
     ErroneousNickname = 432,
+    AccountExists = 433,
     NickCollision = 436,
+    UnavailResource = 437,
     NoRecipient = 411,
     NoTextToSend = 412,
     NoOrigin = 409,
     UnknownCommand = 421,
+    UserOnChannel = 443,
     NeedMoreParams = 461,
+    AlreadyRegistered = 462,
+    PasswdMismatch = 464,
     NoSuchNick = 401,
     NoSuchChannel = 403,
+    AcceptFull = 456,
+    AcceptExist = 457,
+    AcceptNot = 458,
+    UModeUnknownFlag = 501,
+    UsersDontMatch = 502,
+    TargUModeG = 716,
+    CannotSendToChan = 404,
+    UserNotInChannel = 441,
+    NotOnChannel = 442,
+    UnknownMode = 472,
+    NoPrivileges = 481,
+    ChanOPrivsNeeded = 482,
+    OperOnlyChannel = 520,
+    SecureOnlyChannel = 489,
+    NeedReggedNick = 477,
+    NoNonReg = 486,
+    TooManyChannels = 405,
+    InviteOnlyChan = 473,
+    BadChannelKey = 475,
+    ChannelIsFull = 471,
+    BannedFromChan = 474,
+    InvalidModeParam = 696,
+    NoMotd = 422,
+    NotRegistered = 451,
+    InputTooLong = 417,
+    BadChanMask = 476,
 }
 
-/// This is the name of your server, all messages originating from
-/// the server should be listed as from this name.
+/// The name the server goes by unless configured otherwise. All messages
+/// originating from the server are listed as from its name.
 pub const SERVER_NAME: &str = "iris-server";
 
-impl std::fmt::Display for ErrorType {
+/// A reply or error whose source is the server, so formatting it needs to
+/// know what the server is called.
+pub trait ServerMessage {
+    fn fmt_as(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+        server_name: &str,
+    ) -> Result<(), std::fmt::Error>;
+
+    /// Displays this as sent by the server called `server_name`.
+    fn sent_by<'a>(&'a self, server_name: &'a str) -> SentBy<'a, Self> {
+        SentBy {
+            message: self,
+            server_name,
+        }
+    }
+}
+
+/// See [`ServerMessage::sent_by`].
+pub struct SentBy<'a, T: ?Sized> {
+    message: &'a T,
+    server_name: &'a str,
+}
+
+impl<T: ServerMessage + ?Sized> std::fmt::Display for SentBy<'_, T> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.message.fmt_as(fmt, self.server_name)
+    }
+}
+
+impl ServerMessage for ErrorType {
+    fn fmt_as(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+        server_name: &str,
+    ) -> Result<(), std::fmt::Error> {
         match *self {
             ErrorType::NoNickNameGiven => {
-                write!(fmt, ":{SERVER_NAME} 431 :No nickname given.")
+                write!(fmt, ":{server_name} 431 :No nickname given.")
             }
             ErrorType::ErroneousNickname => {
                 // Typo is same as in RFC1459
-                write!(fmt, ":{SERVER_NAME} 432 :Erroneus nickname")
+                write!(fmt, ":{server_name} 432 :Erroneus nickname")
             }
             ErrorType::NoRecipient => {
-                write!(fmt, ":{SERVER_NAME} 411 :No recipient given")
+                write!(fmt, ":{server_name} 411 :No recipient given")
             }
             ErrorType::NoTextToSend => {
-                write!(fmt, ":{SERVER_NAME} 412 :No text to send")
+                write!(fmt, ":{server_name} 412 :No text to send")
             }
             ErrorType::NoOrigin => {
-                write!(fmt, ":{SERVER_NAME} 409 :No origin specified")
+                write!(fmt, ":{server_name} 409 :No origin specified")
             }
             ErrorType::UnknownCommand => {
-                write!(fmt, ":{SERVER_NAME} 421 :Unknown command")
+                write!(fmt, ":{server_name} 421 :Unknown command")
             }
             ErrorType::NeedMoreParams => {
-                write!(fmt, ":{SERVER_NAME} 461 :Not enough parameters")
+                write!(fmt, ":{server_name} 461 :Not enough parameters")
             }
             ErrorType::NoSuchNick => {
-                write!(fmt, ":{SERVER_NAME} 401 :No such nick/channel")
+                write!(fmt, ":{server_name} 401 :No such nick/channel")
             }
             ErrorType::NoSuchChannel => {
-                write!(fmt, ":{SERVER_NAME} 403 :No such channel")
+                write!(fmt, ":{server_name} 403 :No such channel")
+            }
+            ErrorType::AccountExists => {
+                write!(fmt, ":{server_name} 433 :Nickname is already registered")
+            }
+            ErrorType::AlreadyRegistered => {
+                write!(fmt, ":{server_name} 462 :You may not reregister")
+            }
+            ErrorType::PasswdMismatch => {
+                write!(fmt, ":{server_name} 464 :Password incorrect")
             }
             ErrorType::NickCollision => {
-                write!(fmt, ":{SERVER_NAME} 436 :Nickname collision")
+                write!(fmt, ":{server_name} 436 :Nickname collision")
+            }
+            ErrorType::AcceptFull => {
+                write!(fmt, ":{server_name} 456 :Accept list is full")
+            }
+            ErrorType::AcceptExist => {
+                write!(fmt, ":{server_name} 457 :is already on your accept list")
+            }
+            ErrorType::AcceptNot => {
+                write!(fmt, ":{server_name} 458 :is not on your accept list")
+            }
+            ErrorType::UModeUnknownFlag => {
+                write!(fmt, ":{server_name} 501 :Unknown MODE flag")
+            }
+            ErrorType::UsersDontMatch => {
+                write!(
+                    fmt,
+                    ":{server_name} 502 :Cannot change mode for other users"
+                )
+            }
+            ErrorType::TargUModeG => {
+                write!(
+                    fmt,
+                    ":{server_name} 716 :is in +g mode (server-side ignore)"
+                )
+            }
+            ErrorType::CannotSendToChan => {
+                write!(fmt, ":{server_name} 404 :Cannot send to channel")
+            }
+            ErrorType::UnavailResource => {
+                write!(
+                    fmt,
+                    ":{server_name} 437 :Nickname is temporarily unavailable"
+                )
+            }
+            ErrorType::UserNotInChannel => {
+                write!(fmt, ":{server_name} 441 :They aren't on that channel")
+            }
+            ErrorType::UserOnChannel => {
+                write!(fmt, ":{server_name} 443 :is already on channel")
+            }
+            ErrorType::NotOnChannel => {
+                write!(fmt, ":{server_name} 442 :You're not on that channel")
+            }
+            ErrorType::UnknownMode => {
+                write!(fmt, ":{server_name} 472 :is unknown mode char to me")
+            }
+            ErrorType::NoPrivileges => {
+                write!(
+                    fmt,
+                    ":{server_name} 481 :Permission Denied- You're not an IRC operator"
+                )
+            }
+            ErrorType::ChanOPrivsNeeded => {
+                write!(fmt, ":{server_name} 482 :You're not channel operator")
+            }
+            ErrorType::OperOnlyChannel => {
+                write!(
+                    fmt,
+                    ":{server_name} 520 :Cannot join channel (you must be an IRC operator)"
+                )
+            }
+            ErrorType::SecureOnlyChannel => {
+                write!(
+                    fmt,
+                    ":{server_name} 489 :Cannot join channel (you must be connected via TLS)"
+                )
+            }
+            ErrorType::NeedReggedNick => {
+                write!(
+                    fmt,
+                    ":{server_name} 477 :You need to identify to an account first"
+                )
+            }
+            ErrorType::NoNonReg => {
+                write!(
+                    fmt,
+                    ":{server_name} 486 :You must identify to an account to message that user"
+                )
+            }
+            ErrorType::NoMotd => {
+                write!(fmt, ":{server_name} 422 :MOTD File is missing")
+            }
+            ErrorType::NotRegistered => {
+                write!(fmt, ":{server_name} 451 :You have not registered")
+            }
+            ErrorType::InviteOnlyChan => {
+                write!(fmt, ":{server_name} 473 :Cannot join channel (+i)")
+            }
+            ErrorType::BadChannelKey => {
+                write!(fmt, ":{server_name} 475 :Cannot join channel (+k)")
+            }
+            ErrorType::ChannelIsFull => {
+                write!(fmt, ":{server_name} 471 :Cannot join channel (+l)")
+            }
+            ErrorType::BannedFromChan => {
+                write!(fmt, ":{server_name} 474 :Cannot join channel (+b)")
+            }
+            ErrorType::InvalidModeParam => {
+                write!(fmt, ":{server_name} 696 :Invalid mode parameter")
+            }
+            ErrorType::TooManyChannels => {
+                write!(
+                    fmt,
+                    ":{server_name} 405 :Cannot create channel (channel creation limit reached)"
+                )
+            }
+            ErrorType::InputTooLong => {
+                write!(fmt, ":{server_name} 417 :Input line was too long")
+            }
+            ErrorType::BadChanMask => {
+                write!(fmt, ":{server_name} 476 :Bad Channel Mask")
             }
         }
     }
 }
 
+impl std::fmt::Display for ErrorType {
+    /// Formats as sent under the default [`SERVER_NAME`].
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.fmt_as(fmt, SERVER_NAME)
+    }
+}
+
 /// Given an IRC command, this will split it up into component parts.
 /// Particularly, the prefix (optionally), then all space-separated args,
 /// then (optionally) the final argument.
@@ -77,20 +275,131 @@ fn split_command(cmd: &str) -> Vec<&str> {
     }
 }
 
+/// Characters that would let a field end the line it is relayed in, and
+/// forge lines of its own on other clients' connections.
+const UNSAFE_CHARS: [char; 3] = ['\r', '\n', '\0'];
+
+/// Strips [`UNSAFE_CHARS`] from a field. Every field of a message goes
+/// through this as it is parsed, so nothing the server relays can contain them.
+pub fn sanitize(field: &str) -> String {
+    field.replace(UNSAFE_CHARS, "")
+}
+
+/// Other names for commands, for people typing them by hand. Aliases from
+/// [`crate::config::ServerConfig::aliases`] are checked first.
+pub const BUILTIN_ALIASES: &[(&str, &str)] = &[("MSG", "PRIVMSG"), ("LEAVE", "PART")];
+
+/// The command `verb` names, with case ignored and aliases resolved.
+fn canonical_command(verb: &str, aliases: &std::collections::HashMap<String, String>) -> String {
+    let verb = verb.to_ascii_uppercase();
+    aliases
+        .get(&verb)
+        .map(String::as_str)
+        .or_else(|| {
+            BUILTIN_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == verb)
+                .map(|(_, command)| *command)
+        })
+        .map_or(verb.clone(), str::to_string)
+}
+
+/// Whether `verb` could name a command: letters, or a three-digit numeric.
+fn is_command_word(verb: &str) -> bool {
+    !verb.is_empty() && verb.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Whether `line` is a NOTICE, checked without parsing it, so that a NOTICE
+/// that can't be parsed doesn't get an error back either.
+pub fn is_notice(line: &str, aliases: &std::collections::HashMap<String, String>) -> bool {
+    split_command(line)
+        .first()
+        .is_some_and(|verb| canonical_command(verb, aliases) == "NOTICE")
+}
+
+/// Tokens advertised to clients in RPL_ISUPPORT that don't depend on the
+/// server's configuration. See [`crate::config::ServerConfig::isupport_tokens`].
+pub const ISUPPORT_TOKENS: &[&str] = &[
+    "CALLERID=g",
+    "CASEMAPPING=ascii",
+    "ELIST=U",
+    "PREFIX=(ov)@+",
+    "STATUSMSG=@+",
+];
+
+/// The longest nick allowed.
+pub const MAX_NICK_LEN: usize = 16;
+
+/// The characters besides letters that a nick may start with. Digits and
+/// `-` may follow, but can't lead.
+pub const NICK_SPECIALS: &str = "[]\\`_^{|}";
+
+/// What every channel name starts with.
+pub const CHANNEL_PREFIX: char = '#';
+
+/// The longest channel name allowed, prefix included.
+pub const MAX_CHANNEL_LEN: usize = 50;
+
+/// The user modes clients may set, as listed in RPL_MYINFO.
+pub const USER_MODES: &str = "giRw";
+
+/// Every channel mode, as listed in RPL_MYINFO.
+pub const CHANNEL_MODES: &str = "CMOPRbciklmnostvz";
+
+/// The channel modes that take an argument, as listed in RPL_MYINFO.
+pub const CHANNEL_ARGUMENT_MODES: &str = "bklov";
+
+/// The privilege a member holds within a channel, from lowest to highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemberStatus {
+    Regular,
+    Voice,
+    Op,
+}
+
+impl MemberStatus {
+    /// The status a target prefix such as the `@` in `@#channel` refers to.
+    pub fn from_prefix(prefix: char) -> Option<Self> {
+        match prefix {
+            '@' => Some(MemberStatus::Op),
+            '+' => Some(MemberStatus::Voice),
+            _ => None,
+        }
+    }
+
+    /// The prefix used for this status, if it has one.
+    pub fn prefix(&self) -> Option<char> {
+        match self {
+            MemberStatus::Regular => None,
+            MemberStatus::Voice => Some('+'),
+            MemberStatus::Op => Some('@'),
+        }
+    }
+}
+
 /// A person or channel to whom a command is addressed.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Target {
     Channel(Channel),
+    /// Only the members of a channel with at least the given status,
+    /// e.g. `@#channel`.
+    ChannelStatus(MemberStatus, Channel),
     User(Nick),
 }
 
-impl From<String> for Target {
-    fn from(value: String) -> Self {
-        if value.starts_with('#') {
-            Target::Channel(Channel(value))
-        } else {
-            Target::User(Nick(value))
-        }
+impl TryFrom<String> for Target {
+    type Error = ErrorType;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut chars = value.chars();
+        let status = chars.next().and_then(MemberStatus::from_prefix);
+        Ok(match status {
+            Some(status) if chars.as_str().starts_with(CHANNEL_PREFIX) => {
+                Target::ChannelStatus(status, Channel::try_from(chars.as_str().to_string())?)
+            }
+            _ if value.starts_with(CHANNEL_PREFIX) => Target::Channel(Channel::try_from(value)?),
+            _ => Target::User(Nick(value)),
+        })
     }
 }
 
@@ -98,64 +407,217 @@ impl std::fmt::Display for Target {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             Target::Channel(s) => write!(fmt, "{s}"),
+            Target::ChannelStatus(status, s) => match status.prefix() {
+                Some(prefix) => write!(fmt, "{prefix}{s}"),
+                None => write!(fmt, "{s}"),
+            },
             Target::User(s) => write!(fmt, "{s}"),
         }
     }
 }
 
-/// A nickname.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Hashes `value` as its ASCII lowercase, without allocating, so that
+/// names differing only in case hash alike.
+fn hash_ascii_lowercase<H: std::hash::Hasher>(value: &str, state: &mut H) {
+    for byte in value.bytes() {
+        state.write_u8(byte.to_ascii_lowercase());
+    }
+    // Ends the name, as hashing a `str` does
+    state.write_u8(0xff);
+}
+
+/// A nickname. Anything a user picks goes through [`Nick::parse`].
+/// Nicks compare and hash ignoring ASCII case, but show as they were given.
+#[derive(Debug, Clone)]
 pub struct Nick(pub String);
 
-impl TryFrom<String> for Nick {
-    type Error = ErrorType;
+impl PartialEq for Nick {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        let value = value;
-        if (1..10).contains(&value.len())
-            && value.is_ascii()
-            && value.chars().next().unwrap_or('!').is_alphabetic()
-            && value.chars().all(char::is_alphanumeric)
+impl Eq for Nick {}
+
+impl std::hash::Hash for Nick {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        hash_ascii_lowercase(&self.0, state);
+    }
+}
+
+impl Nick {
+    /// `value` as a nick: a letter or one of [`NICK_SPECIALS`], then any
+    /// of those, digits or `-`, up to [`MAX_NICK_LEN`] in all.
+    pub fn parse(value: &str) -> Result<Nick, ErrorType> {
+        let leads = |c: char| c.is_ascii_alphabetic() || NICK_SPECIALS.contains(c);
+        let mut chars = value.chars();
+        if value.len() <= MAX_NICK_LEN
+            && chars.next().is_some_and(leads)
+            && chars.all(|c| leads(c) || c.is_ascii_digit() || c == '-')
         {
-            Ok(Nick(value))
+            Ok(Nick(value.to_string()))
         } else {
             Err(ErrorType::ErroneousNickname)
         }
     }
 }
 
+impl TryFrom<String> for Nick {
+    type Error = ErrorType;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Nick::parse(&value)
+    }
+}
+
 impl std::fmt::Display for Nick {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         self.0.fmt(fmt)
     }
 }
 
-/// An IRC channel.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// An IRC channel. Like [`Nick`]s, channel names ignore ASCII case.
+#[derive(Debug, Clone)]
 pub struct Channel(pub String);
 
-impl TryFrom<String> for Channel {
-    type Error = ErrorType;
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        if (1..200).contains(&value.len())
-            && value.chars().next().unwrap_or('!') == '#'
+impl Eq for Channel {}
+
+impl std::hash::Hash for Channel {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        hash_ascii_lowercase(&self.0, state);
+    }
+}
+
+impl Channel {
+    /// `value` as the name of a channel that may be created: the
+    /// [`CHANNEL_PREFIX`], then no spaces, commas or control characters,
+    /// up to [`MAX_CHANNEL_LEN`] in all.
+    pub fn parse(value: &str) -> Result<Channel, ErrorType> {
+        if value.len() <= MAX_CHANNEL_LEN
+            && value.starts_with(CHANNEL_PREFIX)
             && value.is_ascii()
-            && value[1..].chars().all(char::is_alphanumeric)
+            && !value.contains(|c: char| c == ' ' || c == ',' || c.is_ascii_control())
         {
-            Ok(Channel(value))
+            Ok(Channel(value.to_string()))
         } else {
-            Err(ErrorType::NoSuchChannel)
+            Err(ErrorType::BadChanMask)
         }
     }
 }
 
+/// For channels that should already exist, so a bad name is answered as
+/// one that doesn't.
+impl TryFrom<String> for Channel {
+    type Error = ErrorType;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Channel::parse(&value).map_err(|_| ErrorType::NoSuchChannel)
+    }
+}
+
 impl std::fmt::Display for Channel {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         self.0.fmt(fmt)
     }
 }
 
+/// A `nick!user@host` pattern, such as a ban. `*` stands for any run of
+/// characters and `?` for any one, and case is ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hostmask(pub String);
+
+impl Hostmask {
+    /// Whether `hostmask`, a user's `nick!user@host`, fits the pattern.
+    pub fn matches(&self, hostmask: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let text: Vec<char> = hostmask.chars().collect();
+        let (mut p, mut t) = (0, 0);
+        // Where the last `*` was, and how much of the text it has taken
+        let mut star = None;
+        while t < text.len() {
+            if p < pattern.len() && (pattern[p] == '?' || pattern[p].eq_ignore_ascii_case(&text[t]))
+            {
+                p += 1;
+                t += 1;
+            } else if p < pattern.len() && pattern[p] == '*' {
+                star = Some((p, t));
+                p += 1;
+            } else if let Some((star_p, star_t)) = star {
+                // Let the `*` take one more character and try again
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl From<String> for Hostmask {
+    /// Fills in whatever parts of the mask are missing, so `tom` becomes
+    /// `tom!*@*` and `*@host` becomes `*!*@host`.
+    fn from(mask: String) -> Self {
+        let mask = match (mask.contains('!'), mask.contains('@')) {
+            (true, true) => mask,
+            (true, false) => format!("{mask}@*"),
+            (false, true) => format!("*!{mask}"),
+            (false, false) => format!("{mask}!*@*"),
+        };
+        Hostmask(mask)
+    }
+}
+
+impl std::fmt::Display for Hostmask {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.0.fmt(fmt)
+    }
+}
+
+/// Who a relayed message came from, as shown at the start of the line:
+/// `nick!user@host` for users, and just the name for the server itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    /// The user's nick, or the server's name.
+    pub name: String,
+    /// The username and host, or `None` when they aren't known.
+    pub user_host: Option<(String, String)>,
+}
+
+impl Prefix {
+    /// The server itself, shown by its name.
+    pub fn server(server_name: &str) -> Self {
+        Prefix {
+            name: server_name.to_string(),
+            user_host: None,
+        }
+    }
+
+    /// A user known only by their nick.
+    pub fn nick(nick: &Nick) -> Self {
+        Prefix {
+            name: nick.to_string(),
+            user_host: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.user_host {
+            Some((user, host)) => write!(fmt, "{}!{user}@{host}", self.name),
+            None => self.name.fmt(fmt),
+        }
+    }
+}
+
 /// A message to set the nickname.
 /// For example: `NICK tfpk\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -176,298 +638,3702 @@ impl TryFrom<Vec<String>> for NickMsg {
     }
 }
 
-/// A message to join a channel.
-/// For example: `JOIN #channel\r\n`
+/// Joining a single channel, with the key given for it, if any.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct JoinMsg {
     pub channel: Channel,
+    pub key: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for JoinMsg {
+/// A message to join some channels, or to leave every channel with
+/// `JOIN 0`. Keys pair up with channels in order.
+/// For example: `JOIN #rust,#go key1\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinChannelsMsg {
+    /// Each channel named, with its key if it has one. Names are checked
+    /// as each channel is joined, so one bad name doesn't stop the rest.
+    Channels(Vec<(String, Option<String>)>),
+    PartAll,
+}
+
+impl TryFrom<Vec<String>> for JoinChannelsMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
-            .and_then(Channel::try_from)
-            .map(|channel| JoinMsg { channel })
+        let mut value = value.into_iter().skip(1);
+        let channels = value.next().ok_or(ErrorType::NeedMoreParams)?;
+        if channels == "0" {
+            return Ok(JoinChannelsMsg::PartAll);
+        }
+        let keys = value.next().unwrap_or_default();
+        let mut keys = keys.split(',').map(str::to_string);
+        let channels: Vec<_> = channels
+            .split(',')
+            .filter(|channel| !channel.is_empty())
+            .map(|channel| {
+                (
+                    channel.to_string(),
+                    keys.next().filter(|key| !key.is_empty()),
+                )
+            })
+            .collect();
+        if channels.is_empty() {
+            return Err(ErrorType::NeedMoreParams);
+        }
+        Ok(JoinChannelsMsg::Channels(channels))
     }
 }
 
-/// A message to leave a channel.
-/// For example: `PART #channel\r\n`
+/// Leaving a single channel, optionally saying why.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PartMsg {
     pub channel: Channel,
+    pub reason: Option<String>,
+}
+
+/// A message to leave some channels, optionally saying why.
+/// For example: `PART #rust,#go :Gone for lunch\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartChannelsMsg {
+    /// Checked as each channel is left, so one bad name doesn't stop the
+    /// rest.
+    pub channels: Vec<String>,
+    pub reason: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for PartMsg {
+impl TryFrom<Vec<String>> for PartChannelsMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
-            .and_then(Channel::try_from)
-            .map(|channel| PartMsg { channel })
+        let mut value = value.into_iter().skip(1);
+        let channels: Vec<_> = value
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|channel| !channel.is_empty())
+            .map(str::to_string)
+            .collect();
+        if channels.is_empty() {
+            return Err(ErrorType::NeedMoreParams);
+        }
+        Ok(PartChannelsMsg {
+            channels,
+            reason: value.last().filter(|reason| !reason.is_empty()),
+        })
     }
 }
 
-/// A message to register a new user.
-// For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
+/// A message to remove someone from a channel, optionally saying why.
+/// For example: `KICK #channel tom :Stop spamming\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UserMsg {
-    pub real_name: String,
+pub struct KickMsg {
+    pub channel: Channel,
+    pub nick: Nick,
+    pub reason: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for UserMsg {
+impl TryFrom<Vec<String>> for KickMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(4)
-            .ok_or(ErrorType::NeedMoreParams)
-            .map(|real_name| UserMsg { real_name })
+        let mut value = value.into_iter().skip(1);
+        let channel = Channel::try_from(value.next().ok_or(ErrorType::NeedMoreParams)?)?;
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+        Ok(KickMsg {
+            channel,
+            nick,
+            reason: value.last().filter(|reason| !reason.is_empty()),
+        })
     }
 }
 
-/// A private message.
-/// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
+/// A message from an operator to every user with +w.
+/// For example: `WALLOPS :Restarting in five minutes\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PrivMsg {
-    pub target: Target,
+pub struct WallopsMsg {
     pub message: String,
 }
 
-impl TryFrom<Vec<String>> for PrivMsg {
+impl TryFrom<Vec<String>> for WallopsMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        Ok(PrivMsg {
-            target: Target::from(value.get(1).ok_or(ErrorType::NoRecipient)?.to_string()),
-            // skip(2) here skips the PRIVMSG instruction and target.
-            message: value
-                .into_iter()
-                .skip(2)
-                .last()
-                .ok_or(ErrorType::NoTextToSend)?,
-        })
+        value
+            .into_iter()
+            .nth(1)
+            .filter(|message| !message.is_empty())
+            .ok_or(ErrorType::NeedMoreParams)
+            .map(|message| WallopsMsg { message })
     }
 }
 
-/// The last message a user will send before leaving.
-/// For example: `QUIT :Leaving now!`
+/// A message from an operator to disconnect a user. Without a reason, the
+/// operator's nick is given.
+/// For example: `KILL tom :Spamming\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct QuitMsg {
-    pub message: Option<String>,
+pub struct KillMsg {
+    pub nick: Nick,
+    pub reason: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for QuitMsg {
+impl TryFrom<Vec<String>> for KillMsg {
     type Error = ErrorType;
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        Ok(QuitMsg {
-            // skip(1) here skips the QUIT instruction.
-            message: value.into_iter().skip(1).last(),
+        let mut value = value.into_iter().skip(1);
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+        Ok(KillMsg {
+            nick,
+            reason: value.last().filter(|reason| !reason.is_empty()),
         })
     }
 }
 
-/// A list of every possible message that can be sent.
+/// A message inviting someone to a channel.
+/// For example: `INVITE tom #rust\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Message {
-    Nick(NickMsg),
-    User(UserMsg),
-    PrivMsg(PrivMsg),
-    Ping(String),
-    Join(JoinMsg),
-    Part(PartMsg),
-    Quit(QuitMsg),
+pub struct InviteMsg {
+    pub nick: Nick,
+    pub channel: Channel,
 }
 
-/// To parse a message, construct this struct.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UnparsedMessage<'a> {
-    pub sender_nick: Nick,
-    pub message: &'a str,
+impl TryFrom<Vec<String>> for InviteMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+        let channel = Channel::try_from(value.next().ok_or(ErrorType::NeedMoreParams)?)?;
+        Ok(InviteMsg { nick, channel })
+    }
 }
 
-/// After parsing an `UnparsedMessage`, this struct will be created.
+/// Asks for a channel's topic, or sets it if `topic` is given. An empty
+/// topic clears it.
+/// For example: `TOPIC #rust :All things Rust\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParsedMessage {
-    pub sender_nick: Nick,
-    pub message: Message,
+pub struct TopicMsg {
+    pub channel: Channel,
+    pub topic: Option<String>,
 }
 
-impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
+impl TryFrom<Vec<String>> for TopicMsg {
     type Error = ErrorType;
-    fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
-        let command = split_command(value.message)
-            .into_iter()
-            .map(str::to_string)
-            .collect::<Vec<_>>();
-
-        let message = match command[0].as_str() {
-            "PING" => Ok(Message::Ping(
-                // Skip here ignores the "PING".
-                command
-                    .iter()
-                    .skip(1)
-                    .last()
-                    .ok_or(ErrorType::NoOrigin)?
-                    .to_string(),
-            )),
-            "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
-            "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
-            "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
-            "JOIN" => Ok(Message::Join(JoinMsg::try_from(command)?)),
-            "PART" => Ok(Message::Part(PartMsg::try_from(command)?)),
-            "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
-            _ => Err(ErrorType::UnknownCommand),
-        }?;
 
-        Ok(ParsedMessage {
-            sender_nick: value.sender_nick,
-            message,
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let channel = Channel::try_from(value.next().ok_or(ErrorType::NeedMoreParams)?)?;
+        Ok(TopicMsg {
+            channel,
+            topic: value.last(),
         })
     }
 }
 
+/// Asks who is in a channel, or in every channel if none is given.
+/// For example: `NAMES #rust\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PrivReply {
-    pub message: PrivMsg,
-    pub sender_nick: Nick,
+pub struct NamesMsg {
+    pub channel: Option<Channel>,
+}
+
+impl TryFrom<Vec<String>> for NamesMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let channel = value
+            .into_iter()
+            .nth(1)
+            .map(Channel::try_from)
+            .transpose()?;
+        Ok(NamesMsg { channel })
+    }
+}
+
+/// Asks which channels exist. Only the channels named are listed if any
+/// are, and only those with more than `min_members` members if it is set.
+/// For example: `LIST #rust,#go\r\n` or `LIST >10\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListMsg {
+    pub channels: Vec<Channel>,
+    pub min_members: Option<usize>,
+}
+
+impl TryFrom<Vec<String>> for ListMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut list_msg = ListMsg {
+            channels: Vec::new(),
+            min_members: None,
+        };
+        for param in value.into_iter().skip(1) {
+            match param.strip_prefix('>') {
+                // A filter that isn't a number filters nothing
+                Some(count) => list_msg.min_members = count.parse().ok(),
+                None => {
+                    list_msg.channels = param
+                        .split(',')
+                        .map(|channel| Channel::try_from(channel.to_string()))
+                        .collect::<Result<_, _>>()?;
+                }
+            }
+        }
+        Ok(list_msg)
+    }
+}
+
+/// Asks about the members of a channel, or about a single user.
+/// For example: `WHO #rust\r\n` or `WHO tom\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoMsg {
+    pub mask: String,
+}
+
+impl TryFrom<Vec<String>> for WhoMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .into_iter()
+            .nth(1)
+            .ok_or(ErrorType::NeedMoreParams)
+            .map(|mask| WhoMsg { mask })
+    }
+}
+
+/// Asks about a user. Only the last parameter counts, so the
+/// `WHOIS server nick` form works too.
+/// For example: `WHOIS tom\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisMsg {
+    pub nick: Nick,
+}
+
+impl TryFrom<Vec<String>> for WhoisMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .into_iter()
+            .skip(1)
+            .last()
+            .ok_or(ErrorType::NoNickNameGiven)
+            .map(|nick| WhoisMsg { nick: Nick(nick) })
+    }
+}
+
+/// The most nicks ISON or USERHOST will look up at once. Any more are
+/// ignored.
+pub const MAX_PRESENCE_NICKS: usize = 5;
+
+/// The nicks given to ISON or USERHOST, whether as separate parameters or
+/// as one space-separated trailing parameter.
+fn presence_nicks(value: Vec<String>) -> Result<Vec<Nick>, ErrorType> {
+    let nicks: Vec<_> = value
+        .iter()
+        .skip(1)
+        .flat_map(|param| param.split_whitespace())
+        .take(MAX_PRESENCE_NICKS)
+        .map(|nick| Nick(nick.to_string()))
+        .collect();
+    if nicks.is_empty() {
+        return Err(ErrorType::NeedMoreParams);
+    }
+    Ok(nicks)
+}
+
+/// Asks which of some nicks are online.
+/// For example: `ISON tom ann bob\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsonMsg {
+    pub nicks: Vec<Nick>,
+}
+
+impl TryFrom<Vec<String>> for IsonMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        presence_nicks(value).map(|nicks| IsonMsg { nicks })
+    }
+}
+
+/// Asks the hosts of some users.
+/// For example: `USERHOST tom ann\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserhostMsg {
+    pub nicks: Vec<Nick>,
+}
+
+impl TryFrom<Vec<String>> for UserhostMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        presence_nicks(value).map(|nicks| UserhostMsg { nicks })
+    }
+}
+
+/// Marks the sender as away, with a message for anyone who writes to them.
+/// Without a message (or with an empty one) it marks them as back.
+/// For example: `AWAY :Gone to lunch\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayMsg {
+    pub message: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for AwayMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(AwayMsg {
+            message: value
+                .into_iter()
+                .skip(1)
+                .last()
+                .filter(|message| !message.is_empty()),
+        })
+    }
+}
+
+/// A channel's topic, and who set it when.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: Nick,
+    /// Seconds since the Unix epoch.
+    pub set_at: u64,
+}
+
+/// An operator changing someone else's nick.
+/// For example: `SANICK tom thomas\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaNickMsg {
+    pub nick: Nick,
+    pub new_nick: Nick,
+}
+
+impl TryFrom<Vec<String>> for SaNickMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+        let new_nick = Nick::try_from(value.next().ok_or(ErrorType::NeedMoreParams)?)?;
+        Ok(SaNickMsg { nick, new_nick })
+    }
+}
+
+/// An operator moving someone into or out of a channel.
+/// For example: `SAJOIN tom #rust\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForceChannelMsg {
+    pub nick: Nick,
+    pub channel: Channel,
+}
+
+impl TryFrom<Vec<String>> for ForceChannelMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+        let channel = Channel::try_from(value.next().ok_or(ErrorType::NeedMoreParams)?)?;
+        Ok(ForceChannelMsg { nick, channel })
+    }
+}
+
+/// A message to register a new user.
+// For example: `USER tom ignored ignored :Thomas Kunc\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMsg {
+    pub username: String,
+    pub real_name: String,
+}
+
+impl TryFrom<Vec<String>> for UserMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter();
+        let username = value.nth(1).ok_or(ErrorType::NeedMoreParams)?;
+        let real_name = value.nth(2).ok_or(ErrorType::NeedMoreParams)?;
+        Ok(UserMsg {
+            username,
+            real_name,
+        })
+    }
+}
+
+/// A private message.
+/// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivMsg {
+    pub target: Target,
+    pub message: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct JoinReply {
-    pub message: JoinMsg,
-    pub sender_nick: Nick,
-}
+impl TryFrom<Vec<String>> for PrivMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(PrivMsg {
+            target: Target::try_from(value.get(1).ok_or(ErrorType::NoRecipient)?.to_string())?,
+            // skip(2) here skips the PRIVMSG instruction and target.
+            message: value
+                .into_iter()
+                .skip(2)
+                .last()
+                .ok_or(ErrorType::NoTextToSend)?,
+        })
+    }
+}
+
+/// Whether a [`PrivMsg`] was sent as a PRIVMSG or a NOTICE. A NOTICE is
+/// delivered the same way, but nothing it causes is ever answered, not even
+/// with an error, so two programs can't reply to each other forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    PrivMsg,
+    Notice,
+}
+
+impl MessageKind {
+    /// The reply that delivers `reply` as this kind of message.
+    pub fn reply(self, reply: PrivReply) -> Reply {
+        match self {
+            MessageKind::PrivMsg => Reply::PrivMsg(reply),
+            MessageKind::Notice => Reply::Notice(reply),
+        }
+    }
+}
+
+/// The last message a user will send before leaving.
+/// For example: `QUIT :Leaving now!`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuitMsg {
+    pub message: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for QuitMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(QuitMsg {
+            // skip(1) here skips the QUIT instruction.
+            message: value.into_iter().skip(1).last(),
+        })
+    }
+}
+
+/// A message to register the sender's nick as an account.
+/// For example: `REGISTER hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterMsg {
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for RegisterMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .into_iter()
+            .nth(1)
+            .ok_or(ErrorType::NeedMoreParams)
+            .map(|password| RegisterMsg { password })
+    }
+}
+
+/// A message to identify to the account owning the sender's nick.
+/// For example: `IDENTIFY hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifyMsg {
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for IdentifyMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .into_iter()
+            .nth(1)
+            .ok_or(ErrorType::NeedMoreParams)
+            .map(|password| IdentifyMsg { password })
+    }
+}
+
+/// Asks to become a server operator.
+/// For example: `OPER admin hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperMsg {
+    pub name: String,
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for OperMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        match &value[..] {
+            [_, name, password, ..] => Ok(OperMsg {
+                name: name.clone(),
+                password: password.clone(),
+            }),
+            _ => Err(ErrorType::NeedMoreParams),
+        }
+    }
+}
+
+/// A message to disconnect a stale session using a registered nick.
+/// For example: `GHOST tfpk hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GhostMsg {
+    pub nick: Nick,
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for GhostMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        Ok(GhostMsg {
+            nick: Nick(value.next().ok_or(ErrorType::NeedMoreParams)?),
+            password: value.next().ok_or(ErrorType::NeedMoreParams)?,
+        })
+    }
+}
+
+/// A message to manage the certificate fingerprints that identify the
+/// sender's account. Fingerprints are matched in lowercase.
+/// For example: `CERT ADD <fingerprint>\r\n`, `CERT DEL <fingerprint>\r\n`
+/// or `CERT LIST\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertMsg {
+    Add(String),
+    Del(String),
+    List,
+}
+
+impl TryFrom<Vec<String>> for CertMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let subcommand = value.next().ok_or(ErrorType::NeedMoreParams)?;
+        let mut fingerprint = || {
+            value
+                .next()
+                .map(|fingerprint| fingerprint.to_ascii_lowercase())
+                .ok_or(ErrorType::NeedMoreParams)
+        };
+        match subcommand.to_ascii_uppercase().as_str() {
+            "ADD" => Ok(CertMsg::Add(fingerprint()?)),
+            "DEL" => Ok(CertMsg::Del(fingerprint()?)),
+            "LIST" => Ok(CertMsg::List),
+            _ => Err(ErrorType::UnknownCommand),
+        }
+    }
+}
+
+/// A single channel mode, along with its argument if it takes one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelMode {
+    Op(Nick),
+    Voice(Nick),
+    OperOnly,
+    Persistent,
+    StripFormatting,
+    BlockFormatting,
+    SecureOnly,
+    RegisteredOnly,
+    RegisteredSpeak,
+    NoExternal,
+    TopicOps,
+    Moderated,
+    InviteOnly,
+    /// Hidden from LIST, NAMES and WHOIS for anyone outside (+s).
+    Secret,
+    /// Joining needs this key (+k).
+    Key(String),
+    /// No more than this many members (+l). Removing it needs no number,
+    /// so it is 0 then.
+    Limit(usize),
+    /// Users matching the mask may neither join nor speak (+b). Without a
+    /// mask, this asks for the ban list instead.
+    Ban(Option<Hostmask>),
+}
+
+impl ChannelMode {
+    /// The letter used for this mode in a MODE command.
+    pub fn letter(&self) -> char {
+        match self {
+            ChannelMode::Op(_) => 'o',
+            ChannelMode::Voice(_) => 'v',
+            ChannelMode::OperOnly => 'O',
+            ChannelMode::Persistent => 'P',
+            ChannelMode::StripFormatting => 'c',
+            ChannelMode::BlockFormatting => 'C',
+            ChannelMode::SecureOnly => 'z',
+            ChannelMode::RegisteredOnly => 'R',
+            ChannelMode::RegisteredSpeak => 'M',
+            ChannelMode::NoExternal => 'n',
+            ChannelMode::TopicOps => 't',
+            ChannelMode::Moderated => 'm',
+            ChannelMode::InviteOnly => 'i',
+            ChannelMode::Secret => 's',
+            ChannelMode::Key(_) => 'k',
+            ChannelMode::Limit(_) => 'l',
+            ChannelMode::Ban(_) => 'b',
+        }
+    }
+
+    /// The argument this mode was given, if any.
+    pub fn argument(&self) -> Option<String> {
+        match self {
+            ChannelMode::Op(nick) | ChannelMode::Voice(nick) => Some(nick.to_string()),
+            ChannelMode::Key(key) => Some(key.clone()),
+            ChannelMode::Limit(limit) => (*limit > 0).then(|| limit.to_string()),
+            ChannelMode::Ban(mask) => mask.as_ref().map(Hostmask::to_string),
+            ChannelMode::OperOnly
+            | ChannelMode::Persistent
+            | ChannelMode::StripFormatting
+            | ChannelMode::BlockFormatting
+            | ChannelMode::SecureOnly
+            | ChannelMode::RegisteredOnly
+            | ChannelMode::RegisteredSpeak
+            | ChannelMode::NoExternal
+            | ChannelMode::TopicOps
+            | ChannelMode::Moderated
+            | ChannelMode::InviteOnly
+            | ChannelMode::Secret => None,
+        }
+    }
+}
+
+/// A mode being added (`+`) or removed (`-`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: ChannelMode,
+}
+
+/// A message to view or change the modes of a channel.
+/// For example: `MODE #channel +ov alice bob\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeMsg {
+    pub channel: Channel,
+    pub changes: Vec<ModeChange>,
+}
+
+impl TryFrom<Vec<String>> for ModeMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let channel = value
+            .next()
+            .ok_or(ErrorType::NeedMoreParams)
+            .and_then(Channel::try_from)?;
+
+        let mut changes = Vec::new();
+        if let Some(letters) = value.next() {
+            // Arguments are handed out in order to the letters that need them.
+            let mut arguments = value;
+            for (adding, letter) in mode_letters(&letters) {
+                let mode = match letter {
+                    'o' => {
+                        ChannelMode::Op(Nick(arguments.next().ok_or(ErrorType::NeedMoreParams)?))
+                    }
+                    'v' => {
+                        ChannelMode::Voice(Nick(arguments.next().ok_or(ErrorType::NeedMoreParams)?))
+                    }
+                    'O' => ChannelMode::OperOnly,
+                    'P' => ChannelMode::Persistent,
+                    'c' => ChannelMode::StripFormatting,
+                    'C' => ChannelMode::BlockFormatting,
+                    'z' => ChannelMode::SecureOnly,
+                    'R' => ChannelMode::RegisteredOnly,
+                    'M' => ChannelMode::RegisteredSpeak,
+                    'n' => ChannelMode::NoExternal,
+                    't' => ChannelMode::TopicOps,
+                    'm' => ChannelMode::Moderated,
+                    'i' => ChannelMode::InviteOnly,
+                    's' => ChannelMode::Secret,
+                    // The key is only needed to set one; `-k` alone removes it
+                    'k' if adding => ChannelMode::Key(
+                        arguments
+                            .next()
+                            .filter(|key| !key.is_empty())
+                            .ok_or(ErrorType::NeedMoreParams)?,
+                    ),
+                    'k' => ChannelMode::Key(arguments.next().unwrap_or_else(|| "*".to_string())),
+                    'l' if adding => {
+                        let limit = arguments.next().ok_or(ErrorType::NeedMoreParams)?;
+                        match limit.parse() {
+                            Ok(limit) if limit > 0 => ChannelMode::Limit(limit),
+                            _ => return Err(ErrorType::InvalidModeParam),
+                        }
+                    }
+                    'l' => ChannelMode::Limit(0),
+                    'b' => ChannelMode::Ban(arguments.next().map(Hostmask::from)),
+                    _ => return Err(ErrorType::UnknownMode),
+                };
+                changes.push(ModeChange { adding, mode });
+            }
+        }
+
+        Ok(ModeMsg { channel, changes })
+    }
+}
+
+/// Splits mode letters such as `+ov-v` into `(adding, letter)` pairs, in
+/// order. Letters before any sign are being added.
+pub fn mode_letters(letters: &str) -> impl Iterator<Item = (bool, char)> + '_ {
+    letters
+        .chars()
+        .scan(true, |adding, letter| {
+            Some(match letter {
+                '+' | '-' => {
+                    *adding = letter == '+';
+                    None
+                }
+                _ => Some((*adding, letter)),
+            })
+        })
+        .flatten()
+}
+
+/// Formats mode changes, given as `(adding, letter, argument)`, the way
+/// they appear in a MODE command, e.g. `+ov-v alice bob carol`.
+fn format_mode_changes(changes: impl IntoIterator<Item = (bool, char, Option<String>)>) -> String {
+    let mut letters = String::new();
+    let mut arguments = Vec::new();
+    let mut sign = None;
+    for (adding, letter, argument) in changes {
+        if sign != Some(adding) {
+            letters.push(if adding { '+' } else { '-' });
+            sign = Some(adding);
+        }
+        letters.push(letter);
+        arguments.extend(argument);
+    }
+
+    std::iter::once(letters)
+        .chain(arguments)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A mode that applies to a user rather than a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UserMode {
+    /// Hidden from WHO and NAMES for anyone not sharing a channel (+i).
+    Invisible,
+    /// Only accept private messages from users on the accept list (+g).
+    CallerId,
+    /// Only accept private messages from identified users (+R).
+    RegisteredOnly,
+    /// Receive WALLOPS from operators (+w).
+    Wallops,
+}
+
+impl UserMode {
+    /// The letter used for this mode in a MODE command.
+    pub fn letter(&self) -> char {
+        match self {
+            UserMode::Invisible => 'i',
+            UserMode::CallerId => 'g',
+            UserMode::RegisteredOnly => 'R',
+            UserMode::Wallops => 'w',
+        }
+    }
+}
+
+/// A user mode being added (`+`) or removed (`-`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserModeChange {
+    pub adding: bool,
+    pub mode: UserMode,
+}
+
+/// A message to view or change a user's own modes.
+/// For example: `MODE tfpk +g\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserModeMsg {
+    pub nick: Nick,
+    pub changes: Vec<UserModeChange>,
+}
+
+impl TryFrom<Vec<String>> for UserModeMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let mut value = value.into_iter().skip(1);
+        let nick = Nick(value.next().ok_or(ErrorType::NeedMoreParams)?);
+
+        let mut changes = Vec::new();
+        for (adding, letter) in mode_letters(&value.next().unwrap_or_default()) {
+            let mode = match letter {
+                'i' => UserMode::Invisible,
+                'g' => UserMode::CallerId,
+                'R' => UserMode::RegisteredOnly,
+                'w' => UserMode::Wallops,
+                _ => return Err(ErrorType::UModeUnknownFlag),
+            };
+            changes.push(UserModeChange { adding, mode });
+        }
+
+        Ok(UserModeMsg { nick, changes })
+    }
+}
+
+/// One entry of an ACCEPT command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcceptEntry {
+    Add(Nick),
+    Remove(Nick),
+    List,
+}
+
+/// A message to manage the caller-ID accept list.
+/// For example: `ACCEPT tom,-jerry\r\n` or `ACCEPT *\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptMsg {
+    pub entries: Vec<AcceptEntry>,
+}
+
+impl TryFrom<Vec<String>> for AcceptMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let entries = value
+            .get(1)
+            .ok_or(ErrorType::NeedMoreParams)?
+            .split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry {
+                "*" => AcceptEntry::List,
+                _ => match entry.strip_prefix('-') {
+                    Some(nick) => AcceptEntry::Remove(Nick(nick.to_string())),
+                    None => AcceptEntry::Add(Nick(entry.to_string())),
+                },
+            })
+            .collect();
+
+        Ok(AcceptMsg { entries })
+    }
+}
+
+/// A list of every possible message that can be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Nick(NickMsg),
+    User(UserMsg),
+    /// A connection password, checked before the client may register.
+    Pass(String),
+    PrivMsg(PrivMsg),
+    /// Sent like a PRIVMSG, but never answered.
+    Notice(PrivMsg),
+    Ping(String),
+    /// The answer to a PING, carrying its token.
+    Pong(String),
+    Join(JoinChannelsMsg),
+    Part(PartChannelsMsg),
+    Kick(KickMsg),
+    Invite(InviteMsg),
+    Quit(QuitMsg),
+    Topic(TopicMsg),
+    Names(NamesMsg),
+    List(ListMsg),
+    Who(WhoMsg),
+    Whois(WhoisMsg),
+    Away(AwayMsg),
+    /// Asks for the message of the day. Naming a server is allowed, but
+    /// there is only this one.
+    Motd,
+    /// Asks how many users and channels there are.
+    Lusers,
+    /// Asks which version of the server this is.
+    Version,
+    /// Asks the server's local time.
+    Time,
+    /// Asks about the server itself.
+    Info,
+    Ison(IsonMsg),
+    Userhost(UserhostMsg),
+    Oper(OperMsg),
+    Kill(KillMsg),
+    Wallops(WallopsMsg),
+    Mode(ModeMsg),
+    UserMode(UserModeMsg),
+    Accept(AcceptMsg),
+    Register(RegisterMsg),
+    Identify(IdentifyMsg),
+    Ghost(GhostMsg),
+    Cert(CertMsg),
+    /// A server operator making someone join a channel.
+    SaJoin(ForceChannelMsg),
+    /// A server operator making someone leave a channel.
+    SaPart(ForceChannelMsg),
+    /// A server operator changing someone's nick.
+    SaNick(SaNickMsg),
+    /// A server operator asking for the server's internal state.
+    Debug,
+    /// A server operator asking for the server's channels and users as JSON.
+    Export,
+    /// A server operator asking for a snapshot to be saved.
+    Snapshot,
+    /// A well-formed command the server doesn't know, as it was sent.
+    Unknown(String),
+}
+
+/// To parse a message, construct this struct.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnparsedMessage<'a> {
+    /// Who sent it, once they have a nick.
+    pub sender_nick: Option<Nick>,
+    pub message: &'a str,
+}
+
+/// After parsing an `UnparsedMessage`, this struct will be created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedMessage {
+    pub sender_nick: Option<Nick>,
+    pub message: Message,
+}
+
+impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
+    type Error = ErrorType;
+    fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
+        Self::parse(value, &std::collections::HashMap::new())
+    }
+}
+
+impl ParsedMessage {
+    /// Parses `value`, taking the commands in `aliases` to mean the ones they
+    /// map to, as well as the [`BUILTIN_ALIASES`].
+    pub fn parse(
+        value: UnparsedMessage<'_>,
+        aliases: &std::collections::HashMap<String, String>,
+    ) -> Result<Self, ErrorType> {
+        let command = split_command(value.message)
+            .into_iter()
+            .map(sanitize)
+            .collect::<Vec<_>>();
+
+        let message = match canonical_command(&command[0], aliases).as_str() {
+            "PING" => Ok(Message::Ping(
+                // Skip here ignores the "PING".
+                command
+                    .iter()
+                    .skip(1)
+                    .last()
+                    .ok_or(ErrorType::NoOrigin)?
+                    .to_string(),
+            )),
+            "PONG" => Ok(Message::Pong(
+                command
+                    .iter()
+                    .skip(1)
+                    .last()
+                    .ok_or(ErrorType::NoOrigin)?
+                    .to_string(),
+            )),
+            "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
+            "NOTICE" => Ok(Message::Notice(PrivMsg::try_from(command)?)),
+            "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
+            "PASS" => Ok(Message::Pass(
+                command.get(1).ok_or(ErrorType::NeedMoreParams)?.to_string(),
+            )),
+            "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
+            "JOIN" => Ok(Message::Join(JoinChannelsMsg::try_from(command)?)),
+            "PART" => Ok(Message::Part(PartChannelsMsg::try_from(command)?)),
+            "KICK" => Ok(Message::Kick(KickMsg::try_from(command)?)),
+            "INVITE" => Ok(Message::Invite(InviteMsg::try_from(command)?)),
+            "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
+            "TOPIC" => Ok(Message::Topic(TopicMsg::try_from(command)?)),
+            "NAMES" => Ok(Message::Names(NamesMsg::try_from(command)?)),
+            "LIST" => Ok(Message::List(ListMsg::try_from(command)?)),
+            "WHO" => Ok(Message::Who(WhoMsg::try_from(command)?)),
+            "WHOIS" => Ok(Message::Whois(WhoisMsg::try_from(command)?)),
+            "AWAY" => Ok(Message::Away(AwayMsg::try_from(command)?)),
+            "MOTD" => Ok(Message::Motd),
+            "LUSERS" => Ok(Message::Lusers),
+            "VERSION" => Ok(Message::Version),
+            "TIME" => Ok(Message::Time),
+            "INFO" => Ok(Message::Info),
+            "ISON" => Ok(Message::Ison(IsonMsg::try_from(command)?)),
+            "USERHOST" => Ok(Message::Userhost(UserhostMsg::try_from(command)?)),
+            "OPER" => Ok(Message::Oper(OperMsg::try_from(command)?)),
+            "KILL" => Ok(Message::Kill(KillMsg::try_from(command)?)),
+            "WALLOPS" => Ok(Message::Wallops(WallopsMsg::try_from(command)?)),
+            "MODE" => match command.get(1) {
+                Some(target) if !target.starts_with('#') => {
+                    Ok(Message::UserMode(UserModeMsg::try_from(command)?))
+                }
+                _ => Ok(Message::Mode(ModeMsg::try_from(command)?)),
+            },
+            "ACCEPT" => Ok(Message::Accept(AcceptMsg::try_from(command)?)),
+            "REGISTER" => Ok(Message::Register(RegisterMsg::try_from(command)?)),
+            "IDENTIFY" => Ok(Message::Identify(IdentifyMsg::try_from(command)?)),
+            "GHOST" => Ok(Message::Ghost(GhostMsg::try_from(command)?)),
+            "CERT" => Ok(Message::Cert(CertMsg::try_from(command)?)),
+            "SAJOIN" => Ok(Message::SaJoin(ForceChannelMsg::try_from(command)?)),
+            "SAPART" => Ok(Message::SaPart(ForceChannelMsg::try_from(command)?)),
+            "SANICK" => Ok(Message::SaNick(SaNickMsg::try_from(command)?)),
+            "DEBUG" => Ok(Message::Debug),
+            "EXPORT" => Ok(Message::Export),
+            "SNAPSHOT" => Ok(Message::Snapshot),
+            _ if is_command_word(&command[0]) => Ok(Message::Unknown(command[0].clone())),
+            _ => Err(ErrorType::UnknownCommand),
+        }?;
+
+        Ok(ParsedMessage {
+            sender_nick: value.sender_nick,
+            message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivReply {
+    pub message: PrivMsg,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinReply {
+    pub message: JoinMsg,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartReply {
+    pub message: PartMsg,
+    pub sender: Prefix,
+}
+
+/// Tells a user, and everyone sharing a channel with them, that their
+/// nick has changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NickReply {
+    /// Who they were.
+    pub sender: Prefix,
+    pub new_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuitReply {
+    pub message: QuitMsg,
+    pub sender: Prefix,
+}
+
+/// Tells a channel its topic has changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicReply {
+    pub sender: Prefix,
+    pub channel: Channel,
+    pub topic: String,
+}
+
+/// Tells a user what a channel's topic is, or that it has none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicIsReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub topic: Option<Topic>,
+}
+
+/// Lists a channel's members, with their status prefixes, over as many
+/// lines as it takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamesReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub names: Vec<String>,
+}
+
+/// Ends a NAMES listing. `mask` is the channel asked about, or `*` when
+/// every channel was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndOfNamesReply {
+    pub target_nick: Nick,
+    pub mask: String,
+}
+
+/// One channel in a LIST reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub channel: Channel,
+    pub members: usize,
+    /// Empty if the channel has no topic.
+    pub topic: String,
+}
+
+/// Lists channels, between a header and an end marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListReply {
+    pub target_nick: Nick,
+    pub entries: Vec<ListEntry>,
+}
+
+/// One user in a WHO reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoEntry {
+    /// The channel asked about, or `*` when asking about a user.
+    pub channel: String,
+    pub nick: Nick,
+    pub user: String,
+    pub host: String,
+    pub oper: bool,
+    pub away: bool,
+    /// The user's status in `channel`.
+    pub status: MemberStatus,
+    pub real_name: String,
+}
+
+/// Tells a user they are now a server operator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YoureOperReply {
+    pub target_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WallopsReply {
+    pub message: WallopsMsg,
+    pub sender: Prefix,
+}
+
+/// Tells a user the server doesn't know the command they sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCommandReply {
+    /// `None` before they have a nick, which is shown as `*`.
+    pub target_nick: Option<Nick>,
+    pub command: String,
+}
+
+/// The last line sent before the server closes a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosingLinkReply {
+    /// `None` before they have a nick, which is shown as `*`.
+    pub target_nick: Option<Nick>,
+    pub reason: String,
+}
+
+/// Which of the nicks asked about are online.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsonReply {
+    pub target_nick: Nick,
+    pub nicks: Vec<Nick>,
+}
+
+/// One user in a USERHOST reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserhostEntry {
+    pub nick: Nick,
+    pub user: String,
+    pub host: String,
+    pub oper: bool,
+    pub away: bool,
+}
+
+/// The hosts of the users asked about. Nicks not online are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserhostReply {
+    pub target_nick: Nick,
+    pub entries: Vec<UserhostEntry>,
+}
+
+/// Describes the users matching a WHO, then marks the end of the list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoReply {
+    pub target_nick: Nick,
+    pub mask: String,
+    pub entries: Vec<WhoEntry>,
+}
+
+/// What WHOIS shows about a user who is online.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisUser {
+    pub user: String,
+    pub host: String,
+    pub real_name: String,
+    /// The user's channels, each with the user's status prefix.
+    pub channels: Vec<String>,
+    /// The user's away message, if they are away.
+    pub away: Option<String>,
+}
+
+/// Describes a user, or says there is no such user, then marks the end of
+/// the WHOIS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub user: Option<WhoisUser>,
+}
+
+/// Tells the sender of a private message that its recipient is away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub message: String,
+}
+
+/// Confirms that the user is now marked as away, or as back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayStatusReply {
+    pub target_nick: Nick,
+    pub away: bool,
+}
+
+/// The message of the day, from its start to its end. Lines too long to
+/// send are wrapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MotdReply {
+    pub target_nick: Nick,
+    pub lines: Vec<String>,
+}
+
+/// How many users and channels there are. There is only ever one server,
+/// so its local and global counts are the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LusersReply {
+    pub target_nick: Nick,
+    pub users: usize,
+    /// How many of `users` are invisible.
+    pub invisible: usize,
+    /// The most users there have been at once since the server started.
+    pub max_users: usize,
+    pub channels: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReply {
+    pub target_nick: Nick,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeReply {
+    pub target_nick: Nick,
+    /// Already formatted, as RFC 2822.
+    pub time: String,
+}
+
+/// A few lines about the server, followed by the end of the list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfoReply {
+    pub target_nick: Nick,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ISupportReply {
+    pub target_nick: Nick,
+    pub tokens: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeReply {
+    pub message: ModeMsg,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelModeIsReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub modes: String,
+}
+
+/// Lists a channel's bans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanListReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub masks: Vec<Hostmask>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserModeReply {
+    pub message: UserModeMsg,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserModeIsReply {
+    pub target_nick: Nick,
+    pub modes: String,
+}
+
+/// Tells the invited user who invited them, and where to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteReply {
+    pub sender: Prefix,
+    pub message: InviteMsg,
+}
+
+/// Confirms to the inviter that the invitation was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvitingReply {
+    pub target_nick: Nick,
+    pub message: InviteMsg,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptListReply {
+    pub target_nick: Nick,
+    pub accepted: Vec<Nick>,
+}
+
+/// Tells a +g user that someone not on their accept list messaged them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallerIdNotifyReply {
+    pub target_nick: Nick,
+    pub sender: Prefix,
+}
+
+/// Tells a sender that the +g user they messaged has been notified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargNotifyReply {
+    pub target_nick: Nick,
+    pub notified_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KickReply {
+    pub sender: Prefix,
+    pub channel: Channel,
+    pub kicked_nick: Nick,
+    pub reason: String,
+}
+
+/// A notice sent to a user by the server itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerNoticeReply {
+    pub target_nick: Nick,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedInReply {
+    pub target_nick: Nick,
+    pub account: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WelcomeReply {
+    pub target_nick: Nick,
+    pub message: String,
+}
+
+/// Which server the user is on, and what it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YourHostReply {
+    pub target_nick: Nick,
+    pub version: String,
+}
+
+/// When the server started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedReply {
+    pub target_nick: Nick,
+    /// Already formatted, as RFC 2822.
+    pub created: String,
+}
+
+/// The server's name and version, and the modes it supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MyInfoReply {
+    pub target_nick: Nick,
+    pub version: String,
+}
+
+/// Every possible reply to a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Checks the client is still there. They should answer with PONG.
+    Ping,
+    Pong(String),
+    Welcome(WelcomeReply),
+    YourHost(YourHostReply),
+    Created(CreatedReply),
+    MyInfo(MyInfoReply),
+    ISupport(ISupportReply),
+    PrivMsg(PrivReply),
+    Notice(PrivReply),
+    Join(JoinReply),
+    Part(PartReply),
+    Nick(NickReply),
+    Error(ErrorType),
+    Quit(QuitReply),
+    Topic(TopicReply),
+    TopicIs(TopicIsReply),
+    Names(NamesReply),
+    EndOfNames(EndOfNamesReply),
+    List(ListReply),
+    Who(WhoReply),
+    Whois(WhoisReply),
+    Away(AwayReply),
+    AwayStatus(AwayStatusReply),
+    Motd(MotdReply),
+    Lusers(LusersReply),
+    Version(VersionReply),
+    Time(TimeReply),
+    Info(InfoReply),
+    Ison(IsonReply),
+    Userhost(UserhostReply),
+    YoureOper(YoureOperReply),
+    ClosingLink(ClosingLinkReply),
+    UnknownCommand(UnknownCommandReply),
+    Wallops(WallopsReply),
+    Mode(ModeReply),
+    ChannelModeIs(ChannelModeIsReply),
+    BanList(BanListReply),
+    UserMode(UserModeReply),
+    UserModeIs(UserModeIsReply),
+    AcceptList(AcceptListReply),
+    CallerIdNotify(CallerIdNotifyReply),
+    TargNotify(TargNotifyReply),
+    LoggedIn(LoggedInReply),
+    Kick(KickReply),
+    Invite(InviteReply),
+    Inviting(InvitingReply),
+    ServerNotice(ServerNoticeReply),
+}
+
+/// Writes `prefix` then `text` as one line, cutting `text` short so the
+/// line fits in [`MAX_LINE_LEN`] once its CRLF is added.
+fn write_fitted(
+    fmt: &mut std::fmt::Formatter<'_>,
+    prefix: &str,
+    text: &str,
+) -> Result<(), std::fmt::Error> {
+    let room = (MAX_LINE_LEN - 2).saturating_sub(prefix.len());
+    write!(fmt, "{prefix}{}\r\n", truncate(text, room))
+}
+
+impl ServerMessage for Reply {
+    fn fmt_as(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+        server_name: &str,
+    ) -> Result<(), std::fmt::Error> {
+        match self {
+            Reply::Ping => write!(fmt, "PING :{server_name}\r\n"),
+            Reply::Pong(p) => write!(fmt, ":{server_name} PONG {server_name} :{p}\r\n"),
+            Reply::Welcome(r) => {
+                let nick = &r.target_nick;
+                let message = &r.message;
+                write!(fmt, ":{server_name} 001 {nick} :{message}\r\n")
+            }
+            Reply::YourHost(r) => {
+                let nick = &r.target_nick;
+                let version = &r.version;
+                write!(
+                    fmt,
+                    ":{server_name} 002 {nick} :Your host is {server_name}, running version iris-{version}\r\n"
+                )
+            }
+            Reply::Created(r) => {
+                let nick = &r.target_nick;
+                let created = &r.created;
+                write!(
+                    fmt,
+                    ":{server_name} 003 {nick} :This server was created {created}\r\n"
+                )
+            }
+            Reply::MyInfo(r) => {
+                let nick = &r.target_nick;
+                let version = &r.version;
+                write!(
+                    fmt,
+                    ":{server_name} 004 {nick} {server_name} iris-{version} {USER_MODES} {CHANNEL_MODES} {CHANNEL_ARGUMENT_MODES}\r\n"
+                )
+            }
+            Reply::ISupport(r) => {
+                let nick = &r.target_nick;
+                let tokens = r.tokens.join(" ");
+                write!(
+                    fmt,
+                    ":{server_name} 005 {nick} {tokens} :are supported by this server\r\n"
+                )
+            }
+            Reply::PrivMsg(r) => {
+                let nick = &r.message.target;
+                let from = &r.sender;
+                let prefix = format!(":{from} PRIVMSG {nick} :");
+                write_fitted(fmt, &prefix, &r.message.message)
+            }
+            Reply::Notice(r) => {
+                let nick = &r.message.target;
+                let from = &r.sender;
+                let prefix = format!(":{from} NOTICE {nick} :");
+                write_fitted(fmt, &prefix, &r.message.message)
+            }
+            Reply::Error(e) => {
+                e.fmt_as(fmt, server_name)?;
+                write!(fmt, "\r\n")
+            }
+            Reply::Join(r) => {
+                let sender = &r.sender;
+                let channel = &r.message.channel;
+                write!(fmt, ":{sender} JOIN {channel}\r\n")
+            }
+            Reply::Part(r) => {
+                let sender = &r.sender;
+                let channel = &r.message.channel;
+                match &r.message.reason {
+                    Some(reason) => write!(fmt, ":{sender} PART {channel} :{reason}\r\n"),
+                    None => write!(fmt, ":{sender} PART {channel}\r\n"),
+                }
+            }
+            Reply::Nick(r) => {
+                let sender = &r.sender;
+                let new_nick = &r.new_nick;
+                write!(fmt, ":{sender} NICK {new_nick}\r\n")
+            }
+            Reply::Quit(r) => {
+                let sender = &r.sender;
+                let message = r.message.message.as_ref().unwrap_or(&sender.name);
+                write!(fmt, ":{sender} QUIT :{message}\r\n")
+            }
+            Reply::Topic(r) => {
+                let sender = &r.sender;
+                let channel = &r.channel;
+                let topic = &r.topic;
+                write!(fmt, ":{sender} TOPIC {channel} :{topic}\r\n")
+            }
+            Reply::TopicIs(r) => {
+                let nick = &r.target_nick;
+                let channel = &r.channel;
+                match &r.topic {
+                    Some(topic) => {
+                        let text = &topic.text;
+                        let set_by = &topic.set_by;
+                        let set_at = topic.set_at;
+                        write!(fmt, ":{server_name} 332 {nick} {channel} :{text}\r\n")?;
+                        write!(
+                            fmt,
+                            ":{server_name} 333 {nick} {channel} {set_by} {set_at}\r\n"
+                        )
+                    }
+                    None => write!(
+                        fmt,
+                        ":{server_name} 331 {nick} {channel} :No topic is set\r\n"
+                    ),
+                }
+            }
+            Reply::Names(r) => {
+                let nick = &r.target_nick;
+                let channel = &r.channel;
+                let prefix = format!(":{server_name} 353 {nick} = {channel} :");
+                // Leaves room for the CRLF
+                let room = (MAX_LINE_LEN - 2).saturating_sub(prefix.len());
+                let mut names = String::new();
+                for name in &r.names {
+                    if !names.is_empty() && names.len() + 1 + name.len() > room {
+                        write!(fmt, "{prefix}{names}\r\n")?;
+                        names.clear();
+                    }
+                    if !names.is_empty() {
+                        names.push(' ');
+                    }
+                    names.push_str(name);
+                }
+                if !names.is_empty() {
+                    write!(fmt, "{prefix}{names}\r\n")?;
+                }
+                Ok(())
+            }
+            Reply::EndOfNames(r) => {
+                let nick = &r.target_nick;
+                let mask = &r.mask;
+                write!(
+                    fmt,
+                    ":{server_name} 366 {nick} {mask} :End of /NAMES list\r\n"
+                )
+            }
+            Reply::List(r) => {
+                let nick = &r.target_nick;
+                write!(fmt, ":{server_name} 321 {nick} Channel :Users  Name\r\n")?;
+                for entry in &r.entries {
+                    let channel = &entry.channel;
+                    let members = entry.members;
+                    let topic = &entry.topic;
+                    write!(
+                        fmt,
+                        ":{server_name} 322 {nick} {channel} {members} :{topic}\r\n"
+                    )?;
+                }
+                write!(fmt, ":{server_name} 323 {nick} :End of /LIST\r\n")
+            }
+            Reply::Who(r) => {
+                let nick = &r.target_nick;
+                for entry in &r.entries {
+                    let channel = &entry.channel;
+                    let who = &entry.nick;
+                    let user = &entry.user;
+                    let host = &entry.host;
+                    let real_name = &entry.real_name;
+                    // Here or Gone
+                    let mut flags = if entry.away { "G" } else { "H" }.to_string();
+                    if entry.oper {
+                        flags.push('*');
+                    }
+                    flags.extend(entry.status.prefix());
+                    write!(
+                        fmt,
+                        ":{server_name} 352 {nick} {channel} {user} {host} {server_name} {who} {flags} :0 {real_name}\r\n"
+                    )?;
+                }
+                let mask = &r.mask;
+                write!(
+                    fmt,
+                    ":{server_name} 315 {nick} {mask} :End of /WHO list\r\n"
+                )
+            }
+            Reply::YoureOper(r) => {
+                let nick = &r.target_nick;
+                write!(
+                    fmt,
+                    ":{server_name} 381 {nick} :You are now an IRC operator\r\n"
+                )
+            }
+            Reply::Wallops(r) => {
+                let sender = &r.sender;
+                let message = &r.message.message;
+                write!(fmt, ":{sender} WALLOPS :{message}\r\n")
+            }
+            Reply::ClosingLink(r) => {
+                let nick = r.target_nick.as_ref().map_or("*", |nick| nick.0.as_str());
+                let reason = &r.reason;
+                write!(fmt, "ERROR :Closing Link: {nick} ({reason})\r\n")
+            }
+            Reply::UnknownCommand(r) => {
+                let nick = r.target_nick.as_ref().map_or("*", |nick| nick.0.as_str());
+                let command = &r.command;
+                write!(
+                    fmt,
+                    ":{server_name} 421 {nick} {command} :Unknown command\r\n"
+                )
+            }
+            Reply::Ison(r) => {
+                let nick = &r.target_nick;
+                let nicks = r
+                    .nicks
+                    .iter()
+                    .map(|nick| nick.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                write!(fmt, ":{server_name} 303 {nick} :{nicks}\r\n")
+            }
+            Reply::Userhost(r) => {
+                let nick = &r.target_nick;
+                let entries = r
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        let oper = if entry.oper { "*" } else { "" };
+                        let away = if entry.away { '-' } else { '+' };
+                        format!("{}{oper}={away}{}@{}", entry.nick, entry.user, entry.host)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                write!(fmt, ":{server_name} 302 {nick} :{entries}\r\n")
+            }
+            Reply::Whois(r) => {
+                let nick = &r.target_nick;
+                let who = &r.nick;
+                match &r.user {
+                    Some(user) => {
+                        let username = &user.user;
+                        let host = &user.host;
+                        let real_name = &user.real_name;
+                        write!(
+                            fmt,
+                            ":{server_name} 311 {nick} {who} {username} {host} * :{real_name}\r\n"
+                        )?;
+                        if !user.channels.is_empty() {
+                            let channels = user.channels.join(" ");
+                            write!(fmt, ":{server_name} 319 {nick} {who} :{channels}\r\n")?;
+                        }
+                        if let Some(message) = &user.away {
+                            write!(fmt, ":{server_name} 301 {nick} {who} :{message}\r\n")?;
+                        }
+                    }
+                    None => {
+                        ErrorType::NoSuchNick.fmt_as(fmt, server_name)?;
+                        write!(fmt, "\r\n")?;
+                    }
+                }
+                write!(
+                    fmt,
+                    ":{server_name} 318 {nick} {who} :End of /WHOIS list\r\n"
+                )
+            }
+            Reply::Away(r) => {
+                let nick = &r.target_nick;
+                let who = &r.nick;
+                let message = &r.message;
+                write!(fmt, ":{server_name} 301 {nick} {who} :{message}\r\n")
+            }
+            Reply::AwayStatus(r) => {
+                let nick = &r.target_nick;
+                if r.away {
+                    write!(
+                        fmt,
+                        ":{server_name} 306 {nick} :You have been marked as being away\r\n"
+                    )
+                } else {
+                    write!(
+                        fmt,
+                        ":{server_name} 305 {nick} :You are no longer marked as being away\r\n"
+                    )
+                }
+            }
+            Reply::Motd(r) => {
+                let nick = &r.target_nick;
+                write!(
+                    fmt,
+                    ":{server_name} 375 {nick} :- {server_name} Message of the day - \r\n"
+                )?;
+                let prefix = format!(":{server_name} 372 {nick} :- ");
+                // Leaves room for the CRLF
+                let room = (MAX_LINE_LEN - 2).saturating_sub(prefix.len());
+                for line in &r.lines {
+                    for piece in wrap_words(line, room) {
+                        write!(fmt, "{prefix}{piece}\r\n")?;
+                    }
+                }
+                write!(fmt, ":{server_name} 376 {nick} :End of /MOTD command.\r\n")
+            }
+            Reply::Lusers(r) => {
+                let nick = &r.target_nick;
+                let users = r.users;
+                let invisible = r.invisible;
+                let visible = users - invisible;
+                let max = r.max_users;
+                let channels = r.channels;
+                write!(
+                    fmt,
+                    ":{server_name} 251 {nick} :There are {visible} users and {invisible} invisible on 1 servers\r\n"
+                )?;
+                write!(
+                    fmt,
+                    ":{server_name} 254 {nick} {channels} :channels formed\r\n"
+                )?;
+                write!(
+                    fmt,
+                    ":{server_name} 255 {nick} :I have {users} clients and 0 servers\r\n"
+                )?;
+                write!(
+                    fmt,
+                    ":{server_name} 265 {nick} {users} {max} :Current local users {users}, max {max}\r\n"
+                )?;
+                write!(
+                    fmt,
+                    ":{server_name} 266 {nick} {users} {max} :Current global users {users}, max {max}\r\n"
+                )
+            }
+            Reply::Version(r) => {
+                let nick = &r.target_nick;
+                let version = &r.version;
+                write!(
+                    fmt,
+                    ":{server_name} 351 {nick} iris-{version} {server_name} :\r\n"
+                )
+            }
+            Reply::Time(r) => {
+                let nick = &r.target_nick;
+                let time = &r.time;
+                write!(fmt, ":{server_name} 391 {nick} {server_name} :{time}\r\n")
+            }
+            Reply::Info(r) => {
+                let nick = &r.target_nick;
+                for line in &r.lines {
+                    write!(fmt, ":{server_name} 371 {nick} :{line}\r\n")?;
+                }
+                write!(fmt, ":{server_name} 374 {nick} :End of /INFO list.\r\n")
+            }
+            Reply::Mode(r) => {
+                let sender = &r.sender;
+                let channel = &r.message.channel;
+                let changes =
+                    format_mode_changes(r.message.changes.iter().map(|change| {
+                        (change.adding, change.mode.letter(), change.mode.argument())
+                    }));
+                write!(fmt, ":{sender} MODE {channel} {changes}\r\n")
+            }
+            Reply::ChannelModeIs(r) => {
+                let nick = &r.target_nick;
+                let channel = &r.channel;
+                let modes = &r.modes;
+                write!(fmt, ":{server_name} 324 {nick} {channel} {modes}\r\n")
+            }
+            Reply::BanList(r) => {
+                let nick = &r.target_nick;
+                let channel = &r.channel;
+                for mask in &r.masks {
+                    write!(fmt, ":{server_name} 367 {nick} {channel} {mask}\r\n")?;
+                }
+                write!(
+                    fmt,
+                    ":{server_name} 368 {nick} {channel} :End of channel ban list\r\n"
+                )
+            }
+            Reply::UserMode(r) => {
+                let sender = &r.sender;
+                let nick = &r.message.nick;
+                let changes = format_mode_changes(
+                    r.message
+                        .changes
+                        .iter()
+                        .map(|change| (change.adding, change.mode.letter(), None)),
+                );
+                write!(fmt, ":{sender} MODE {nick} {changes}\r\n")
+            }
+            Reply::UserModeIs(r) => {
+                let nick = &r.target_nick;
+                let modes = &r.modes;
+                write!(fmt, ":{server_name} 221 {nick} {modes}\r\n")
+            }
+            Reply::AcceptList(r) => {
+                let nick = &r.target_nick;
+                let accepted = r
+                    .accepted
+                    .iter()
+                    .map(Nick::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                write!(fmt, ":{server_name} 281 {nick} {accepted}\r\n")?;
+                write!(fmt, ":{server_name} 282 {nick} :End of /ACCEPT list.\r\n")
+            }
+            Reply::CallerIdNotify(r) => {
+                let nick = &r.target_nick;
+                let sender = &r.sender.name;
+                let user_host = r.sender.user_host.as_ref().map_or_else(
+                    || "*@*".to_string(),
+                    |(user, host)| format!("{user}@{host}"),
+                );
+                write!(
+                    fmt,
+                    ":{server_name} 718 {nick} {sender} {user_host} :is messaging you, and you have umode +g.\r\n"
+                )
+            }
+            Reply::Kick(r) => {
+                let sender = &r.sender;
+                let channel = &r.channel;
+                let kicked = &r.kicked_nick;
+                let reason = &r.reason;
+                write!(fmt, ":{sender} KICK {channel} {kicked} :{reason}\r\n")
+            }
+            Reply::Invite(r) => {
+                let sender = &r.sender;
+                let invited = &r.message.nick;
+                let channel = &r.message.channel;
+                write!(fmt, ":{sender} INVITE {invited} :{channel}\r\n")
+            }
+            Reply::Inviting(r) => {
+                let nick = &r.target_nick;
+                let invited = &r.message.nick;
+                let channel = &r.message.channel;
+                write!(fmt, ":{server_name} 341 {nick} {invited} {channel}\r\n")
+            }
+            Reply::ServerNotice(r) => {
+                let nick = &r.target_nick;
+                let message = &r.message;
+                write!(fmt, ":{server_name} NOTICE {nick} :{message}\r\n")
+            }
+            Reply::LoggedIn(r) => {
+                let nick = &r.target_nick;
+                let account = &r.account;
+                write!(
+                    fmt,
+                    ":{server_name} 900 {nick} {account} :You are now logged in as {account}\r\n"
+                )
+            }
+            Reply::TargNotify(r) => {
+                let nick = &r.target_nick;
+                let notified = &r.notified_nick;
+                write!(
+                    fmt,
+                    ":{server_name} 717 {nick} {notified} :has been informed that you messaged them.\r\n"
+                )
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Reply {
+    /// Formats as sent under the default [`SERVER_NAME`].
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.fmt_as(fmt, SERVER_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// How a user registered as `nick` from localhost appears as a sender.
+    fn user_prefix(nick: &str) -> Prefix {
+        Prefix {
+            name: nick.to_string(),
+            user_host: Some((nick.to_string(), "127.0.0.1".to_string())),
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PING :host-name with space\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Ping("host-name with space".to_string())
+        )
+    }
+
+    #[test]
+    fn test_pong() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("PONG :token\r\n"),
+            Ok(Message::Pong("token".to_string()))
+        );
+        assert_eq!(
+            parse("PONG tom :iris-server\r\n"),
+            Ok(Message::Pong("iris-server".to_string()))
+        );
+        assert_eq!(parse("PONG\r\n"), Err(ErrorType::NoOrigin));
+    }
+
+    #[test]
+    fn test_privmsg() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PRIVMSG tom :Hi Tom, how are you?\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::PrivMsg(PrivMsg {
+                target: Target::User(Nick("tom".to_string())),
+                message: "Hi Tom, how are you?".to_string()
+            })
+        )
+    }
+
+    #[test]
+    fn test_notice() {
+        use std::collections::HashMap;
+
+        let message = PrivMsg {
+            target: Target::Channel(Channel("#rust".to_string())),
+            message: "Build finished".to_string(),
+        };
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "NOTICE #rust :Build finished\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Notice(message.clone())
+        );
+        assert_eq!(
+            MessageKind::Notice
+                .reply(PrivReply {
+                    message,
+                    sender: user_prefix("bot"),
+                })
+                .to_string(),
+            ":bot!bot@127.0.0.1 NOTICE #rust :Build finished\r\n"
+        );
+
+        let no_aliases = HashMap::new();
+        assert!(is_notice("NOTICE\r\n", &no_aliases));
+        assert!(is_notice("notice tom :hi\r\n", &no_aliases));
+        assert!(!is_notice("PRIVMSG tom :hi\r\n", &no_aliases));
+        let aliases = HashMap::from([("N".to_string(), "NOTICE".to_string())]);
+        assert!(is_notice("N tom :hi\r\n", &aliases));
+    }
+
+    #[test]
+    fn test_nick() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "NICK tfpk\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Nick(NickMsg {
+                nick: Nick("tfpk".to_string())
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "NICK tfpkasdfasdfasdfasdf\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            }),
+            Err(ErrorType::ErroneousNickname)
+        );
+    }
+
+    #[test]
+    fn test_nick_parse() {
+        let valid = |nick| Nick::parse(nick) == Ok(Nick(nick.to_string()));
+        for nick in ["tom", "Tom2", "[away]", "`tom`", "_t-o-m_", "{x}|y^z\\"] {
+            assert!(valid(nick), "{nick}");
+        }
+        assert!(valid(&"a".repeat(MAX_NICK_LEN)));
+        for nick in [
+            "",
+            "#hello",
+            "2tom",
+            "-tom",
+            "tom smith",
+            "tom,ann",
+            "tom!x@y",
+            "t\u{e9}o",
+            "*",
+        ] {
+            assert_eq!(
+                Nick::parse(nick),
+                Err(ErrorType::ErroneousNickname),
+                "{nick}"
+            );
+        }
+        assert_eq!(
+            Nick::parse(&"a".repeat(MAX_NICK_LEN + 1)),
+            Err(ErrorType::ErroneousNickname)
+        );
+    }
+
+    #[test]
+    fn test_unsafe_chars_are_stripped() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("QUIT :bye\r\nPRIVMSG #chan :forged\r\n"),
+            Ok(Message::Quit(QuitMsg {
+                message: Some("byePRIVMSG #chan :forged".to_string())
+            }))
+        );
+        assert_eq!(
+            parse("USER tom 0 * :Tom\rPRIVMSG #chan :forged\0\r\n"),
+            Ok(Message::User(UserMsg {
+                username: "tom".to_string(),
+                real_name: "TomPRIVMSG #chan :forged".to_string()
+            }))
+        );
+        assert_eq!(
+            parse("PRIVMSG #chan :hi\r:evil PRIVMSG #chan :forged\r\n"),
+            Ok(Message::PrivMsg(PrivMsg {
+                target: Target::Channel(Channel("#chan".to_string())),
+                message: "hi:evil PRIVMSG #chan :forged".to_string()
+            }))
+        );
+        assert_eq!(
+            parse("GHOST to\rm hunter2\r\n"),
+            Ok(Message::Ghost(GhostMsg {
+                nick: Nick("tom".to_string()),
+                password: "hunter2".to_string()
+            }))
+        );
+        assert_eq!(
+            parse("NICK to\0m\r\n"),
+            Ok(Message::Nick(NickMsg {
+                nick: Nick("tom".to_string())
+            }))
+        );
+    }
+
+    #[test]
+    fn test_mode_pairs_arguments_in_order() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #chan +ov-v alice bob carol\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Mode(ModeMsg {
+                channel: Channel("#chan".to_string()),
+                changes: vec![
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::Op(Nick("alice".to_string()))
+                    },
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::Voice(Nick("bob".to_string()))
+                    },
+                    ModeChange {
+                        adding: false,
+                        mode: ChannelMode::Voice(Nick("carol".to_string()))
+                    },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_mode_combines_flags() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #chan +nt-m\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Mode(ModeMsg {
+                channel: Channel("#chan".to_string()),
+                changes: vec![
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::NoExternal
+                    },
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::TopicOps
+                    },
+                    ModeChange {
+                        adding: false,
+                        mode: ChannelMode::Moderated
+                    },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_mode_errors() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #chan +ov alice\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            }),
+            Err(ErrorType::NeedMoreParams)
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #chan +q alice\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            }),
+            Err(ErrorType::UnknownMode)
+        );
+    }
+
+    #[test]
+    fn test_mode_reply_groups_signs() {
+        let reply = Reply::Mode(ModeReply {
+            message: ModeMsg {
+                channel: Channel("#chan".to_string()),
+                changes: vec![
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::Op(Nick("alice".to_string())),
+                    },
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::Voice(Nick("bob".to_string())),
+                    },
+                    ModeChange {
+                        adding: false,
+                        mode: ChannelMode::Op(Nick("carol".to_string())),
+                    },
+                ],
+            },
+            sender: user_prefix("dave"),
+        });
+        assert_eq!(
+            reply.to_string(),
+            ":dave!dave@127.0.0.1 MODE #chan +ov-o alice bob carol\r\n"
+        );
+    }
+    #[test]
+    fn test_statusmsg_target() {
+        let target = |target: &str| Target::try_from(target.to_string());
+        assert_eq!(
+            target("@#chan"),
+            Ok(Target::ChannelStatus(
+                MemberStatus::Op,
+                Channel("#chan".to_string())
+            ))
+        );
+        assert_eq!(
+            target("+#chan"),
+            Ok(Target::ChannelStatus(
+                MemberStatus::Voice,
+                Channel("#chan".to_string())
+            ))
+        );
+        assert_eq!(
+            target("#chan"),
+            Ok(Target::Channel(Channel("#chan".to_string())))
+        );
+        assert_eq!(target("@#chan").unwrap().to_string(), "@#chan");
+        assert_eq!(target("@#ch\x07an"), Err(ErrorType::NoSuchChannel));
+    }
+
+    #[test]
+    fn test_names_ignore_case() {
+        use std::collections::HashSet;
+
+        assert_eq!(Nick("Alice".to_string()), Nick("aLiCe".to_string()));
+        assert_ne!(Nick("Alice".to_string()), Nick("Alicia".to_string()));
+        assert_eq!(Channel("#Rust".to_string()), Channel("#RUST".to_string()));
+        let nicks = HashSet::from([Nick("Alice".to_string())]);
+        assert!(nicks.contains(&Nick("ALICE".to_string())));
+        let channels = HashSet::from([Channel("#rust".to_string())]);
+        assert!(channels.contains(&Channel("#Rust".to_string())));
+        // Only ASCII letters fold
+        assert_ne!(Channel("#[a]".to_string()), Channel("#{a}".to_string()));
+        // Names still show as they were given
+        assert_eq!(Nick("Alice".to_string()).to_string(), "Alice");
+        assert_eq!(Channel("#Rust".to_string()).to_string(), "#Rust");
+    }
+
+    #[test]
+    fn test_channel_parse() {
+        let valid = |name| Channel::parse(name) == Ok(Channel(name.to_string()));
+        for name in ["#rust", "#rust-lang", "#c++", "#", "#a.b_c"] {
+            assert!(valid(name), "{name}");
+        }
+        assert!(valid(&format!("#{}", "a".repeat(MAX_CHANNEL_LEN - 1))));
+        for name in ["rust", "", "#a b", "#a,b", "#bell\x07", "#t\u{e9}"] {
+            assert_eq!(Channel::parse(name), Err(ErrorType::BadChanMask), "{name}");
+        }
+        assert_eq!(
+            Channel::parse(&format!("#{}", "a".repeat(MAX_CHANNEL_LEN))),
+            Err(ErrorType::BadChanMask)
+        );
+        // Looking up a badly named channel finds nothing
+        assert_eq!(
+            Channel::try_from("rust".to_string()),
+            Err(ErrorType::NoSuchChannel)
+        );
+    }
+    #[test]
+    fn test_user_mode() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE tom +g\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::UserMode(UserModeMsg {
+                nick: Nick("tom".to_string()),
+                changes: vec![UserModeChange {
+                    adding: true,
+                    mode: UserMode::CallerId
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn test_registration_burst() {
+        let nick = || Nick("tom".to_string());
+        let burst = [
+            Reply::Welcome(WelcomeReply {
+                target_nick: nick(),
+                message: "Welcome to this server, Tom!".to_string(),
+            }),
+            Reply::YourHost(YourHostReply {
+                target_nick: nick(),
+                version: "0.1.0".to_string(),
+            }),
+            Reply::Created(CreatedReply {
+                target_nick: nick(),
+                created: "Thu, 15 Oct 2026 12:00:00 +0000".to_string(),
+            }),
+            Reply::MyInfo(MyInfoReply {
+                target_nick: nick(),
+                version: "0.1.0".to_string(),
+            }),
+            Reply::ISupport(ISupportReply {
+                target_nick: nick(),
+                tokens: vec!["CHANTYPES=#".to_string(), "NICKLEN=16".to_string()],
+            }),
+        ];
+        assert_eq!(
+            burst
+                .iter()
+                .map(|reply| format!("{reply}"))
+                .collect::<String>(),
+            ":iris-server 001 tom :Welcome to this server, Tom!\r\n\
+             :iris-server 002 tom :Your host is iris-server, running version iris-0.1.0\r\n\
+             :iris-server 003 tom :This server was created Thu, 15 Oct 2026 12:00:00 +0000\r\n\
+             :iris-server 004 tom iris-server iris-0.1.0 giRw CMOPRbciklmnostvz bklov\r\n\
+             :iris-server 005 tom CHANTYPES=# NICKLEN=16 :are supported by this server\r\n"
+        );
+    }
+
+    #[test]
+    fn test_advertised_modes_are_parsed() {
+        let parse = |message: String| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message: &message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        for letter in ('A'..='Z').chain('a'..='z') {
+            let user_mode = parse(format!("MODE tom +{letter}\r\n"));
+            assert_eq!(user_mode.is_ok(), USER_MODES.contains(letter), "{letter}");
+
+            let channel_mode = parse(format!("MODE #rust +{letter} 1\r\n"));
+            assert_eq!(
+                channel_mode.is_ok(),
+                CHANNEL_MODES.contains(letter),
+                "{letter}"
+            );
+            if let Ok(Message::Mode(mode_msg)) = channel_mode {
+                let mode = &mode_msg.changes[0].mode;
+                assert_eq!(mode.letter(), letter);
+                assert_eq!(
+                    mode.argument().is_some(),
+                    CHANNEL_ARGUMENT_MODES.contains(letter),
+                    "{letter}"
+                );
+            }
+        }
+
+        let too_long = "a".repeat(MAX_NICK_LEN + 1);
+        assert!(Nick::try_from(too_long[1..].to_string()).is_ok());
+        assert!(Nick::try_from(too_long).is_err());
+    }
+
+    #[test]
+    fn test_mode_letters() {
+        let letters = |letters| mode_letters(letters).collect::<Vec<_>>();
+        assert_eq!(letters("+ov-v"), [(true, 'o'), (true, 'v'), (false, 'v')]);
+        assert_eq!(letters("i-w+g"), [(true, 'i'), (false, 'w'), (true, 'g')]);
+        assert_eq!(letters("-+-"), []);
+        assert_eq!(letters(""), []);
+    }
+
+    #[test]
+    fn test_invisible_mode() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE tom +i-w\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::UserMode(UserModeMsg {
+                nick: Nick("tom".to_string()),
+                changes: vec![
+                    UserModeChange {
+                        adding: true,
+                        mode: UserMode::Invisible
+                    },
+                    UserModeChange {
+                        adding: false,
+                        mode: UserMode::Wallops
+                    },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_accept() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "ACCEPT tom,-jerry,*\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Accept(AcceptMsg {
+                entries: vec![
+                    AcceptEntry::Add(Nick("tom".to_string())),
+                    AcceptEntry::Remove(Nick("jerry".to_string())),
+                    AcceptEntry::List,
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_debug() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "DEBUG\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Debug
+        );
+    }
+
+    #[test]
+    fn test_export_and_snapshot() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "EXPORT\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Export
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "SNAPSHOT\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Snapshot
+        );
+    }
+
+    #[test]
+    fn test_pass() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PASS secret\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Pass("secret".to_string())
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PASS\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            }),
+            Err(ErrorType::NeedMoreParams)
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        use std::collections::HashMap;
+
+        let parse = |message, aliases: &HashMap<String, String>| {
+            ParsedMessage::parse(
+                UnparsedMessage {
+                    message,
+                    sender_nick: Some(Nick("Person".to_string())),
+                },
+                aliases,
+            )
+            .map(|parsed| parsed.message)
+        };
+        let no_aliases = HashMap::new();
+        assert_eq!(
+            parse("MSG tom :hi\r\n", &no_aliases),
+            parse("PRIVMSG tom :hi\r\n", &no_aliases)
+        );
+        assert_eq!(
+            parse("leave #rust\r\n", &no_aliases),
+            parse("PART #rust\r\n", &no_aliases)
+        );
+        assert_eq!(
+            parse("privmsg tom :hi\r\n", &no_aliases),
+            parse("PRIVMSG tom :hi\r\n", &no_aliases)
+        );
+        assert_eq!(
+            parse("J #rust\r\n", &no_aliases),
+            Ok(Message::Unknown("J".to_string()))
+        );
+
+        let aliases = HashMap::from([("J".to_string(), "JOIN".to_string())]);
+        assert_eq!(
+            parse("j #rust\r\n", &aliases),
+            parse("JOIN #rust\r\n", &no_aliases)
+        );
+        assert!(parse("MSG tom :hi\r\n", &aliases).is_ok());
+    }
+
+    #[test]
+    fn test_join_channels() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let join = |channels: &[(&str, Option<&str>)]| {
+            Ok(Message::Join(JoinChannelsMsg::Channels(
+                channels
+                    .iter()
+                    .map(|(channel, key)| (channel.to_string(), key.map(str::to_string)))
+                    .collect(),
+            )))
+        };
+        assert_eq!(parse("JOIN #rust\r\n"), join(&[("#rust", None)]));
+        assert_eq!(
+            parse("JOIN #rust,#go,#c key1,key2\r\n"),
+            join(&[("#rust", Some("key1")), ("#go", Some("key2")), ("#c", None)])
+        );
+        // Bad names are left for the handler to report
+        assert_eq!(
+            parse("JOIN #rust,go,,#c ,key\r\n"),
+            join(&[("#rust", None), ("go", Some("key")), ("#c", None)])
+        );
+        assert_eq!(
+            parse("JOIN 0\r\n"),
+            Ok(Message::Join(JoinChannelsMsg::PartAll))
+        );
+        assert_eq!(parse("JOIN\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("JOIN ,\r\n"), Err(ErrorType::NeedMoreParams));
+    }
+
+    #[test]
+    fn test_part() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let part = |channels: &[&str], reason: Option<&str>| {
+            Ok(Message::Part(PartChannelsMsg {
+                channels: channels.iter().map(|channel| channel.to_string()).collect(),
+                reason: reason.map(str::to_string),
+            }))
+        };
+        assert_eq!(parse("PART #rust\r\n"), part(&["#rust"], None));
+        assert_eq!(
+            parse("PART #rust,go :Gone for lunch\r\n"),
+            part(&["#rust", "go"], Some("Gone for lunch"))
+        );
+        assert_eq!(parse("PART #rust :\r\n"), part(&["#rust"], None));
+        assert_eq!(parse("PART\r\n"), Err(ErrorType::NeedMoreParams));
+
+        let reply = |reason: Option<&str>| {
+            format!(
+                "{}",
+                Reply::Part(PartReply {
+                    message: PartMsg {
+                        channel: Channel("#rust".to_string()),
+                        reason: reason.map(str::to_string),
+                    },
+                    sender: user_prefix("tom"),
+                })
+            )
+        };
+        assert_eq!(reply(None), ":tom!tom@127.0.0.1 PART #rust\r\n");
+        assert_eq!(
+            reply(Some("Gone for lunch")),
+            ":tom!tom@127.0.0.1 PART #rust :Gone for lunch\r\n"
+        );
+    }
+
+    #[test]
+    fn test_kick() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let kick = |reason: Option<&str>| {
+            Ok(Message::Kick(KickMsg {
+                channel: Channel("#rust".to_string()),
+                nick: Nick("tom".to_string()),
+                reason: reason.map(str::to_string),
+            }))
+        };
+        assert_eq!(
+            parse("KICK #rust tom :Stop spamming\r\n"),
+            kick(Some("Stop spamming"))
+        );
+        assert_eq!(parse("KICK #rust tom\r\n"), kick(None));
+        assert_eq!(parse("KICK #rust tom :\r\n"), kick(None));
+        assert_eq!(parse("KICK #rust\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("KICK rust tom\r\n"), Err(ErrorType::NoSuchChannel));
+    }
+
+    #[test]
+    fn test_invite() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let invite = InviteMsg {
+            nick: Nick("ann".to_string()),
+            channel: Channel("#rust".to_string()),
+        };
+        assert_eq!(
+            parse("INVITE ann #rust\r\n"),
+            Ok(Message::Invite(invite.clone()))
+        );
+        assert_eq!(parse("INVITE ann\r\n"), Err(ErrorType::NeedMoreParams));
+
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Inviting(InvitingReply {
+                    target_nick: Nick("tom".to_string()),
+                    message: invite.clone(),
+                })
+            ),
+            ":iris-server 341 tom ann #rust\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Invite(InviteReply {
+                    sender: user_prefix("tom"),
+                    message: invite,
+                })
+            ),
+            ":tom!tom@127.0.0.1 INVITE ann :#rust\r\n"
+        );
+    }
+
+    #[test]
+    fn test_force_channel() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("SAJOIN tom #rust\r\n"),
+            Ok(Message::SaJoin(ForceChannelMsg {
+                nick: Nick("tom".to_string()),
+                channel: Channel("#rust".to_string()),
+            }))
+        );
+        assert!(matches!(
+            parse("SAPART tom #rust\r\n"),
+            Ok(Message::SaPart(_))
+        ));
+        assert_eq!(parse("SAJOIN tom\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("SAPART tom rust\r\n"), Err(ErrorType::NoSuchChannel));
+    }
+
+    #[test]
+    fn test_secure_only_mode() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #safe +z\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Mode(ModeMsg {
+                channel: Channel("#safe".to_string()),
+                changes: vec![ModeChange {
+                    adding: true,
+                    mode: ChannelMode::SecureOnly,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_key_mode() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let key = |adding, key: &str| {
+            Ok(Message::Mode(ModeMsg {
+                channel: Channel("#rust".to_string()),
+                changes: vec![ModeChange {
+                    adding,
+                    mode: ChannelMode::Key(key.to_string()),
+                }],
+            }))
+        };
+        assert_eq!(parse("MODE #rust +k secret\r\n"), key(true, "secret"));
+        assert_eq!(parse("MODE #rust +k\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("MODE #rust -k secret\r\n"), key(false, "secret"));
+        assert_eq!(parse("MODE #rust -k\r\n"), key(false, "*"));
+    }
+
+    #[test]
+    fn test_limit_mode() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let limit = |adding, limit| ModeMsg {
+            channel: Channel("#rust".to_string()),
+            changes: vec![ModeChange {
+                adding,
+                mode: ChannelMode::Limit(limit),
+            }],
+        };
+        assert_eq!(
+            parse("MODE #rust +l 25\r\n"),
+            Ok(Message::Mode(limit(true, 25)))
+        );
+        assert_eq!(
+            parse("MODE #rust -l\r\n"),
+            Ok(Message::Mode(limit(false, 0)))
+        );
+        assert_eq!(parse("MODE #rust +l\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(
+            parse("MODE #rust +l lots\r\n"),
+            Err(ErrorType::InvalidModeParam)
+        );
+        assert_eq!(
+            parse("MODE #rust +l 0\r\n"),
+            Err(ErrorType::InvalidModeParam)
+        );
+
+        let reply = |message| {
+            format!(
+                "{}",
+                Reply::Mode(ModeReply {
+                    message,
+                    sender: user_prefix("tom"),
+                })
+            )
+        };
+        assert_eq!(
+            reply(limit(true, 25)),
+            ":tom!tom@127.0.0.1 MODE #rust +l 25\r\n"
+        );
+        assert_eq!(
+            reply(limit(false, 0)),
+            ":tom!tom@127.0.0.1 MODE #rust -l\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hostmask() {
+        let mask = |mask: &str| Hostmask::from(mask.to_string());
+        assert_eq!(mask("tom"), Hostmask("tom!*@*".to_string()));
+        assert_eq!(mask("*@10.0.0.1"), Hostmask("*!*@10.0.0.1".to_string()));
+        assert_eq!(mask("tom!thomas"), Hostmask("tom!thomas@*".to_string()));
+        assert_eq!(mask("tom!*@*"), Hostmask("tom!*@*".to_string()));
+
+        let user = "Tom!thomas@10.0.0.1";
+        assert!(mask("tom").matches(user));
+        assert!(mask("*!*@10.0.0.*").matches(user));
+        assert!(mask("t?m!*thomas@*").matches(user));
+        assert!(mask("*").matches(user));
+        assert!(mask("*!*@*1").matches(user));
+        assert!(!mask("to").matches(user));
+        assert!(!mask("t?m!*@10.0.0.2").matches(user));
+        assert!(!mask("*!thomas@*.2").matches(user));
+    }
+
+    #[test]
+    fn test_ban_mode() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let ban = |adding, mask: Option<&str>| ModeMsg {
+            channel: Channel("#rust".to_string()),
+            changes: vec![ModeChange {
+                adding,
+                mode: ChannelMode::Ban(mask.map(|mask| Hostmask(mask.to_string()))),
+            }],
+        };
+        assert_eq!(
+            parse("MODE #rust +b tom\r\n"),
+            Ok(Message::Mode(ban(true, Some("tom!*@*"))))
+        );
+        assert_eq!(
+            parse("MODE #rust -b *!*@10.0.0.1\r\n"),
+            Ok(Message::Mode(ban(false, Some("*!*@10.0.0.1"))))
+        );
+        assert_eq!(
+            parse("MODE #rust +b\r\n"),
+            Ok(Message::Mode(ban(true, None)))
+        );
+
+        let reply = Reply::BanList(BanListReply {
+            target_nick: Nick("tom".to_string()),
+            channel: Channel("#rust".to_string()),
+            masks: vec![
+                Hostmask("ann!*@*".to_string()),
+                Hostmask("*!*@10.0.0.1".to_string()),
+            ],
+        });
+        assert_eq!(
+            format!("{reply}"),
+            ":iris-server 367 tom #rust ann!*@*\r\n\
+             :iris-server 367 tom #rust *!*@10.0.0.1\r\n\
+             :iris-server 368 tom #rust :End of channel ban list\r\n"
+        );
+    }
+
+    #[test]
+    fn test_registered_only_modes() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MODE #club +R-M\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
+            })
+            .unwrap()
+            .message,
+            Message::Mode(ModeMsg {
+                channel: Channel("#club".to_string()),
+                changes: vec![
+                    ModeChange {
+                        adding: true,
+                        mode: ChannelMode::RegisteredOnly,
+                    },
+                    ModeChange {
+                        adding: false,
+                        mode: ChannelMode::RegisteredSpeak,
+                    },
+                ],
+            })
+        );
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PartReply {
-    pub message: PartMsg,
-    pub sender_nick: Nick,
-}
+    #[test]
+    fn test_cert() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("CERT add ABC123\r\n"),
+            Ok(Message::Cert(CertMsg::Add("abc123".to_string())))
+        );
+        assert_eq!(
+            parse("CERT DEL abc123\r\n"),
+            Ok(Message::Cert(CertMsg::Del("abc123".to_string())))
+        );
+        assert_eq!(parse("CERT LIST\r\n"), Ok(Message::Cert(CertMsg::List)));
+        assert_eq!(parse("CERT ADD\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("CERT\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("CERT SHOW\r\n"), Err(ErrorType::UnknownCommand));
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct QuitReply {
-    pub message: QuitMsg,
-    pub sender_nick: Nick,
-}
+    #[test]
+    fn test_unknown_command() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("CAP LS 302\r\n"),
+            Ok(Message::Unknown("CAP".to_string()))
+        );
+        assert_eq!(
+            parse("userip tom\r\n"),
+            Ok(Message::Unknown("userip".to_string()))
+        );
+        assert_eq!(parse(" LS\r\n"), Err(ErrorType::UnknownCommand));
+        assert_eq!(parse("C@P LS\r\n"), Err(ErrorType::UnknownCommand));
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::UnknownCommand(UnknownCommandReply {
+                    target_nick: Some(Nick("tom".to_string())),
+                    command: "CAP".to_string(),
+                })
+            ),
+            ":iris-server 421 tom CAP :Unknown command\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::UnknownCommand(UnknownCommandReply {
+                    target_nick: None,
+                    command: "CAP".to_string(),
+                })
+            ),
+            ":iris-server 421 * CAP :Unknown command\r\n"
+        );
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct WelcomeReply {
-    pub target_nick: Nick,
-    pub message: String,
-}
+    #[test]
+    fn test_sanick() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("SANICK tom thomas\r\n"),
+            Ok(Message::SaNick(SaNickMsg {
+                nick: Nick("tom".to_string()),
+                new_nick: Nick("thomas".to_string()),
+            }))
+        );
+        assert_eq!(parse("SANICK tom\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(
+            parse("SANICK tom 9lives\r\n"),
+            Err(ErrorType::ErroneousNickname)
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Nick(NickReply {
+                    sender: user_prefix("tom"),
+                    new_nick: Nick("thomas".to_string()),
+                })
+            ),
+            ":tom!tom@127.0.0.1 NICK thomas\r\n"
+        );
+    }
 
-/// Every possible reply to a message.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Reply {
-    Pong(String),
-    Welcome(WelcomeReply),
-    PrivMsg(PrivReply),
-    Join(JoinReply),
-    Part(PartReply),
-    Error(ErrorType),
-    Quit(QuitReply),
-}
+    #[test]
+    fn test_topic() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let topic = |topic: Option<&str>| {
+            Ok(Message::Topic(TopicMsg {
+                channel: Channel("#rust".to_string()),
+                topic: topic.map(str::to_string),
+            }))
+        };
+        assert_eq!(parse("TOPIC #rust\r\n"), topic(None));
+        assert_eq!(
+            parse("TOPIC #rust :All things Rust\r\n"),
+            topic(Some("All things Rust"))
+        );
+        assert_eq!(parse("TOPIC #rust :\r\n"), topic(Some("")));
+        assert_eq!(parse("TOPIC\r\n"), Err(ErrorType::NeedMoreParams));
 
-impl std::fmt::Display for Reply {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        match self {
-            Reply::Pong(p) => write!(fmt, "PONG :{p}\r\n"),
-            Reply::Welcome(r) => {
-                let nick = &r.target_nick;
-                let message = &r.message;
-                write!(fmt, ":{SERVER_NAME} 001 {nick} :{message}\r\n")
-            }
-            Reply::PrivMsg(r) => {
-                let nick = &r.message.target;
-                let message = &r.message.message;
-                let from = &r.sender_nick;
-                write!(fmt, ":{from} PRIVMSG {nick} :{message}\r\n")
-            }
-            Reply::Error(e) => {
-                write!(fmt, ":{SERVER_NAME} {e}\r\n")
-            }
-            Reply::Join(r) => {
-                let sender = &r.sender_nick;
-                let channel = &r.message.channel;
-                write!(fmt, ":{sender} JOIN {channel}\r\n")
-            }
-            Reply::Part(r) => {
-                let sender = &r.sender_nick;
-                let channel = &r.message.channel;
-                write!(fmt, ":{sender} PART {channel}\r\n")
-            }
-            Reply::Quit(r) => {
-                let sender = &r.sender_nick.to_string();
-                let message = &r.message.message.as_ref().unwrap_or(sender);
-                write!(fmt, ":{sender} QUIT :{message}\r\n")
-            }
-        }
+        let topic_is = |topic| {
+            format!(
+                "{}",
+                Reply::TopicIs(TopicIsReply {
+                    target_nick: Nick("tom".to_string()),
+                    channel: Channel("#rust".to_string()),
+                    topic,
+                })
+            )
+        };
+        assert_eq!(
+            topic_is(None),
+            ":iris-server 331 tom #rust :No topic is set\r\n"
+        );
+        assert_eq!(
+            topic_is(Some(Topic {
+                text: "All things Rust".to_string(),
+                set_by: Nick("ann".to_string()),
+                set_at: 1697385600,
+            })),
+            ":iris-server 332 tom #rust :All things Rust\r\n\
+             :iris-server 333 tom #rust ann 1697385600\r\n"
+        );
     }
-}
 
-mod tests {
-    #[allow(unused_imports)]
-    use super::*;
+    #[test]
+    fn test_names() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("NAMES #rust\r\n"),
+            Ok(Message::Names(NamesMsg {
+                channel: Some(Channel("#rust".to_string())),
+            }))
+        );
+        assert_eq!(
+            parse("NAMES\r\n"),
+            Ok(Message::Names(NamesMsg { channel: None }))
+        );
+        assert_eq!(parse("NAMES rust\r\n"), Err(ErrorType::NoSuchChannel));
+
+        // Long lists are split so that no line is too long to send
+        let names: Vec<String> = (0..100).map(|index| format!("@member{index}")).collect();
+        let reply = format!(
+            "{}",
+            Reply::Names(NamesReply {
+                target_nick: Nick("tom".to_string()),
+                channel: Channel("#rust".to_string()),
+                names: names.clone(),
+            })
+        );
+        let lines: Vec<&str> = reply.split_terminator("\r\n").collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() + 2 <= MAX_LINE_LEN));
+        let listed: Vec<&str> = lines
+            .iter()
+            .flat_map(|line| {
+                line.strip_prefix(":iris-server 353 tom = #rust :")
+                    .unwrap()
+                    .split(' ')
+            })
+            .collect();
+        assert_eq!(listed, names);
+    }
 
     #[test]
-    fn test_ping() {
+    fn test_list() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let list = |channels: &[&str], min_members| {
+            Ok(Message::List(ListMsg {
+                channels: channels
+                    .iter()
+                    .map(|channel| Channel(channel.to_string()))
+                    .collect(),
+                min_members,
+            }))
+        };
+        assert_eq!(parse("LIST\r\n"), list(&[], None));
+        assert_eq!(parse("LIST #rust,#go\r\n"), list(&["#rust", "#go"], None));
+        assert_eq!(parse("LIST >10\r\n"), list(&[], Some(10)));
+        assert_eq!(parse("LIST #rust >2\r\n"), list(&["#rust"], Some(2)));
+        assert_eq!(parse("LIST >many\r\n"), list(&[], None));
+        assert_eq!(parse("LIST #rust,go\r\n"), Err(ErrorType::NoSuchChannel));
+
         assert_eq!(
+            format!(
+                "{}",
+                Reply::List(ListReply {
+                    target_nick: Nick("tom".to_string()),
+                    entries: vec![
+                        ListEntry {
+                            channel: Channel("#go".to_string()),
+                            members: 1,
+                            topic: String::new(),
+                        },
+                        ListEntry {
+                            channel: Channel("#rust".to_string()),
+                            members: 3,
+                            topic: "All things Rust".to_string(),
+                        },
+                    ],
+                })
+            ),
+            ":iris-server 321 tom Channel :Users  Name\r\n\
+             :iris-server 322 tom #go 1 :\r\n\
+             :iris-server 322 tom #rust 3 :All things Rust\r\n\
+             :iris-server 323 tom :End of /LIST\r\n"
+        );
+    }
+
+    #[test]
+    fn test_who() {
+        let parse = |message| {
             ParsedMessage::try_from(UnparsedMessage {
-                message: "PING :host-name with space\r\n",
-                sender_nick: Nick("Person".to_string())
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
             })
-            .unwrap()
-            .message,
-            Message::Ping("host-name with space".to_string())
-        )
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("WHO #rust\r\n"),
+            Ok(Message::Who(WhoMsg {
+                mask: "#rust".to_string()
+            }))
+        );
+        assert_eq!(parse("WHO\r\n"), Err(ErrorType::NeedMoreParams));
+
+        let entry = |nick: &str, oper, away, status| WhoEntry {
+            channel: "#rust".to_string(),
+            nick: Nick(nick.to_string()),
+            user: format!("~{nick}"),
+            host: "127.0.0.1".to_string(),
+            oper,
+            away,
+            status,
+            real_name: format!("{nick} smith"),
+        };
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Who(WhoReply {
+                    target_nick: Nick("tom".to_string()),
+                    mask: "#rust".to_string(),
+                    entries: vec![
+                        entry("ann", true, false, MemberStatus::Op),
+                        entry("bob", false, true, MemberStatus::Regular),
+                    ],
+                })
+            ),
+            ":iris-server 352 tom #rust ~ann 127.0.0.1 iris-server ann H*@ :0 ann smith\r\n\
+             :iris-server 352 tom #rust ~bob 127.0.0.1 iris-server bob G :0 bob smith\r\n\
+             :iris-server 315 tom #rust :End of /WHO list\r\n"
+        );
     }
 
     #[test]
-    fn test_privmsg() {
+    fn test_whois() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let whois = |nick: &str| {
+            Ok(Message::Whois(WhoisMsg {
+                nick: Nick(nick.to_string()),
+            }))
+        };
+        assert_eq!(parse("WHOIS tom\r\n"), whois("tom"));
+        assert_eq!(parse("WHOIS iris-server tom\r\n"), whois("tom"));
+        assert_eq!(parse("WHOIS\r\n"), Err(ErrorType::NoNickNameGiven));
+
+        let reply = |user| {
+            format!(
+                "{}",
+                Reply::Whois(WhoisReply {
+                    target_nick: Nick("ann".to_string()),
+                    nick: Nick("tom".to_string()),
+                    user,
+                })
+            )
+        };
+        assert_eq!(
+            reply(Some(WhoisUser {
+                user: "tsmith".to_string(),
+                host: "127.0.0.1".to_string(),
+                real_name: "Tom Smith".to_string(),
+                channels: vec!["@#go".to_string(), "#rust".to_string()],
+                away: Some("Gone to lunch".to_string()),
+            })),
+            ":iris-server 311 ann tom tsmith 127.0.0.1 * :Tom Smith\r\n\
+             :iris-server 319 ann tom :@#go #rust\r\n\
+             :iris-server 301 ann tom :Gone to lunch\r\n\
+             :iris-server 318 ann tom :End of /WHOIS list\r\n"
+        );
         assert_eq!(
+            reply(None),
+            ":iris-server 401 :No such nick/channel\r\n\
+             :iris-server 318 ann tom :End of /WHOIS list\r\n"
+        );
+    }
+
+    #[test]
+    fn test_away() {
+        let parse = |message| {
             ParsedMessage::try_from(UnparsedMessage {
-                message: "PRIVMSG tom :Hi Tom, how are you?\r\n",
-                sender_nick: Nick("Person".to_string())
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
             })
-            .unwrap()
-            .message,
-            Message::PrivMsg(PrivMsg {
-                target: Target::User(Nick("tom".to_string())),
-                message: "Hi Tom, how are you?".to_string()
+            .map(|parsed| parsed.message)
+        };
+        let away = |message: Option<&str>| {
+            Ok(Message::Away(AwayMsg {
+                message: message.map(str::to_string),
+            }))
+        };
+        assert_eq!(
+            parse("AWAY :Gone to lunch\r\n"),
+            away(Some("Gone to lunch"))
+        );
+        assert_eq!(parse("AWAY\r\n"), away(None));
+        assert_eq!(parse("AWAY :\r\n"), away(None));
+
+        let tom = Nick("tom".to_string());
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Away(AwayReply {
+                    target_nick: tom.clone(),
+                    nick: Nick("ann".to_string()),
+                    message: "Gone to lunch".to_string(),
+                })
+            ),
+            ":iris-server 301 tom ann :Gone to lunch\r\n"
+        );
+        let status = |away| {
+            format!(
+                "{}",
+                Reply::AwayStatus(AwayStatusReply {
+                    target_nick: tom.clone(),
+                    away,
+                })
+            )
+        };
+        assert_eq!(
+            status(true),
+            ":iris-server 306 tom :You have been marked as being away\r\n"
+        );
+        assert_eq!(
+            status(false),
+            ":iris-server 305 tom :You are no longer marked as being away\r\n"
+        );
+    }
+
+    #[test]
+    fn test_motd() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "MOTD\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
             })
-        )
+            .map(|parsed| parsed.message),
+            Ok(Message::Motd)
+        );
+
+        let long_line = ["word"; 120].join(" ");
+        let motd = format!(
+            "{}",
+            Reply::Motd(MotdReply {
+                target_nick: Nick("tom".to_string()),
+                lines: vec!["Welcome!".to_string(), String::new(), long_line.clone()],
+            })
+        );
+        let lines: Vec<_> = motd.split_inclusive("\r\n").collect();
+        assert_eq!(
+            lines[..3],
+            [
+                ":iris-server 375 tom :- iris-server Message of the day - \r\n",
+                ":iris-server 372 tom :- Welcome!\r\n",
+                ":iris-server 372 tom :- \r\n",
+            ]
+        );
+        assert_eq!(
+            lines.last(),
+            Some(&":iris-server 376 tom :End of /MOTD command.\r\n")
+        );
+        // The long line is wrapped between words, with nothing lost
+        let wrapped = &lines[3..lines.len() - 1];
+        assert_eq!(wrapped.len(), 2);
+        assert!(wrapped
+            .iter()
+            .all(|line| line.len() <= MAX_LINE_LEN && line.ends_with("word\r\n")));
+        let text: Vec<_> = wrapped
+            .iter()
+            .map(|line| {
+                line.trim_start_matches(":iris-server 372 tom :- ")
+                    .trim_end()
+            })
+            .collect();
+        assert_eq!(text.join(" "), long_line);
     }
 
     #[test]
-    fn test_nick() {
+    fn test_lusers() {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
-                message: "NICK tfpk\r\n",
-                sender_nick: Nick("Person".to_string())
+                message: "LUSERS\r\n",
+                sender_nick: Some(Nick("Person".to_string()))
             })
-            .unwrap()
-            .message,
-            Message::Nick(NickMsg {
-                nick: Nick("tfpk".to_string())
+            .map(|parsed| parsed.message),
+            Ok(Message::Lusers)
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Lusers(LusersReply {
+                    target_nick: Nick("tom".to_string()),
+                    users: 2,
+                    invisible: 1,
+                    max_users: 3,
+                    channels: 1,
+                })
+            ),
+            ":iris-server 251 tom :There are 1 users and 1 invisible on 1 servers\r\n\
+             :iris-server 254 tom 1 :channels formed\r\n\
+             :iris-server 255 tom :I have 2 clients and 0 servers\r\n\
+             :iris-server 265 tom 2 3 :Current local users 2, max 3\r\n\
+             :iris-server 266 tom 2 3 :Current global users 2, max 3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_version_time_info() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(parse("VERSION\r\n"), Ok(Message::Version));
+        assert_eq!(parse("TIME\r\n"), Ok(Message::Time));
+        assert_eq!(parse("INFO\r\n"), Ok(Message::Info));
+
+        let tom = || Nick("tom".to_string());
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Version(VersionReply {
+                    target_nick: tom(),
+                    version: "1.2.3".to_string(),
+                })
+            ),
+            ":iris-server 351 tom iris-1.2.3 iris-server :\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Time(TimeReply {
+                    target_nick: tom(),
+                    time: "Thu, 15 Oct 2026 12:00:00 +0000".to_string(),
+                })
+            ),
+            ":iris-server 391 tom iris-server :Thu, 15 Oct 2026 12:00:00 +0000\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Info(InfoReply {
+                    target_nick: tom(),
+                    lines: vec!["iris".to_string(), "Hello".to_string()],
+                })
+            ),
+            ":iris-server 371 tom :iris\r\n\
+             :iris-server 371 tom :Hello\r\n\
+             :iris-server 374 tom :End of /INFO list.\r\n"
+        );
+        assert_eq!(
+            ErrorType::NotRegistered.to_string(),
+            ":iris-server 451 :You have not registered"
+        );
+    }
+
+    #[test]
+    fn test_ison_userhost() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
             })
+            .map(|parsed| parsed.message)
+        };
+        let nicks = |nicks: &[&str]| nicks.iter().map(|nick| Nick(nick.to_string())).collect();
+        assert_eq!(
+            parse("ISON tom ann\r\n"),
+            Ok(Message::Ison(IsonMsg {
+                nicks: nicks(&["tom", "ann"])
+            }))
+        );
+        // Clients often send the list as one trailing parameter
+        assert_eq!(
+            parse("ISON :tom ann\r\n"),
+            Ok(Message::Ison(IsonMsg {
+                nicks: nicks(&["tom", "ann"])
+            }))
+        );
+        assert_eq!(
+            parse("USERHOST a b c d e f\r\n"),
+            Ok(Message::Userhost(UserhostMsg {
+                nicks: nicks(&["a", "b", "c", "d", "e"])
+            }))
+        );
+        assert_eq!(parse("ISON\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("USERHOST :\r\n"), Err(ErrorType::NeedMoreParams));
+
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Ison(IsonReply {
+                    target_nick: Nick("tom".to_string()),
+                    nicks: nicks(&["tom", "ann"]),
+                })
+            ),
+            ":iris-server 303 tom :tom ann\r\n"
         );
         assert_eq!(
+            format!(
+                "{}",
+                Reply::Userhost(UserhostReply {
+                    target_nick: Nick("tom".to_string()),
+                    entries: vec![
+                        UserhostEntry {
+                            nick: Nick("ann".to_string()),
+                            user: "annie".to_string(),
+                            host: "127.0.0.1".to_string(),
+                            oper: true,
+                            away: false,
+                        },
+                        UserhostEntry {
+                            nick: Nick("bob".to_string()),
+                            user: "bob".to_string(),
+                            host: "127.0.0.1".to_string(),
+                            oper: false,
+                            away: true,
+                        },
+                    ],
+                })
+            ),
+            ":iris-server 302 tom :ann*=+annie@127.0.0.1 bob=-bob@127.0.0.1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_oper() {
+        let parse = |message| {
             ParsedMessage::try_from(UnparsedMessage {
-                message: "NICK tfpkasdfasdfasdf\r\n",
-                sender_nick: Nick("Person".to_string())
-            }),
-            Err(ErrorType::ErroneousNickname)
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("OPER admin hunter2\r\n"),
+            Ok(Message::Oper(OperMsg {
+                name: "admin".to_string(),
+                password: "hunter2".to_string(),
+            }))
+        );
+        assert_eq!(parse("OPER admin\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::YoureOper(YoureOperReply {
+                    target_nick: Nick("tom".to_string()),
+                })
+            ),
+            ":iris-server 381 tom :You are now an IRC operator\r\n"
+        );
+    }
+
+    #[test]
+    fn test_kill() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        assert_eq!(
+            parse("KILL tom :Spamming\r\n"),
+            Ok(Message::Kill(KillMsg {
+                nick: Nick("tom".to_string()),
+                reason: Some("Spamming".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse("KILL tom\r\n"),
+            Ok(Message::Kill(KillMsg {
+                nick: Nick("tom".to_string()),
+                reason: None,
+            }))
+        );
+        assert_eq!(parse("KILL\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::ClosingLink(ClosingLinkReply {
+                    target_nick: Some(Nick("tom".to_string())),
+                    reason: "Killed (ann (Spamming))".to_string(),
+                })
+            ),
+            "ERROR :Closing Link: tom (Killed (ann (Spamming)))\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::ClosingLink(ClosingLinkReply {
+                    target_nick: None,
+                    reason: "Bad password".to_string(),
+                })
+            ),
+            "ERROR :Closing Link: * (Bad password)\r\n"
+        );
+    }
+
+    #[test]
+    fn test_wallops() {
+        let parse = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Some(Nick("Person".to_string())),
+            })
+            .map(|parsed| parsed.message)
+        };
+        let wallops_msg = WallopsMsg {
+            message: "Restarting soon".to_string(),
+        };
+        assert_eq!(
+            parse("WALLOPS :Restarting soon\r\n"),
+            Ok(Message::Wallops(wallops_msg.clone()))
+        );
+        assert_eq!(parse("WALLOPS\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(parse("WALLOPS :\r\n"), Err(ErrorType::NeedMoreParams));
+        assert_eq!(
+            parse("MODE tom +w\r\n"),
+            Ok(Message::UserMode(UserModeMsg {
+                nick: Nick("tom".to_string()),
+                changes: vec![UserModeChange {
+                    adding: true,
+                    mode: UserMode::Wallops
+                }],
+            }))
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Reply::Wallops(WallopsReply {
+                    message: wallops_msg,
+                    sender: user_prefix("ann"),
+                })
+            ),
+            ":ann!ann@127.0.0.1 WALLOPS :Restarting soon\r\n"
+        );
+    }
+
+    #[test]
+    fn test_relayed_text_is_cut_to_fit() {
+        // As long as a client can send, which the sender's prefix then lengthens
+        let text = "é".repeat((MAX_LINE_LEN - "PRIVMSG ann :\r\n".len()) / 2);
+        let reply = |text: &str| {
+            Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: Target::User(Nick("ann".to_string())),
+                    message: text.to_string(),
+                },
+                sender: user_prefix("tom"),
+            })
+            .to_string()
+        };
+        let line = reply(&text);
+        assert!(line.len() <= MAX_LINE_LEN);
+        assert!(line.starts_with(":tom!tom@127.0.0.1 PRIVMSG ann :éé"));
+        assert!(line.ends_with("é…\r\n"));
+
+        // Anything that fits is left alone
+        let fits = "x".repeat(MAX_LINE_LEN - ":tom!tom@127.0.0.1 PRIVMSG ann :\r\n".len());
+        assert_eq!(reply(&fits).len(), MAX_LINE_LEN);
+        assert!(reply(&fits).ends_with("x\r\n"));
+    }
+
+    #[test]
+    fn test_relayed_replies_carry_full_prefix() {
+        assert_eq!(user_prefix("tom").to_string(), "tom!tom@127.0.0.1");
+        assert_eq!(Prefix::server("iris-server").to_string(), "iris-server");
+
+        let channel = Channel("#rust".to_string());
+        let golden = [
+            (
+                Reply::PrivMsg(PrivReply {
+                    message: PrivMsg {
+                        target: Target::User(Nick("ann".to_string())),
+                        message: "hi".to_string(),
+                    },
+                    sender: user_prefix("tom"),
+                }),
+                ":tom!tom@127.0.0.1 PRIVMSG ann :hi\r\n",
+            ),
+            (
+                Reply::Join(JoinReply {
+                    message: JoinMsg {
+                        channel: channel.clone(),
+                        key: None,
+                    },
+                    sender: user_prefix("tom"),
+                }),
+                ":tom!tom@127.0.0.1 JOIN #rust\r\n",
+            ),
+            (
+                Reply::Quit(QuitReply {
+                    message: QuitMsg { message: None },
+                    sender: user_prefix("tom"),
+                }),
+                ":tom!tom@127.0.0.1 QUIT :tom\r\n",
+            ),
+            (
+                Reply::Topic(TopicReply {
+                    sender: user_prefix("tom"),
+                    channel: channel.clone(),
+                    topic: "Rust talk".to_string(),
+                }),
+                ":tom!tom@127.0.0.1 TOPIC #rust :Rust talk\r\n",
+            ),
+            (
+                Reply::Kick(KickReply {
+                    sender: user_prefix("tom"),
+                    channel: channel.clone(),
+                    kicked_nick: Nick("ann".to_string()),
+                    reason: "Spam".to_string(),
+                }),
+                ":tom!tom@127.0.0.1 KICK #rust ann :Spam\r\n",
+            ),
+            (
+                Reply::Kick(KickReply {
+                    sender: Prefix::server("iris-server"),
+                    channel,
+                    kicked_nick: Nick("ann".to_string()),
+                    reason: "Flooding".to_string(),
+                }),
+                ":iris-server KICK #rust ann :Flooding\r\n",
+            ),
+            (
+                Reply::UserMode(UserModeReply {
+                    message: UserModeMsg {
+                        nick: Nick("tom".to_string()),
+                        changes: vec![UserModeChange {
+                            adding: true,
+                            mode: UserMode::Wallops,
+                        }],
+                    },
+                    sender: user_prefix("tom"),
+                }),
+                ":tom!tom@127.0.0.1 MODE tom +w\r\n",
+            ),
+            (
+                Reply::CallerIdNotify(CallerIdNotifyReply {
+                    target_nick: Nick("ann".to_string()),
+                    sender: user_prefix("tom"),
+                }),
+                ":iris-server 718 ann tom tom@127.0.0.1 :is messaging you, and you have umode +g.\r\n",
+            ),
+        ];
+        for (reply, expected) in golden {
+            assert_eq!(reply.to_string(), expected);
+        }
     }
 }
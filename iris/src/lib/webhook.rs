@@ -0,0 +1,378 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    json::Json,
+    types::{Channel, Nick},
+};
+
+/// Events waiting for a webhook beyond this many push out the oldest.
+pub const QUEUE_CAPACITY: usize = 256;
+
+/// How many times delivery of an event is tried before it is dropped.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// How long to wait before the first retry. Each retry waits twice as long.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long connecting, sending or reading the response may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The kinds of event a webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Registered,
+    Quit,
+    ChannelCreated,
+    OperAction,
+    ServerStart,
+}
+
+impl EventKind {
+    /// The name used in configuration and in the JSON body.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Registered => "registered",
+            EventKind::Quit => "quit",
+            EventKind::ChannelCreated => "channel-created",
+            EventKind::OperAction => "oper-action",
+            EventKind::ServerStart => "server-start",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            EventKind::Registered,
+            EventKind::Quit,
+            EventKind::ChannelCreated,
+            EventKind::OperAction,
+            EventKind::ServerStart,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == value)
+        .ok_or_else(|| format!("unknown webhook event {value:?}"))
+    }
+}
+
+/// Something notable that happened on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Registered {
+        nick: Nick,
+    },
+    /// `reason` is only delivered to webhooks that opt in to quit contents.
+    Quit {
+        nick: Nick,
+        reason: String,
+    },
+    ChannelCreated {
+        channel: Channel,
+        nick: Nick,
+    },
+    /// An operator used their privileges, e.g. `MODE #rust +P`.
+    OperAction {
+        nick: Nick,
+        action: String,
+    },
+    ServerStart,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Registered { .. } => EventKind::Registered,
+            Event::Quit { .. } => EventKind::Quit,
+            Event::ChannelCreated { .. } => EventKind::ChannelCreated,
+            Event::OperAction { .. } => EventKind::OperAction,
+            Event::ServerStart => EventKind::ServerStart,
+        }
+    }
+
+    /// The JSON body posted for this event. Message contents are left out
+    /// unless `contents` is set.
+    pub fn to_json(&self, server_name: &str, timestamp: u128, contents: bool) -> String {
+        let mut fields = vec![
+            ("timestamp", Json::Number(timestamp as f64)),
+            ("event", Json::from(self.kind().name())),
+            ("server", Json::from(server_name)),
+        ];
+        match self {
+            Event::Registered { nick } => fields.push(("nick", Json::from(nick.to_string()))),
+            Event::Quit { nick, reason } => {
+                fields.push(("nick", Json::from(nick.to_string())));
+                if contents {
+                    fields.push(("reason", Json::from(reason.as_str())));
+                }
+            }
+            Event::ChannelCreated { channel, nick } => {
+                fields.push(("channel", Json::from(channel.to_string())));
+                fields.push(("nick", Json::from(nick.to_string())));
+            }
+            Event::OperAction { nick, action } => {
+                fields.push(("nick", Json::from(nick.to_string())));
+                fields.push(("action", Json::from(action.as_str())));
+            }
+            Event::ServerStart => {}
+        }
+        Json::object(fields).to_string()
+    }
+}
+
+/// Where a webhook posts to. Only plain `http://` URLs are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let rest = value
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{value:?} is not an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| format!("bad port in {value:?}"))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {value:?}"));
+        }
+        Ok(WebhookUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for WebhookUrl {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// One endpoint to notify, and what about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    pub url: WebhookUrl,
+    /// The events delivered. Others are never queued.
+    pub events: Vec<EventKind>,
+    /// The events whose message contents, such as a quit reason, are
+    /// included. Contents are left out of every other event.
+    pub contents: Vec<EventKind>,
+}
+
+/// Events waiting for one webhook's worker.
+#[derive(Default)]
+struct EventQueue {
+    events: Mutex<VecDeque<(u128, Event)>>,
+    ready: Condvar,
+}
+
+impl EventQueue {
+    /// Queues `event` without ever blocking on delivery, dropping the
+    /// oldest waiting event if the queue is full.
+    fn push(&self, timestamp: u128, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= QUEUE_CAPACITY {
+            events.pop_front();
+            log::warn!("Webhook queue full, dropped the oldest event");
+        }
+        events.push_back((timestamp, event));
+        self.ready.notify_one();
+    }
+
+    /// Waits for the next event.
+    fn pop(&self) -> (u128, Event) {
+        let events = self.events.lock().unwrap();
+        let mut events = self
+            .ready
+            .wait_while(events, |events| events.is_empty())
+            .unwrap();
+        events.pop_front().unwrap()
+    }
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    queue: Arc<EventQueue>,
+}
+
+/// Delivers server events to the configured webhooks. Each webhook has its
+/// own queue and worker thread, so a slow endpoint only holds up itself.
+#[derive(Default)]
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+}
+
+impl Webhooks {
+    /// Starts a worker for each webhook.
+    pub fn launch(configs: &[WebhookConfig], server_name: &str) -> Self {
+        let hooks = configs
+            .iter()
+            .map(|config| {
+                let queue = Arc::new(EventQueue::default());
+                let worker_queue = queue.clone();
+                let worker_config = config.clone();
+                let server_name = server_name.to_string();
+                thread::spawn(move || deliver_events(&worker_config, &worker_queue, &server_name));
+                Webhook {
+                    config: config.clone(),
+                    queue,
+                }
+            })
+            .collect();
+        Self { hooks }
+    }
+
+    /// Queues `event` for every webhook that subscribes to it. Never waits
+    /// on delivery.
+    pub fn notify(&self, event: Event) {
+        let kind = event.kind();
+        let timestamp = now_millis();
+        for hook in &self.hooks {
+            if hook.config.events.contains(&kind) {
+                hook.queue.push(timestamp, event.clone());
+            }
+        }
+    }
+}
+
+/// Posts events from `queue` to the webhook until the process exits.
+fn deliver_events(config: &WebhookConfig, queue: &EventQueue, server_name: &str) {
+    loop {
+        let (timestamp, event) = queue.pop();
+        let contents = config.contents.contains(&event.kind());
+        let body = event.to_json(server_name, timestamp, contents);
+
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match post(&config.url, &body) {
+                Ok(status) if (200..300).contains(&status) => break,
+                Ok(status) if (400..500).contains(&status) => {
+                    log::warn!("Webhook {} refused an event: {}", config.url, status);
+                    break;
+                }
+                Ok(status) => log::warn!("Webhook {} failed: {}", config.url, status),
+                Err(err) => log::warn!("Webhook {} failed: {}", config.url, err),
+            }
+            if attempt == MAX_ATTEMPTS {
+                log::error!("Gave up delivering an event to webhook {}", config.url);
+            } else {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+/// Posts `body` as JSON to `url`, returning the response's status code.
+fn post(url: &WebhookUrl, body: &str) -> io::Result<u16> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host did not resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.host,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad HTTP response"))
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            "http://127.0.0.1:9000/hooks/irc".parse(),
+            Ok(WebhookUrl {
+                host: "127.0.0.1".to_string(),
+                port: 9000,
+                path: "/hooks/irc".to_string(),
+            })
+        );
+        assert_eq!(
+            "http://example.com".parse(),
+            Ok(WebhookUrl {
+                host: "example.com".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            })
+        );
+        assert!("https://example.com/".parse::<WebhookUrl>().is_err());
+        assert!("http://:80/".parse::<WebhookUrl>().is_err());
+        assert!("http://example.com:http/".parse::<WebhookUrl>().is_err());
+    }
+
+    #[test]
+    fn test_event_json() {
+        let quit = Event::Quit {
+            nick: Nick("tom".to_string()),
+            reason: "said \"bye\"\n".to_string(),
+        };
+        assert_eq!(
+            quit.to_json("iris-server", 1697385600123, false),
+            r#"{"timestamp":1697385600123,"event":"quit","server":"iris-server","nick":"tom"}"#
+        );
+        assert_eq!(
+            quit.to_json("iris-server", 1697385600123, true),
+            r#"{"timestamp":1697385600123,"event":"quit","server":"iris-server","nick":"tom","reason":"said \"bye\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn test_queue_drops_oldest() {
+        let queue = EventQueue::default();
+        for timestamp in 0..QUEUE_CAPACITY as u128 + 2 {
+            queue.push(timestamp, Event::ServerStart);
+        }
+        assert_eq!(queue.pop().0, 2);
+        assert_eq!(queue.events.lock().unwrap().len(), QUEUE_CAPACITY - 1);
+    }
+}
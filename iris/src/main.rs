@@ -1,214 +1,597 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use iris_lib::{
-    connect::{ConnectionError, ConnectionManager, ConnectionWrite},
-    helpers::{
-        join_channel, part_channel, private_msg_channel, private_msg_user, quit_server,
-        write_to_conn,
-    },
-    types::{
-        Channel, ErrorType, Message, Nick, ParsedMessage, Reply, Target, UnparsedMessage,
-        WelcomeReply, SERVER_NAME,
+    client::check,
+    clock::find_local_offset,
+    config::{
+        parse_alias, parse_operators, validate_server_name, Operator, RepeatFilter, ServerConfig,
+        DEFAULT_FANOUT_THRESHOLD, DEFAULT_FANOUT_WORKERS, DEFAULT_MAX_CHANNELS, DEFAULT_REASON_LEN,
+        DEFAULT_SEND_QUEUE_LEN, DEFAULT_TOPIC_LEN,
     },
+    connect::ConnectionManager,
+    server::{run_server, serve, ServerState},
+    snapshot::Snapshot,
+    transcript::{read_transcript, replay, TranscriptConfig},
+    types::SERVER_NAME,
+    webhook::{EventKind, WebhookConfig, WebhookUrl},
 };
 use simple_logger::SimpleLogger;
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread;
-use std::{collections::HashMap, net::IpAddr};
+use std::time::Duration;
+
+/// An IRC server. It runs when no command is given, as with `iris serve`.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    arguments: Arguments,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server.
+    Serve(Box<Arguments>),
+    /// Check a running server answers, exiting 0 if it does. Meant for
+    /// container healthchecks and deploy scripts.
+    Check(CheckArguments),
+}
+
+#[derive(Parser)]
+struct CheckArguments {
+    /// The server to check.
+    #[clap(default_value = "127.0.0.1:6991")]
+    address: SocketAddr,
 
+    /// Seconds the whole check may take before it counts as a failure.
+    #[clap(long, default_value = "5")]
+    timeout_secs: u64,
+}
+
+/// Every argument can also be set through the `IRIS_*` environment variable
+/// named alongside it. Arguments given on the command line take precedence,
+/// then the environment, then the defaults.
 #[derive(Parser)]
 struct Arguments {
-    #[clap(default_value = "127.0.0.1")]
+    #[clap(env = "IRIS_BIND", default_value = "127.0.0.1")]
     ip_address: IpAddr,
 
-    #[clap(default_value = "6991")]
+    /// 0 lets the operating system pick a free port, which is reported once
+    /// the server is listening.
+    #[clap(env = "IRIS_PORT", default_value = "6991")]
     port: u16,
+
+    /// Once listening, write the port the server is on to this file, so
+    /// scripts can find it when launching on port 0.
+    #[clap(long, env = "IRIS_PORT_FILE")]
+    port_file: Option<PathBuf>,
+
+    /// What the server calls itself in everything it sends. Must look like
+    /// a hostname.
+    #[clap(long, env = "IRIS_SERVER_NAME", default_value = SERVER_NAME, value_parser = validate_server_name)]
+    server_name: String,
+
+    /// Secret used to cloak user hostnames. Real addresses are shown when unset.
+    /// Prefer the environment variable so the secret stays out of the process list.
+    #[clap(long, env = "IRIS_CLOAK_SECRET", hide_env_values = true)]
+    cloak_secret: Option<String>,
+
+    /// Seconds a departed user's nick stays reserved for their address.
+    #[clap(long, env = "IRIS_NICK_HOLD_SECS", default_value = "60")]
+    nick_hold_secs: u64,
+
+    /// Identical channel messages allowed in a row before further copies are
+    /// dropped. The repetition filter is off when unset.
+    #[clap(long, env = "IRIS_REPEAT_LIMIT")]
+    repeat_limit: Option<u32>,
+
+    /// Identical channel messages in a row before the sender is kicked.
+    #[clap(long, env = "IRIS_REPEAT_KICK_LIMIT", default_value = "10")]
+    repeat_kick_limit: u32,
+
+    /// Seconds after which a run of identical messages stops being counted.
+    #[clap(long, env = "IRIS_REPEAT_WINDOW_SECS", default_value = "30")]
+    repeat_window_secs: u64,
+
+    /// Seconds a client can go without reading before writes to them give up
+    /// and they are disconnected. 0 waits forever.
+    #[clap(long, env = "IRIS_WRITE_TIMEOUT_SECS", default_value = "10")]
+    write_timeout_secs: u64,
+
+    /// Messages that may wait to be written to a client before they are
+    /// disconnected.
+    #[clap(long, env = "IRIS_SEND_QUEUE_LEN", default_value_t = DEFAULT_SEND_QUEUE_LEN)]
+    send_queue_len: usize,
+
+    /// Seconds a client can send nothing before the server pings them. 0
+    /// never pings anyone.
+    #[clap(long, env = "IRIS_PING_INTERVAL_SECS", default_value = "60")]
+    ping_interval_secs: u64,
+
+    /// Seconds a pinged client has to answer before they are disconnected.
+    #[clap(long, env = "IRIS_PING_TIMEOUT_SECS", default_value = "60")]
+    ping_timeout_secs: u64,
+
+    /// Seconds a client has to register after connecting before they are
+    /// disconnected. 0 lets them take as long as they like.
+    #[clap(long, env = "IRIS_REGISTRATION_TIMEOUT_SECS", default_value = "30")]
+    registration_timeout_secs: u64,
+
+    /// Longest QUIT, KICK or PART reason relayed, in bytes. Longer ones are
+    /// cut short with an ellipsis.
+    #[clap(long, env = "IRIS_REASON_LEN", default_value_t = DEFAULT_REASON_LEN)]
+    reason_len: usize,
+
+    /// Longest channel topic kept, in bytes. Longer ones are cut short with
+    /// an ellipsis.
+    #[clap(long, env = "IRIS_TOPIC_LEN", default_value_t = DEFAULT_TOPIC_LEN)]
+    topic_len: usize,
+
+    /// Channel messages to at least this many members are sent by several
+    /// workers at once.
+    #[clap(long, env = "IRIS_FANOUT_THRESHOLD", default_value_t = DEFAULT_FANOUT_THRESHOLD)]
+    fanout_threshold: usize,
+
+    /// How many workers send a large channel message. 1 sends every message
+    /// from the sender's own thread.
+    #[clap(long, env = "IRIS_FANOUT_WORKERS", default_value_t = DEFAULT_FANOUT_WORKERS)]
+    fanout_workers: usize,
+
+    /// Directory to record a transcript of every connection in.
+    #[clap(long, env = "IRIS_TRANSCRIPT")]
+    transcript: Option<PathBuf>,
+
+    /// Leave message text and passwords out of transcripts even with
+    /// --log-message-contents.
+    #[clap(long, env = "IRIS_TRANSCRIPT_REDACT")]
+    transcript_redact: bool,
+
+    /// Show what people say in PRIVMSG and NOTICE in logs and transcripts.
+    /// By default only the length of each message is.
+    #[clap(long, env = "IRIS_LOG_MESSAGE_CONTENTS")]
+    log_message_contents: bool,
+
+    /// http:// URLs to POST a JSON body to whenever a subscribed event
+    /// happens. Comma-separated in the environment.
+    #[clap(
+        long,
+        env = "IRIS_WEBHOOKS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    webhook: Vec<WebhookUrl>,
+
+    /// Events sent to webhooks: registered, quit, channel-created,
+    /// oper-action and server-start.
+    #[clap(
+        long,
+        env = "IRIS_WEBHOOK_EVENTS",
+        value_delimiter = ',',
+        default_value = "registered,quit,channel-created,oper-action,server-start"
+    )]
+    webhook_events: Vec<EventKind>,
+
+    /// Events whose message contents, such as quit reasons, are sent to
+    /// webhooks. Contents are left out of every other event.
+    #[clap(long, env = "IRIS_WEBHOOK_CONTENTS", value_delimiter = ',')]
+    webhook_contents: Vec<EventKind>,
+
+    /// File operators can save a snapshot of the server to with SNAPSHOT,
+    /// for a new server to --restore from.
+    #[clap(long, env = "IRIS_SNAPSHOT")]
+    snapshot: Option<PathBuf>,
+
+    /// Start from a snapshot saved by an earlier server. A snapshot that
+    /// can't be read stops the server from starting.
+    #[clap(long, env = "IRIS_RESTORE", conflicts_with = "replay")]
+    restore: Option<PathBuf>,
+
+    /// Extra names for commands, as NAME=COMMAND, such as J=JOIN. MSG and
+    /// LEAVE are always available. Comma-separated in the environment.
+    #[clap(long, env = "IRIS_ALIASES", value_delimiter = ',', value_parser = parse_alias)]
+    alias: Vec<(String, String)>,
+
+    /// Hold operators to user mode +R too. By default they can message any
+    /// user, identified or not.
+    #[clap(long, env = "IRIS_STRICT_REGISTERED_ONLY")]
+    strict_registered_only: bool,
+
+    /// Most channels that may exist at once. JOIN won't create more.
+    #[clap(long, env = "IRIS_MAX_CHANNELS", default_value_t = DEFAULT_MAX_CHANNELS)]
+    max_channels: usize,
+
+    /// Hold operators to --max-channels too. By default they can always
+    /// create channels.
+    #[clap(long, env = "IRIS_STRICT_MAX_CHANNELS")]
+    strict_max_channels: bool,
+
+    /// File holding the message of the day, sent to everyone as they
+    /// register. It is read once, at startup; clients are told there is no
+    /// message of the day if it can't be.
+    #[clap(long, env = "IRIS_MOTD")]
+    motd: Option<PathBuf>,
+
+    /// File of operators allowed to OPER, one `name password` per line. A
+    /// password can be given as `sha256:` and its SHA-256 in hex. It is read
+    /// once, at startup; nobody can become an operator if it can't be.
+    #[clap(long, env = "IRIS_OPERS")]
+    opers: Option<PathBuf>,
+
+    /// Password clients must send with PASS before registering. Clients
+    /// are disconnected after three wrong or missing passwords.
+    #[clap(long, env = "IRIS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Replay these transcripts against a fresh server, report where its
+    /// replies differ, and exit.
+    #[clap(long, num_args = 1.., conflicts_with = "transcript")]
+    replay: Vec<PathBuf>,
+}
+
+impl Arguments {
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            server_name: self.server_name.clone(),
+            cloak_secret: self.cloak_secret.clone(),
+            nick_hold: Duration::from_secs(self.nick_hold_secs),
+            repeat_filter: self.repeat_limit.map(|repeat_limit| RepeatFilter {
+                suppress_after: repeat_limit,
+                kick_after: self.repeat_kick_limit.max(repeat_limit),
+                window: Duration::from_secs(self.repeat_window_secs),
+            }),
+            transcript: self.transcript.clone().map(|dir| TranscriptConfig {
+                dir,
+                redact: self.transcript_redact,
+            }),
+            write_timeout: Some(Duration::from_secs(self.write_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+            send_queue_len: self.send_queue_len,
+            ping_interval: Some(Duration::from_secs(self.ping_interval_secs))
+                .filter(|interval| !interval.is_zero()),
+            ping_timeout: Duration::from_secs(self.ping_timeout_secs),
+            registration_timeout: Some(Duration::from_secs(self.registration_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+            reason_len: self.reason_len,
+            topic_len: self.topic_len,
+            fanout_threshold: self.fanout_threshold,
+            fanout_workers: self.fanout_workers,
+            webhooks: self
+                .webhook
+                .iter()
+                .map(|url| WebhookConfig {
+                    url: url.clone(),
+                    events: self.webhook_events.clone(),
+                    contents: self.webhook_contents.clone(),
+                })
+                .collect(),
+            snapshot: self.snapshot.clone(),
+            aliases: self.alias.iter().cloned().collect(),
+            registered_only_exempts_opers: !self.strict_registered_only,
+            max_channels: self.max_channels,
+            max_channels_exempts_opers: !self.strict_max_channels,
+            log_message_contents: self.log_message_contents,
+            motd: self.motd.as_deref().and_then(read_motd),
+            operators: self.opers.as_deref().map_or_else(Vec::new, read_operators),
+            password: self.password.clone(),
+        }
+    }
+}
+
+/// Reads the message of the day from `path`, a line at a time.
+fn read_motd(path: &Path) -> Option<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(motd) => Some(motd.lines().map(str::to_string).collect()),
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Reads the operators allowed to OPER from `path`.
+fn read_operators(path: &Path) -> Vec<Operator> {
+    let operators = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| parse_operators(&text));
+    operators.unwrap_or_else(|err| {
+        eprintln!("Unable to read {}: {}", path.display(), err);
+        Vec::new()
+    })
+}
+
+/// Replays `paths` against a fresh server on a free port, exiting with an
+/// error if the server replied differently from the transcripts.
+fn replay_transcripts(paths: &[PathBuf], config: ServerConfig) {
+    let transcripts = paths
+        .iter()
+        .map(|path| {
+            read_transcript(path).unwrap_or_else(|err| {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                process::exit(2);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let connection_manager = ConnectionManager::launch([127, 0, 0, 1], 0).unwrap_or_else(|err| {
+        eprintln!("Unable to replay: {}", err);
+        process::exit(2);
+    });
+    let address = connection_manager.local_addr();
+    thread::spawn(move || run_server(connection_manager, config));
+    let divergences = replay(address, &transcripts).unwrap_or_else(|err| {
+        eprintln!("Unable to replay: {}", err);
+        process::exit(2);
+    });
+
+    for divergence in &divergences {
+        let path = &paths[divergence.transcript];
+        println!("{}: {}", path.display(), divergence);
+    }
+    if !divergences.is_empty() {
+        println!("{} lines diverged.", divergences.len());
+        process::exit(1);
+    }
+    println!("Replay matched.");
+}
+
+/// Checks the server at `arguments.address`, exiting with an error if it
+/// isn't healthy.
+fn check_server(arguments: &CheckArguments) {
+    let timeout = Duration::from_secs(arguments.timeout_secs);
+    match check(arguments.address, timeout) {
+        Ok(()) => println!("{} is healthy.", arguments.address),
+        Err(err) => {
+            eprintln!("Health check of {} failed: {}", arguments.address, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes `port` to `path`. The file appears in one step, so anything
+/// waiting for it never reads half a number.
+fn write_port_file(path: &Path, port: u16) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, format!("{port}\n"))?;
+    fs::rename(&temporary, path)
 }
 
 fn main() {
+    // Only possible while there is one thread
+    find_local_offset();
+    let arguments = match Cli::parse() {
+        Cli {
+            command: Some(Command::Check(arguments)),
+            ..
+        } => {
+            check_server(&arguments);
+            return;
+        }
+        Cli {
+            command: Some(Command::Serve(arguments)),
+            ..
+        } => *arguments,
+        Cli {
+            command: None,
+            arguments,
+        } => arguments,
+    };
     // Initalise logging
     SimpleLogger::new().init().unwrap();
-    let arguments = Arguments::parse();
-    println!(
-        "Launching {} at {}:{}",
-        SERVER_NAME, arguments.ip_address, arguments.port
-    );
-    // Hashmap for storing conn_writes of users
-    let user_map: Arc<Mutex<HashMap<Nick, ConnectionWrite>>> = Arc::new(Mutex::new(HashMap::new()));
-    // Hashmap for storing channels and their users
-    let channels: Arc<Mutex<HashMap<Channel, Vec<Nick>>>> = Arc::new(Mutex::new(HashMap::new()));
-    let mut connection_manager = ConnectionManager::launch(arguments.ip_address, arguments.port);
-    loop {
-        // This function call will block until a new client connects!
-        let (mut conn_read, mut conn_write) = connection_manager.accept_new_connection();
-        let user_map_clone = user_map.clone();
-        let channels_clone = channels.clone();
-        // Spawn a thread for each client that connects
-        thread::spawn(move || {
-            println!("New connection from {}", conn_read.id());
-            let mut nicked = false;
-            let mut nickname = Nick("unregistered user".to_string());
-
-            // First loop only accepts nick/user command - ignores all else
-            loop {
-                println!("Waiting for message...");
-                let message = match conn_read.read_message() {
-                    Ok(message) => message,
-                    Err(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
-                        println!("Lost connection.");
-                        break;
-                    }
-                    Err(_) => {
-                        println!("Invalid message received... ignoring message.");
-                        continue;
-                    }
-                };
-
-                log::info!("Received from {}: {}", nickname, message);
-
-                match ParsedMessage::try_from(UnparsedMessage {
-                    message: &message,
-                    sender_nick: Nick("empty".to_string()),
-                }) {
-                    Ok(parsed) => match parsed.message {
-                        Message::Nick(nick_msg) => {
-                            nickname = nick_msg.nick;
-                            let user_map_mutex = user_map_clone.lock().unwrap();
-
-                            if user_map_mutex.contains_key(&nickname) {
-                                let _ = conn_write
-                                    .write_message(&format!("{}\r\n", ErrorType::NickCollision));
-                                log::warn!(
-                                    "Sent to {}: {}",
-                                    conn_read.id(),
-                                    ErrorType::NickCollision
-                                );
-                            } else {
-                                nicked = true;
-                            }
-                        }
-
-                        Message::User(user_msg) => {
-                            if nicked {
-                                let username = user_msg.real_name;
-                                let reply = WelcomeReply {
-                                    target_nick: Nick(nickname.to_string()),
-                                    message: format!("Welcome to this server, {}!", username),
-                                };
-                                write_to_conn(
-                                    &nickname,
-                                    &mut conn_write,
-                                    format!("{}", Reply::Welcome(reply)),
-                                );
-
-                                let mut user_map_mutex = user_map_clone.lock().unwrap();
-                                user_map_mutex.insert(nickname.clone(), conn_write);
-                                // Break out of loop once valid nick/user is entered
-                                break;
-                            }
-                        }
-
-                        _ => {}
-                    },
-                    Err(err) => {
-                        let _ = conn_write.write_message(&format!("{}\r\n", err));
-                        log::error!("Sent to {}: {}", nickname, err);
-                    }
-                };
+    if !arguments.replay.is_empty() {
+        replay_transcripts(&arguments.replay, arguments.server_config());
+        return;
+    }
+    // Read before binding, so a bad snapshot fails before anything starts
+    let state = ServerState::new(arguments.server_config());
+    if let Some(path) = &arguments.restore {
+        match Snapshot::load(path) {
+            Ok(snapshot) => snapshot.restore(&state),
+            Err(err) => {
+                eprintln!("Unable to restore {}: {}", path.display(), err);
+                process::exit(2);
             }
+        }
+    }
+    let connection_manager = ConnectionManager::launch(arguments.ip_address, arguments.port)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    let address = connection_manager.local_addr();
+    println!("Launching {} at {}", arguments.server_name, address);
+    if let Some(path) = &arguments.port_file {
+        if let Err(err) = write_port_file(path, address.port()) {
+            eprintln!("Unable to write {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+    serve(connection_manager, state);
+}
 
-            // This loop handles all the commands once user has nicked/usered
-            loop {
-                println!("Waiting for message...");
-                let message = match conn_read.read_message() {
-                    Ok(message) => message,
-                    Err(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
-                        println!("Lost connection.");
-                        break;
-                    }
-                    Err(_) => {
-                        println!("Invalid message received... ignoring message.");
-                        continue;
-                    }
-                };
-
-                log::info!("Received from {}: {}", nickname, message);
-
-                match ParsedMessage::try_from(UnparsedMessage {
-                    message: &message,
-                    sender_nick: Nick("empty".to_string()),
-                }) {
-                    Ok(parsed) => match parsed.message {
-                        Message::PrivMsg(priv_msg) => match priv_msg.target {
-                            Target::Channel(channel) => {
-                                let channels_mutex = channels_clone.lock().unwrap();
-                                private_msg_channel(
-                                    channels_mutex,
-                                    user_map_clone.clone(),
-                                    channel,
-                                    priv_msg.message.clone(),
-                                    nickname.clone(),
-                                );
-                            }
-                            Target::User(user) => {
-                                let user_map_mutex = user_map_clone.lock().unwrap();
-                                private_msg_user(
-                                    user_map_mutex,
-                                    &nickname,
-                                    user,
-                                    priv_msg.message.clone(),
-                                );
-                            }
-                        },
-                        Message::Ping(ping_msg) => {
-                            let mut user_map_mutex = user_map_clone.lock().unwrap();
-                            let c_write = user_map_mutex.get_mut(&nickname).unwrap();
-                            write_to_conn(
-                                &nickname,
-                                c_write,
-                                format!("{}", Reply::Pong(ping_msg.clone())),
-                            );
-                            log::info!("Sent to {}: PONG {}", nickname, ping_msg);
-                        }
-                        Message::Join(join_msg) => {
-                            let channels_mutex = channels_clone.lock().unwrap();
-                            join_channel(
-                                channels_mutex,
-                                user_map_clone.clone(),
-                                &nickname,
-                                join_msg,
-                            );
-                        }
-                        Message::Part(part_msg) => {
-                            // Obtain conn write
-                            let channels_mutex = channels_clone.lock().unwrap();
-                            part_channel(
-                                channels_mutex,
-                                user_map_clone.clone(),
-                                part_msg,
-                                &nickname,
-                            );
-                        }
-                        Message::Quit(quit_msg) => {
-                            //save quit msg
-                            let message = match quit_msg.message {
-                                Some(msg) => msg,
-                                None => nickname.to_string(),
-                            };
-                            //go through list of channels and check if user was in it, if so send msg to everyone
-                            let channels_mutex = channels_clone.lock().unwrap();
-                            quit_server(channels_mutex, user_map_clone, &nickname, message);
-                            break;
-                        }
-                        _ => {}
-                    },
-                    Err(err) => {
-                        let mut user_map_mutex = user_map_clone.lock().unwrap();
-                        let c_write = user_map_mutex.get_mut(&nickname).unwrap();
-                        let _ = c_write.write_message(&format!("{}\r\n", err));
-                        log::error!("Sent to {}: {}", nickname, err);
-                    }
-                };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_motd_is_read_at_startup() {
+        let path = std::env::temp_dir().join(format!("iris-motd-{}.txt", process::id()));
+        fs::write(&path, "Welcome to iris!\n\nBe nice.\n").unwrap();
+        let arguments =
+            Arguments::try_parse_from(["iris", "--motd", path.to_str().unwrap()]).unwrap();
+        let config = arguments.server_config();
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            config.motd,
+            Some(vec![
+                "Welcome to iris!".to_string(),
+                String::new(),
+                "Be nice.".to_string()
+            ])
+        );
+
+        // A MOTD that can't be read is the same as none
+        let arguments =
+            Arguments::try_parse_from(["iris", "--motd", path.to_str().unwrap()]).unwrap();
+        assert_eq!(arguments.server_config().motd, None);
+    }
+
+    #[test]
+    fn test_opers_are_read_at_startup() {
+        let path = std::env::temp_dir().join(format!("iris-opers-{}.txt", process::id()));
+        fs::write(&path, "admin secret\n").unwrap();
+        let arguments =
+            Arguments::try_parse_from(["iris", "--opers", path.to_str().unwrap()]).unwrap();
+        let config = arguments.server_config();
+        assert_eq!(config.operators, vec![Operator::new("admin", "secret")]);
+
+        // A malformed file lets nobody in
+        fs::write(&path, "admin\n").unwrap();
+        let config = arguments.server_config();
+        let _ = fs::remove_file(&path);
+        assert_eq!(config.operators, Vec::new());
+    }
+
+    // Environment variables are process-wide, so every case lives in one
+    // test to keep them from racing each other.
+    #[test]
+    fn test_argument_precedence() {
+        std::env::set_var("IRIS_PORT", "7000");
+        std::env::set_var("IRIS_CLOAK_SECRET", "from-env");
+        std::env::set_var("IRIS_NICK_HOLD_SECS", "5");
+
+        // The command line beats the environment...
+        let arguments =
+            Arguments::try_parse_from(["iris", "127.0.0.1", "6000", "--nick-hold-secs", "10"])
+                .unwrap();
+        assert_eq!(arguments.port, 6000);
+        assert_eq!(arguments.server_config().nick_hold, Duration::from_secs(10));
+        // ...which beats the defaults.
+        assert_eq!(
+            arguments.server_config().cloak_secret,
+            Some("from-env".to_string())
+        );
+
+        let arguments = Arguments::try_parse_from(["iris"]).unwrap();
+        assert_eq!(arguments.port, 7000);
+        assert_eq!(arguments.server_config().nick_hold, Duration::from_secs(5));
+
+        // Secrets in the environment stay out of --help
+        std::env::set_var("IRIS_PASSWORD", "hunter2");
+        std::env::set_var("IRIS_WEBHOOKS", "http://127.0.0.1:9000/irc?token=abc123");
+        let help = Cli::try_parse_from(["iris", "--help"])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(help.contains("[env: IRIS_PORT=7000]"));
+        for secret in ["from-env", "hunter2", "abc123"] {
+            assert!(!help.contains(secret), "--help shows {secret:?}");
+        }
+
+        std::env::remove_var("IRIS_PORT");
+        std::env::remove_var("IRIS_CLOAK_SECRET");
+        std::env::remove_var("IRIS_NICK_HOLD_SECS");
+        std::env::remove_var("IRIS_PASSWORD");
+        std::env::remove_var("IRIS_WEBHOOKS");
+
+        assert!(Arguments::try_parse_from(["iris", "--server-name", "not a hostname"]).is_err());
+        let arguments =
+            Arguments::try_parse_from(["iris", "--server-name", "irc.example.net"]).unwrap();
+        assert_eq!(arguments.server_config().server_name, "irc.example.net");
+
+        let arguments = Arguments::try_parse_from(["iris"]).unwrap();
+        assert_eq!(arguments.ip_address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(arguments.port, 6991);
+        assert_eq!(
+            arguments.server_config(),
+            ServerConfig {
+                server_name: SERVER_NAME.to_string(),
+                cloak_secret: None,
+                nick_hold: Duration::from_secs(60),
+                repeat_filter: None,
+                transcript: None,
+                write_timeout: Some(Duration::from_secs(10)),
+                send_queue_len: 512,
+                ping_interval: Some(Duration::from_secs(60)),
+                ping_timeout: Duration::from_secs(60),
+                registration_timeout: Some(Duration::from_secs(30)),
+                reason_len: 300,
+                topic_len: 390,
+                fanout_threshold: 1000,
+                fanout_workers: 4,
+                webhooks: Vec::new(),
+                snapshot: None,
+                aliases: HashMap::new(),
+                registered_only_exempts_opers: true,
+                max_channels: 5000,
+                max_channels_exempts_opers: true,
+                log_message_contents: false,
+                motd: None,
+                operators: Vec::new(),
+                password: None,
             }
-        });
+        );
+
+        let arguments = Arguments::try_parse_from([
+            "iris",
+            "--webhook",
+            "http://127.0.0.1:9000/irc",
+            "--webhook-events",
+            "quit,oper-action",
+            "--webhook-contents",
+            "quit",
+        ])
+        .unwrap();
+        assert_eq!(
+            arguments.server_config().webhooks,
+            vec![WebhookConfig {
+                url: "http://127.0.0.1:9000/irc".parse().unwrap(),
+                events: vec![EventKind::Quit, EventKind::OperAction],
+                contents: vec![EventKind::Quit],
+            }]
+        );
+        assert!(Arguments::try_parse_from(["iris", "--webhook", "https://example.com"]).is_err());
+        assert!(Arguments::try_parse_from(["iris", "--webhook-events", "kick"]).is_err());
+    }
+
+    #[test]
+    fn test_alias_args() {
+        let arguments = Arguments::try_parse_from(["iris", "--alias", "j=join,W=WHOIS"]).unwrap();
+        assert_eq!(
+            arguments.server_config().aliases,
+            HashMap::from([
+                ("J".to_string(), "JOIN".to_string()),
+                ("W".to_string(), "WHOIS".to_string()),
+            ])
+        );
+        assert!(Arguments::try_parse_from(["iris", "--alias", "J"]).is_err());
+        assert!(Arguments::try_parse_from(["iris", "--alias", "J=JO IN"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        // Bare arguments still run the server
+        let cli = Cli::try_parse_from(["iris", "127.0.0.1", "7000"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.arguments.port, 7000);
+
+        let cli = Cli::try_parse_from(["iris", "serve", "127.0.0.1", "7000"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Serve(arguments)) if arguments.port == 7000));
+
+        let cli =
+            Cli::try_parse_from(["iris", "check", "10.0.0.1:6667", "--timeout-secs", "2"]).unwrap();
+        let Some(Command::Check(arguments)) = cli.command else {
+            panic!("expected a check");
+        };
+        assert_eq!(arguments.address, SocketAddr::from(([10, 0, 0, 1], 6667)));
+        assert_eq!(arguments.timeout_secs, 2);
+
+        assert!(Cli::try_parse_from(["iris", "check", "not-an-address"]).is_err());
     }
 }
@@ -0,0 +1,105 @@
+//! Checks that the hot write path reuses its buffers rather than
+//! allocating for every recipient.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::HashMap,
+    io::Read,
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use iris_lib::{
+    config::ServerConfig,
+    connect::ConnectionManager,
+    helpers::{broadcast, LineBuffer},
+    state::UserState,
+    types::Nick,
+};
+
+/// Counts the allocations made by each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many allocations `f` makes on this thread.
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_broadcast_allocations_do_not_grow_with_recipients() {
+    let config = ServerConfig::default();
+    let mut connection_manager = ConnectionManager::launch([127, 0, 0, 1], 0).unwrap();
+    let user_map = Arc::new(Mutex::new(HashMap::new()));
+    let mut clients = Vec::new();
+    let mut nicks = Vec::new();
+    for index in 0..20 {
+        let client = TcpStream::connect(connection_manager.local_addr()).unwrap();
+        let (conn_read, conn_write) = connection_manager.accept_new_connection();
+        let nick = Nick(format!("user{index}"));
+        let user_state = UserState::new(
+            nick.clone(),
+            conn_write,
+            nick.to_string(),
+            nick.to_string(),
+            conn_read.ip(),
+            &config,
+            Instant::now(),
+        );
+        user_map.lock().unwrap().insert(nick.clone(), user_state);
+        clients.push(client);
+        nicks.push(nick);
+    }
+    let message = ":tom PRIVMSG #rust :hello\r\n";
+    // The first write to each connection sizes its buffer
+    broadcast(&user_map, &config, &nicks, message);
+
+    let to_one = allocations_during(|| broadcast(&user_map, &config, &nicks[..1], message));
+    let to_all = allocations_during(|| broadcast(&user_map, &config, &nicks, message));
+    assert_eq!(to_one, to_all);
+
+    let mut received = vec![0; message.len() * 3];
+    clients[0].read_exact(&mut received).unwrap();
+    assert_eq!(received, message.repeat(3).as_bytes());
+}
+
+#[test]
+fn test_line_buffer_is_reused() {
+    let line = ":tom PRIVMSG #rust :hello";
+    LineBuffer::format(line, |_| ());
+
+    assert_eq!(
+        allocations_during(|| LineBuffer::format(line, |text| assert_eq!(text, line))),
+        0
+    );
+    // A buffer already in use falls back to a fresh one
+    let nested = allocations_during(|| {
+        LineBuffer::format(line, |outer| {
+            LineBuffer::format("PING :x", |inner| {
+                assert_eq!((outer, inner), (line, "PING :x"))
+            })
+        })
+    });
+    assert_eq!(nested, 1);
+}
@@ -0,0 +1,117 @@
+//! Launching servers for tests to talk to over TCP, and the endpoints they
+//! call out to.
+
+// Each test binary uses a different subset of these helpers
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+pub use iris_lib::testing::TestClient;
+use iris_lib::{
+    clock::Clock,
+    config::ServerConfig,
+    connect::ConnectionManager,
+    server::{run_server, serve, ServerState},
+};
+
+/// How long to wait for a line before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Launches a server on a free port, returning the address to connect to.
+///
+/// The server runs on a background thread for the rest of the test process.
+pub fn spawn_server(config: ServerConfig) -> SocketAddr {
+    let connection_manager = ConnectionManager::launch([127, 0, 0, 1], 0).unwrap();
+    let address = connection_manager.local_addr();
+    thread::spawn(move || run_server(connection_manager, config));
+    address
+}
+
+/// Like [`spawn_server`], but the server gets the time from `clock`.
+pub fn spawn_server_with_clock(config: ServerConfig, clock: Arc<dyn Clock>) -> SocketAddr {
+    let connection_manager = ConnectionManager::launch([127, 0, 0, 1], 0).unwrap();
+    let address = connection_manager.local_addr();
+    let state = ServerState::with_clock(config, clock);
+    thread::spawn(move || serve(connection_manager, state));
+    address
+}
+
+/// A fresh directory under the system's temporary directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("iris-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// A local HTTP endpoint that records the body of every request it gets.
+pub struct WebhookReceiver {
+    pub url: String,
+    bodies: mpsc::Receiver<String>,
+}
+
+impl WebhookReceiver {
+    /// Starts answering requests with `200 OK` on a free port.
+    pub fn launch() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, bodies) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break;
+                    }
+                    if let Some(length) = header.strip_prefix("Content-Length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let _ = reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+                if sender.send(String::from_utf8(body).unwrap()).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { url, bodies }
+    }
+
+    /// Asserts that the next body, minus its timestamp, is `expected`.
+    pub fn expect(&self, expected: &str) {
+        let body = self
+            .bodies
+            .recv_timeout(TIMEOUT)
+            .expect("webhook should have been called");
+        let (_, rest) = body
+            .split_once(',')
+            .unwrap_or_else(|| panic!("webhook body has no timestamp: {body:?}"));
+        assert_eq!(format!("{{{rest}"), expected);
+    }
+}
+
+/// A local endpoint that accepts connections and then never answers.
+/// Returns its URL.
+pub fn stalled_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let mut held = Vec::new();
+        for stream in listener.incoming() {
+            held.push(stream);
+        }
+    });
+    url
+}
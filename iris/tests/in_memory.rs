@@ -0,0 +1,169 @@
+//! The server driven over in-process connections, where every line each
+//! client receives is checked, in order.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use iris_lib::{clock::ManualClock, config::ServerConfig, testing::TestServer, types::Nick};
+
+#[test]
+fn test_registration() {
+    let server = TestServer::new(ServerConfig::default());
+    let mut tom = server.connect("tom");
+    tom.send("NICK tom");
+    tom.expect_silence();
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":iris-server 001 tom :Welcome to this server, Tom Smith!");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect_only(&[":iris-server 422 :MOTD File is missing"]);
+
+    let user_map = server.state().user_map.lock().unwrap();
+    assert_eq!(user_map[&Nick("tom".to_string())].real_name, "Tom Smith");
+    drop(user_map);
+
+    let mut ann = server.connect("ann");
+    ann.send("NICK tom");
+    ann.expect_only(&[":iris-server 436 :Nickname collision"]);
+}
+
+#[test]
+fn test_join_and_names() {
+    let server = TestServer::new(ServerConfig::default());
+    let mut tom = server.register("tom");
+    let mut ann = server.register("ann");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.expect_silence();
+
+    ann.send("JOIN #rust");
+    tom.expect_only(&[":ann!ann@127.0.0.1 JOIN #rust"]);
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    ann.expect_silence();
+
+    tom.send("NAMES #rust");
+    tom.expect_names("#rust", "@tom ann");
+    tom.expect_silence();
+    ann.expect_silence();
+}
+
+#[test]
+fn test_channel_privmsg_fanout() {
+    let server = TestServer::new(ServerConfig::default());
+    let mut tom = server.register("tom");
+    let mut ann = server.register("ann");
+    let mut bob = server.register("bob");
+    let mut eve = server.register("eve");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    bob.send("JOIN #rust");
+    tom.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    ann.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect_names("#rust", "@tom ann bob");
+
+    ann.send("PRIVMSG #rust :hello everyone");
+    ann.send("PRIVMSG #rust :and again");
+    for client in [&mut tom, &mut ann, &mut bob] {
+        client.expect_only(&[
+            ":ann!ann@127.0.0.1 PRIVMSG #rust :hello everyone",
+            ":ann!ann@127.0.0.1 PRIVMSG #rust :and again",
+        ]);
+    }
+    eve.expect_silence();
+}
+
+#[test]
+fn test_part() {
+    let server = TestServer::new(ServerConfig::default());
+    let mut tom = server.register("tom");
+    let mut ann = server.register("ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("PART #rust");
+    tom.expect_only(&[":ann!ann@127.0.0.1 PART #rust"]);
+    ann.expect_only(&[":ann!ann@127.0.0.1 PART #rust"]);
+
+    tom.send("PRIVMSG #rust :anyone?");
+    tom.expect_only(&[":tom!tom@127.0.0.1 PRIVMSG #rust :anyone?"]);
+    ann.expect_silence();
+
+    ann.send("PART #rust");
+    ann.expect_only(&[":iris-server 442 :You're not on that channel"]);
+}
+
+#[test]
+fn test_quit() {
+    let server = TestServer::new(ServerConfig::default());
+    let mut tom = server.register("tom");
+    let mut ann = server.register("ann");
+    let mut bob = server.register("bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("QUIT :Bye for now");
+    tom.expect_only(&[":ann!ann@127.0.0.1 QUIT :Bye for now"]);
+    ann.expect_closed();
+    // bob shared no channel with ann, so isn't told
+    bob.expect_silence();
+
+    tom.send("NAMES #rust");
+    tom.expect_names("#rust", "@tom");
+
+    bob.disconnect();
+    tom.expect_silence();
+    let user_map = server.state().user_map.lock().unwrap();
+    assert!(!user_map.contains_key(&Nick("ann".to_string())));
+}
+
+#[test]
+fn test_topic_time() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_697_385_600);
+    let clock = Arc::new(ManualClock::starting_at(start));
+    let server = TestServer::with_clock(ServerConfig::default(), clock.clone());
+    let mut tom = server.register("tom");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+
+    tom.send("TOPIC #rust :All things Rust");
+    tom.expect(":tom!tom@127.0.0.1 TOPIC #rust :All things Rust");
+    clock.advance(Duration::from_secs(90));
+    tom.send("TOPIC #rust");
+    tom.expect_only(&[
+        ":iris-server 332 tom #rust :All things Rust",
+        ":iris-server 333 tom #rust tom 1697385600",
+    ]);
+
+    // Setting it again moves the time on
+    tom.send("TOPIC #rust :Rust and nothing else");
+    tom.expect(":tom!tom@127.0.0.1 TOPIC #rust :Rust and nothing else");
+    tom.send("TOPIC #rust");
+    tom.expect_only(&[
+        ":iris-server 332 tom #rust :Rust and nothing else",
+        ":iris-server 333 tom #rust tom 1697385690",
+    ]);
+}
@@ -0,0 +1,2226 @@
+//! End-to-end tests that run the real server and talk to it over TCP.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    process::{Command, Stdio},
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{
+    spawn_server, spawn_server_with_clock, stalled_endpoint, temp_dir, TestClient, WebhookReceiver,
+};
+use iris_lib::{
+    bot::BotEvent,
+    client::{check, ClientError},
+    clock::ManualClock,
+    config::{Operator, RepeatFilter, ServerConfig},
+    connect::{ConnectionError, ConnectionManager},
+    json::Json,
+    server::start_server,
+    types::{Channel, Nick},
+    webhook::{EventKind, WebhookConfig},
+};
+
+#[test]
+fn test_registration() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+
+    // Commands are ignored until the client has registered
+    tom.send("PING hello");
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":iris-server 001 tom :Welcome to this server, Tom Smith!");
+    tom.expect(&format!(
+        ":iris-server 002 tom :Your host is iris-server, running version iris-{}",
+        env!("CARGO_PKG_VERSION")
+    ));
+    tom.expect_prefix(":iris-server 003 tom :This server was created ");
+    tom.expect(&format!(
+        ":iris-server 004 tom iris-server iris-{} giRw CMOPRbciklmnostvz bklov",
+        env!("CARGO_PKG_VERSION")
+    ));
+    tom.expect(
+        ":iris-server 005 tom CALLERID=g CASEMAPPING=ascii CHANNELLEN=50 CHANTYPES=# ELIST=U KICKLEN=300 NICKLEN=16 PREFIX=(ov)@+ STATUSMSG=@+ TOPICLEN=390 :are supported by this server",
+    );
+    tom.expect(":iris-server 422 :MOTD File is missing");
+
+    tom.send("PING hello");
+    tom.expect(":iris-server PONG iris-server :hello");
+}
+
+#[test]
+fn test_registration_order() {
+    let address = spawn_server(ServerConfig::default());
+    let mut ann = TestClient::connect(address, "ann");
+    ann.send("USER ann 0 * :Ann Lee");
+    ann.send("NICK ann");
+    ann.expect(":iris-server 001 ann :Welcome to this server, Ann Lee!");
+
+    // A USER that's too short is turned away, and the last NICK is kept
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("USER tom 0");
+    tom.expect(":iris-server 461 :Not enough parameters");
+    tom.send("NICK tom");
+    tom.send("NICK thomas");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":iris-server 001 thomas :Welcome to this server, Tom Smith!");
+    for _ in 0..5 {
+        tom.expect_prefix(":iris-server ");
+    }
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":iris-server 462 :You may not reregister");
+}
+
+#[test]
+fn test_commands_before_registering() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("NICK tom");
+    for command in ["PRIVMSG ann :hi", "JOIN #rust", "PART #rust"] {
+        tom.send(command);
+        tom.expect(":iris-server 451 :You have not registered");
+    }
+    tom.send("NOTICE ann :hi");
+    tom.send("QUIT :Never mind");
+    tom.expect_closed();
+
+    // Their nick went with them
+    TestClient::register(address, "tom");
+}
+
+#[test]
+fn test_erroneous_nick_at_registration() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("NICK #hello");
+    tom.expect(":iris-server 432 :Erroneus nickname");
+    tom.send("NICK :tom smith");
+    tom.expect(":iris-server 432 :Erroneus nickname");
+
+    // Specials are fine, and registering still works afterwards
+    tom.send("NICK [tom]");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":iris-server 001 [tom] :Welcome to this server, Tom Smith!");
+}
+
+#[test]
+fn test_unknown_commands_are_answered() {
+    let address = spawn_server(ServerConfig::default());
+    let mut ann = TestClient::connect(address, "ann");
+    ann.send("CAP LS 302");
+    ann.expect(":iris-server 421 * CAP :Unknown command");
+
+    let mut tom = TestClient::register(address, "tom");
+    tom.send("USERIP tom");
+    tom.expect(":iris-server 421 tom USERIP :Unknown command");
+    tom.send("PING hello");
+    tom.expect(":iris-server PONG iris-server :hello");
+}
+
+#[test]
+fn test_custom_server_name() {
+    let address = spawn_server(ServerConfig {
+        server_name: "irc.example.net".to_string(),
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::connect(address, "tom");
+
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect(":irc.example.net 001 tom :Welcome to this server, Tom Smith!");
+    tom.expect_prefix(":irc.example.net 002 tom ");
+    tom.expect_prefix(":irc.example.net 003 tom ");
+    tom.expect_prefix(":irc.example.net 004 tom ");
+    tom.expect_prefix(":irc.example.net 005 tom ");
+    tom.expect(":irc.example.net 422 :MOTD File is missing");
+
+    tom.send("PING hello");
+    tom.expect(":irc.example.net PONG irc.example.net :hello");
+    tom.send("PRIVMSG nobody :hello?");
+    tom.expect(":irc.example.net 401 :No such nick/channel");
+}
+
+#[test]
+fn test_nick_collision() {
+    let address = spawn_server(ServerConfig::default());
+    let _tom = TestClient::register(address, "tom");
+    let mut imposter = TestClient::connect(address, "imposter");
+
+    imposter.send("NICK tom");
+    imposter.expect(":iris-server 436 :Nickname collision");
+}
+
+#[test]
+fn test_private_messages() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("PRIVMSG ann :Hi Ann");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG ann :Hi Ann");
+
+    tom.send("PRIVMSG tom :note to self");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG tom :note to self");
+
+    tom.send("PRIVMSG nobody :Hello?");
+    tom.expect(":iris-server 401 :No such nick/channel");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_notices() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("NOTICE ann :Build finished");
+    ann.expect(":tom!tom@127.0.0.1 NOTICE ann :Build finished");
+
+    ann.send("JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@ann");
+    tom.send("JOIN #rust");
+    ann.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@ann tom");
+    tom.send("NOTICE #rust :Deploying");
+    ann.expect(":tom!tom@127.0.0.1 NOTICE #rust :Deploying");
+    tom.expect(":tom!tom@127.0.0.1 NOTICE #rust :Deploying");
+
+    // None of these get an error back, so the PONG comes first
+    tom.send("NOTICE nobody :Hello?");
+    tom.send("NOTICE #nowhere :Hello?");
+    tom.send("NOTICE");
+    tom.send("NOTICE ann");
+    tom.send("PING :done");
+    tom.expect(":iris-server PONG iris-server :done");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_channel_lifecycle() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("PRIVMSG #rust :hello everyone");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :hello everyone");
+    ann.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :hello everyone");
+    bob.expect_silence();
+
+    ann.send("PART #rust");
+    tom.expect(":ann!ann@127.0.0.1 PART #rust");
+    ann.expect(":ann!ann@127.0.0.1 PART #rust");
+
+    tom.send("PRIVMSG #rust :anyone?");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :anyone?");
+    ann.expect_silence();
+
+    // The channel goes away once its last member leaves
+    tom.send("PART #rust");
+    tom.expect(":tom!tom@127.0.0.1 PART #rust");
+    bob.send("PRIVMSG #rust :hello?");
+    bob.expect(":iris-server 403 :No such channel");
+}
+
+#[test]
+fn test_quit() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("QUIT :Bye for now");
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Bye for now");
+    ann.expect_closed();
+
+    tom.send("PRIVMSG ann :Still there?");
+    tom.expect(":iris-server 401 :No such nick/channel");
+}
+
+#[test]
+fn test_long_quit_reason_is_truncated() {
+    let address = spawn_server(ServerConfig {
+        reason_len: 10,
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("QUIT :Goodbye everyone, see you tomorrow");
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Goodbye…");
+}
+
+#[test]
+fn test_quit_reason_cannot_inject_lines() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    bob.send("JOIN #rust");
+    tom.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    ann.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect_names("#rust", "@tom ann bob");
+
+    // A bare CR stays inside the reason, where it's stripped
+    bob.send("QUIT :Bye\rPRIVMSG #rust :forged by bob");
+    tom.expect(":bob!bob@127.0.0.1 QUIT :ByePRIVMSG #rust :forged by bob");
+    ann.expect(":bob!bob@127.0.0.1 QUIT :ByePRIVMSG #rust :forged by bob");
+
+    // A CRLF ends the QUIT, and nothing after it is sent on their behalf
+    ann.send("QUIT :Bye\r\nPRIVMSG #rust :forged by ann");
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Bye");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_messages_cannot_inject_lines() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::connect(address, "ann");
+    ann.send("NICK ann");
+    ann.send("USER ann 0 * :Ann\rPING :forged\0");
+    ann.expect(":iris-server 001 ann :Welcome to this server, AnnPING :forged!");
+    for _ in 0..5 {
+        ann.expect_prefix(":iris-server ");
+    }
+
+    ann.send("PRIVMSG tom :hi\r:iris-server KILL tom :forged");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG tom :hi:iris-server KILL tom :forged");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    ann.send("PRIVMSG #rust :hi\0\rPART #rust");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :hiPART #rust");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_abrupt_disconnect() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.disconnect();
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Connection closed");
+
+    // Their nick is free again, and the channel no longer relays to them
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("PRIVMSG #rust :welcome back");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :welcome back");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_channel_outlives_abrupt_disconnect() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let bob = TestClient::register(address, "bob");
+    for (nick, client) in [("tom", &mut tom), ("ann", &mut ann)] {
+        client.send("JOIN #rust");
+        client.expect(&format!(":{nick}!{nick}@127.0.0.1 JOIN #rust"));
+        client.expect_prefix(":iris-server 353 ");
+        client.expect_prefix(":iris-server 366 ");
+    }
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    let mut bob = bob;
+    bob.send("JOIN #rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect_names("#rust", "@tom ann bob");
+    tom.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    ann.expect(":bob!bob@127.0.0.1 JOIN #rust");
+
+    bob.disconnect();
+    tom.expect(":bob!bob@127.0.0.1 QUIT :Connection closed");
+    ann.expect(":bob!bob@127.0.0.1 QUIT :Connection closed");
+    tom.send("PRIVMSG #rust :still here?");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :still here?");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :still here?");
+    ann.send("NAMES #rust");
+    ann.expect_names("#rust", "@tom ann");
+}
+
+#[test]
+fn test_disconnect_before_registering() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut stranger = TestClient::connect(address, "stranger");
+
+    // Leaving half-registered must not disturb whoever holds the nick
+    stranger.send("NICK tom");
+    stranger.expect(":iris-server 436 :Nickname collision");
+    stranger.disconnect();
+
+    tom.send("PING still-here");
+    tom.expect(":iris-server PONG iris-server :still-here");
+}
+
+#[test]
+fn test_registration_timeout() {
+    let clock = Arc::new(ManualClock::default());
+    // Nobody registered should be pinged while the clock moves on
+    let config = ServerConfig {
+        registration_timeout: Some(Duration::from_secs(30)),
+        ping_interval: None,
+        ..ServerConfig::default()
+    };
+    let address = spawn_server_with_clock(config, clock.clone());
+    let mut tom = TestClient::register(address, "tom");
+    let mut silent = TestClient::connect(address, "silent");
+    let mut slow = TestClient::connect(address, "slow");
+    slow.send("NICK slow");
+
+    // The deadline is on the server's clock, not the wall clock
+    silent.expect_silence();
+    clock.advance(Duration::from_secs(29));
+    silent.expect_silence();
+    clock.advance(Duration::from_secs(1));
+    silent.expect("ERROR :Closing Link: * (Registration timeout)");
+    silent.expect_closed();
+    slow.expect("ERROR :Closing Link: slow (Registration timeout)");
+    slow.expect_closed();
+
+    // The nick they were holding is free again
+    TestClient::register(address, "slow");
+    // Registered users aren't held to the deadline
+    tom.send("PING still-here");
+    tom.expect(":iris-server PONG iris-server :still-here");
+}
+
+#[test]
+fn test_caller_id_notices_follow_the_clock() {
+    let clock = Arc::new(ManualClock::default());
+    // Ann stays quiet for a minute, which would otherwise get her pinged
+    let config = ServerConfig {
+        ping_interval: None,
+        ..ServerConfig::default()
+    };
+    let address = spawn_server_with_clock(config, clock.clone());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    ann.send("MODE ann +g");
+    ann.expect(":ann!ann@127.0.0.1 MODE ann +g");
+
+    tom.send("PRIVMSG ann :Hi Ann");
+    tom.expect(":iris-server 716 :is in +g mode (server-side ignore)");
+    tom.expect(":iris-server 717 tom ann :has been informed that you messaged them.");
+    ann.expect(":iris-server 718 ann tom tom@127.0.0.1 :is messaging you, and you have umode +g.");
+
+    // Ann is only told once a minute
+    clock.advance(Duration::from_secs(59));
+    tom.send("PRIVMSG ann :Hi again");
+    tom.expect(":iris-server 716 :is in +g mode (server-side ignore)");
+    ann.expect_silence();
+
+    clock.advance(Duration::from_secs(1));
+    tom.send("PRIVMSG ann :Hi again");
+    tom.expect(":iris-server 716 :is in +g mode (server-side ignore)");
+    tom.expect(":iris-server 717 tom ann :has been informed that you messaged them.");
+    ann.expect(":iris-server 718 ann tom tom@127.0.0.1 :is messaging you, and you have umode +g.");
+}
+
+#[test]
+fn test_registered_only_user_mode() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut eve = TestClient::register(address, "eve");
+
+    ann.send("MODE ann +R");
+    ann.expect(":ann!ann@127.0.0.1 MODE ann +R");
+    tom.send("PRIVMSG ann :Hi Ann");
+    tom.expect(":iris-server 486 :You must identify to an account to message that user");
+    ann.expect_silence();
+
+    eve.send("REGISTER hunter2");
+    eve.expect_prefix(":iris-server 900 eve eve ");
+    eve.send("PRIVMSG ann :Hi from eve");
+    ann.expect(":eve!eve@127.0.0.1 PRIVMSG ann :Hi from eve");
+
+    ann.send("MODE ann -R");
+    ann.expect(":ann!ann@127.0.0.1 MODE ann -R");
+    tom.send("PRIVMSG ann :Hi again");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG ann :Hi again");
+}
+
+#[test]
+fn test_channel_cap() {
+    let address = spawn_server(ServerConfig {
+        max_channels: 2,
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("JOIN #a");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #a");
+    tom.expect_names("#a", "@tom");
+    tom.send("JOIN #b");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #b");
+    tom.expect_names("#b", "@tom");
+    ann.send("JOIN #c");
+    ann.expect(":iris-server 405 :Cannot create channel (channel creation limit reached)");
+
+    // Existing channels can still be joined
+    ann.send("JOIN #a");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #a");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #a");
+    ann.expect_names("#a", "@tom ann");
+
+    // Once #b empties it is gone, which frees a slot
+    tom.send("PART #b");
+    tom.expect(":tom!tom@127.0.0.1 PART #b");
+    ann.send("JOIN #c");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #c");
+    ann.expect_names("#c", "@ann");
+}
+
+#[test]
+fn test_repeat_filter_follows_the_clock() {
+    let clock = Arc::new(ManualClock::default());
+    let config = ServerConfig {
+        repeat_filter: Some(RepeatFilter {
+            suppress_after: 1,
+            kick_after: 10,
+            window: Duration::from_secs(30),
+        }),
+        ..ServerConfig::default()
+    };
+    let address = spawn_server_with_clock(config, clock.clone());
+    let mut tom = TestClient::register(address, "tom");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("PRIVMSG #rust :ping?");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :ping?");
+    tom.send("PRIVMSG #rust :ping?");
+    tom.expect(":iris-server NOTICE tom :Repeated message to #rust was not delivered");
+
+    clock.advance(Duration::from_secs(30));
+    tom.send("PRIVMSG #rust :ping?");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :ping?");
+}
+
+#[test]
+fn test_debug_needs_oper() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+
+    tom.send("DEBUG");
+    tom.expect(":iris-server 481 :Permission Denied- You're not an IRC operator");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_webhook_delivery() {
+    let receiver = WebhookReceiver::launch();
+    let address = spawn_server(ServerConfig {
+        webhooks: vec![WebhookConfig {
+            url: receiver.url.parse().unwrap(),
+            events: vec![
+                EventKind::Registered,
+                EventKind::Quit,
+                EventKind::ChannelCreated,
+                EventKind::ServerStart,
+            ],
+            contents: Vec::new(),
+        }],
+        ..ServerConfig::default()
+    });
+    receiver.expect(r#"{"event":"server-start","server":"iris-server"}"#);
+
+    let mut tom = TestClient::register(address, "tom");
+    receiver.expect(r#"{"event":"registered","server":"iris-server","nick":"tom"}"#);
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    receiver.expect(
+        r##"{"event":"channel-created","server":"iris-server","channel":"#rust","nick":"tom"}"##,
+    );
+
+    // The reason is message content, which this webhook did not opt in to
+    tom.send("QUIT :secret plans");
+    tom.expect_closed();
+    receiver.expect(r#"{"event":"quit","server":"iris-server","nick":"tom"}"#);
+}
+
+#[test]
+fn test_stalled_webhook_does_not_block() {
+    let address = spawn_server(ServerConfig {
+        webhooks: vec![WebhookConfig {
+            url: stalled_endpoint().parse().unwrap(),
+            events: vec![EventKind::Registered, EventKind::ChannelCreated],
+            contents: Vec::new(),
+        }],
+        ..ServerConfig::default()
+    });
+
+    // Far more events than the endpoint will ever take
+    let mut tom = TestClient::register(address, "tom");
+    for index in 0..50 {
+        tom.send(&format!("JOIN #chan{index}"));
+        tom.expect(&format!(":tom!tom@127.0.0.1 JOIN #chan{index}"));
+        tom.expect_names(&format!("#chan{index}"), "@tom");
+    }
+    let mut ann = TestClient::register(address, "ann");
+    ann.send("PING still-here");
+    ann.expect(":iris-server PONG iris-server :still-here");
+}
+
+#[test]
+fn test_bot_joins_and_chats() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 0).unwrap(),
+        ServerConfig::default(),
+    );
+    let mut bot = server.add_bot("pingbot").unwrap();
+    bot.join("#bots").unwrap();
+
+    let mut tom = TestClient::register(server.local_addr(), "tom");
+    tom.send("JOIN #bots");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #bots");
+    tom.expect_names("#bots", "@pingbot tom");
+
+    tom.send("PRIVMSG #bots :!ping");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #bots :!ping");
+    assert_eq!(
+        bot.next_event(Some(Duration::from_secs(2))),
+        Ok(BotEvent::Message {
+            from: Nick("tom".to_string()),
+            target: "#bots".to_string(),
+            text: "!ping".to_string(),
+        })
+    );
+    bot.say("#bots", "pong").unwrap();
+    tom.expect(":pingbot!pingbot@127.0.0.1 PRIVMSG #bots :pong");
+
+    tom.send("PRIVMSG pingbot :hello");
+    assert_eq!(
+        bot.next_event(Some(Duration::from_secs(2))),
+        Ok(BotEvent::Message {
+            from: Nick("tom".to_string()),
+            target: "pingbot".to_string(),
+            text: "hello".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_bot_is_kicked() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 0).unwrap(),
+        ServerConfig {
+            repeat_filter: Some(RepeatFilter {
+                suppress_after: 1,
+                kick_after: 2,
+                window: Duration::from_secs(30),
+            }),
+            ..ServerConfig::default()
+        },
+    );
+    let mut bot = server.add_bot("spambot").unwrap();
+    bot.join("#bots").unwrap();
+    for _ in 0..3 {
+        bot.say("#bots", "buy now").unwrap();
+    }
+    match bot.next_event(Some(Duration::from_secs(2))) {
+        Ok(BotEvent::Kicked { channel, .. }) => assert_eq!(channel, Channel("#bots".to_string())),
+        other => panic!("expected a kick, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_dropped_bot_leaves() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 0).unwrap(),
+        ServerConfig::default(),
+    );
+    let mut bot = server.add_bot("pingbot").unwrap();
+    bot.join("#bots").unwrap();
+    let mut tom = TestClient::register(server.local_addr(), "tom");
+    tom.send("JOIN #bots");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #bots");
+    tom.expect_names("#bots", "@pingbot tom");
+
+    drop(bot);
+    tom.expect(":pingbot!pingbot@127.0.0.1 QUIT :Connection closed");
+
+    // The nick is free again once the bot has gone
+    server.add_bot("pingbot").unwrap();
+}
+
+#[test]
+fn test_export_matches_state() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 0).unwrap(),
+        ServerConfig::default(),
+    );
+    let mut tom = TestClient::register(server.local_addr(), "tom");
+    let mut ann = TestClient::connect(server.local_addr(), "ann");
+    ann.send("NICK ann");
+    ann.send("USER annie 0 * :Ann Lee");
+    ann.expect(":iris-server 001 ann :Welcome to this server, Ann Lee!");
+    ann.expect_prefix(":iris-server 002 ann ");
+    ann.expect_prefix(":iris-server 003 ann ");
+    ann.expect_prefix(":iris-server 004 ann ");
+    ann.expect_prefix(":iris-server 005 ann ");
+    ann.expect(":iris-server 422 :MOTD File is missing");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!annie@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!annie@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    ann.send("JOIN #go");
+    ann.expect(":ann!annie@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+    tom.send("MODE #rust +v ann");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +v ann");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +v ann");
+    ann.send("REGISTER hunter2");
+    ann.expect_prefix(":iris-server 900 ann ann ");
+    ann.send("TOPIC #go :All things Go");
+    ann.expect(":ann!annie@127.0.0.1 TOPIC #go :All things Go");
+    ann.send("AWAY :Gone fishing");
+    ann.expect_prefix(":iris-server 306 ann ");
+
+    let export: Json = server.export().to_string().parse().unwrap();
+    assert_eq!(export.get("server"), Some(&Json::from("iris-server")));
+
+    let channels = export.get("channels").and_then(Json::as_array).unwrap();
+    let names: Vec<_> = channels
+        .iter()
+        .map(|channel| channel.get("name").and_then(Json::as_str).unwrap())
+        .collect();
+    assert_eq!(names, ["#go", "#rust"]);
+    let members: Vec<_> = channels[1]
+        .get("members")
+        .and_then(Json::as_array)
+        .unwrap()
+        .iter()
+        .map(|member| {
+            (
+                member.get("nick").and_then(Json::as_str).unwrap(),
+                member.get("status").unwrap().as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(members, [("tom", Some("@")), ("ann", Some("+"))]);
+    let topic = channels[0].get("topic").unwrap();
+    assert_eq!(topic.get("text"), Some(&Json::from("All things Go")));
+    assert_eq!(topic.get("set_by"), Some(&Json::from("ann")));
+    assert!(topic
+        .get("set_at")
+        .and_then(Json::as_u64)
+        .is_some_and(|set_at| set_at > 0));
+    assert_eq!(channels[1].get("topic"), Some(&Json::Null));
+
+    let users = export.get("users").and_then(Json::as_array).unwrap();
+    let nicks: Vec<_> = users
+        .iter()
+        .map(|user| user.get("nick").and_then(Json::as_str).unwrap())
+        .collect();
+    assert_eq!(nicks, ["ann", "tom"]);
+    assert_eq!(users[0].get("account"), Some(&Json::from("ann")));
+    assert_eq!(users[0].get("username"), Some(&Json::from("annie")));
+    assert_eq!(users[0].get("real_name"), Some(&Json::from("Ann Lee")));
+    assert_eq!(users[0].get("away"), Some(&Json::from("Gone fishing")));
+    assert_eq!(users[1].get("account"), Some(&Json::Null));
+    assert_eq!(users[1].get("username"), Some(&Json::from("tom")));
+    assert_eq!(users[1].get("away"), Some(&Json::Null));
+    assert_eq!(users[1].get("host"), Some(&Json::from("127.0.0.1")));
+    assert_eq!(users[1].get("oper"), Some(&Json::from(false)));
+    assert!(users[1]
+        .get("connected_since")
+        .and_then(Json::as_u64)
+        .is_some_and(|since| since > 0));
+    // Nothing secret makes it out
+    assert!(!export.to_string().contains("hunter2"));
+}
+
+#[test]
+fn test_export_needs_oper() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+
+    tom.send("EXPORT");
+    tom.expect(":iris-server 481 :Permission Denied- You're not an IRC operator");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_snapshot_needs_oper() {
+    let path = temp_dir("snapshot").join("snapshot.json");
+    let address = spawn_server(ServerConfig {
+        snapshot: Some(path.clone()),
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+
+    tom.send("SNAPSHOT");
+    tom.expect(":iris-server 481 :Permission Denied- You're not an IRC operator");
+    tom.expect_silence();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_health_check() {
+    let address = spawn_server(ServerConfig::default());
+    assert_eq!(check(address, Duration::from_secs(2)), Ok(()));
+
+    // The throwaway nick is gone again, so checks can be repeated
+    assert_eq!(check(address, Duration::from_secs(2)), Ok(()));
+
+    // Checks run side by side pick different nicks
+    let checks: Vec<_> = (0..4)
+        .map(|_| thread::spawn(move || check(address, Duration::from_secs(2))))
+        .collect();
+    for handle in checks {
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+}
+
+#[test]
+fn test_health_check_times_out() {
+    // Accepts connections but never says anything
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let started = Instant::now();
+    assert_eq!(
+        check(address, Duration::from_millis(300)),
+        Err(ClientError::Connection(ConnectionError::Timeout))
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+    drop(listener);
+}
+
+#[test]
+fn test_long_line_is_refused() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+
+    tom.send_raw(format!("PRIVMSG tom :{}\r\n", "x".repeat(600)).as_bytes());
+    tom.expect(":iris-server 417 :Input line was too long");
+
+    // The connection carries on as normal
+    tom.send("PING hello");
+    tom.expect(":iris-server PONG iris-server :hello");
+}
+
+#[test]
+fn test_port_file() {
+    let dir = temp_dir("port-file");
+    fs::create_dir_all(&dir).unwrap();
+    let port_file = dir.join("port");
+    let mut server = Command::new(env!("CARGO_BIN_EXE_iris"))
+        .args(["127.0.0.1", "0", "--port-file"])
+        .arg(&port_file)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let port = loop {
+        if let Ok(contents) = fs::read_to_string(&port_file) {
+            break contents.trim().parse::<u16>().unwrap();
+        }
+        assert!(Instant::now() < deadline, "no port file written");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_ne!(port, 0);
+    let mut tom = TestClient::register(([127, 0, 0, 1], port).into(), "tom");
+    tom.send("PING hello");
+    tom.expect(":iris-server PONG iris-server :hello");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_reregistration_is_refused() {
+    let server = start_server(
+        ConnectionManager::launch([127, 0, 0, 1], 0).unwrap(),
+        ServerConfig::default(),
+    );
+    let mut tom = TestClient::register(server.local_addr(), "tom");
+
+    tom.send("USER imposter 0 * :Someone Else");
+    tom.expect(":iris-server 462 :You may not reregister");
+    tom.send("PASS secret");
+    tom.expect(":iris-server 462 :You may not reregister");
+    tom.expect_silence();
+
+    let export = server.export();
+    let users = export.get("users").and_then(Json::as_array).unwrap();
+    assert_eq!(users[0].get("real_name"), Some(&Json::from("tom")));
+}
+
+#[test]
+fn test_changing_nick_before_registering() {
+    let address = spawn_server(ServerConfig::default());
+    let mut first = TestClient::connect(address, "first");
+    first.send("NICK alice");
+    first.send("NICK bob");
+    first.send("USER bob 0 * :bob");
+    first.expect(":iris-server 001 bob :Welcome to this server, bob!");
+
+    // The first choice was given up
+    TestClient::register(address, "alice");
+}
+
+#[test]
+fn test_pending_nicks_collide() {
+    let address = spawn_server(ServerConfig::default());
+    let mut ann = TestClient::connect(address, "ann");
+    let mut bob = TestClient::connect(address, "bob");
+    ann.send("NICK ann");
+    // Unregistered clients get no PONG, but the error shows the NICK is done
+    ann.send("SYNC");
+    ann.expect(":iris-server 421 ann SYNC :Unknown command");
+    bob.send("NICK bob");
+    bob.send("NICK ann");
+    bob.expect(":iris-server 436 :Nickname collision");
+
+    // Bob keeps their first choice after the second one collides
+    bob.send("USER bob 0 * :bob");
+    bob.expect(":iris-server 001 bob :Welcome to this server, bob!");
+    ann.send("USER ann 0 * :ann");
+    ann.expect(":iris-server 001 ann :Welcome to this server, ann!");
+}
+
+#[test]
+fn test_racing_registrations() {
+    let address = spawn_server(ServerConfig::default());
+    for round in 0..10 {
+        let nick = format!("sam{round}");
+        let barrier = Arc::new(Barrier::new(2));
+        let racers: Vec<_> = (0..2)
+            .map(|_| {
+                let (nick, barrier) = (nick.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let mut client = TestClient::connect(address, &nick);
+                    barrier.wait();
+                    client.send(&format!("NICK {nick}"));
+                    client.send(&format!("USER {nick} 0 * :{nick}"));
+                    let line = client.expect_prefix(":iris-server ");
+                    (client, line)
+                })
+            })
+            .collect();
+        // Both stay connected until the end, so neither frees the nick early
+        let (_clients, mut lines): (Vec<_>, Vec<_>) = racers
+            .into_iter()
+            .map(|racer| racer.join().unwrap())
+            .unzip();
+        lines.sort();
+
+        // Exactly one of them gets the nick
+        assert_eq!(
+            lines,
+            [
+                format!(":iris-server 001 {nick} :Welcome to this server, {nick}!"),
+                ":iris-server 436 :Nickname collision".to_string(),
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_concurrent_churn() {
+    let address = spawn_server(ServerConfig::default());
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let churners: Vec<_> = (0..12)
+        .map(|id| {
+            thread::spawn(move || {
+                let mut round = 0;
+                while Instant::now() < deadline {
+                    let nick = format!("churn{id}x{round}");
+                    let mut socket = TcpStream::connect(address).unwrap();
+                    socket
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .unwrap();
+                    let lines = format!(
+                        "NICK {nick}\r\nUSER {nick} 0 * :{nick}\r\n\
+                         JOIN #churn,#churn{id}\r\nPRIVMSG #churn :hi\r\n\
+                         PRIVMSG churn{}x{round} :hello\r\nPART #churn{id}\r\n\
+                         PRIVMSG #churn :bye\r\n",
+                        (id + 1) % 12
+                    );
+                    socket.write_all(lines.as_bytes()).unwrap();
+                    // Every other round leaves without saying goodbye
+                    if round % 2 == 0 {
+                        socket.write_all(b"QUIT :done\r\n").unwrap();
+                    } else {
+                        socket.shutdown(Shutdown::Write).unwrap();
+                    }
+                    // A deadlocked server would never close the connection
+                    let mut received = Vec::new();
+                    socket
+                        .read_to_end(&mut received)
+                        .unwrap_or_else(|err| panic!("{nick} was never let go: {err}"));
+                    round += 1;
+                }
+            })
+        })
+        .collect();
+    for churner in churners {
+        churner.join().unwrap();
+    }
+
+    // Everyone who churned through is gone again
+    let mut tom = TestClient::register(address, "tom");
+    tom.send("JOIN #churn");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #churn");
+    tom.expect_names("#churn", "@tom");
+}
+
+#[test]
+fn test_pending_nick_freed_on_disconnect() {
+    let address = spawn_server(ServerConfig::default());
+    let mut ann = TestClient::connect(address, "ann");
+    let mut bob = TestClient::connect(address, "bob");
+    ann.send("NICK ann");
+    ann.send("SYNC");
+    ann.expect(":iris-server 421 ann SYNC :Unknown command");
+    bob.send("NICK ann");
+    bob.expect(":iris-server 436 :Nickname collision");
+
+    // Nothing is sent when the reservation goes, so keep asking until it has
+    ann.disconnect();
+    let mut attempts = 0;
+    loop {
+        bob.send("NICK ann");
+        bob.send("SYNC");
+        match bob.expect_prefix(":iris-server ").as_str() {
+            ":iris-server 421 ann SYNC :Unknown command" => break,
+            line => assert_eq!(line, ":iris-server 436 :Nickname collision"),
+        }
+        bob.expect(":iris-server 421 * SYNC :Unknown command");
+        attempts += 1;
+        assert!(attempts < 50, "ann's nick was never released");
+        thread::sleep(Duration::from_millis(20));
+    }
+    bob.send("USER bob 0 * :bob");
+    bob.expect(":iris-server 001 ann :Welcome to this server, bob!");
+}
+
+#[test]
+fn test_configured_alias() {
+    let address = spawn_server(ServerConfig {
+        aliases: [("J".to_string(), "JOIN".to_string())].into(),
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    tom.send("j #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("MSG #rust :hello");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :hello");
+    tom.send("LEAVE #rust");
+    tom.expect(":tom!tom@127.0.0.1 PART #rust");
+}
+
+#[test]
+fn test_nick_change() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    tom.send("NICK thomas");
+    tom.expect(":tom!tom@127.0.0.1 NICK thomas");
+    ann.expect(":tom!tom@127.0.0.1 NICK thomas");
+    tom.send("PRIVMSG #rust :hello");
+    tom.expect(":thomas!tom@127.0.0.1 PRIVMSG #rust :hello");
+    ann.expect(":thomas!tom@127.0.0.1 PRIVMSG #rust :hello");
+
+    ann.send("NICK thomas");
+    ann.expect(":iris-server 436 :Nickname collision");
+    ann.send("NICK 9lives");
+    ann.expect(":iris-server 432 :Erroneus nickname");
+
+    // The old nick is free for someone else
+    TestClient::register(address, "tom");
+}
+
+#[test]
+fn test_names_ignore_case() {
+    let address = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::register(address, "Alice");
+    let mut bob = TestClient::register(address, "bob");
+
+    // alice is the same nick as Alice
+    let mut imposter = TestClient::connect(address, "imposter");
+    imposter.send("NICK alice");
+    imposter.expect(":iris-server 436 :Nickname collision");
+
+    alice.send("JOIN #Rust");
+    alice.expect(":Alice!Alice@127.0.0.1 JOIN #Rust");
+    alice.expect_names("#Rust", "@Alice");
+    // The channel keeps the case it was created with
+    bob.send("JOIN #rust");
+    alice.expect(":bob!bob@127.0.0.1 JOIN #Rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #Rust");
+    bob.expect_names("#Rust", "@Alice bob");
+
+    bob.send("PRIVMSG #RUST :hi all");
+    alice.expect(":bob!bob@127.0.0.1 PRIVMSG #Rust :hi all");
+    bob.expect(":bob!bob@127.0.0.1 PRIVMSG #Rust :hi all");
+    bob.send("PRIVMSG ALICE :hi you");
+    alice.expect(":bob!bob@127.0.0.1 PRIVMSG ALICE :hi you");
+
+    // Changing only the case is allowed, and shown to everyone
+    alice.send("NICK alice");
+    alice.expect(":Alice!Alice@127.0.0.1 NICK alice");
+    bob.expect(":Alice!Alice@127.0.0.1 NICK alice");
+    bob.send("NAMES #rust");
+    bob.expect_names("#Rust", "@alice bob");
+
+    bob.send("PART #rUsT");
+    alice.expect(":bob!bob@127.0.0.1 PART #Rust");
+    bob.expect(":bob!bob@127.0.0.1 PART #Rust");
+}
+
+#[test]
+fn test_topic() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("TOPIC #rust");
+    tom.expect(":iris-server 331 tom #rust :No topic is set");
+
+    // Only members may set it, but anyone may ask
+    ann.send("TOPIC #rust :Go is better");
+    ann.expect(":iris-server 442 :You're not on that channel");
+    tom.send("TOPIC #rust :All things Rust");
+    tom.expect(":tom!tom@127.0.0.1 TOPIC #rust :All things Rust");
+    ann.send("TOPIC #rust");
+    ann.expect(":iris-server 332 ann #rust :All things Rust");
+    ann.expect_prefix(":iris-server 333 ann #rust tom ");
+
+    // Joining shows the topic straight after the join, then who is there
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":iris-server 332 ann #rust :All things Rust");
+    ann.expect_prefix(":iris-server 333 ann #rust tom ");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("TOPIC #rust :");
+    tom.expect(":ann!ann@127.0.0.1 TOPIC #rust :");
+    ann.expect(":ann!ann@127.0.0.1 TOPIC #rust :");
+    tom.send("TOPIC #rust");
+    tom.expect(":iris-server 331 tom #rust :No topic is set");
+    tom.send("TOPIC #go");
+    tom.expect(":iris-server 403 :No such channel");
+}
+
+#[test]
+fn test_long_topic_is_truncated() {
+    let address = spawn_server(ServerConfig {
+        topic_len: 10,
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    tom.send("TOPIC #rust :All things Rust, all of the time");
+    tom.expect(":tom!tom@127.0.0.1 TOPIC #rust :All thi…");
+    ann.expect(":tom!tom@127.0.0.1 TOPIC #rust :All thi…");
+    ann.send("TOPIC #rust");
+    ann.expect(":iris-server 332 ann #rust :All thi…");
+}
+
+#[test]
+fn test_names() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #go");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+    tom.send("MODE #rust +v tom");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +v tom");
+
+    // Anyone may ask, members or not
+    ann.send("NAMES #rust");
+    ann.expect_names("#rust", "@tom");
+    ann.send("NAMES #nowhere");
+    ann.expect(":iris-server 366 ann #nowhere :End of /NAMES list");
+    ann.send("NAMES");
+    ann.expect(":iris-server 353 ann = #go :@ann");
+    ann.expect(":iris-server 353 ann = #rust :@tom");
+    ann.expect(":iris-server 366 ann * :End of /NAMES list");
+}
+
+#[test]
+fn test_list() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    ann.send("JOIN #go");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+    tom.send("TOPIC #rust :All things Rust");
+    tom.expect(":tom!tom@127.0.0.1 TOPIC #rust :All things Rust");
+    ann.expect(":tom!tom@127.0.0.1 TOPIC #rust :All things Rust");
+
+    tom.send("LIST");
+    tom.expect(":iris-server 321 tom Channel :Users  Name");
+    tom.expect(":iris-server 322 tom #go 1 :");
+    tom.expect(":iris-server 322 tom #rust 2 :All things Rust");
+    tom.expect(":iris-server 323 tom :End of /LIST");
+
+    tom.send("LIST #go,#nowhere");
+    tom.expect(":iris-server 321 tom Channel :Users  Name");
+    tom.expect(":iris-server 322 tom #go 1 :");
+    tom.expect(":iris-server 323 tom :End of /LIST");
+
+    tom.send("LIST >1");
+    tom.expect(":iris-server 321 tom Channel :Users  Name");
+    tom.expect(":iris-server 322 tom #rust 2 :All things Rust");
+    tom.expect(":iris-server 323 tom :End of /LIST");
+}
+
+#[test]
+fn test_who() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("WHO #rust");
+    ann.expect(":iris-server 352 ann #rust tom 127.0.0.1 iris-server tom H@ :0 tom");
+    ann.expect(":iris-server 352 ann #rust ann 127.0.0.1 iris-server ann H :0 ann");
+    ann.expect(":iris-server 315 ann #rust :End of /WHO list");
+    ann.send("WHO tom");
+    ann.expect(":iris-server 352 ann * tom 127.0.0.1 iris-server tom H :0 tom");
+    ann.expect(":iris-server 315 ann tom :End of /WHO list");
+
+    // Nobody there is still an answer
+    ann.send("WHO #nowhere");
+    ann.expect(":iris-server 315 ann #nowhere :End of /WHO list");
+    ann.send("WHO nobody");
+    ann.expect(":iris-server 315 ann nobody :End of /WHO list");
+}
+
+#[test]
+fn test_whois() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect_prefix(":iris-server 001 tom ");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect(":iris-server 422 :MOTD File is missing");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #go");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+    tom.send("JOIN #go");
+    ann.expect(":tom!tom@127.0.0.1 JOIN #go");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #go");
+    tom.expect_names("#go", "@ann tom");
+
+    ann.send("WHOIS tom");
+    ann.expect(":iris-server 311 ann tom tom 127.0.0.1 * :Tom Smith");
+    ann.expect(":iris-server 319 ann tom :#go @#rust");
+    ann.expect(":iris-server 318 ann tom :End of /WHOIS list");
+
+    ann.send("WHOIS nobody");
+    ann.expect(":iris-server 401 :No such nick/channel");
+    ann.expect(":iris-server 318 ann nobody :End of /WHOIS list");
+}
+
+#[test]
+fn test_away() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("AWAY :Gone to lunch");
+    tom.expect(":iris-server 306 tom :You have been marked as being away");
+    ann.send("PRIVMSG tom :Are you there?");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG tom :Are you there?");
+    ann.expect(":iris-server 301 ann tom :Gone to lunch");
+    ann.send("NOTICE tom :Never mind");
+    tom.expect(":ann!ann@127.0.0.1 NOTICE tom :Never mind");
+    ann.send("WHO tom");
+    ann.expect(":iris-server 352 ann * tom 127.0.0.1 iris-server tom G :0 tom");
+    ann.expect(":iris-server 315 ann tom :End of /WHO list");
+    ann.send("WHOIS tom");
+    ann.expect(":iris-server 311 ann tom tom 127.0.0.1 * :tom");
+    ann.expect(":iris-server 301 ann tom :Gone to lunch");
+    ann.expect(":iris-server 318 ann tom :End of /WHOIS list");
+
+    tom.send("AWAY");
+    tom.expect(":iris-server 305 tom :You are no longer marked as being away");
+    ann.send("PRIVMSG tom :Welcome back");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG tom :Welcome back");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_speaking_and_topic_modes() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("MODE #rust +m");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("MODE #rust +nt-m");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +nt-m");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +nt-m");
+    tom.send("MODE #rust");
+    tom.expect(":iris-server 324 tom #rust +nt");
+
+    bob.send("PRIVMSG #rust :hello from outside");
+    bob.expect(":iris-server 404 :Cannot send to channel");
+    ann.send("TOPIC #rust :All things Rust");
+    ann.expect(":iris-server 482 :You're not channel operator");
+
+    tom.send("MODE #rust +m");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +m");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +m");
+    ann.send("PRIVMSG #rust :can anyone hear me?");
+    ann.expect(":iris-server 404 :Cannot send to channel");
+    tom.send("PRIVMSG #rust :only me");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :only me");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :only me");
+
+    tom.send("MODE #rust +x");
+    tom.expect_prefix(":iris-server 472 ");
+}
+
+#[test]
+fn test_kick() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("KICK #rust tom");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("KICK #rust bob");
+    tom.expect(":iris-server 441 :They aren't on that channel");
+    tom.send("KICK #nowhere ann");
+    tom.expect(":iris-server 403 :No such channel");
+    bob.send("KICK #rust ann");
+    bob.expect(":iris-server 442 :You're not on that channel");
+
+    tom.send("KICK #rust ann :Stop spamming");
+    tom.expect(":tom!tom@127.0.0.1 KICK #rust ann :Stop spamming");
+    ann.expect(":tom!tom@127.0.0.1 KICK #rust ann :Stop spamming");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    tom.send("KICK #rust ann");
+    tom.expect(":tom!tom@127.0.0.1 KICK #rust ann :tom");
+    ann.expect(":tom!tom@127.0.0.1 KICK #rust ann :tom");
+    tom.send("PRIVMSG #rust :just me now");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :just me now");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_invite() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("MODE #rust +i");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +i");
+
+    ann.send("JOIN #rust");
+    ann.expect(":iris-server 473 :Cannot join channel (+i)");
+    tom.send("INVITE ann #rust");
+    tom.expect(":iris-server 341 tom ann #rust");
+    ann.expect(":tom!tom@127.0.0.1 INVITE ann :#rust");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    tom.send("INVITE ann #rust");
+    tom.expect(":iris-server 443 :is already on channel");
+    ann.send("INVITE tom #rust");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("INVITE nobody #rust");
+    tom.expect(":iris-server 401 :No such nick/channel");
+
+    // The invitation was used up
+    ann.send("PART #rust");
+    tom.expect(":ann!ann@127.0.0.1 PART #rust");
+    ann.expect(":ann!ann@127.0.0.1 PART #rust");
+    ann.send("JOIN #rust");
+    ann.expect(":iris-server 473 :Cannot join channel (+i)");
+}
+
+#[test]
+fn test_motd() {
+    let address = spawn_server(ServerConfig {
+        motd: Some(vec!["Welcome to iris!".to_string(), "Be nice.".to_string()]),
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect_prefix(":iris-server 001 tom ");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    let motd = [
+        ":iris-server 375 tom :- iris-server Message of the day - ",
+        ":iris-server 372 tom :- Welcome to iris!",
+        ":iris-server 372 tom :- Be nice.",
+        ":iris-server 376 tom :End of /MOTD command.",
+    ];
+    motd.iter().for_each(|line| tom.expect(line));
+
+    tom.send("MOTD");
+    motd.iter().for_each(|line| tom.expect(line));
+}
+
+#[test]
+fn test_relayed_messages_carry_full_prefix() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("NICK tom");
+    tom.send("USER tsmith 0 * :Tom Smith");
+    tom.expect_prefix(":iris-server 001 tom ");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect(":iris-server 422 :MOTD File is missing");
+    let mut ann = TestClient::register(address, "ann");
+
+    // The username comes from USER, not the nick
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tsmith@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    tom.send("PRIVMSG ann :hi");
+    ann.expect(":tom!tsmith@127.0.0.1 PRIVMSG ann :hi");
+    tom.send("TOPIC #rust :Rust talk");
+    tom.expect(":tom!tsmith@127.0.0.1 TOPIC #rust :Rust talk");
+    ann.expect(":tom!tsmith@127.0.0.1 TOPIC #rust :Rust talk");
+    tom.send("KICK #rust ann :Bye");
+    tom.expect(":tom!tsmith@127.0.0.1 KICK #rust ann :Bye");
+    ann.expect(":tom!tsmith@127.0.0.1 KICK #rust ann :Bye");
+}
+
+#[test]
+fn test_lusers() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.send("QUIT");
+    tom.expect_prefix(":ann!ann@127.0.0.1 QUIT");
+    tom.send("LUSERS");
+    tom.expect(":iris-server 251 tom :There are 1 users and 0 invisible on 1 servers");
+    tom.expect(":iris-server 254 tom 1 :channels formed");
+    tom.expect(":iris-server 255 tom :I have 1 clients and 0 servers");
+    // The peak is remembered after ann leaves
+    tom.expect(":iris-server 265 tom 1 2 :Current local users 1, max 2");
+    tom.expect(":iris-server 266 tom 1 2 :Current global users 1, max 2");
+}
+
+#[test]
+fn test_version_time_info() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("VERSION");
+    tom.expect(":iris-server 451 :You have not registered");
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect_prefix(":iris-server 001 tom ");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect(":iris-server 422 :MOTD File is missing");
+
+    tom.send("VERSION");
+    tom.expect(&format!(
+        ":iris-server 351 tom iris-{} iris-server :",
+        env!("CARGO_PKG_VERSION")
+    ));
+    tom.send("TIME");
+    tom.expect_prefix(":iris-server 391 tom iris-server :");
+    tom.send("INFO");
+    tom.expect_prefix(":iris-server 371 tom :iris ");
+    tom.expect_prefix(":iris-server 371 tom :");
+    tom.expect(":iris-server 371 tom :Running as iris-server.");
+    tom.expect(":iris-server 374 tom :End of /INFO list.");
+}
+
+#[test]
+fn test_ison_userhost() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    ann.send("AWAY :Gone to lunch");
+    ann.expect(":iris-server 306 ann :You have been marked as being away");
+
+    tom.send("ISON ann bob tom");
+    tom.expect(":iris-server 303 tom :ann tom");
+    tom.send("ISON :bob");
+    tom.expect(":iris-server 303 tom :");
+    tom.send("USERHOST tom ann bob");
+    tom.expect(":iris-server 302 tom :tom=+tom@127.0.0.1 ann=-ann@127.0.0.1");
+    tom.send("ISON");
+    tom.expect(":iris-server 461 :Not enough parameters");
+}
+
+#[test]
+fn test_oper() {
+    let address = spawn_server(ServerConfig {
+        operators: vec![Operator::new("admin", "hunter2")],
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("OPER admin hunter3");
+    tom.expect(":iris-server 464 :Password incorrect");
+    tom.send("OPER tom hunter2");
+    tom.expect(":iris-server 464 :Password incorrect");
+    tom.send("OPER admin");
+    tom.expect(":iris-server 461 :Not enough parameters");
+
+    tom.send("OPER admin hunter2");
+    tom.expect(":iris-server 381 tom :You are now an IRC operator");
+    // Operators show as such to everyone else
+    ann.send("WHO tom");
+    ann.expect(":iris-server 352 ann * tom 127.0.0.1 iris-server tom H* :0 tom");
+    ann.expect(":iris-server 315 ann tom :End of /WHO list");
+}
+
+#[test]
+fn test_kill() {
+    let address = spawn_server(ServerConfig {
+        operators: vec![Operator::new("admin", "hunter2")],
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    bob.send("JOIN #rust");
+    tom.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect_names("#rust", "@tom bob");
+
+    bob.send("KILL tom :Spamming");
+    bob.expect(":iris-server 481 :Permission Denied- You're not an IRC operator");
+    ann.send("OPER admin hunter2");
+    ann.expect(":iris-server 381 ann :You are now an IRC operator");
+    ann.send("KILL nobody");
+    ann.expect(":iris-server 401 :No such nick/channel");
+
+    ann.send("KILL tom :Spamming");
+    tom.expect("ERROR :Closing Link: tom (Killed (ann (Spamming)))");
+    tom.expect_closed();
+    bob.expect(":tom!tom@127.0.0.1 QUIT :Killed (ann (Spamming))");
+    // tom is gone, and their nick is free again
+    ann.send("ISON tom bob");
+    ann.expect(":iris-server 303 ann :bob");
+    let mut tom = TestClient::register(address, "tom");
+    tom.send("ISON tom");
+    tom.expect(":iris-server 303 tom :tom");
+}
+
+#[test]
+fn test_wallops() {
+    let address = spawn_server(ServerConfig {
+        operators: vec![Operator::new("admin", "hunter2")],
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("MODE tom +w");
+    tom.expect(":tom!tom@127.0.0.1 MODE tom +w");
+    tom.send("MODE tom");
+    tom.expect(":iris-server 221 tom +w");
+
+    bob.send("WALLOPS :Hello everyone");
+    bob.expect(":iris-server 481 :Permission Denied- You're not an IRC operator");
+    ann.send("OPER admin hunter2");
+    ann.expect(":iris-server 381 ann :You are now an IRC operator");
+    ann.send("WALLOPS :Restarting soon");
+    tom.expect(":ann!ann@127.0.0.1 WALLOPS :Restarting soon");
+    // Only +w users hear it, the sender included
+    ann.expect_silence();
+    bob.expect_silence();
+}
+
+#[test]
+fn test_quit_reaches_each_neighbour_once() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    for channel in ["#rust", "#go"] {
+        tom.send(&format!("JOIN {channel}"));
+        tom.expect(&format!(":tom!tom@127.0.0.1 JOIN {channel}"));
+        tom.expect_names(channel, "@tom");
+        ann.send(&format!("JOIN {channel}"));
+        tom.expect(&format!(":ann!ann@127.0.0.1 JOIN {channel}"));
+    }
+    ann.send("QUIT :Bye");
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Bye");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_server_password() {
+    let address = spawn_server(ServerConfig {
+        password: Some("hunter2".to_string()),
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("PASS hunter2");
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :Tom Smith");
+    tom.expect_prefix(":iris-server 001 tom ");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect(":iris-server 422 :MOTD File is missing");
+    tom.send("PASS hunter2");
+    tom.expect(":iris-server 462 :You may not reregister");
+
+    // Three wrong or missing passwords and they are gone
+    let mut ann = TestClient::connect(address, "ann");
+    ann.send("PASS hunter3");
+    ann.expect(":iris-server 464 :Password incorrect");
+    ann.send("NICK ann");
+    ann.send("USER ann 0 * :Ann Smith");
+    ann.expect(":iris-server 464 :Password incorrect");
+    ann.send("USER ann 0 * :Ann Smith");
+    ann.expect(":iris-server 464 :Password incorrect");
+    ann.expect("ERROR :Closing Link: * (Bad password)");
+    ann.expect_closed();
+
+    // Their nick wasn't kept for them
+    let mut ann = TestClient::connect(address, "ann");
+    ann.send("PASS hunter2");
+    ann.send("NICK ann");
+    ann.send("USER ann 0 * :Ann Smith");
+    ann.expect_prefix(":iris-server 001 ann ");
+}
+
+#[test]
+fn test_join_several_channels() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    ann.send("JOIN #go");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+
+    // A bad name in the middle doesn't stop the rest
+    tom.send("JOIN #rust,rust,#go key1,key2");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.expect(":iris-server 476 :Bad Channel Mask");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #go");
+    tom.expect_names("#go", "@ann tom");
+    ann.expect(":tom!tom@127.0.0.1 JOIN #go");
+
+    // Channels are left in order
+    tom.send("JOIN 0");
+    tom.expect(":tom!tom@127.0.0.1 PART #go");
+    tom.expect(":tom!tom@127.0.0.1 PART #rust");
+    ann.expect(":tom!tom@127.0.0.1 PART #go");
+    tom.send("NAMES #go");
+    tom.expect_names("#go", "@ann");
+}
+
+#[test]
+fn test_bad_channel_names() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let too_long = format!("#{}", "a".repeat(50));
+    for name in ["rust", "#ding\x07", too_long.as_str()] {
+        tom.send(&format!("JOIN {name}"));
+        tom.expect(":iris-server 476 :Bad Channel Mask");
+    }
+    tom.send("PRIVMSG #ding\x07 :hello");
+    tom.expect(":iris-server 403 :No such channel");
+    tom.send("PART rust");
+    tom.expect(":iris-server 403 :No such channel");
+    tom.send("LIST");
+    tom.expect(":iris-server 321 tom Channel :Users  Name");
+    tom.expect(":iris-server 323 tom :End of /LIST");
+
+    // Punctuation other than commas is fine
+    tom.send("JOIN #rust-lang");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust-lang");
+    tom.expect_names("#rust-lang", "@tom");
+}
+
+#[test]
+fn test_part_several_channels() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    for channel in ["#rust", "#go"] {
+        tom.send(&format!("JOIN {channel}"));
+        tom.expect(&format!(":tom!tom@127.0.0.1 JOIN {channel}"));
+        tom.expect_names(channel, "@tom");
+        ann.send(&format!("JOIN {channel}"));
+        tom.expect(&format!(":ann!ann@127.0.0.1 JOIN {channel}"));
+        ann.expect(&format!(":ann!ann@127.0.0.1 JOIN {channel}"));
+        ann.expect_names(channel, "@tom ann");
+    }
+
+    ann.send("PART #rust,#nowhere,#go :Gone for lunch");
+    tom.expect(":ann!ann@127.0.0.1 PART #rust :Gone for lunch");
+    ann.expect(":ann!ann@127.0.0.1 PART #rust :Gone for lunch");
+    ann.expect(":iris-server 403 :No such channel");
+    tom.expect(":ann!ann@127.0.0.1 PART #go :Gone for lunch");
+    ann.expect(":ann!ann@127.0.0.1 PART #go :Gone for lunch");
+
+    ann.send("PART #rust");
+    ann.expect(":iris-server 442 :You're not on that channel");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_ping_timeout() {
+    let clock = Arc::new(ManualClock::default());
+    let address = spawn_server_with_clock(ServerConfig::default(), clock.clone());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    // Anything sent puts off the ping
+    clock.advance(Duration::from_secs(30));
+    ann.send("PING :still here");
+    ann.expect(":iris-server PONG iris-server :still here");
+    clock.advance(Duration::from_secs(30));
+    tom.expect("PING :iris-server");
+    ann.expect_silence();
+
+    clock.advance(Duration::from_secs(30));
+    ann.expect("PING :iris-server");
+    ann.send("PONG :iris-server");
+    // Sent after the PONG, so answered once it has been read
+    ann.send("PING :sync");
+    ann.expect(":iris-server PONG iris-server :sync");
+
+    // Tom never answered
+    clock.advance(Duration::from_secs(30));
+    tom.expect("ERROR :Closing Link: tom (Ping timeout: 60 seconds)");
+    tom.expect_closed();
+    ann.expect(":tom!tom@127.0.0.1 QUIT :Ping timeout: 60 seconds");
+    ann.expect_silence();
+}
+
+#[test]
+fn test_pong_is_accepted_silently() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::connect(address, "tom");
+    tom.send("PONG :early");
+    tom.expect_silence();
+    tom.send("NICK tom");
+    tom.send("USER tom 0 * :tom");
+    tom.expect(":iris-server 001 tom :Welcome to this server, tom!");
+    tom.expect_prefix(":iris-server 002 tom ");
+    tom.expect_prefix(":iris-server 003 tom ");
+    tom.expect_prefix(":iris-server 004 tom ");
+    tom.expect_prefix(":iris-server 005 tom ");
+    tom.expect(":iris-server 422 :MOTD File is missing");
+
+    tom.send("PONG :token");
+    tom.send("PONG iris-server");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_channel_key() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("MODE #rust +k secret");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +k secret");
+
+    // Only members are shown the key
+    tom.send("MODE #rust");
+    tom.expect(":iris-server 324 tom #rust +nk secret");
+    ann.send("MODE #rust");
+    ann.expect(":iris-server 324 ann #rust +nk");
+    ann.send("LIST");
+    ann.expect(":iris-server 321 ann Channel :Users  Name");
+    ann.expect(":iris-server 322 ann #rust 1 :");
+    ann.expect(":iris-server 323 ann :End of /LIST");
+
+    ann.send("JOIN #rust");
+    ann.expect(":iris-server 475 :Cannot join channel (+k)");
+    ann.send("JOIN #rust wrong");
+    ann.expect(":iris-server 475 :Cannot join channel (+k)");
+    ann.send("JOIN #go,#rust x,secret");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #go");
+    ann.expect_names("#go", "@ann");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    ann.send("MODE #rust +k mine");
+    ann.expect(":iris-server 482 :You're not channel operator");
+
+    // A new key replaces the old one, and -k needs no key
+    tom.send("MODE #rust +k newer");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +k newer");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +k newer");
+    tom.send("MODE #rust -k");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust -k *");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust -k *");
+    tom.send("MODE #rust");
+    tom.expect(":iris-server 324 tom #rust +n");
+}
+
+#[test]
+fn test_channel_limit() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("MODE #rust +l none");
+    tom.expect(":iris-server 696 :Invalid mode parameter");
+    tom.send("MODE #rust +l 0");
+    tom.expect(":iris-server 696 :Invalid mode parameter");
+    tom.send("MODE #rust +l 2");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +l 2");
+    tom.send("MODE #rust");
+    tom.expect(":iris-server 324 tom #rust +nl 2");
+
+    // Several users race for the last place, and only one gets it
+    let mut racers: Vec<_> = ["ann", "bob", "cat", "dan"]
+        .into_iter()
+        .map(|nick| (nick, TestClient::register(address, nick)))
+        .collect();
+    for (_, racer) in &mut racers {
+        racer.send("JOIN #rust");
+    }
+    let mut winners = Vec::new();
+    for (nick, racer) in &mut racers {
+        let line = racer.expect_prefix(":");
+        if line == format!(":{nick}!{nick}@127.0.0.1 JOIN #rust") {
+            winners.push(nick.to_string());
+        } else {
+            assert_eq!(line, ":iris-server 471 :Cannot join channel (+l)");
+        }
+    }
+    assert_eq!(winners.len(), 1);
+    tom.expect(&format!(":{0}!{0}@127.0.0.1 JOIN #rust", winners[0]));
+    tom.expect_silence();
+
+    tom.send("MODE #rust -l");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust -l");
+    let (nick, racer) = racers
+        .iter_mut()
+        .find(|(nick, _)| *nick != winners[0])
+        .unwrap();
+    racer.send("JOIN #rust");
+    racer.expect(&format!(":{nick}!{nick}@127.0.0.1 JOIN #rust"));
+}
+
+#[test]
+fn test_channel_bans() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("MODE #rust +b tom");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("MODE #rust +b ann");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +b ann!*@*");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +b ann!*@*");
+    tom.send("MODE #rust +b *!robert@127.0.0.*");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +b *!robert@127.0.0.*");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +b *!robert@127.0.0.*");
+
+    // Anyone can see the list
+    ann.send("MODE #rust +b");
+    ann.expect(":iris-server 367 ann #rust ann!*@*");
+    ann.expect(":iris-server 367 ann #rust *!robert@127.0.0.*");
+    ann.expect(":iris-server 368 ann #rust :End of channel ban list");
+
+    // Banned members stay, but can't speak, and can't come back
+    ann.send("PRIVMSG #rust :let me in");
+    ann.expect(":iris-server 404 :Cannot send to channel");
+    tom.expect_silence();
+    ann.send("PART #rust");
+    tom.expect(":ann!ann@127.0.0.1 PART #rust");
+    ann.expect(":ann!ann@127.0.0.1 PART #rust");
+    ann.send("JOIN #rust");
+    ann.expect(":iris-server 474 :Cannot join channel (+b)");
+
+    // Usernames come from USER
+    let mut bob = TestClient::connect(address, "bob");
+    bob.send("NICK bob");
+    bob.send("USER robert 0 * :Bob");
+    bob.expect(":iris-server 001 bob :Welcome to this server, Bob!");
+    bob.expect_prefix(":iris-server 002 bob ");
+    bob.expect_prefix(":iris-server 003 bob ");
+    bob.expect_prefix(":iris-server 004 bob ");
+    bob.expect_prefix(":iris-server 005 bob ");
+    bob.expect(":iris-server 422 :MOTD File is missing");
+    bob.send("JOIN #rust");
+    bob.expect(":iris-server 474 :Cannot join channel (+b)");
+
+    tom.send("MODE #rust -b ANN!*@*");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust -b ANN!*@*");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+}
+
+#[test]
+fn test_moderated_channel_with_voice() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    bob.send("JOIN #rust");
+    tom.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    ann.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect(":bob!bob@127.0.0.1 JOIN #rust");
+    bob.expect_names("#rust", "@tom ann bob");
+
+    ann.send("MODE #rust +v bob");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("MODE #rust +mv ann");
+    for client in [&mut tom, &mut ann, &mut bob] {
+        client.expect(":tom!tom@127.0.0.1 MODE #rust +mv ann");
+    }
+    bob.send("NAMES #rust");
+    bob.expect_names("#rust", "@tom +ann bob");
+
+    tom.send("PRIVMSG #rust :ops can talk");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :ops can talk");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :ops can talk");
+    bob.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :ops can talk");
+    ann.send("PRIVMSG #rust :so can voices");
+    ann.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :so can voices");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :so can voices");
+    bob.expect(":ann!ann@127.0.0.1 PRIVMSG #rust :so can voices");
+    bob.send("PRIVMSG #rust :and me?");
+    bob.expect(":iris-server 404 :Cannot send to channel");
+    tom.expect_silence();
+    ann.expect_silence();
+
+    // Voice doesn't survive leaving
+    ann.send("PART #rust");
+    for client in [&mut tom, &mut ann, &mut bob] {
+        client.expect(":ann!ann@127.0.0.1 PART #rust");
+    }
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    bob.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom bob ann");
+    ann.send("PRIVMSG #rust :hello again");
+    ann.expect(":iris-server 404 :Cannot send to channel");
+    tom.expect_silence();
+}
+
+#[test]
+fn test_secret_channels() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("JOIN #go");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #go");
+    tom.expect_names("#go", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    ann.send("MODE #rust +s");
+    ann.expect(":iris-server 482 :You're not channel operator");
+    tom.send("MODE #rust +s-n");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust +s-n");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust +s-n");
+
+    // Members see the channel everywhere
+    ann.send("MODE #rust");
+    ann.expect(":iris-server 324 ann #rust +s");
+    ann.send("LIST");
+    ann.expect(":iris-server 321 ann Channel :Users  Name");
+    ann.expect(":iris-server 322 ann #go 1 :");
+    ann.expect(":iris-server 322 ann #rust 2 :");
+    ann.expect(":iris-server 323 ann :End of /LIST");
+    ann.send("NAMES");
+    ann.expect(":iris-server 353 ann = #go :@tom");
+    ann.expect(":iris-server 353 ann = #rust :@tom ann");
+    ann.expect(":iris-server 366 ann * :End of /NAMES list");
+    ann.send("WHOIS tom");
+    ann.expect_prefix(":iris-server 311 ann tom ");
+    ann.expect(":iris-server 319 ann tom :@#go @#rust");
+    ann.expect(":iris-server 318 ann tom :End of /WHOIS list");
+
+    // Everyone else doesn't
+    bob.send("MODE #rust");
+    bob.expect(":iris-server 324 bob #rust +");
+    bob.send("LIST");
+    bob.expect(":iris-server 321 bob Channel :Users  Name");
+    bob.expect(":iris-server 322 bob #go 1 :");
+    bob.expect(":iris-server 323 bob :End of /LIST");
+    bob.send("LIST #rust");
+    bob.expect(":iris-server 321 bob Channel :Users  Name");
+    bob.expect(":iris-server 323 bob :End of /LIST");
+    bob.send("NAMES");
+    bob.expect(":iris-server 353 bob = #go :@tom");
+    bob.expect(":iris-server 366 bob * :End of /NAMES list");
+    bob.send("NAMES #rust");
+    bob.expect(":iris-server 366 bob #rust :End of /NAMES list");
+    bob.send("WHOIS tom");
+    bob.expect_prefix(":iris-server 311 bob tom ");
+    bob.expect(":iris-server 319 bob tom :@#go");
+    bob.expect(":iris-server 318 bob tom :End of /WHOIS list");
+
+    // Messages still get through
+    bob.send("PRIVMSG #rust :anyone there?");
+    tom.expect(":bob!bob@127.0.0.1 PRIVMSG #rust :anyone there?");
+    ann.expect(":bob!bob@127.0.0.1 PRIVMSG #rust :anyone there?");
+
+    tom.send("MODE #rust -s");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust -s");
+    ann.expect(":tom!tom@127.0.0.1 MODE #rust -s");
+    bob.send("LIST #rust");
+    bob.expect(":iris-server 321 bob Channel :Users  Name");
+    bob.expect(":iris-server 322 bob #rust 2 :");
+    bob.expect(":iris-server 323 bob :End of /LIST");
+}
+
+#[test]
+fn test_new_channels_refuse_outside_messages() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    tom.send("MODE #rust");
+    tom.expect(":iris-server 324 tom #rust +n");
+
+    bob.send("PRIVMSG #rust :hello from outside");
+    bob.expect(":iris-server 404 :Cannot send to channel");
+    tom.expect_silence();
+
+    tom.send("MODE #rust -n");
+    tom.expect(":tom!tom@127.0.0.1 MODE #rust -n");
+    bob.send("PRIVMSG #rust :hello from outside");
+    tom.expect(":bob!bob@127.0.0.1 PRIVMSG #rust :hello from outside");
+    bob.expect_silence();
+}
+
+#[test]
+fn test_invisible_users() {
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    let mut bob = TestClient::register(address, "bob");
+    tom.send("MODE ann +i");
+    tom.expect(":iris-server 502 :Cannot change mode for other users");
+    tom.send("MODE tom +i");
+    tom.expect(":tom!tom@127.0.0.1 MODE tom +i");
+    tom.send("MODE tom");
+    tom.expect(":iris-server 221 tom +i");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+
+    // Sharing a channel, ann still sees tom
+    ann.send("WHO tom");
+    ann.expect(":iris-server 352 ann * tom 127.0.0.1 iris-server tom H :0 tom");
+    ann.expect(":iris-server 315 ann tom :End of /WHO list");
+    ann.send("NAMES");
+    ann.expect(":iris-server 353 ann = #rust :@tom ann");
+    ann.expect(":iris-server 366 ann * :End of /NAMES list");
+
+    // bob doesn't
+    bob.send("WHO tom");
+    bob.expect(":iris-server 315 bob tom :End of /WHO list");
+    bob.send("WHO #rust");
+    bob.expect(":iris-server 352 bob #rust ann 127.0.0.1 iris-server ann H :0 ann");
+    bob.expect(":iris-server 315 bob #rust :End of /WHO list");
+    bob.send("NAMES");
+    bob.expect(":iris-server 353 bob = #rust :ann");
+    bob.expect(":iris-server 366 bob * :End of /NAMES list");
+    bob.send("LUSERS");
+    bob.expect(":iris-server 251 bob :There are 2 users and 1 invisible on 1 servers");
+    bob.expect(":iris-server 254 bob 1 :channels formed");
+    bob.expect(":iris-server 255 bob :I have 3 clients and 0 servers");
+    bob.expect_prefix(":iris-server 265 bob 3 ");
+    bob.expect_prefix(":iris-server 266 bob 3 ");
+
+    tom.send("MODE tom -i");
+    tom.expect(":tom!tom@127.0.0.1 MODE tom -i");
+    bob.send("WHO tom");
+    bob.expect(":iris-server 352 bob * tom 127.0.0.1 iris-server tom H :0 tom");
+    bob.expect(":iris-server 315 bob tom :End of /WHO list");
+}
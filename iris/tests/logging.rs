@@ -0,0 +1,68 @@
+//! What ends up in the server's log. Loggers are process-wide, so this gets
+//! a test binary of its own.
+
+mod common;
+
+use std::{sync::Mutex, thread, time::Duration};
+
+use common::{spawn_server, TestClient};
+use iris_lib::config::ServerConfig;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Keeps every line logged, for the test to look through.
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.lines.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+#[test]
+fn test_message_contents_are_not_logged() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let address = spawn_server(ServerConfig::default());
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    tom.send("PRIVMSG #rust :secret plans");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :secret plans");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :secret plans");
+    ann.send("PRIVMSG tom :more secrets");
+    tom.expect(":ann!ann@127.0.0.1 PRIVMSG tom :more secrets");
+
+    // The sender logs after writing, so give it a moment
+    thread::sleep(Duration::from_millis(100));
+    let lines = LOGGER.lines.lock().unwrap().clone();
+    let logged = |expected: &str| lines.iter().any(|line| line.trim_end() == expected);
+    assert!(logged("Received from tom: PRIVMSG #rust :<12 bytes>"));
+    assert!(logged(
+        "Sent to ann: :tom!tom@127.0.0.1 PRIVMSG #rust :<12 bytes>"
+    ));
+    assert!(logged(
+        "Sent to tom: :ann!ann@127.0.0.1 PRIVMSG tom :<12 bytes>"
+    ));
+    assert!(!lines
+        .iter()
+        .any(|line| line.contains("secret plans") || line.contains("more secrets")));
+}
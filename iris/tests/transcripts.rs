@@ -0,0 +1,121 @@
+//! Tests that record transcripts from a real server and replay them.
+
+mod common;
+
+use std::{fs, path::Path, thread, time::Duration};
+
+use common::{spawn_server, temp_dir, TestClient};
+use iris_lib::{
+    config::ServerConfig,
+    transcript::{read_transcript, replay, Divergence, Entry, TranscriptConfig},
+};
+
+/// Reads every transcript in `dir`, in file name order.
+fn read_transcripts(dir: &Path) -> Vec<Vec<Entry>> {
+    let mut paths = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| read_transcript(path).unwrap())
+        .collect()
+}
+
+/// Records a short conversation between two clients into `dir`.
+fn record_conversation(dir: &Path, redact: bool) {
+    let address = spawn_server(ServerConfig {
+        transcript: Some(TranscriptConfig {
+            dir: dir.to_path_buf(),
+            redact,
+        }),
+        log_message_contents: true,
+        ..ServerConfig::default()
+    });
+    let mut tom = TestClient::register(address, "tom");
+    let mut ann = TestClient::register(address, "ann");
+
+    tom.send("JOIN #rust");
+    tom.expect(":tom!tom@127.0.0.1 JOIN #rust");
+    tom.expect_names("#rust", "@tom");
+    ann.send("JOIN #rust");
+    tom.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect(":ann!ann@127.0.0.1 JOIN #rust");
+    ann.expect_names("#rust", "@tom ann");
+    tom.send("PRIVMSG #rust :secret plans");
+    tom.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :secret plans");
+    ann.expect(":tom!tom@127.0.0.1 PRIVMSG #rust :secret plans");
+    ann.send("QUIT :Bye");
+    tom.expect(":ann!ann@127.0.0.1 QUIT :Bye");
+    tom.send("QUIT");
+    tom.expect_closed();
+
+    // Give the writer thread a moment to catch up
+    thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn test_record_and_replay() {
+    let dir = temp_dir("record-and-replay");
+    record_conversation(&dir, false);
+
+    let transcripts = read_transcripts(&dir);
+    assert_eq!(transcripts.len(), 2);
+    assert!(transcripts[0]
+        .iter()
+        .any(|entry| entry.line == "PRIVMSG #rust :secret plans"));
+
+    let address = spawn_server(ServerConfig::default());
+    assert_eq!(replay(address, &transcripts).unwrap(), vec![]);
+}
+
+#[test]
+fn test_redacted_transcript() {
+    let dir = temp_dir("redacted-transcript");
+    record_conversation(&dir, true);
+
+    let transcripts = read_transcripts(&dir);
+    let lines = transcripts
+        .iter()
+        .flatten()
+        .map(|entry| entry.line.as_str())
+        .collect::<Vec<_>>();
+    assert!(lines.contains(&"PRIVMSG #rust :<12 bytes>"));
+    assert!(lines.contains(&":tom!tom@127.0.0.1 PRIVMSG #rust :<12 bytes>"));
+    assert!(!lines.iter().any(|line| line.contains("secret plans")));
+}
+
+#[test]
+fn test_replay_reports_divergence() {
+    let mut transcripts = read_transcripts(Path::new("tests/transcripts/channel-chat"));
+    let welcome = transcripts[0]
+        .iter_mut()
+        .find(|entry| entry.line.contains(" 001 "))
+        .unwrap();
+    let actual = welcome.line.clone();
+    let altered = ":iris-server 001 ann :Welcome to another server, ann!".to_string();
+    welcome.line = altered.clone();
+
+    let address = spawn_server(ServerConfig::default());
+    assert_eq!(
+        replay(address, &transcripts).unwrap(),
+        vec![Divergence {
+            transcript: 0,
+            expected: Some(altered),
+            actual: Some(actual),
+        }]
+    );
+}
+
+/// Every directory under `tests/transcripts` is a recorded session that the
+/// server must keep replying to in exactly the same way.
+#[test]
+fn test_recorded_sessions() {
+    for session in fs::read_dir("tests/transcripts").unwrap() {
+        let session = session.unwrap().path();
+        let address = spawn_server(ServerConfig::default());
+        let divergences = replay(address, &read_transcripts(&session)).unwrap();
+        assert_eq!(divergences, vec![], "{} diverged", session.display());
+    }
+}